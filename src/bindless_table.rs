@@ -0,0 +1,661 @@
+use super::{DeviceContext, HalResultExt, LoadedImage, RenderError, RetirementQueue};
+use core::mem::ManuallyDrop;
+use gfx_hal::{
+    adapter::{Adapter, PhysicalDevice},
+    device::Device,
+    image::{Filter, Layout, SamplerInfo, WrapMode},
+    pso::{
+        Descriptor, DescriptorPoolCreateFlags, DescriptorRangeDesc, DescriptorSetLayoutBinding,
+        DescriptorSetWrite, DescriptorType, ShaderStageFlags,
+    },
+    Backend, DescriptorPool, Features,
+};
+use std::{mem, ops::Deref, sync::Arc};
+
+/// Binding of the sampled image array in the table's descriptor set.
+pub const BINDLESS_IMAGE_BINDING: u32 = 0;
+/// Binding of the sampler array in the table's descriptor set.
+pub const BINDLESS_SAMPLER_BINDING: u32 = 1;
+
+/// A descriptor set holding an array of sampled images and a handful of
+/// samplers. Register a texture and you get back its slot in the image array;
+/// push that (and which sampler you want) to the shader and you can draw any
+/// number of textures without ever rebinding a set.
+///
+/// There's one copy of the set per frame in flight, so registering a texture never
+/// touches a set the GPU might still be reading. Changes reach a frame's copy when
+/// `descriptor_set` hands it out. Slots with no texture in them point at the
+/// default texture, so a stray index samples that instead of a destroyed image.
+///
+/// In GLSL, the set looks like:
+///
+/// ```glsl
+/// layout(set = 0, binding = 0) uniform texture2D textures[CAPACITY];
+/// layout(set = 0, binding = 1) uniform sampler samplers[SAMPLER_COUNT];
+/// ```
+pub struct BindlessTable<B: Backend> {
+    pub descriptor_set_layout: ManuallyDrop<B::DescriptorSetLayout>,
+    pub descriptor_pool: ManuallyDrop<B::DescriptorPool>,
+    descriptor_sets: Vec<B::DescriptorSet>,
+    //  Slots that changed since each set was last handed out
+    stale_slots: Vec<Vec<u32>>,
+    pub samplers: Vec<B::Sampler>,
+    pub filters: Vec<Filter>,
+    pub stage_flags: ShaderStageFlags,
+    pub capacity: u32,
+    default_texture: LoadedImage<B>,
+    textures: Vec<Option<LoadedImage<B>>>,
+    free_slots: Vec<u32>,
+    retired: RetirementQueue<(u32, LoadedImage<B>)>,
+    pub device: Arc<DeviceContext<B>>,
+}
+
+impl<B: Backend> BindlessTable<B> {
+    /// Makes a table with room for `capacity` textures, and one sampler per
+    /// entry in `filters`. The index of a filter in `filters` is the index of
+    /// its sampler in the shader. Empty slots show `default_texture`.
+    ///
+    /// The device has to support dynamically indexing sampled image arrays.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        capacity: u32,
+        filters: &[Filter],
+        stage_flags: ShaderStageFlags,
+        frames_in_flight: usize,
+        default_texture: LoadedImage<B>,
+    ) -> Result<Self, failure::Error> {
+        if !adapter
+            .physical_device
+            .features()
+            .contains(Features::SHADER_SAMPLED_IMAGE_ARRAY_DYNAMIC_INDEXING)
+        {
+            return Err(RenderError::unsupported(
+                "BindlessTable",
+                "This device can't dynamically index arrays of sampled images!",
+            )
+            .into());
+        }
+        let limits = adapter.physical_device.limits();
+        let max_images = limits
+            .max_per_stage_descriptor_sampled_images
+            .min(limits.max_descriptor_set_sampled_images);
+        if capacity == 0 || capacity as usize > max_images {
+            bail_invalid!(
+                "BindlessTable",
+                "A bindless table needs between 1 and {} textures, but we asked for {}!",
+                max_images,
+                capacity
+            );
+        }
+        if filters.is_empty() || filters.len() > limits.max_per_stage_descriptor_samplers {
+            bail_invalid!(
+                "BindlessTable",
+                "A bindless table needs between 1 and {} samplers, but we asked for {}!",
+                limits.max_per_stage_descriptor_samplers,
+                filters.len()
+            );
+        }
+        if frames_in_flight == 0 {
+            bail_invalid!(
+                "BindlessTable",
+                "A bindless table needs at least one frame in flight!"
+            );
+        }
+
+        let sampler_count = filters.len();
+        unsafe {
+            let mut partial: PartialTable<'_, B> = PartialTable {
+                device: &device.device,
+                samplers: Vec::with_capacity(sampler_count),
+                descriptor_set_layout: None,
+                descriptor_pool: None,
+            };
+
+            for &filter in filters {
+                partial.samplers.push(
+                    device
+                        .create_sampler(SamplerInfo::new(filter, WrapMode::Clamp))
+                        .making("Sampler", None)?,
+                );
+            }
+
+            let descriptor_set_layout = partial.descriptor_set_layout.get_or_insert(
+                device
+                    .create_descriptor_set_layout(
+                        &[
+                            DescriptorSetLayoutBinding {
+                                binding: BINDLESS_IMAGE_BINDING,
+                                ty: DescriptorType::SampledImage,
+                                count: capacity as usize,
+                                stage_flags,
+                                immutable_samplers: false,
+                            },
+                            DescriptorSetLayoutBinding {
+                                binding: BINDLESS_SAMPLER_BINDING,
+                                ty: DescriptorType::Sampler,
+                                count: sampler_count,
+                                stage_flags,
+                                immutable_samplers: false,
+                            },
+                        ],
+                        &[],
+                    )
+                    .making("DescriptorSetLayout", None)?,
+            );
+
+            let descriptor_pool = partial.descriptor_pool.get_or_insert(
+                device
+                    .create_descriptor_pool(
+                        frames_in_flight,
+                        [
+                            DescriptorRangeDesc {
+                                ty: DescriptorType::SampledImage,
+                                count: capacity as usize * frames_in_flight,
+                            },
+                            DescriptorRangeDesc {
+                                ty: DescriptorType::Sampler,
+                                count: sampler_count * frames_in_flight,
+                            },
+                        ],
+                        DescriptorPoolCreateFlags::empty(),
+                    )
+                    .making("DescriptorPool", None)?,
+            );
+
+            //  The pool takes the sets with it if anything after this fails
+            let mut descriptor_sets = Vec::with_capacity(frames_in_flight);
+            for _ in 0..frames_in_flight {
+                descriptor_sets.push(
+                    descriptor_pool
+                        .allocate_set(descriptor_set_layout)
+                        .making("DescriptorSet", None)?,
+                );
+            }
+
+            let default_view = default_texture.image_view.deref();
+            device.write_descriptor_sets(descriptor_sets.iter().flat_map(|set| {
+                vec![
+                    DescriptorSetWrite {
+                        set,
+                        binding: BINDLESS_SAMPLER_BINDING,
+                        array_offset: 0,
+                        descriptors: partial
+                            .samplers
+                            .iter()
+                            .map(Descriptor::Sampler)
+                            .collect::<Vec<_>>(),
+                    },
+                    DescriptorSetWrite {
+                        set,
+                        binding: BINDLESS_IMAGE_BINDING,
+                        array_offset: 0,
+                        descriptors: (0..capacity)
+                            .map(|_| Descriptor::Image(default_view, Layout::ShaderReadOnlyOptimal))
+                            .collect(),
+                    },
+                ]
+            }));
+
+            let (samplers, descriptor_set_layout, descriptor_pool) = partial.finish();
+            device.track("BindlessTable");
+            Ok(BindlessTable {
+                descriptor_set_layout: manual_new!(descriptor_set_layout),
+                descriptor_pool: manual_new!(descriptor_pool),
+                descriptor_sets,
+                stale_slots: vec![Vec::new(); frames_in_flight],
+                samplers,
+                filters: filters.to_vec(),
+                stage_flags,
+                capacity,
+                default_texture,
+                textures: Vec::new(),
+                free_slots: Vec::new(),
+                retired: RetirementQueue::new(),
+                device: Arc::clone(device),
+            })
+        }
+    }
+
+    /// Puts `texture` in a free slot and returns the slot's index, which is what
+    /// the shader indexes the image array with. The table owns the texture from here on.
+    pub fn register_texture(&mut self, texture: LoadedImage<B>) -> Result<u32, failure::Error> {
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None if (self.textures.len() as u32) < self.capacity => {
+                self.textures.push(None);
+                self.textures.len() as u32 - 1
+            }
+            None => {
+                return Err(RenderError::exhausted(
+                    "BindlessTable",
+                    self.capacity.into(),
+                    format!(
+                        "The bindless table is full! All {} slots are taken.",
+                        self.capacity
+                    ),
+                )
+                .into())
+            }
+        };
+
+        self.textures[slot as usize] = Some(texture);
+        self.mark_stale(slot);
+
+        Ok(slot)
+    }
+
+    /// The set to bind while recording `frame`. Call it once the GPU is done with
+    /// the frame that last used this copy, `frame - frames_in_flight`; any textures
+    /// registered or unregistered since then get written into it first.
+    pub fn descriptor_set(&mut self, frame: u64) -> &B::DescriptorSet {
+        let index = (frame % self.descriptor_sets.len() as u64) as usize;
+        let mut stale = mem::take(&mut self.stale_slots[index]);
+        if !stale.is_empty() {
+            stale.sort_unstable();
+            stale.dedup();
+
+            let set = &self.descriptor_sets[index];
+            let textures = &self.textures;
+            let default_texture = &self.default_texture;
+            unsafe {
+                self.device.write_descriptor_sets(stale.iter().map(|&slot| {
+                    let texture = textures[slot as usize].as_ref().unwrap_or(default_texture);
+                    DescriptorSetWrite {
+                        set,
+                        binding: BINDLESS_IMAGE_BINDING,
+                        array_offset: slot as usize,
+                        descriptors: Some(Descriptor::Image(
+                            texture.image_view.deref(),
+                            Layout::ShaderReadOnlyOptimal,
+                        )),
+                    }
+                }));
+            }
+        }
+        &self.descriptor_sets[index]
+    }
+
+    pub fn texture(&self, slot: u32) -> Option<&LoadedImage<B>> {
+        self.textures.get(slot as usize).and_then(Option::as_ref)
+    }
+
+    pub fn texture_mut(&mut self, slot: u32) -> Option<&mut LoadedImage<B>> {
+        self.textures
+            .get_mut(slot as usize)
+            .and_then(Option::as_mut)
+    }
+
+    /// Takes the texture out of `slot`, which goes back to showing the default
+    /// texture. Frames up to `last_used_frame` might still be sampling it, so it's
+    /// kept alive (and the slot kept out of use) until `retire_frames` says they're done.
+    pub fn unregister_texture(&mut self, slot: u32, last_used_frame: u64) -> bool {
+        match self.textures.get_mut(slot as usize).and_then(Option::take) {
+            Some(texture) => {
+                self.retired.retire(last_used_frame, (slot, texture));
+                self.mark_stale(slot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Destroys textures whose last frame is at or before `completed_frame`
+    /// and opens their slots back up.
+    pub fn retire_frames(&mut self, completed_frame: u64) {
+        for (slot, texture) in self.retired.drain_completed(completed_frame) {
+            drop(texture);
+            self.free_slots.push(slot);
+        }
+    }
+
+    /// Makes the table again on `device` after the old one was lost. Every slot
+    /// that had a texture gets the one `texture` makes for it, and slots waiting
+    /// on their frames are free again straight away. The descriptor sets are new
+    /// ones, so get them from `descriptor_set` again. If anything fails, the table
+    /// is left as it was, so recovery can be tried again.
+    pub fn recover(
+        &mut self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        default_texture: LoadedImage<B>,
+        mut texture: impl FnMut(u32) -> Result<LoadedImage<B>, failure::Error>,
+    ) -> Result<(), failure::Error> {
+        let mut table = BindlessTable::new(
+            adapter,
+            device,
+            self.capacity,
+            &self.filters,
+            self.stage_flags,
+            self.descriptor_sets.len(),
+            default_texture,
+        )?;
+        for (slot, old) in self.textures.iter().enumerate() {
+            table.textures.push(match old {
+                Some(_) => Some(texture(slot as u32)?),
+                None => None,
+            });
+            if old.is_some() {
+                table.mark_stale(slot as u32);
+            }
+        }
+
+        //  The old device is gone, so nothing can still be using what we retired
+        table.free_slots = mem::take(&mut self.free_slots);
+        for (slot, _) in self.retired.drain_all() {
+            table.free_slots.push(slot);
+        }
+        *self = table;
+        Ok(())
+    }
+
+    /// How many textures are in the table right now.
+    pub fn len(&self) -> usize {
+        self.textures
+            .iter()
+            .filter(|texture| texture.is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn mark_stale(&mut self, slot: u32) {
+        for stale in &mut self.stale_slots {
+            stale.push(slot);
+        }
+    }
+
+    unsafe fn destroy_samplers(device: &B::Device, samplers: &mut Vec<B::Sampler>) {
+        for sampler in samplers.drain(..) {
+            device.destroy_sampler(sampler);
+        }
+    }
+}
+
+impl<B: Backend> Drop for BindlessTable<B> {
+    fn drop(&mut self) {
+        use core::ptr::read;
+        unsafe {
+            // Destroying the pool frees the sets along with it.
+            self.descriptor_sets.clear();
+            self.device
+                .destroy_descriptor_pool(manual_drop!(self.descriptor_pool));
+            self.device
+                .destroy_descriptor_set_layout(manual_drop!(self.descriptor_set_layout));
+            BindlessTable::<B>::destroy_samplers(&self.device, &mut self.samplers);
+        }
+        self.device.untrack("BindlessTable");
+    }
+}
+
+/// The samplers, layout and pool `new` has made so far. If we bail out before
+/// the `BindlessTable` exists, dropping this hands them back to the device.
+struct PartialTable<'a, B: Backend> {
+    device: &'a B::Device,
+    samplers: Vec<B::Sampler>,
+    descriptor_set_layout: Option<B::DescriptorSetLayout>,
+    descriptor_pool: Option<B::DescriptorPool>,
+}
+
+impl<'a, B: Backend> PartialTable<'a, B> {
+    fn finish(mut self) -> (Vec<B::Sampler>, B::DescriptorSetLayout, B::DescriptorPool) {
+        (
+            mem::take(&mut self.samplers),
+            self.descriptor_set_layout.take().unwrap(),
+            self.descriptor_pool.take().unwrap(),
+        )
+    }
+}
+
+impl<'a, B: Backend> Drop for PartialTable<'a, B> {
+    fn drop(&mut self) {
+        unsafe {
+            // Destroying the pool frees any sets along with it.
+            if let Some(descriptor_pool) = self.descriptor_pool.take() {
+                self.device.destroy_descriptor_pool(descriptor_pool);
+            }
+            if let Some(descriptor_set_layout) = self.descriptor_set_layout.take() {
+                self.device
+                    .destroy_descriptor_set_layout(descriptor_set_layout);
+            }
+            BindlessTable::<B>::destroy_samplers(self.device, &mut self.samplers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BindlessTable, BINDLESS_IMAGE_BINDING};
+    use crate::{
+        mock_backend::{self, Mock},
+        DeviceContext, ErrorKind, LoadedImage,
+    };
+    use gfx_hal::{image::Filter, pso::ShaderStageFlags, Features};
+    use std::sync::Arc;
+
+    fn table(device: &Arc<DeviceContext<Mock>>, capacity: u32) -> BindlessTable<Mock> {
+        BindlessTable::new(
+            &mock_backend::adapter(),
+            device,
+            capacity,
+            &[Filter::Nearest, Filter::Linear],
+            ShaderStageFlags::FRAGMENT,
+            2,
+            texture(device),
+        )
+        .unwrap()
+    }
+
+    fn image_writes(device: &DeviceContext<Mock>) -> usize {
+        device
+            .descriptor_writes()
+            .iter()
+            .filter(|&&(binding, kind)| binding == BINDLESS_IMAGE_BINDING && kind == "Image")
+            .count()
+    }
+
+    fn texture(device: &Arc<DeviceContext<Mock>>) -> LoadedImage<Mock> {
+        LoadedImage::new(
+            &mock_backend::adapter(),
+            device,
+            &mut mock_backend::command_pool(device),
+            &mut mock_backend::command_queue(),
+            &[0xFF; 2 * 2 * 4],
+            2,
+            2,
+            Filter::Nearest,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn hands_out_slots_until_full() {
+        let device = mock_backend::device();
+        let mut table = table(&device, 2);
+
+        assert_eq!(table.register_texture(texture(&device)).unwrap(), 0);
+        assert_eq!(table.register_texture(texture(&device)).unwrap(), 1);
+
+        let error = table.register_texture(texture(&device)).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::PoolExhausted);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn slots_come_back_once_their_frame_is_done() {
+        let device = mock_backend::device();
+        let mut table = table(&device, 1);
+        let slot = table.register_texture(texture(&device)).unwrap();
+
+        assert!(table.unregister_texture(slot, 5));
+        assert!(table.texture(slot).is_none());
+        assert!(table.register_texture(texture(&device)).is_err());
+
+        table.retire_frames(4);
+        assert!(table.register_texture(texture(&device)).is_err());
+
+        table.retire_frames(5);
+        assert_eq!(table.register_texture(texture(&device)).unwrap(), slot);
+    }
+
+    #[test]
+    fn rejects_more_textures_than_the_device_allows() {
+        let device = mock_backend::device();
+        let adapter = mock_backend::adapter();
+        let too_many = adapter
+            .physical_device
+            .limits
+            .max_per_stage_descriptor_sampled_images as u32
+            + 1;
+
+        assert!(BindlessTable::new(
+            &adapter,
+            &device,
+            too_many,
+            &[Filter::Nearest],
+            ShaderStageFlags::FRAGMENT,
+            2,
+            texture(&device),
+        )
+        .is_err());
+        assert!(device.live_objects().is_empty());
+    }
+
+    #[test]
+    fn new_unwinds_from_every_failure() {
+        for &(call, skip) in &[
+            ("create_sampler", 0),
+            ("create_sampler", 1),
+            ("create_descriptor_set_layout", 0),
+            ("create_descriptor_pool", 0),
+            ("allocate_set", 1),
+        ] {
+            let device = mock_backend::device();
+            let default_texture = texture(&device);
+            device.inject_fault(call, skip);
+
+            let table = BindlessTable::new(
+                &mock_backend::adapter(),
+                &device,
+                4,
+                &[Filter::Nearest, Filter::Linear],
+                ShaderStageFlags::FRAGMENT,
+                2,
+                default_texture,
+            );
+            assert!(table.is_err(), "{} #{} didn't fail", call, skip);
+            assert!(
+                device.live_objects().is_empty(),
+                "{} #{} leaked",
+                call,
+                skip
+            );
+            assert!(
+                device.live_resources().is_empty(),
+                "{} #{} leaked",
+                call,
+                skip
+            );
+        }
+    }
+
+    #[test]
+    fn needs_dynamic_indexing() {
+        let device = mock_backend::device();
+        let mut adapter = mock_backend::adapter();
+        adapter.physical_device.features = Features::empty();
+
+        let error = BindlessTable::new(
+            &adapter,
+            &device,
+            4,
+            &[Filter::Nearest],
+            ShaderStageFlags::FRAGMENT,
+            2,
+            texture(&device),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::UnsupportedFormat);
+        assert!(device.live_objects().is_empty());
+    }
+
+    #[test]
+    fn every_slot_starts_out_on_the_default_texture() {
+        let device = mock_backend::device();
+        let _table = table(&device, 4);
+
+        //  Four slots in each of the two sets
+        assert_eq!(image_writes(&device), 8);
+    }
+
+    #[test]
+    fn changes_wait_for_their_frame_to_come_around() {
+        let device = mock_backend::device();
+        let mut table = table(&device, 4);
+        let before = image_writes(&device);
+
+        //  Registering doesn't touch a set the GPU might be reading
+        let slot = table.register_texture(texture(&device)).unwrap();
+        assert_eq!(image_writes(&device), before);
+
+        //  Each set picks the texture up when it's next handed out, once
+        table.descriptor_set(1);
+        assert_eq!(image_writes(&device), before + 1);
+        table.descriptor_set(1);
+        assert_eq!(image_writes(&device), before + 1);
+        table.descriptor_set(2);
+        assert_eq!(image_writes(&device), before + 2);
+
+        //  Unregistering puts the default texture back in both
+        table.unregister_texture(slot, 2);
+        table.descriptor_set(3);
+        table.descriptor_set(4);
+        assert_eq!(image_writes(&device), before + 4);
+    }
+
+    #[test]
+    fn dropping_the_table_drops_its_textures() {
+        let device = mock_backend::device();
+        let mut table = table(&device, 4);
+        table.register_texture(texture(&device)).unwrap();
+        let retired = table.register_texture(texture(&device)).unwrap();
+        table.unregister_texture(retired, 1);
+
+        drop(table);
+        assert!(device.live_objects().is_empty());
+        assert!(device.live_resources().is_empty());
+    }
+
+    #[test]
+    fn recovery_refills_the_slots_that_had_textures() {
+        let lost = mock_backend::device();
+        let mut table = table(&lost, 4);
+        let kept = table.register_texture(texture(&lost)).unwrap();
+        let retired = table.register_texture(texture(&lost)).unwrap();
+        table.unregister_texture(retired, 5);
+
+        let device = mock_backend::device();
+        let mut asked_for = Vec::new();
+        table
+            .recover(
+                &mock_backend::adapter(),
+                &device,
+                texture(&device),
+                |slot| {
+                    asked_for.push(slot);
+                    Ok(texture(&device))
+                },
+            )
+            .unwrap();
+
+        assert_eq!(asked_for, vec![kept]);
+        assert!(Arc::ptr_eq(&table.texture(kept).unwrap().device, &device));
+        assert!(Arc::ptr_eq(&table.device, &device));
+        assert_eq!(table.register_texture(texture(&device)).unwrap(), retired);
+        assert!(lost.live_objects().is_empty());
+    }
+}
@@ -1,81 +1,256 @@
-use super::{BufferBundleError, BufferError};
+use super::{submit_one_shot, DeviceContext, HalResultExt, RenderError};
 use core::mem::ManuallyDrop;
 use gfx_hal::{
     adapter::{Adapter, MemoryTypeId, PhysicalDevice},
     buffer,
+    command::BufferCopy,
     device::Device,
-    memory::{Properties, Requirements},
-    Backend,
+    memory::{Barrier, Dependencies, Properties, Requirements},
+    pool::CommandPool,
+    pso::PipelineStage,
+    Backend, Capability, CommandQueue, Supports, Transfer,
 };
-use std::{marker::PhantomData, mem};
+use std::{mem, ops::Range, sync::Arc};
 
 pub struct BufferBundle<B: Backend> {
     pub buffer: ManuallyDrop<B::Buffer>,
     pub requirements: Requirements,
     pub mapped: Option<*mut u8>,
     pub memory: ManuallyDrop<B::Memory>,
-    pub phantom: PhantomData<B::Device>,
+    pub device: Arc<DeviceContext<B>>,
 }
 
 impl<B: Backend> BufferBundle<B> {
     pub fn new(
         adapter: &Adapter<B>,
-        device: &B::Device,
+        device: &Arc<DeviceContext<B>>,
         size: u64,
         usage: buffer::Usage,
         map_it: bool,
     ) -> Result<Self, failure::Error> {
         unsafe {
-            let mut buffer = device
-                .create_buffer(size, usage)
-                .map_err(|e| BufferBundleError::Creation(e))?;
-
-            let requirements = device.get_buffer_requirements(&buffer);
-            let memory_type_id = adapter
-                .physical_device
-                .memory_properties()
-                .memory_types
-                .iter()
-                .enumerate()
-                .find(|&(id, memory_type)| {
-                    requirements.type_mask & (1 << id) != 0
-                        && memory_type.properties.contains(Properties::CPU_VISIBLE)
-                })
-                .map(|(id, _)| MemoryTypeId(id))
-                .ok_or(BufferError::MemoryId)?;
-            let memory = device
-                .allocate_memory(memory_type_id, requirements.size)
-                .map_err(|e| BufferError::Allocate(e))?;
+            BufferBundle::allocate(
+                adapter,
+                device,
+                size,
+                usage,
+                Properties::CPU_VISIBLE,
+                map_it,
+            )
+        }
+    }
 
+    /// Makes a buffer in device-local memory and fills it with `data` through
+    /// a host-visible staging buffer. Use this for geometry which doesn't change
+    /// after it's been uploaded, so the GPU isn't reading it over the bus every frame.
+    pub fn new_device_local<T: Copy, C: Capability + Supports<Transfer>>(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        data: &[T],
+        usage: buffer::Usage,
+    ) -> Result<Self, failure::Error> {
+        let size = mem::size_of_val(data) as u64;
+        if size == 0 {
+            bail_invalid!(
+                "BufferBundle",
+                "Can't make a device-local buffer out of no data!"
+            );
+        }
+
+        unsafe {
+            // Fill up a staging bundle in memory we can see...
+            let staging_bundle =
+                BufferBundle::new(adapter, device, size, buffer::Usage::TRANSFER_SRC, false)?;
+
+            let mut writer = device
+                .acquire_mapping_writer::<T>(&staging_bundle.memory, 0..size)
+                .making("StagingBuffer", Some(size))?;
+            writer[..data.len()].copy_from_slice(data);
             device
-                .bind_buffer_memory(&memory, 0, &mut buffer)
-                .map_err(|e| BufferError::Bind(e))?;
-
-            let mapped = if map_it {
-                Some(device.map_memory(&memory, 0..requirements.size)?)
-            } else {
-                None
-            };
-
-            Ok(Self {
-                buffer: manual_new!(buffer),
-                requirements,
-                memory: manual_new!(memory),
-                phantom: PhantomData,
-                mapped,
+                .release_mapping_writer(writer)
+                .making("StagingBuffer", Some(size))?;
+
+            // ...and then copy it over to memory we can't.
+            let device_bundle = BufferBundle::allocate(
+                adapter,
+                device,
+                size,
+                usage | buffer::Usage::TRANSFER_DST,
+                Properties::DEVICE_LOCAL,
+                false,
+            )?;
+
+            let (dst_access, dst_stage) = BufferBundle::<B>::first_use(usage);
+            submit_one_shot(&device.device, command_pool, command_queue, |cmd_buffer| {
+                cmd_buffer.copy_buffer(
+                    &staging_bundle.buffer,
+                    &device_bundle.buffer,
+                    [BufferCopy {
+                        src: 0,
+                        dst: 0,
+                        size,
+                    }],
+                );
+
+                //  Make the copy visible to whatever reads the buffer next
+                let buffer_barrier = Barrier::whole_buffer(
+                    &*device_bundle.buffer,
+                    buffer::Access::TRANSFER_WRITE..dst_access,
+                );
+                cmd_buffer.pipeline_barrier(
+                    PipelineStage::TRANSFER..dst_stage,
+                    Dependencies::empty(),
+                    &[buffer_barrier],
+                );
+            })?;
+
+            Ok(device_bundle)
+        }
+    }
+
+    /// Makes a buffer backed by memory which has at least `properties`. Mapping
+    /// only makes sense if you've asked for `CPU_VISIBLE` memory!
+    ///
+    /// # Safety
+    ///
+    /// `device` has to have been opened on `adapter`.
+    pub unsafe fn allocate(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        size: u64,
+        usage: buffer::Usage,
+        properties: Properties,
+        map_it: bool,
+    ) -> Result<Self, failure::Error> {
+        let mut buffer = device
+            .create_buffer(size, usage)
+            .making("Buffer", Some(size))?;
+
+        let requirements = device.get_buffer_requirements(&buffer);
+        let memory = match BufferBundle::bind_memory(
+            adapter,
+            device,
+            &requirements,
+            properties,
+            &mut buffer,
+        ) {
+            Ok(memory) => memory,
+            Err(e) => {
+                //  Don't leave the buffer lying around if we couldn't back it
+                device.destroy_buffer(buffer);
+                return Err(e);
+            }
+        };
+
+        device.track("BufferBundle");
+        let mut bundle = Self {
+            buffer: manual_new!(buffer),
+            requirements,
+            memory: manual_new!(memory),
+            device: Arc::clone(device),
+            mapped: None,
+        };
+
+        // From here on, the bundle cleans up after itself if anything goes wrong.
+        if map_it {
+            bundle.mapped = Some(
+                device
+                    .map_memory(&bundle.memory, 0..requirements.size)
+                    .making("Memory", Some(requirements.size))?,
+            );
+        }
+
+        Ok(bundle)
+    }
+
+    /// Allocates memory with `properties` for `buffer` and binds it. If binding
+    /// fails, the memory is freed again before we hand back the error.
+    unsafe fn bind_memory(
+        adapter: &Adapter<B>,
+        device: &B::Device,
+        requirements: &Requirements,
+        properties: Properties,
+        buffer: &mut B::Buffer,
+    ) -> Result<B::Memory, failure::Error> {
+        let memory_type_id = adapter
+            .physical_device
+            .memory_properties()
+            .memory_types
+            .iter()
+            .enumerate()
+            .find(|&(id, memory_type)| {
+                requirements.type_mask & (1 << id) != 0
+                    && memory_type.properties.contains(properties)
             })
+            .map(|(id, _)| MemoryTypeId(id))
+            .ok_or_else(|| {
+                RenderError::unsupported(
+                    "Memory",
+                    format!("No memory type for a buffer has {:?}!", properties),
+                )
+            })?;
+        let memory = device
+            .allocate_memory(memory_type_id, requirements.size)
+            .making("Memory", Some(requirements.size))?;
+
+        if let Err(e) = device.bind_buffer_memory(&memory, 0, buffer) {
+            device.free_memory(memory);
+            return Err(RenderError::from_hal("Memory", Some(requirements.size), e).into());
+        }
+
+        Ok(memory)
+    }
+
+    /// Where and how a buffer with this usage gets read after we've copied into it.
+    fn first_use(usage: buffer::Usage) -> (buffer::Access, PipelineStage) {
+        let mut access = buffer::Access::empty();
+        let mut stages = PipelineStage::empty();
+
+        if usage.contains(buffer::Usage::VERTEX) {
+            access |= buffer::Access::VERTEX_BUFFER_READ;
+            stages |= PipelineStage::VERTEX_INPUT;
+        }
+        if usage.contains(buffer::Usage::INDEX) {
+            access |= buffer::Access::INDEX_BUFFER_READ;
+            stages |= PipelineStage::VERTEX_INPUT;
+        }
+        if usage.contains(buffer::Usage::INDIRECT) {
+            access |= buffer::Access::INDIRECT_COMMAND_READ;
+            stages |= PipelineStage::DRAW_INDIRECT;
+        }
+        if usage.intersects(buffer::Usage::UNIFORM | buffer::Usage::UNIFORM_TEXEL) {
+            access |= buffer::Access::CONSTANT_BUFFER_READ;
+            stages |= PipelineStage::VERTEX_SHADER
+                | PipelineStage::FRAGMENT_SHADER
+                | PipelineStage::COMPUTE_SHADER;
+        }
+        if usage.intersects(buffer::Usage::STORAGE | buffer::Usage::STORAGE_TEXEL) {
+            access |= buffer::Access::SHADER_READ | buffer::Access::SHADER_WRITE;
+            stages |= PipelineStage::VERTEX_SHADER
+                | PipelineStage::FRAGMENT_SHADER
+                | PipelineStage::COMPUTE_SHADER;
+        }
+        if usage.contains(buffer::Usage::TRANSFER_SRC) {
+            access |= buffer::Access::TRANSFER_READ;
+            stages |= PipelineStage::TRANSFER;
+        }
+
+        if stages.is_empty() {
+            (buffer::Access::MEMORY_READ, PipelineStage::BOTTOM_OF_PIPE)
+        } else {
+            (access, stages)
         }
     }
 
     pub fn update_buffer<T>(&mut self, verts: &[T], vertex_offset: usize) {
-        assert!(
-            self.requirements.size >= (verts.len() * mem::size_of::<T>() + vertex_offset) as u64
-        );
+        assert!(self.requirements.size >= (mem::size_of_val(verts) + vertex_offset) as u64);
 
         // copy vertex data
         unsafe {
             if let Some(map) = &self.mapped {
-                let dest = map.offset((vertex_offset * mem::size_of::<T>()) as isize);
+                let dest = map.add(vertex_offset * mem::size_of::<T>());
 
                 let src = &verts[0];
                 std::ptr::copy_nonoverlapping(src, dest as *mut T, verts.len());
@@ -88,14 +263,216 @@ impl<B: Backend> BufferBundle<B> {
         self.requirements.size >= size
     }
 
-    pub unsafe fn manually_drop(&self, device: &B::Device) {
-        use core::ptr::read;
-        device.destroy_buffer(manual_drop!(self.buffer));
-        device.free_memory(manual_drop!(self.memory));
+    /// Makes everything written into the mapped memory visible to the GPU.
+    ///
+    /// # Safety
+    ///
+    /// The buffer has to be mapped.
+    pub unsafe fn flush(&self) -> Result<(), failure::Error> {
+        self.device
+            .flush_mapped_memory_ranges(&[(&*self.memory, ..)])
+            .making("Memory", None)?;
+        Ok(())
     }
 
-    pub unsafe fn flush(&self, device: &B::Device) -> Result<(), failure::Error> {
-        device.flush_mapped_memory_ranges(&[(&*self.memory, ..)])?;
+    /// Like `flush`, but only for the bytes in `range`. The range gets widened out
+    /// to multiples of `atom_size`, which is the device's `non_coherent_atom_size`.
+    ///
+    /// # Safety
+    ///
+    /// The buffer has to be mapped.
+    pub unsafe fn flush_range(
+        &self,
+        range: Range<u64>,
+        atom_size: u64,
+    ) -> Result<(), failure::Error> {
+        let atom_size = atom_size.max(1);
+        let start = range.start / atom_size * atom_size;
+        let end = (range.end.div_ceil(atom_size) * atom_size).min(self.requirements.size);
+        self.device
+            .flush_mapped_memory_ranges(&[(&*self.memory, start..end)])
+            .making("Memory", None)?;
         Ok(())
     }
 }
+
+impl<B: Backend> Drop for BufferBundle<B> {
+    fn drop(&mut self) {
+        use core::ptr::read;
+        unsafe {
+            if self.mapped.is_some() {
+                self.device.unmap_memory(&self.memory);
+            }
+            self.device.destroy_buffer(manual_drop!(self.buffer));
+            self.device.free_memory(manual_drop!(self.memory));
+        }
+        self.device.untrack("BufferBundle");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferBundle;
+    use crate::{
+        mock_backend::{self, Mock, MockCommand},
+        ErrorKind, RenderError,
+    };
+    use gfx_hal::{adapter::MemoryTypeId, buffer, memory::Properties, pso::PipelineStage};
+
+    fn new_bundle(
+        device: &std::sync::Arc<crate::DeviceContext<Mock>>,
+    ) -> Result<(), failure::Error> {
+        BufferBundle::new(
+            &mock_backend::adapter(),
+            device,
+            64,
+            buffer::Usage::VERTEX,
+            true,
+        )
+        .map(drop)
+    }
+
+    #[test]
+    fn new_gives_everything_back_when_dropped() {
+        let device = mock_backend::device();
+        new_bundle(&device).unwrap();
+
+        assert!(device.live_objects().is_empty());
+        assert!(device.live_resources().is_empty());
+    }
+
+    #[test]
+    fn new_unwinds_from_every_failure() {
+        for &call in &[
+            "create_buffer",
+            "allocate_memory",
+            "bind_buffer_memory",
+            "map_memory",
+        ] {
+            let device = mock_backend::device();
+            device.inject_fault(call, 0);
+
+            assert!(new_bundle(&device).is_err(), "{} didn't fail", call);
+            assert!(device.live_objects().is_empty(), "{} leaked", call);
+            assert!(device.live_resources().is_empty(), "{} leaked", call);
+        }
+    }
+
+    #[test]
+    fn new_unwinds_without_a_memory_type() {
+        let device = mock_backend::device();
+        let adapter = mock_backend::adapter_with_memory(vec![Properties::DEVICE_LOCAL]);
+
+        let error = match BufferBundle::new(&adapter, &device, 64, buffer::Usage::VERTEX, false) {
+            Ok(_) => panic!("Made a buffer without a memory type for it!"),
+            Err(e) => e,
+        };
+        assert_eq!(ErrorKind::of(&error), ErrorKind::UnsupportedFormat);
+        assert!(device.live_objects().is_empty());
+    }
+
+    #[test]
+    fn running_out_of_memory_says_what_and_how_much() {
+        let device = mock_backend::device();
+        device.inject_fault("allocate_memory", 0);
+
+        let error = new_bundle(&device).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::OutOfDeviceMemory);
+        match error.downcast_ref::<RenderError>() {
+            Some(RenderError::OutOfMemory {
+                resource, bytes, ..
+            }) => {
+                assert_eq!(*resource, "Memory");
+                assert!(bytes.unwrap() >= 64);
+            }
+            other => panic!("Expected running out of memory, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn new_device_local_copies_then_makes_the_copy_visible_to_its_first_use() {
+        let device = mock_backend::device();
+        let bundle = BufferBundle::new_device_local(
+            &mock_backend::adapter(),
+            &device,
+            &mut mock_backend::command_pool(&device),
+            &mut mock_backend::command_queue(),
+            &[0u16; 6],
+            buffer::Usage::INDEX,
+        )
+        .unwrap();
+
+        //  The staging buffer's host-visible, and the one we keep isn't
+        assert_eq!(
+            device.allocations(),
+            vec![(MemoryTypeId(1), 12), (MemoryTypeId(0), 12)]
+        );
+        assert_eq!(
+            device.take_commands(),
+            vec![
+                MockCommand::CopyBuffer {
+                    src: 0,
+                    dst: 0,
+                    size: 12
+                },
+                MockCommand::BufferBarrier {
+                    stages: PipelineStage::TRANSFER..PipelineStage::VERTEX_INPUT,
+                    access: buffer::Access::TRANSFER_WRITE..buffer::Access::INDEX_BUFFER_READ,
+                },
+            ]
+        );
+
+        drop(bundle);
+        assert!(device.live_objects().is_empty());
+        assert!(device.live_resources().is_empty());
+    }
+
+    #[test]
+    fn new_device_local_unwinds_from_every_failure() {
+        let faults: &[(&'static str, usize)] = &[
+            // The staging bundle...
+            ("create_buffer", 0),
+            ("allocate_memory", 0),
+            ("bind_buffer_memory", 0),
+            ("map_memory", 0),
+            ("flush_mapped_memory_ranges", 0),
+            // ...the device-local bundle...
+            ("create_buffer", 1),
+            ("allocate_memory", 1),
+            ("bind_buffer_memory", 1),
+            // ...and the copy between them.
+            ("create_fence", 0),
+            ("wait_for_fence", 0),
+        ];
+
+        for &(call, skip) in faults {
+            let device = mock_backend::device();
+            let mut command_pool = mock_backend::command_pool(&device);
+            let mut command_queue = mock_backend::command_queue();
+            device.inject_fault(call, skip);
+
+            let bundle = BufferBundle::new_device_local(
+                &mock_backend::adapter(),
+                &device,
+                &mut command_pool,
+                &mut command_queue,
+                &[1.0f32, 2.0, 3.0],
+                buffer::Usage::VERTEX,
+            );
+
+            assert!(bundle.is_err(), "{} #{} didn't fail", call, skip);
+            assert!(
+                device.live_objects().is_empty(),
+                "{} #{} leaked",
+                call,
+                skip
+            );
+            assert!(
+                device.live_resources().is_empty(),
+                "{} #{} leaked",
+                call,
+                skip
+            );
+        }
+    }
+}
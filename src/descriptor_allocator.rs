@@ -0,0 +1,328 @@
+use super::{DeviceContext, HalResultExt, RenderError};
+use core::mem::ManuallyDrop;
+use gfx_hal::{
+    device::Device,
+    pso::{AllocationError, DescriptorPoolCreateFlags, DescriptorRangeDesc},
+    Backend, DescriptorPool,
+};
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+pub type SharedDescriptorAllocator<B> = Arc<Mutex<DescriptorAllocator<B>>>;
+
+/// Which layout a set was allocated for, so we can count them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LayoutId(usize);
+
+#[derive(Default)]
+struct LayoutSets {
+    live: usize,
+    /// Set once the layout's gone, so it's forgotten when its last set is.
+    unregistered: bool,
+}
+
+struct Pool<B: Backend> {
+    pool: B::DescriptorPool,
+    allocated: usize,
+    full: bool,
+}
+
+/// Hands out descriptor sets from a list of pools, making a new pool whenever
+/// all the ones we have are full. Sets come back as `PooledDescriptorSet`s,
+/// which give themselves back to their pool when they're dropped.
+pub struct DescriptorAllocator<B: Backend> {
+    pub device: Arc<DeviceContext<B>>,
+    pub sets_per_pool: usize,
+    pub ranges: Vec<DescriptorRangeDesc>,
+    pools: Vec<Pool<B>>,
+    layouts: HashMap<LayoutId, LayoutSets>,
+    next_layout: usize,
+}
+
+impl<B: Backend> DescriptorAllocator<B> {
+    /// Every pool we make holds `sets_per_pool` sets and the descriptors in `ranges`,
+    /// just like the arguments to `create_descriptor_pool`.
+    pub fn new(
+        device: &Arc<DeviceContext<B>>,
+        sets_per_pool: usize,
+        ranges: &[DescriptorRangeDesc],
+    ) -> Result<SharedDescriptorAllocator<B>, failure::Error> {
+        if sets_per_pool == 0 {
+            bail_invalid!(
+                "DescriptorAllocator",
+                "A descriptor pool needs room for at least one set!"
+            );
+        }
+
+        device.track("DescriptorAllocator");
+        Ok(Arc::new(Mutex::new(DescriptorAllocator {
+            device: Arc::clone(device),
+            sets_per_pool,
+            ranges: ranges.to_vec(),
+            pools: Vec::new(),
+            layouts: HashMap::new(),
+            next_layout: 0,
+        })))
+    }
+
+    /// Gets an id to allocate sets of a layout with, so we can keep count of them.
+    pub fn register_layout(&mut self) -> LayoutId {
+        let layout = LayoutId(self.next_layout);
+        self.next_layout += 1;
+        self.layouts.insert(layout, LayoutSets::default());
+        layout
+    }
+
+    /// Forgets `layout` once every set of it has been given back. Call it when
+    /// the layout is destroyed, like `PipelineBundle` does, so a long session
+    /// of hot reloads doesn't pile them up.
+    pub fn unregister_layout(&mut self, layout: LayoutId) {
+        if let Some(sets) = self.layouts.get_mut(&layout) {
+            if sets.live == 0 {
+                self.layouts.remove(&layout);
+            } else {
+                sets.unregistered = true;
+            }
+        }
+    }
+
+    /// How many sets of `layout` are alive right now.
+    pub fn live_sets(&self, layout: LayoutId) -> usize {
+        self.layouts.get(&layout).map_or(0, |sets| sets.live)
+    }
+
+    /// How many layouts we're keeping count for.
+    pub fn layout_count(&self) -> usize {
+        self.layouts.len()
+    }
+
+    pub fn pool_count(&self) -> usize {
+        self.pools.len()
+    }
+
+    /// Allocates a set of `layout` from the first pool with room, making a new
+    /// pool if there isn't one. Returns the set and which pool it came from.
+    unsafe fn allocate(
+        &mut self,
+        layout_id: LayoutId,
+        layout: &B::DescriptorSetLayout,
+    ) -> Result<(B::DescriptorSet, usize), failure::Error> {
+        let sets = match self.layouts.get_mut(&layout_id) {
+            Some(sets) if !sets.unregistered => sets,
+            _ => bail_invalid!(
+                "DescriptorAllocator",
+                "Can't allocate a set of a layout that isn't registered!"
+            ),
+        };
+
+        for (index, pool) in self.pools.iter_mut().enumerate() {
+            if pool.full {
+                continue;
+            }
+
+            match pool.pool.allocate_set(layout) {
+                Ok(set) => {
+                    pool.allocated += 1;
+                    sets.live += 1;
+                    return Ok((set, index));
+                }
+                //  This one's used up, so try the next
+                Err(AllocationError::OutOfPoolMemory) | Err(AllocationError::FragmentedPool) => {
+                    pool.full = true;
+                }
+                Err(e) => return Err(RenderError::from_hal("DescriptorSet", None, e).into()),
+            }
+        }
+
+        //  Everything's full, so it's time for a new pool
+        let mut pool = self
+            .device
+            .create_descriptor_pool(
+                self.sets_per_pool,
+                &self.ranges,
+                DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET,
+            )
+            .making("DescriptorPool", None)?;
+
+        match pool.allocate_set(layout) {
+            Ok(set) => {
+                self.pools.push(Pool {
+                    pool,
+                    allocated: 1,
+                    full: false,
+                });
+                sets.live += 1;
+                Ok((set, self.pools.len() - 1))
+            }
+            Err(e) => {
+                self.device.destroy_descriptor_pool(pool);
+                Err(RenderError::from_hal("DescriptorSet", None, e).into())
+            }
+        }
+    }
+
+    unsafe fn free(&mut self, pool_index: usize, layout_id: LayoutId, set: B::DescriptorSet) {
+        let pool = &mut self.pools[pool_index];
+        pool.pool.free_sets(Some(set));
+        pool.allocated -= 1;
+        pool.full = false;
+
+        if let Some(sets) = self.layouts.get_mut(&layout_id) {
+            sets.live -= 1;
+            if sets.unregistered && sets.live == 0 {
+                self.layouts.remove(&layout_id);
+            }
+        }
+    }
+}
+
+impl<B: Backend> Drop for DescriptorAllocator<B> {
+    fn drop(&mut self) {
+        // Every set holds onto us, so by now they've all been given back.
+        unsafe {
+            for pool in self.pools.drain(..) {
+                self.device.destroy_descriptor_pool(pool.pool);
+            }
+        }
+        self.device.untrack("DescriptorAllocator");
+    }
+}
+
+/// A descriptor set which goes back to its pool when dropped.
+pub struct PooledDescriptorSet<B: Backend> {
+    set: ManuallyDrop<B::DescriptorSet>,
+    pool_index: usize,
+    layout_id: LayoutId,
+    allocator: SharedDescriptorAllocator<B>,
+}
+
+impl<B: Backend> PooledDescriptorSet<B> {
+    pub fn allocate(
+        allocator: &SharedDescriptorAllocator<B>,
+        layout_id: LayoutId,
+        layout: &B::DescriptorSetLayout,
+    ) -> Result<Self, failure::Error> {
+        let (set, pool_index) = unsafe {
+            allocator
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .allocate(layout_id, layout)?
+        };
+
+        Ok(PooledDescriptorSet {
+            set: manual_new!(set),
+            pool_index,
+            layout_id,
+            allocator: Arc::clone(allocator),
+        })
+    }
+}
+
+impl<B: Backend> Deref for PooledDescriptorSet<B> {
+    type Target = B::DescriptorSet;
+
+    fn deref(&self) -> &B::DescriptorSet {
+        &self.set
+    }
+}
+
+impl<B: Backend> Drop for PooledDescriptorSet<B> {
+    fn drop(&mut self) {
+        use core::ptr::read;
+        unsafe {
+            self.allocator
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .free(self.pool_index, self.layout_id, manual_drop!(self.set));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DescriptorAllocator, PooledDescriptorSet};
+    use crate::mock_backend::{self, MockHandle};
+
+    #[test]
+    fn grows_a_new_pool_when_the_last_one_fills_up() {
+        let device = mock_backend::device();
+        let allocator = DescriptorAllocator::new(&device, 2, &[]).unwrap();
+        let layout = allocator.lock().unwrap().register_layout();
+
+        let sets: Vec<_> = (0..5)
+            .map(|_| PooledDescriptorSet::allocate(&allocator, layout, &MockHandle).unwrap())
+            .collect();
+
+        assert_eq!(allocator.lock().unwrap().pool_count(), 3);
+        assert_eq!(allocator.lock().unwrap().live_sets(layout), 5);
+
+        drop(sets);
+        assert_eq!(allocator.lock().unwrap().live_sets(layout), 0);
+    }
+
+    #[test]
+    fn freed_sets_are_reused_before_growing() {
+        let device = mock_backend::device();
+        let allocator = DescriptorAllocator::new(&device, 2, &[]).unwrap();
+        let layout = allocator.lock().unwrap().register_layout();
+
+        let first = PooledDescriptorSet::allocate(&allocator, layout, &MockHandle).unwrap();
+        let _second = PooledDescriptorSet::allocate(&allocator, layout, &MockHandle).unwrap();
+        drop(first);
+        let _third = PooledDescriptorSet::allocate(&allocator, layout, &MockHandle).unwrap();
+
+        assert_eq!(allocator.lock().unwrap().pool_count(), 1);
+    }
+
+    #[test]
+    fn counts_sets_per_layout() {
+        let device = mock_backend::device();
+        let allocator = DescriptorAllocator::new(&device, 4, &[]).unwrap();
+        let (sprites, materials) = {
+            let mut allocator = allocator.lock().unwrap();
+            (allocator.register_layout(), allocator.register_layout())
+        };
+
+        let _sprite = PooledDescriptorSet::allocate(&allocator, sprites, &MockHandle).unwrap();
+        let material = PooledDescriptorSet::allocate(&allocator, materials, &MockHandle).unwrap();
+        let _other = PooledDescriptorSet::allocate(&allocator, materials, &MockHandle).unwrap();
+        drop(material);
+
+        assert_eq!(allocator.lock().unwrap().live_sets(sprites), 1);
+        assert_eq!(allocator.lock().unwrap().live_sets(materials), 1);
+    }
+
+    #[test]
+    fn unregistered_layouts_are_forgotten_once_their_sets_are_gone() {
+        let device = mock_backend::device();
+        let allocator = DescriptorAllocator::new(&device, 4, &[]).unwrap();
+        let layout = allocator.lock().unwrap().register_layout();
+        let set = PooledDescriptorSet::allocate(&allocator, layout, &MockHandle).unwrap();
+
+        allocator.lock().unwrap().unregister_layout(layout);
+        assert_eq!(allocator.lock().unwrap().layout_count(), 1);
+        assert!(PooledDescriptorSet::allocate(&allocator, layout, &MockHandle).is_err());
+
+        drop(set);
+        assert_eq!(allocator.lock().unwrap().layout_count(), 0);
+        assert_eq!(allocator.lock().unwrap().live_sets(layout), 0);
+    }
+
+    #[test]
+    fn dropping_everything_gives_the_pools_back() {
+        let device = mock_backend::device();
+        let allocator = DescriptorAllocator::new(&device, 1, &[]).unwrap();
+        let layout = allocator.lock().unwrap().register_layout();
+
+        let set = PooledDescriptorSet::allocate(&allocator, layout, &MockHandle).unwrap();
+        let _ = PooledDescriptorSet::allocate(&allocator, layout, &MockHandle).unwrap();
+        drop(allocator);
+        drop(set);
+
+        assert!(device.live_objects().is_empty());
+        assert!(device.live_resources().is_empty());
+    }
+}
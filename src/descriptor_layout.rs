@@ -0,0 +1,290 @@
+use super::{DeviceContext, HalResultExt};
+use core::mem::ManuallyDrop;
+use gfx_hal::{
+    device::Device,
+    pso::{DescriptorRangeDesc, DescriptorSetLayoutBinding, DescriptorType, ShaderStageFlags},
+    Backend,
+};
+use std::{ops::Deref, sync::Arc};
+
+/// Declares the bindings of a descriptor set layout one at a time, so whatever
+/// fills in the sets later can look up what goes where instead of guessing.
+///
+/// ```ignore
+/// let layout = DescriptorLayoutBuilder::new()
+///     .binding(0, DescriptorType::UniformBuffer, 1, ShaderStageFlags::VERTEX)
+///     .binding(2, DescriptorType::CombinedImageSampler, 1, ShaderStageFlags::FRAGMENT)
+///     .build(&device)?;
+/// ```
+pub struct DescriptorLayoutBuilder<'a, B: Backend> {
+    bindings: Vec<DescriptorSetLayoutBinding>,
+    immutable_samplers: Vec<&'a B::Sampler>,
+}
+
+impl<'a, B: Backend> DescriptorLayoutBuilder<'a, B> {
+    pub fn new() -> Self {
+        DescriptorLayoutBuilder {
+            bindings: Vec::new(),
+            immutable_samplers: Vec::new(),
+        }
+    }
+
+    /// Declares `count` descriptors of type `ty` at `binding`.
+    pub fn binding(
+        mut self,
+        binding: u32,
+        ty: DescriptorType,
+        count: usize,
+        stage_flags: ShaderStageFlags,
+    ) -> Self {
+        self.bindings.push(DescriptorSetLayoutBinding {
+            binding,
+            ty,
+            count,
+            stage_flags,
+            immutable_samplers: false,
+        });
+        self
+    }
+
+    /// Declares a `Sampler` or `CombinedImageSampler` binding whose samplers are
+    /// baked into the layout. There's one descriptor per sampler in `samplers`.
+    pub fn immutable_samplers(
+        mut self,
+        binding: u32,
+        ty: DescriptorType,
+        stage_flags: ShaderStageFlags,
+        samplers: &[&'a B::Sampler],
+    ) -> Self {
+        self.bindings.push(DescriptorSetLayoutBinding {
+            binding,
+            ty,
+            count: samplers.len(),
+            stage_flags,
+            immutable_samplers: true,
+        });
+        self.immutable_samplers.extend_from_slice(samplers);
+        self
+    }
+
+    /// Declares an already filled out binding, like the ones `UniformRing::layout_binding`
+    /// and `PipelineBundle::storage_buffer_binding` give back.
+    pub fn push(mut self, binding: DescriptorSetLayoutBinding) -> Self {
+        self.bindings.push(binding);
+        self
+    }
+
+    pub fn build(
+        self,
+        device: &Arc<DeviceContext<B>>,
+    ) -> Result<DescriptorLayout<B>, failure::Error> {
+        for (i, declared) in self.bindings.iter().enumerate() {
+            if declared.count == 0 {
+                bail_invalid!(
+                    "DescriptorLayout",
+                    "Binding {} doesn't have any descriptors in it!",
+                    declared.binding
+                );
+            }
+            if self.bindings[..i]
+                .iter()
+                .any(|earlier| earlier.binding == declared.binding)
+            {
+                bail_invalid!(
+                    "DescriptorLayout",
+                    "Binding {} was declared twice!",
+                    declared.binding
+                );
+            }
+            if declared.immutable_samplers
+                && declared.ty != DescriptorType::Sampler
+                && declared.ty != DescriptorType::CombinedImageSampler
+            {
+                bail_invalid!(
+                    "DescriptorLayout",
+                    "Binding {} is a {:?}, which can't have immutable samplers!",
+                    declared.binding,
+                    declared.ty
+                );
+            }
+        }
+
+        //  The immutable samplers get handed out to bindings in the order we declared them
+        let layout = unsafe {
+            device
+                .create_descriptor_set_layout(&self.bindings, self.immutable_samplers)
+                .making("DescriptorSetLayout", None)?
+        };
+
+        device.track("DescriptorLayout");
+        Ok(DescriptorLayout {
+            layout: manual_new!(layout),
+            bindings: self.bindings,
+            device: Arc::clone(device),
+        })
+    }
+}
+
+impl<'a, B: Backend> Default for DescriptorLayoutBuilder<'a, B> {
+    fn default() -> Self {
+        DescriptorLayoutBuilder::new()
+    }
+}
+
+/// A descriptor set layout along with the bindings it was declared with.
+pub struct DescriptorLayout<B: Backend> {
+    pub layout: ManuallyDrop<B::DescriptorSetLayout>,
+    pub bindings: Vec<DescriptorSetLayoutBinding>,
+    pub device: Arc<DeviceContext<B>>,
+}
+
+impl<B: Backend> DescriptorLayout<B> {
+    pub fn binding(&self, binding: u32) -> Option<&DescriptorSetLayoutBinding> {
+        self.bindings
+            .iter()
+            .find(|declared| declared.binding == binding)
+    }
+
+    /// What a pool needs to hold `sets` sets of this layout, ready to hand to a
+    /// `DescriptorAllocator`.
+    pub fn pool_ranges(&self, sets: usize) -> Vec<DescriptorRangeDesc> {
+        let mut ranges: Vec<DescriptorRangeDesc> = Vec::new();
+        for declared in &self.bindings {
+            match ranges.iter_mut().find(|range| range.ty == declared.ty) {
+                Some(range) => range.count += declared.count * sets,
+                None => ranges.push(DescriptorRangeDesc {
+                    ty: declared.ty,
+                    count: declared.count * sets,
+                }),
+            }
+        }
+        ranges
+    }
+}
+
+impl<B: Backend> Deref for DescriptorLayout<B> {
+    type Target = B::DescriptorSetLayout;
+
+    fn deref(&self) -> &B::DescriptorSetLayout {
+        &self.layout
+    }
+}
+
+impl<B: Backend> Drop for DescriptorLayout<B> {
+    fn drop(&mut self) {
+        use core::ptr::read;
+        unsafe {
+            self.device
+                .destroy_descriptor_set_layout(manual_drop!(self.layout));
+        }
+        self.device.untrack("DescriptorLayout");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DescriptorLayoutBuilder;
+    use crate::mock_backend::{self, Mock, MockHandle};
+    use gfx_hal::pso::{DescriptorType, ShaderStageFlags};
+
+    #[test]
+    fn pool_ranges_add_up_bindings_of_the_same_type() {
+        let device = mock_backend::device();
+        let layout = DescriptorLayoutBuilder::<Mock>::new()
+            .binding(
+                0,
+                DescriptorType::SampledImage,
+                2,
+                ShaderStageFlags::FRAGMENT,
+            )
+            .binding(1, DescriptorType::Sampler, 1, ShaderStageFlags::FRAGMENT)
+            .binding(
+                3,
+                DescriptorType::SampledImage,
+                1,
+                ShaderStageFlags::FRAGMENT,
+            )
+            .build(&device)
+            .unwrap();
+
+        let ranges = layout.pool_ranges(4);
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].ty, DescriptorType::SampledImage);
+        assert_eq!(ranges[0].count, 12);
+        assert_eq!(ranges[1].ty, DescriptorType::Sampler);
+        assert_eq!(ranges[1].count, 4);
+        assert_eq!(layout.binding(3).unwrap().count, 1);
+        assert!(layout.binding(2).is_none());
+    }
+
+    #[test]
+    fn immutable_samplers_count_as_the_binding_size() {
+        let device = mock_backend::device();
+        let layout = DescriptorLayoutBuilder::<Mock>::new()
+            .immutable_samplers(
+                0,
+                DescriptorType::CombinedImageSampler,
+                ShaderStageFlags::FRAGMENT,
+                &[&MockHandle, &MockHandle],
+            )
+            .build(&device)
+            .unwrap();
+
+        assert!(layout.bindings[0].immutable_samplers);
+        assert_eq!(layout.bindings[0].count, 2);
+    }
+
+    #[test]
+    fn rejects_bad_declarations() {
+        let device = mock_backend::device();
+        let twice = DescriptorLayoutBuilder::<Mock>::new()
+            .binding(
+                0,
+                DescriptorType::SampledImage,
+                1,
+                ShaderStageFlags::FRAGMENT,
+            )
+            .binding(0, DescriptorType::Sampler, 1, ShaderStageFlags::FRAGMENT)
+            .build(&device);
+        let empty = DescriptorLayoutBuilder::<Mock>::new()
+            .binding(
+                0,
+                DescriptorType::SampledImage,
+                0,
+                ShaderStageFlags::FRAGMENT,
+            )
+            .build(&device);
+        let immutable_image = DescriptorLayoutBuilder::<Mock>::new()
+            .immutable_samplers(
+                0,
+                DescriptorType::SampledImage,
+                ShaderStageFlags::FRAGMENT,
+                &[&MockHandle],
+            )
+            .build(&device);
+
+        assert!(twice.is_err());
+        assert!(empty.is_err());
+        assert!(immutable_image.is_err());
+        assert!(device.live_objects().is_empty());
+    }
+
+    #[test]
+    fn dropping_the_layout_destroys_it() {
+        let device = mock_backend::device();
+        let layout = DescriptorLayoutBuilder::<Mock>::new()
+            .binding(
+                0,
+                DescriptorType::UniformBuffer,
+                1,
+                ShaderStageFlags::VERTEX,
+            )
+            .build(&device)
+            .unwrap();
+        assert_eq!(device.live_resources(), vec![("DescriptorLayout", 1)]);
+
+        drop(layout);
+        assert!(device.live_objects().is_empty());
+        assert!(device.live_resources().is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+use super::HalResultExt;
+use gfx_hal::{device::Device, Backend};
+use std::{ops::Deref, sync::Arc};
+
+#[cfg(debug_assertions)]
+use std::{collections::HashMap, sync::Mutex};
+
+/// Owns the logical device. Everything we make on the GPU holds an `Arc` to one of
+/// these, so it can clean up after itself in `Drop` and the device can't go away
+/// underneath it.
+///
+/// In debug builds we also count what's alive, so `teardown` can tell you what
+/// you forgot to drop.
+pub struct DeviceContext<B: Backend> {
+    pub device: B::Device,
+    #[cfg(debug_assertions)]
+    live: Mutex<HashMap<&'static str, usize>>,
+}
+
+impl<B: Backend> DeviceContext<B> {
+    pub fn new(device: B::Device) -> Arc<Self> {
+        Arc::new(DeviceContext {
+            device,
+            #[cfg(debug_assertions)]
+            live: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Notes that a resource called `name` was made on this device.
+    #[allow(unused_variables)]
+    pub fn track(&self, name: &'static str) {
+        #[cfg(debug_assertions)]
+        {
+            let mut live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+            *live.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    /// Notes that a resource called `name` was destroyed.
+    #[allow(unused_variables)]
+    pub fn untrack(&self, name: &'static str) {
+        #[cfg(debug_assertions)]
+        {
+            let mut live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(count) = live.get_mut(name) {
+                *count -= 1;
+                if *count == 0 {
+                    live.remove(name);
+                }
+            }
+        }
+    }
+
+    /// Everything still alive on this device, by name. Always empty in release builds.
+    pub fn live_resources(&self) -> Vec<(&'static str, usize)> {
+        #[cfg(debug_assertions)]
+        {
+            let live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+            let mut resources: Vec<_> = live.iter().map(|(name, count)| (*name, *count)).collect();
+            resources.sort();
+            resources
+        }
+
+        #[cfg(not(debug_assertions))]
+        Vec::new()
+    }
+
+    /// Waits for the device to go idle and lets go of our handle to it. If anything
+    /// is still holding onto the device, it lives on until that's dropped too, and we
+    /// hand back whatever is still alive (always nothing in release builds). Our
+    /// handle is let go of even if waiting fails.
+    pub fn teardown(context: Arc<Self>) -> Result<Vec<(&'static str, usize)>, failure::Error> {
+        let idle = context.device.wait_idle().making("Device", None);
+        let leaked = match Arc::try_unwrap(context) {
+            Ok(_) => Vec::new(),
+            Err(context) => context.live_resources(),
+        };
+        idle?;
+        Ok(leaked)
+    }
+}
+
+impl<B: Backend> Deref for DeviceContext<B> {
+    type Target = B::Device;
+
+    fn deref(&self) -> &B::Device {
+        &self.device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceContext;
+    use crate::{mock_backend, BufferBundle};
+    use gfx_hal::buffer;
+
+    #[test]
+    fn teardown_reports_what_is_still_alive() {
+        let device = mock_backend::device();
+        let bundle = BufferBundle::new(
+            &mock_backend::adapter(),
+            &device,
+            64,
+            buffer::Usage::VERTEX,
+            true,
+        )
+        .unwrap();
+
+        let leaked = DeviceContext::teardown(device).unwrap();
+        assert_eq!(leaked, vec![("BufferBundle", 1)]);
+        drop(bundle);
+    }
+
+    #[test]
+    fn teardown_of_an_empty_device_reports_nothing() {
+        let device = mock_backend::device();
+        assert!(DeviceContext::teardown(device).unwrap().is_empty());
+    }
+}
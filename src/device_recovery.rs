@@ -0,0 +1,546 @@
+use super::{
+    decode_texture, BufferBundle, DeviceContext, LoadedImage, PipelineBundle, RetirementQueue,
+    TextureOptions,
+};
+use gfx_hal::{
+    adapter::Adapter, buffer, pool::CommandPool, Backend, Capability, CommandQueue, Supports,
+    Transfer,
+};
+use std::{mem, path::PathBuf, slice, sync::Arc};
+
+/// Which texture in a `ResourceRegistry`. It stays the same through a recovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureHandle(usize);
+
+/// Which buffer in a `ResourceRegistry`. It stays the same through a recovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BufferHandle(usize);
+
+/// Where a texture's pixels came from, so we can get them again.
+#[derive(Debug, Clone)]
+pub enum TextureSource {
+    /// RGBA8 pixels, which we keep a copy of.
+    Pixels {
+        data: Vec<u8>,
+        width: usize,
+        height: usize,
+    },
+    /// An image file, which gets loaded again.
+    File(PathBuf),
+}
+
+/// What goes in a buffer when it's made again.
+#[derive(Debug, Clone)]
+pub enum BufferSource {
+    /// A device-local buffer, which gets these bytes uploaded again.
+    Contents(Vec<u8>),
+    /// A mapped buffer of this many bytes. It comes back empty, since whatever
+    /// the CPU writes into it gets written again next frame anyway.
+    Mapped(u64),
+}
+
+impl BufferSource {
+    /// The bytes of `data`, for a device-local buffer of `T`s.
+    ///
+    /// # Safety
+    ///
+    /// `T` mustn't have any padding, since padding bytes are uninitialized and
+    /// we read every byte of `data`.
+    pub unsafe fn contents<T: Copy>(data: &[T]) -> Self {
+        let bytes = slice::from_raw_parts(data.as_ptr() as *const u8, mem::size_of_val(data));
+        BufferSource::Contents(bytes.to_vec())
+    }
+}
+
+struct RecoverableTexture<B: Backend> {
+    texture: LoadedImage<B>,
+    source: TextureSource,
+    options: TextureOptions,
+}
+
+struct RecoverableBuffer<B: Backend> {
+    bundle: BufferBundle<B>,
+    source: BufferSource,
+    usage: buffer::Usage,
+}
+
+/// Textures and buffers which remember what they were made from, so they can
+/// all be made again on a new device after the old one is lost.
+///
+/// When anything gives back an error whose `ErrorKind` is `DeviceLost`, make a
+/// new device, a new pipeline bundle on it, and call `recover`. Every handle
+/// points at its remade resource afterwards. Anything written into a texture
+/// after it was registered isn't remembered, so register it again instead.
+///
+/// Everything else that owns textures or buffers, like a `TextureAtlas` or a
+/// `UniformRing`, has a `recover` of its own to call at the same time.
+pub struct ResourceRegistry<B: Backend> {
+    textures: Vec<Option<RecoverableTexture<B>>>,
+    buffers: Vec<Option<RecoverableBuffer<B>>>,
+    retired_textures: RetirementQueue<LoadedImage<B>>,
+    retired_buffers: RetirementQueue<BufferBundle<B>>,
+}
+
+impl<B: Backend> ResourceRegistry<B> {
+    pub fn new() -> Self {
+        ResourceRegistry {
+            textures: Vec::new(),
+            buffers: Vec::new(),
+            retired_textures: RetirementQueue::new(),
+            retired_buffers: RetirementQueue::new(),
+        }
+    }
+
+    /// Makes a texture out of `source` with a descriptor set from `pipeline_bundle`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn register_texture<C: Capability + Supports<Transfer>>(
+        &mut self,
+        source: TextureSource,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+        options: impl Into<TextureOptions>,
+    ) -> Result<TextureHandle, failure::Error> {
+        if let TextureSource::Pixels {
+            data,
+            width,
+            height,
+        } = &source
+        {
+            let needed = width
+                .checked_mul(*height)
+                .and_then(|pixels| pixels.checked_mul(4));
+            if needed.is_none_or(|needed| data.len() < needed) {
+                bail_invalid!(
+                    "Texture",
+                    "A {}x{} texture needs more than the {} bytes of pixels we were given!",
+                    width,
+                    height,
+                    data.len()
+                );
+            }
+        }
+
+        let options = options.into();
+        let texture = make_texture(
+            &source,
+            options,
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            pipeline_bundle,
+        )?;
+
+        self.textures.push(Some(RecoverableTexture {
+            texture,
+            source,
+            options,
+        }));
+        Ok(TextureHandle(self.textures.len() - 1))
+    }
+
+    /// Makes a buffer out of `source`, device-local if it has contents and
+    /// mapped if it doesn't.
+    pub fn register_buffer<C: Capability + Supports<Transfer>>(
+        &mut self,
+        source: BufferSource,
+        usage: buffer::Usage,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+    ) -> Result<BufferHandle, failure::Error> {
+        let bundle = make_buffer(&source, usage, adapter, device, command_pool, command_queue)?;
+
+        self.buffers.push(Some(RecoverableBuffer {
+            bundle,
+            source,
+            usage,
+        }));
+        Ok(BufferHandle(self.buffers.len() - 1))
+    }
+
+    pub fn texture(&self, handle: TextureHandle) -> Option<&LoadedImage<B>> {
+        self.textures
+            .get(handle.0)
+            .and_then(Option::as_ref)
+            .map(|entry| &entry.texture)
+    }
+
+    pub fn buffer(&self, handle: BufferHandle) -> Option<&BufferBundle<B>> {
+        self.buffers
+            .get(handle.0)
+            .and_then(Option::as_ref)
+            .map(|entry| &entry.bundle)
+    }
+
+    /// Forgets the texture, which is destroyed once `retire_frames` says frames
+    /// up to `last_used_frame` are done with it.
+    pub fn unregister_texture(&mut self, handle: TextureHandle, last_used_frame: u64) -> bool {
+        match self.textures.get_mut(handle.0).and_then(Option::take) {
+            Some(entry) => {
+                self.retired_textures.retire(last_used_frame, entry.texture);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Like `unregister_texture`, for a buffer.
+    pub fn unregister_buffer(&mut self, handle: BufferHandle, last_used_frame: u64) -> bool {
+        match self.buffers.get_mut(handle.0).and_then(Option::take) {
+            Some(entry) => {
+                self.retired_buffers.retire(last_used_frame, entry.bundle);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Destroys everything unregistered whose last frame is at or before `completed_frame`.
+    pub fn retire_frames(&mut self, completed_frame: u64) {
+        self.retired_textures.drain_completed(completed_frame);
+        self.retired_buffers.drain_completed(completed_frame);
+    }
+
+    /// Makes every texture and buffer again on `device`, with descriptor sets from
+    /// `pipeline_bundle`, which has to have been made on `device` too. Nothing is
+    /// replaced unless everything could be made, so a failed recovery can be tried again.
+    pub fn recover<C: Capability + Supports<Transfer>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+    ) -> Result<(), failure::Error> {
+        //  If anything fails, what we've made so far gets dropped on the way out
+        let mut textures = Vec::with_capacity(self.textures.len());
+        for entry in self.textures.iter().flatten() {
+            textures.push(make_texture(
+                &entry.source,
+                entry.options,
+                adapter,
+                device,
+                command_pool,
+                command_queue,
+                pipeline_bundle,
+            )?);
+        }
+
+        let mut buffers = Vec::with_capacity(self.buffers.len());
+        for entry in self.buffers.iter().flatten() {
+            buffers.push(make_buffer(
+                &entry.source,
+                entry.usage,
+                adapter,
+                device,
+                command_pool,
+                command_queue,
+            )?);
+        }
+
+        //  The old device is gone, so nothing can still be using what we retired
+        self.retired_textures.drain_all();
+        self.retired_buffers.drain_all();
+
+        for (entry, texture) in self.textures.iter_mut().flatten().zip(textures) {
+            entry.texture = texture;
+        }
+        for (entry, bundle) in self.buffers.iter_mut().flatten().zip(buffers) {
+            entry.bundle = bundle;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Backend> Default for ResourceRegistry<B> {
+    fn default() -> Self {
+        ResourceRegistry::new()
+    }
+}
+
+fn make_texture<B: Backend, C: Capability + Supports<Transfer>>(
+    source: &TextureSource,
+    options: TextureOptions,
+    adapter: &Adapter<B>,
+    device: &Arc<DeviceContext<B>>,
+    command_pool: &mut CommandPool<B, C>,
+    command_queue: &mut CommandQueue<B, C>,
+    pipeline_bundle: &mut PipelineBundle<B>,
+) -> Result<LoadedImage<B>, failure::Error> {
+    match source {
+        TextureSource::Pixels {
+            data,
+            width,
+            height,
+        } => LoadedImage::allocate_and_create(
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            pipeline_bundle,
+            data,
+            *width,
+            *height,
+            options,
+        ),
+        TextureSource::File(path) => {
+            let image = decode_texture(path)?;
+            LoadedImage::allocate_and_create(
+                adapter,
+                device,
+                command_pool,
+                command_queue,
+                pipeline_bundle,
+                &image,
+                image.width() as usize,
+                image.height() as usize,
+                options,
+            )
+        }
+    }
+}
+
+fn make_buffer<B: Backend, C: Capability + Supports<Transfer>>(
+    source: &BufferSource,
+    usage: buffer::Usage,
+    adapter: &Adapter<B>,
+    device: &Arc<DeviceContext<B>>,
+    command_pool: &mut CommandPool<B, C>,
+    command_queue: &mut CommandQueue<B, C>,
+) -> Result<BufferBundle<B>, failure::Error> {
+    match source {
+        BufferSource::Contents(data) => BufferBundle::new_device_local(
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            data,
+            usage,
+        ),
+        BufferSource::Mapped(size) => BufferBundle::new(adapter, device, *size, usage, true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferSource, ResourceRegistry, TextureSource};
+    use crate::{
+        mock_backend::{self, Mock},
+        DeviceContext, ErrorKind,
+    };
+    use gfx_hal::{buffer, image::Filter};
+    use std::{env, fs, sync::Arc};
+
+    fn count(device: &DeviceContext<Mock>, kind: &str) -> usize {
+        device
+            .live_objects()
+            .iter()
+            .find(|&&(live, _)| live == kind)
+            .map_or(0, |&(_, count)| count)
+    }
+
+    fn pixels() -> TextureSource {
+        TextureSource::Pixels {
+            data: vec![0xFF; 2 * 2 * 4],
+            width: 2,
+            height: 2,
+        }
+    }
+
+    #[test]
+    fn recovery_remakes_everything_on_the_new_device_with_the_same_handles() {
+        let path = env::temp_dir().join("device_recovery_remakes.png");
+        image::RgbaImage::new(4, 4).save(&path).unwrap();
+
+        let adapter = mock_backend::adapter();
+        let lost = mock_backend::device();
+        let mut lost_bundle = mock_backend::pipeline_bundle(&lost, 4);
+        let mut registry = ResourceRegistry::new();
+        let mut pool = mock_backend::command_pool(&lost);
+        let mut queue = mock_backend::command_queue();
+
+        let from_pixels = registry
+            .register_texture(
+                pixels(),
+                &adapter,
+                &lost,
+                &mut pool,
+                &mut queue,
+                &mut lost_bundle,
+                Filter::Nearest,
+            )
+            .unwrap();
+        let from_file = registry
+            .register_texture(
+                TextureSource::File(path.clone()),
+                &adapter,
+                &lost,
+                &mut pool,
+                &mut queue,
+                &mut lost_bundle,
+                Filter::Linear,
+            )
+            .unwrap();
+        let vertices = registry
+            .register_buffer(
+                unsafe { BufferSource::contents(&[1.0f32, 2.0, 3.0]) },
+                buffer::Usage::VERTEX,
+                &adapter,
+                &lost,
+                &mut pool,
+                &mut queue,
+            )
+            .unwrap();
+        let uniforms = registry
+            .register_buffer(
+                BufferSource::Mapped(256),
+                buffer::Usage::UNIFORM,
+                &adapter,
+                &lost,
+                &mut pool,
+                &mut queue,
+            )
+            .unwrap();
+
+        //  Then the driver resets in the middle of an upload
+        lost.inject_fault("wait_for_fence", 0);
+        let error = registry
+            .register_texture(
+                pixels(),
+                &adapter,
+                &lost,
+                &mut pool,
+                &mut queue,
+                &mut lost_bundle,
+                Filter::Nearest,
+            )
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::DeviceLost);
+        drop(pool);
+
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 4);
+        registry
+            .recover(
+                &adapter,
+                &device,
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+                &mut pipeline_bundle,
+            )
+            .unwrap();
+
+        for &handle in &[from_pixels, from_file] {
+            let texture = registry.texture(handle).unwrap();
+            assert!(Arc::ptr_eq(&texture.device, &device));
+            assert!(texture.descriptor_set.is_some());
+        }
+        assert_eq!(registry.texture(from_file).unwrap().width, 4);
+        assert_eq!(registry.buffer(vertices).unwrap().requirements.size, 12);
+        assert!(registry.buffer(uniforms).unwrap().mapped.is_some());
+        assert_eq!(count(&device, "Image"), 2);
+        assert_eq!(count(&device, "Buffer"), 2);
+
+        //  Nothing's left on the old device apart from its pipeline bundle
+        assert_eq!(count(&lost, "Image"), 0);
+        assert_eq!(count(&lost, "Buffer"), 0);
+        drop(lost_bundle);
+        assert!(lost.live_resources().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pixels_have_to_cover_the_whole_texture() {
+        let adapter = mock_backend::adapter();
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 4);
+        let mut registry = ResourceRegistry::new();
+
+        for &(width, height) in &[(2, 3), (usize::MAX, 2)] {
+            let error = registry
+                .register_texture(
+                    TextureSource::Pixels {
+                        data: vec![0xFF; 2 * 2 * 4],
+                        width,
+                        height,
+                    },
+                    &adapter,
+                    &device,
+                    &mut mock_backend::command_pool(&device),
+                    &mut mock_backend::command_queue(),
+                    &mut pipeline_bundle,
+                    Filter::Nearest,
+                )
+                .unwrap_err();
+            assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+        }
+        assert_eq!(count(&device, "Image"), 0);
+    }
+
+    #[test]
+    fn a_failed_recovery_keeps_the_old_resources_and_can_be_tried_again() {
+        let adapter = mock_backend::adapter();
+        let lost = mock_backend::device();
+        let mut lost_bundle = mock_backend::pipeline_bundle(&lost, 4);
+        let mut registry = ResourceRegistry::new();
+        let mut handles = Vec::new();
+        for _ in 0..2 {
+            handles.push(
+                registry
+                    .register_texture(
+                        pixels(),
+                        &adapter,
+                        &lost,
+                        &mut mock_backend::command_pool(&lost),
+                        &mut mock_backend::command_queue(),
+                        &mut lost_bundle,
+                        Filter::Nearest,
+                    )
+                    .unwrap(),
+            );
+        }
+
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 4);
+        device.inject_fault("create_image", 1);
+        let mut pool = mock_backend::command_pool(&device);
+        let mut queue = mock_backend::command_queue();
+        assert!(registry
+            .recover(
+                &adapter,
+                &device,
+                &mut pool,
+                &mut queue,
+                &mut pipeline_bundle
+            )
+            .is_err());
+        assert_eq!(count(&device, "Image"), 0);
+        assert!(Arc::ptr_eq(
+            &registry.texture(handles[1]).unwrap().device,
+            &lost
+        ));
+
+        registry
+            .recover(
+                &adapter,
+                &device,
+                &mut pool,
+                &mut queue,
+                &mut pipeline_bundle,
+            )
+            .unwrap();
+        for &handle in &handles {
+            assert!(Arc::ptr_eq(
+                &registry.texture(handle).unwrap().device,
+                &device
+            ));
+        }
+        assert_eq!(count(&device, "Image"), 2);
+        assert_eq!(count(&lost, "Image"), 0);
+    }
+}
@@ -1,4 +1,9 @@
-use gfx_hal::device::{OomOrDeviceLost, OutOfMemory};
+// `failure`'s `#[derive(Fail)]` puts its impls inside a `const _`, which newer
+// compilers warn about. The impls are fine, so quiet it until we move off `failure`.
+#![allow(non_local_definitions)]
+
+use gfx_hal::device::{AllocationError, BindError, OomOrDeviceLost, OutOfMemory};
+use std::fmt;
 
 #[allow(unused_macros)]
 macro_rules! quick_from {
@@ -11,98 +16,383 @@ macro_rules! quick_from {
     };
 }
 
-use gfx_hal::buffer::CreationError;
+/// The sort of thing that went wrong, for deciding what to do about an error
+/// without picking through its message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    OutOfHostMemory,
+    /// Freeing some textures or buffers and trying again might get there.
+    OutOfDeviceMemory,
+    /// Everything made on the device is gone and has to be made again.
+    DeviceLost,
+    /// We were asked for something that doesn't make sense, so trying again won't help.
+    InvalidArgument,
+    /// The device can't do a format, usage or kind of memory we need.
+    UnsupportedFormat,
+    /// Something with a fixed size is full, like a bindless table or a uniform ring.
+    PoolExhausted,
+    /// Anything else, like a file that won't load.
+    Other,
+}
+
+impl ErrorKind {
+    /// The kind of anything one of our functions gave back. Only a `RenderError`
+    /// has a kind of its own, and everything else is `Other`.
+    pub fn of(error: &failure::Error) -> ErrorKind {
+        error
+            .downcast_ref::<RenderError>()
+            .map_or(ErrorKind::Other, RenderError::kind)
+    }
+}
+
+/// Everything that goes wrong making and using resources, along with which sort
+/// of resource it was. Our functions hand these back as `failure::Error`s, so
+/// `ErrorKind::of` or `downcast_ref` gets them out again.
 #[derive(Debug, Fail)]
-pub enum BufferBundleError {
-    Creation(#[cause] CreationError),
+pub enum RenderError {
+    /// `bytes` is how much we asked for, when we know.
+    OutOfMemory {
+        resource: &'static str,
+        memory: OutOfMemory,
+        bytes: Option<u64>,
+    },
+    DeviceLost {
+        resource: &'static str,
+    },
+    InvalidArgument {
+        resource: &'static str,
+        message: String,
+    },
+    UnsupportedFormat {
+        resource: &'static str,
+        message: String,
+    },
+    /// `capacity` is in whatever the resource counts in: slots, bytes or pixels.
+    PoolExhausted {
+        resource: &'static str,
+        capacity: u64,
+        message: String,
+    },
+    /// The backend turned us down for some other reason.
+    Backend {
+        resource: &'static str,
+        message: String,
+    },
+    /// A file wouldn't read or decode.
+    Load {
+        resource: &'static str,
+        message: String,
+    },
+}
+
+impl RenderError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RenderError::OutOfMemory {
+                memory: OutOfMemory::OutOfHostMemory,
+                ..
+            } => ErrorKind::OutOfHostMemory,
+            RenderError::OutOfMemory {
+                memory: OutOfMemory::OutOfDeviceMemory,
+                ..
+            } => ErrorKind::OutOfDeviceMemory,
+            RenderError::DeviceLost { .. } => ErrorKind::DeviceLost,
+            RenderError::InvalidArgument { .. } => ErrorKind::InvalidArgument,
+            RenderError::UnsupportedFormat { .. } => ErrorKind::UnsupportedFormat,
+            RenderError::PoolExhausted { .. } => ErrorKind::PoolExhausted,
+            RenderError::Backend { .. } | RenderError::Load { .. } => ErrorKind::Other,
+        }
+    }
+
+    /// What we were making or using, like `"Image"` or `"UniformRing"`.
+    pub fn resource(&self) -> &'static str {
+        match self {
+            RenderError::OutOfMemory { resource, .. }
+            | RenderError::DeviceLost { resource }
+            | RenderError::InvalidArgument { resource, .. }
+            | RenderError::UnsupportedFormat { resource, .. }
+            | RenderError::PoolExhausted { resource, .. }
+            | RenderError::Backend { resource, .. }
+            | RenderError::Load { resource, .. } => resource,
+        }
+    }
+
+    pub(crate) fn invalid(resource: &'static str, message: impl Into<String>) -> Self {
+        RenderError::InvalidArgument {
+            resource,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn unsupported(resource: &'static str, message: impl Into<String>) -> Self {
+        RenderError::UnsupportedFormat {
+            resource,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn exhausted(
+        resource: &'static str,
+        capacity: u64,
+        message: impl Into<String>,
+    ) -> Self {
+        RenderError::PoolExhausted {
+            resource,
+            capacity,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn load(resource: &'static str, message: impl Into<String>) -> Self {
+        RenderError::Load {
+            resource,
+            message: message.into(),
+        }
+    }
+
+    /// Sorts a gfx-hal error into one of ours.
+    pub(crate) fn from_hal(
+        resource: &'static str,
+        bytes: Option<u64>,
+        error: impl HalError,
+    ) -> Self {
+        match error.kind() {
+            ErrorKind::OutOfHostMemory => RenderError::OutOfMemory {
+                resource,
+                memory: OutOfMemory::OutOfHostMemory,
+                bytes,
+            },
+            ErrorKind::OutOfDeviceMemory => RenderError::OutOfMemory {
+                resource,
+                memory: OutOfMemory::OutOfDeviceMemory,
+                bytes,
+            },
+            ErrorKind::DeviceLost => RenderError::DeviceLost { resource },
+            ErrorKind::UnsupportedFormat => RenderError::unsupported(resource, error.to_string()),
+            ErrorKind::PoolExhausted => RenderError::exhausted(resource, 0, error.to_string()),
+            ErrorKind::InvalidArgument => RenderError::invalid(resource, error.to_string()),
+            ErrorKind::Other => RenderError::Backend {
+                resource,
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::OutOfMemory {
+                resource,
+                memory,
+                bytes: Some(bytes),
+            } => write!(f, "{} making a {} of {} bytes!", memory, resource, bytes),
+            RenderError::OutOfMemory {
+                resource, memory, ..
+            } => write!(f, "{} making a {}!", memory, resource),
+            RenderError::DeviceLost { resource } => {
+                write!(f, "The device was lost while we were using a {}!", resource)
+            }
+            RenderError::InvalidArgument { message, .. }
+            | RenderError::UnsupportedFormat { message, .. }
+            | RenderError::PoolExhausted { message, .. }
+            | RenderError::Load { message, .. } => write!(f, "{}", message),
+            RenderError::Backend { resource, message } => {
+                write!(f, "Couldn't make a {}! => {}", resource, message)
+            }
+        }
+    }
 }
 
-impl std::fmt::Display for BufferBundleError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let write_this = match self {
-            BufferBundleError::Creation(e) => format!("Buffer creation error! => {}", e),
-        };
+/// The gfx-hal errors we know how to sort into kinds.
+pub(crate) trait HalError: fmt::Display {
+    fn kind(&self) -> ErrorKind;
+}
 
-        write!(f, "{}", write_this)
+impl HalError for OutOfMemory {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            OutOfMemory::OutOfHostMemory => ErrorKind::OutOfHostMemory,
+            OutOfMemory::OutOfDeviceMemory => ErrorKind::OutOfDeviceMemory,
+        }
     }
 }
 
-#[derive(Debug, Fail)]
-pub enum BufferError {
-    MemoryId,
-    Allocate(#[cause] gfx_hal::device::AllocationError),
-    Bind(#[cause] gfx_hal::device::BindError),
+impl HalError for OomOrDeviceLost {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            OomOrDeviceLost::OutOfMemory(e) => e.kind(),
+            OomOrDeviceLost::DeviceLost(_) => ErrorKind::DeviceLost,
+        }
+    }
 }
 
-impl std::fmt::Display for BufferError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let write_this = match self {
-            BufferError::MemoryId => format!("MemoryID Error"),
-            BufferError::Allocate(e) => format!("Buffer allocation error! => {}", e),
-            BufferError::Bind(e) => format!("Buffer binding error! => {}", e),
-        };
+impl HalError for gfx_hal::error::HostExecutionError {
+    fn kind(&self) -> ErrorKind {
+        use gfx_hal::error::HostExecutionError;
+        match self {
+            HostExecutionError::OutOfHostMemory => ErrorKind::OutOfHostMemory,
+            HostExecutionError::OutOfDeviceMemory => ErrorKind::OutOfDeviceMemory,
+            HostExecutionError::DeviceLost => ErrorKind::DeviceLost,
+        }
+    }
+}
 
-        write!(f, "{}", write_this)
+impl HalError for AllocationError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            AllocationError::OutOfMemory(e) => e.kind(),
+            AllocationError::TooManyObjects => ErrorKind::PoolExhausted,
+        }
     }
 }
 
-#[derive(Debug, Fail)]
-pub enum LoadedImageError {
-    AcquireMappingWriter(#[cause] gfx_hal::mapping::Error),
-    ReleaseMappingWriter(#[cause] OutOfMemory),
-    CreateImage(#[cause] gfx_hal::image::CreationError),
-    ImageView(#[cause] gfx_hal::image::ViewError),
-    Sampler(#[cause] gfx_hal::device::AllocationError),
-    UploadFence(#[cause] OutOfMemory),
-    WaitForFence(#[cause] OomOrDeviceLost),
-}
-
-impl std::fmt::Display for LoadedImageError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let write_this = match self {
-            LoadedImageError::AcquireMappingWriter(e) => format!(
-                "Couldn't acquire a mapping writer to the staging buffer! => {}",
-                e
-            ),
-            LoadedImageError::ReleaseMappingWriter(e) => format!(
-                "Couldn't release the mapping writer to the staging buffer! => {}",
-                e
-            ),
-            LoadedImageError::CreateImage(e) => format!("Couldn't create the image! => {}", e),
-            LoadedImageError::ImageView(e) => format!("Couldn't create the image view! => {}", e),
-            LoadedImageError::Sampler(e) => format!("Couldn't create the sampler! => {}", e),
-            LoadedImageError::UploadFence(e) => {
-                format!("Couldn't create the upload fence! => {}", e)
+impl HalError for BindError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            BindError::OutOfMemory(e) => e.kind(),
+            BindError::WrongMemory => ErrorKind::UnsupportedFormat,
+            BindError::OutOfBounds => ErrorKind::InvalidArgument,
+        }
+    }
+}
+
+impl HalError for gfx_hal::buffer::CreationError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            gfx_hal::buffer::CreationError::OutOfMemory(e) => e.kind(),
+            gfx_hal::buffer::CreationError::UnsupportedUsage { .. } => ErrorKind::UnsupportedFormat,
+        }
+    }
+}
+
+impl HalError for gfx_hal::image::CreationError {
+    fn kind(&self) -> ErrorKind {
+        use gfx_hal::image::CreationError;
+        match self {
+            CreationError::OutOfMemory(e) => e.kind(),
+            CreationError::Format(_)
+            | CreationError::Kind
+            | CreationError::Samples(_)
+            | CreationError::Usage(_) => ErrorKind::UnsupportedFormat,
+            CreationError::Size(_) | CreationError::Data(_) => ErrorKind::InvalidArgument,
+        }
+    }
+}
+
+impl HalError for gfx_hal::image::ViewError {
+    fn kind(&self) -> ErrorKind {
+        use gfx_hal::image::ViewError;
+        match self {
+            ViewError::OutOfMemory(e) => e.kind(),
+            ViewError::BadFormat(_) | ViewError::BadKind(_) | ViewError::Usage(_) => {
+                ErrorKind::UnsupportedFormat
             }
-            LoadedImageError::WaitForFence(e) => format!("Couldn't wait for the fence! => {}", e),
-        };
+            ViewError::Level(_) | ViewError::Layer(_) => ErrorKind::InvalidArgument,
+            ViewError::Unsupported => ErrorKind::Other,
+        }
+    }
+}
 
-        write!(f, "{}", write_this)
+impl HalError for gfx_hal::mapping::Error {
+    fn kind(&self) -> ErrorKind {
+        use gfx_hal::mapping::Error;
+        match self {
+            Error::OutOfMemory(e) => e.kind(),
+            Error::InvalidAccess | Error::OutOfBounds => ErrorKind::InvalidArgument,
+            Error::MappingFailed => ErrorKind::OutOfHostMemory,
+        }
     }
 }
 
-#[derive(Debug, Fail)]
-pub enum MemoryWritingError {
-    #[fail(
-        display = "Couldn't acquire a mapping writer to the staging buffer! => {}",
-        _0
-    )]
-    AcquireMappingWriter(#[cause] gfx_hal::mapping::Error),
-    #[fail(
-        display = "Couldn't release the mapping writer to the staging buffer! => {}",
-        _0
-    )]
-    ReleaseMappingWriter(#[cause] OutOfMemory),
-}
-
-quick_from!(
-    MemoryWritingError,
-    MemoryWritingError::AcquireMappingWriter,
-    gfx_hal::mapping::Error
-);
-
-quick_from!(
-    MemoryWritingError,
-    MemoryWritingError::ReleaseMappingWriter,
-    OutOfMemory
-);
+impl HalError for gfx_hal::device::ShaderError {
+    fn kind(&self) -> ErrorKind {
+        use gfx_hal::device::ShaderError;
+        match self {
+            ShaderError::OutOfMemory(e) => e.kind(),
+            ShaderError::UnsupportedStage(_) => ErrorKind::UnsupportedFormat,
+            ShaderError::CompilationFailed(_)
+            | ShaderError::MissingEntryPoint(_)
+            | ShaderError::InterfaceMismatch(_) => ErrorKind::InvalidArgument,
+        }
+    }
+}
+
+impl HalError for gfx_hal::pso::CreationError {
+    fn kind(&self) -> ErrorKind {
+        use gfx_hal::pso::CreationError;
+        match self {
+            CreationError::OutOfMemory(e) => e.kind(),
+            CreationError::Shader(e) => e.kind(),
+            CreationError::InvalidSubpass(_) => ErrorKind::InvalidArgument,
+            CreationError::Other => ErrorKind::Other,
+        }
+    }
+}
+
+impl HalError for gfx_hal::pso::AllocationError {
+    fn kind(&self) -> ErrorKind {
+        use gfx_hal::pso::AllocationError;
+        match self {
+            AllocationError::OutOfHostMemory => ErrorKind::OutOfHostMemory,
+            AllocationError::OutOfDeviceMemory => ErrorKind::OutOfDeviceMemory,
+            AllocationError::OutOfPoolMemory | AllocationError::FragmentedPool => {
+                ErrorKind::PoolExhausted
+            }
+            AllocationError::IncompatibleLayout => ErrorKind::InvalidArgument,
+        }
+    }
+}
+
+/// Tags gfx-hal errors with what we were making when they happened.
+pub(crate) trait HalResultExt<T> {
+    fn making(self, resource: &'static str, bytes: Option<u64>) -> Result<T, RenderError>;
+}
+
+impl<T, E: HalError> HalResultExt<T> for Result<T, E> {
+    fn making(self, resource: &'static str, bytes: Option<u64>) -> Result<T, RenderError> {
+        self.map_err(|e| RenderError::from_hal(resource, bytes, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorKind, HalResultExt, RenderError};
+    use gfx_hal::{
+        device::{AllocationError, OomOrDeviceLost, OutOfMemory},
+        pso,
+    };
+
+    #[test]
+    fn hal_errors_keep_their_kind() {
+        let oom: Result<(), _> = Err(AllocationError::OutOfMemory(OutOfMemory::OutOfHostMemory));
+        assert_eq!(
+            oom.making("Memory", Some(256)).unwrap_err().kind(),
+            ErrorKind::OutOfHostMemory
+        );
+
+        let lost = RenderError::from_hal(
+            "Fence",
+            None,
+            OomOrDeviceLost::DeviceLost(gfx_hal::device::DeviceLost),
+        );
+        assert_eq!(lost.kind(), ErrorKind::DeviceLost);
+        assert_eq!(lost.resource(), "Fence");
+
+        let full =
+            RenderError::from_hal("DescriptorSet", None, pso::AllocationError::OutOfPoolMemory);
+        assert_eq!(full.kind(), ErrorKind::PoolExhausted);
+    }
+
+    #[test]
+    fn only_render_errors_have_a_kind() {
+        let ours: failure::Error = RenderError::invalid("UniformRing", "Too big!").into();
+        assert_eq!(ErrorKind::of(&ours), ErrorKind::InvalidArgument);
+        assert_eq!(ours.to_string(), "Too big!");
+
+        assert_eq!(
+            ErrorKind::of(&format_err!("Couldn't read a file!")),
+            ErrorKind::Other
+        );
+    }
+}
@@ -0,0 +1,548 @@
+use super::{
+    DeviceContext, HoleList, LoadedImage, PipelineBundle, Rect, RectInt, RenderError,
+    SkylinePacker, Vec2Int,
+};
+use gfx_hal::{
+    adapter::Adapter, image::Filter, pool::CommandPool, Backend, Capability, CommandQueue,
+    Supports, Transfer,
+};
+use std::{collections::HashMap, sync::Arc};
+
+/// Empty pixels around every glyph, so linear filtering never picks up a neighbour.
+const GLYPH_PADDING: u32 = 1;
+
+/// A glyph as coverage values, one byte per pixel, ready to go in a `GlyphCache`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RasterizedGlyph {
+    pub width: u32,
+    pub height: u32,
+    pub coverage: Vec<u8>,
+    /// Where the top left corner of the bitmap goes, relative to the pen on the baseline.
+    pub bearing: Vec2Int,
+    /// How far the pen moves along afterwards.
+    pub advance: f32,
+}
+
+/// Turns characters into bitmaps. With the `font` feature, every `ab_glyph`
+/// font is one, so a TTF or OTF file can go straight into a `GlyphCache`.
+pub trait GlyphRasterizer {
+    /// Rasterizes `character` at `size` pixels high, or `None` if there's no
+    /// glyph for it. Characters with nothing to draw, like spaces, come back
+    /// with an empty bitmap.
+    fn rasterize(&self, character: char, size: u32) -> Option<RasterizedGlyph>;
+}
+
+#[cfg(feature = "font")]
+impl<F: ab_glyph::Font> GlyphRasterizer for F {
+    fn rasterize(&self, character: char, size: u32) -> Option<RasterizedGlyph> {
+        use ab_glyph::ScaleFont;
+
+        let id = self.glyph_id(character);
+        if id.0 == 0 {
+            return None;
+        }
+        let scaled = self.as_scaled(size as f32);
+        let advance = scaled.h_advance(id);
+
+        let outlined = match self.outline_glyph(id.with_scale(size as f32)) {
+            Some(outlined) => outlined,
+            None => {
+                return Some(RasterizedGlyph {
+                    width: 0,
+                    height: 0,
+                    coverage: Vec::new(),
+                    bearing: Vec2Int::new(0, 0),
+                    advance,
+                })
+            }
+        };
+
+        let bounds = outlined.px_bounds();
+        let width = bounds.width() as u32;
+        let height = bounds.height() as u32;
+        let mut coverage = vec![0; (width * height) as usize];
+        outlined.draw(|x, y, c| {
+            if x < width && y < height {
+                coverage[(y * width + x) as usize] = (c.min(1.0) * 255.0) as u8;
+            }
+        });
+
+        Some(RasterizedGlyph {
+            width,
+            height,
+            coverage,
+            bearing: Vec2Int::new(bounds.min.x as i32, bounds.min.y as i32),
+            advance,
+        })
+    }
+}
+
+/// A glyph that's in the cache texture, with everything needed to draw it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CachedGlyph {
+    /// Where it is in the cache texture.
+    pub uv: Rect,
+    pub size: Vec2Int,
+    pub bearing: Vec2Int,
+    pub advance: f32,
+}
+
+struct CacheEntry {
+    glyph: CachedGlyph,
+    /// Padded rectangle in the texture. Empty for glyphs with nothing to draw.
+    rect: RectInt,
+    last_used: u64,
+}
+
+/// Rasterizes glyphs on the CPU as they're asked for and keeps them in one
+/// RGBA texture: white, with the glyph's coverage in the alpha channel. New
+/// glyphs go into a copy of the texture on our side, and `flush` uploads the
+/// part that changed in one go, so call it before drawing anything that uses
+/// them.
+///
+/// When there's no room for a glyph, the least recently used glyphs are evicted
+/// until there is. Glyphs used since the last `begin_frame` are never evicted,
+/// since whatever's being drawn this frame still points at them.
+pub struct GlyphCache<B: Backend, R: GlyphRasterizer> {
+    pub rasterizer: R,
+    pub texture: LoadedImage<B>,
+    pub size: u32,
+    entries: HashMap<(char, u32), CacheEntry>,
+    packer: SkylinePacker,
+    holes: HoleList,
+    pixels: Vec<u8>,
+    /// Everything changed since the last flush. Empty when nothing has.
+    dirty: RectInt,
+    frame: u64,
+}
+
+impl<B: Backend, R: GlyphRasterizer> GlyphCache<B, R> {
+    /// Makes an empty `size` by `size` cache texture, with a descriptor set from
+    /// `pipeline_bundle`.
+    pub fn new<C: Capability + Supports<Transfer>>(
+        rasterizer: R,
+        size: u32,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+    ) -> Result<Self, failure::Error> {
+        let bytes = (size as usize)
+            .checked_mul(size as usize)
+            .and_then(|pixels| pixels.checked_mul(4));
+        let pixels = match bytes {
+            Some(bytes) => vec![0; bytes],
+            None => bail_invalid!(
+                "GlyphCache",
+                "A {}x{} glyph cache is too big to keep a copy of!",
+                size,
+                size
+            ),
+        };
+        let texture = LoadedImage::allocate_and_create(
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            pipeline_bundle,
+            &pixels,
+            size as usize,
+            size as usize,
+            Filter::Linear,
+        )?;
+
+        Ok(GlyphCache {
+            rasterizer,
+            texture,
+            size,
+            entries: HashMap::new(),
+            packer: SkylinePacker::new(size, size),
+            holes: HoleList::new(),
+            pixels,
+            dirty: RectInt::default(),
+            frame: 0,
+        })
+    }
+
+    /// Starts a new frame. Glyphs from earlier frames can be evicted from here on.
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Finds `character` at `size` pixels high in the cache, rasterizing it first
+    /// if it isn't there yet. Gives back `None` if the font doesn't have it.
+    pub fn glyph(
+        &mut self,
+        character: char,
+        size: u32,
+    ) -> Result<Option<CachedGlyph>, failure::Error> {
+        let frame = self.frame;
+        if let Some(entry) = self.entries.get_mut(&(character, size)) {
+            entry.last_used = frame;
+            return Ok(Some(entry.glyph));
+        }
+
+        let rasterized = match self.rasterizer.rasterize(character, size) {
+            Some(rasterized) => rasterized,
+            None => return Ok(None),
+        };
+
+        let padded = (
+            rasterized.width + GLYPH_PADDING * 2,
+            rasterized.height + GLYPH_PADDING * 2,
+        );
+        if padded.0 > self.size || padded.1 > self.size {
+            bail_invalid!(
+                "GlyphCache",
+                "{:?} at {}px is {}x{}, which doesn't fit in a {}x{} glyph cache!",
+                character,
+                size,
+                rasterized.width,
+                rasterized.height,
+                self.size,
+                self.size
+            );
+        }
+
+        let rect = if rasterized.width == 0 || rasterized.height == 0 {
+            RectInt::default()
+        } else {
+            let (x, y) = self.allocate(padded.0, padded.1)?;
+            self.write(x + GLYPH_PADDING, y + GLYPH_PADDING, &rasterized);
+            RectInt::new(
+                Vec2Int::new(x as i32, y as i32),
+                Vec2Int::new(padded.0 as i32, padded.1 as i32),
+            )
+        };
+
+        let glyph_size = Vec2Int::new(rasterized.width as i32, rasterized.height as i32);
+        let padding = Vec2Int::new(GLYPH_PADDING as i32, GLYPH_PADDING as i32);
+        let origin = rect.origin() + padding;
+        let glyph = CachedGlyph {
+            uv: RectInt::new(origin, glyph_size)
+                .to_uv(Vec2Int::new(self.size as i32, self.size as i32)),
+            size: glyph_size,
+            bearing: rasterized.bearing,
+            advance: rasterized.advance,
+        };
+
+        self.entries.insert(
+            (character, size),
+            CacheEntry {
+                glyph,
+                rect,
+                last_used: frame,
+            },
+        );
+        Ok(Some(glyph))
+    }
+
+    /// Uploads every glyph added since the last flush.
+    pub fn flush<C: Capability + Supports<Transfer>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+    ) -> Result<(), failure::Error> {
+        let dirty = self.dirty;
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        let width = dirty.width() as usize;
+        let mut block = Vec::with_capacity(dirty.area() as usize * 4);
+        for y in dirty.min.y..dirty.max.y {
+            let row = (y as usize * self.size as usize + dirty.min.x as usize) * 4;
+            block.extend_from_slice(&self.pixels[row..row + width * 4]);
+        }
+
+        self.texture
+            .edit_image(dirty, &block, adapter, command_pool, command_queue)?;
+        self.dirty = RectInt::default();
+        Ok(())
+    }
+
+    /// Makes the cache texture again on `device` after the old one was lost, with
+    /// a descriptor set from `pipeline_bundle`. We've got every glyph's pixels
+    /// already, so nothing has to be rasterized again.
+    pub fn recover<C: Capability + Supports<Transfer>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+    ) -> Result<(), failure::Error> {
+        self.texture = LoadedImage::allocate_and_create(
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            pipeline_bundle,
+            &self.pixels,
+            self.size as usize,
+            self.size as usize,
+            Filter::Linear,
+        )?;
+        self.dirty = RectInt::default();
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Finds room for a padded glyph, evicting old glyphs until there is some.
+    fn allocate(&mut self, width: u32, height: u32) -> Result<(u32, u32), failure::Error> {
+        loop {
+            if let Some(corner) = self.holes.take(width, height) {
+                return Ok(corner);
+            }
+            if let Some(corner) = self.packer.pack(width, height) {
+                return Ok(corner);
+            }
+
+            let frame = self.frame;
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.last_used < frame && !entry.rect.is_empty())
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(&key, _)| key);
+            match oldest.and_then(|key| self.entries.remove(&key)) {
+                Some(entry) => {
+                    self.clear(entry.rect);
+                    if self.entries.values().all(|entry| entry.rect.is_empty()) {
+                        //  Nothing's left in the texture, so start packing it from scratch
+                        self.packer.clear();
+                        self.holes.clear();
+                    } else {
+                        self.holes.add(entry.rect);
+                    }
+                }
+                None => {
+                    return Err(RenderError::exhausted(
+                        "GlyphCache",
+                        u64::from(self.size) * u64::from(self.size),
+                        format!(
+                            "The glyph cache is full of glyphs used this frame! Make it bigger than {}x{}.",
+                            self.size, self.size
+                        ),
+                    )
+                    .into())
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, x: u32, y: u32, rasterized: &RasterizedGlyph) {
+        for row in 0..rasterized.height {
+            for column in 0..rasterized.width {
+                let coverage = rasterized.coverage[(row * rasterized.width + column) as usize];
+                let i = (((y + row) * self.size + x + column) * 4) as usize;
+                self.pixels[i..i + 4].copy_from_slice(&[255, 255, 255, coverage]);
+            }
+        }
+        self.mark_dirty(RectInt::new(
+            Vec2Int::new(x as i32, y as i32),
+            Vec2Int::new(rasterized.width as i32, rasterized.height as i32),
+        ));
+    }
+
+    /// Blanks out an evicted glyph, padding and all, so whatever goes there
+    /// next doesn't have bits of it in its padding.
+    fn clear(&mut self, rect: RectInt) {
+        let width = rect.width() as usize;
+        for row in rect.min.y..rect.max.y {
+            let i = (row as usize * self.size as usize + rect.min.x as usize) * 4;
+            for byte in &mut self.pixels[i..i + width * 4] {
+                *byte = 0;
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    fn mark_dirty(&mut self, changed: RectInt) {
+        self.dirty = self.dirty.union(&changed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GlyphCache, GlyphRasterizer, RasterizedGlyph};
+    use crate::{
+        mock_backend::{self, Mock},
+        DeviceContext, ErrorKind, PipelineBundle, Rect, RectInt, Vec2, Vec2Int,
+    };
+    use std::sync::Arc;
+
+    /// Every glyph is a solid square as many pixels across as its size.
+    struct Squares;
+
+    impl GlyphRasterizer for Squares {
+        fn rasterize(&self, character: char, size: u32) -> Option<RasterizedGlyph> {
+            match character {
+                ' ' => Some(RasterizedGlyph {
+                    width: 0,
+                    height: 0,
+                    coverage: Vec::new(),
+                    bearing: Vec2Int::new(0, 0),
+                    advance: size as f32 / 2.0,
+                }),
+                'a'..='z' => Some(RasterizedGlyph {
+                    width: size,
+                    height: size,
+                    coverage: vec![255; (size * size) as usize],
+                    bearing: Vec2Int::new(0, -(size as i32)),
+                    advance: size as f32,
+                }),
+                _ => None,
+            }
+        }
+    }
+
+    fn cache(
+        device: &Arc<DeviceContext<Mock>>,
+        pipeline_bundle: &mut PipelineBundle<Mock>,
+    ) -> GlyphCache<Mock, Squares> {
+        GlyphCache::new(
+            Squares,
+            16,
+            &mock_backend::adapter(),
+            device,
+            &mut mock_backend::command_pool(device),
+            &mut mock_backend::command_queue(),
+            pipeline_bundle,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn caches_glyphs_and_uploads_what_changed() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let mut cache = cache(&device, &mut pipeline_bundle);
+
+        let a = cache.glyph('a', 4).unwrap().unwrap();
+        assert_eq!(cache.glyph('a', 4).unwrap(), Some(a));
+        assert_eq!(
+            a.uv,
+            Rect::from_min_max(Vec2::new(1.0, 1.0) / 16.0, Vec2::new(5.0, 5.0) / 16.0)
+        );
+        assert_eq!(
+            cache.glyph('b', 2).unwrap().unwrap().size,
+            Vec2Int::new(2, 2)
+        );
+        assert_eq!(cache.glyph(' ', 4).unwrap().unwrap().advance, 2.0);
+        assert_eq!(cache.glyph('?', 4).unwrap(), None);
+        assert_eq!(cache.len(), 3);
+
+        assert_eq!(
+            cache.dirty,
+            RectInt::from_min_max(Vec2Int::new(1, 1), Vec2Int::new(9, 5))
+        );
+        cache
+            .flush(
+                &mock_backend::adapter(),
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+            )
+            .unwrap();
+        assert!(cache.dirty.is_empty());
+        assert_eq!(
+            &cache.pixels[(16 + 1) * 4..(16 + 1) * 4 + 4],
+            &[255, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_glyphs_when_full() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let mut cache = cache(&device, &mut pipeline_bundle);
+
+        //  Four 8x8 padded glyphs fill the cache
+        for character in "abcd".chars() {
+            cache.glyph(character, 6).unwrap();
+        }
+        cache.begin_frame();
+        for character in "bcd".chars() {
+            cache.glyph(character, 6).unwrap();
+        }
+        let e = cache.glyph('e', 6).unwrap().unwrap();
+
+        assert_eq!(cache.len(), 4);
+        assert!(!cache.entries.contains_key(&('a', 6)));
+        assert_eq!(e.uv.min.x, 1.0 / 16.0);
+
+        //  Everything's been used this frame, so there's nothing to evict
+        assert!(cache.glyph('f', 6).is_err());
+        cache.begin_frame();
+        assert!(cache.glyph('f', 6).is_ok());
+    }
+
+    #[test]
+    fn a_glyph_as_big_as_the_cache_fits_once_the_rest_are_evicted() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let mut cache = cache(&device, &mut pipeline_bundle);
+
+        for character in "abcd".chars() {
+            cache.glyph(character, 6).unwrap();
+        }
+        cache.begin_frame();
+
+        let z = cache.glyph('z', 14).unwrap().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(z.uv.min, Vec2::new(1.0, 1.0) / 16.0);
+    }
+
+    #[test]
+    fn a_cache_too_big_to_copy_is_refused() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let error = GlyphCache::new(
+            Squares,
+            u32::MAX,
+            &mock_backend::adapter(),
+            &device,
+            &mut mock_backend::command_pool(&device),
+            &mut mock_backend::command_queue(),
+            &mut pipeline_bundle,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+    }
+
+    #[test]
+    fn recovery_keeps_the_glyphs_without_rasterizing_them_again() {
+        let lost = mock_backend::device();
+        let mut lost_bundle = mock_backend::pipeline_bundle(&lost, 1);
+        let mut cache = cache(&lost, &mut lost_bundle);
+        let a = cache.glyph('a', 4).unwrap().unwrap();
+
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        cache
+            .recover(
+                &mock_backend::adapter(),
+                &device,
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+                &mut pipeline_bundle,
+            )
+            .unwrap();
+
+        assert!(Arc::ptr_eq(&cache.texture.device, &device));
+        assert!(cache.dirty.is_empty());
+        assert_eq!(cache.glyph('a', 4).unwrap(), Some(a));
+        drop(lost_bundle);
+        assert!(lost.live_objects().is_empty());
+    }
+}
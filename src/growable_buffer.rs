@@ -0,0 +1,450 @@
+use super::{submit_one_shot, BufferBundle, DeviceContext, RetirementQueue};
+use gfx_hal::{
+    adapter::{Adapter, PhysicalDevice},
+    buffer,
+    command::BufferCopy,
+    memory::{Barrier, Dependencies, Properties},
+    pool::CommandPool,
+    pso::PipelineStage,
+    Backend, Capability, CommandQueue, Supports, Transfer,
+};
+use std::{mem, sync::Arc};
+
+/// A buffer for data whose size changes every frame, like immediate-mode debug
+/// lines or UI vertices. When a write doesn't fit, we allocate a bigger buffer,
+/// carry the old contents over, and keep the old buffer alive until the frames
+/// which were drawing from it are done.
+pub struct GrowableBuffer<B: Backend> {
+    pub bundle: BufferBundle<B>,
+    pub capacity: u64,
+    pub usage: buffer::Usage,
+    pub properties: Properties,
+    pub growth_factor: f64,
+    pub retired: RetirementQueue<BufferBundle<B>>,
+}
+
+impl<B: Backend> GrowableBuffer<B> {
+    /// A mapped, host-visible buffer. This is what you want for data you write every frame.
+    pub fn new(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        capacity: u64,
+        usage: buffer::Usage,
+        growth_factor: f64,
+    ) -> Result<Self, failure::Error> {
+        GrowableBuffer::with_properties(
+            adapter,
+            device,
+            capacity,
+            usage,
+            Properties::CPU_VISIBLE,
+            growth_factor,
+        )
+    }
+
+    /// A device-local buffer. You'll have to fill it on the GPU (with `copy_buffer`
+    /// or a compute shader), and growing it means `reserve_on_gpu`.
+    pub fn new_device_local(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        capacity: u64,
+        usage: buffer::Usage,
+        growth_factor: f64,
+    ) -> Result<Self, failure::Error> {
+        GrowableBuffer::with_properties(
+            adapter,
+            device,
+            capacity,
+            usage,
+            Properties::DEVICE_LOCAL,
+            growth_factor,
+        )
+    }
+
+    fn with_properties(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        capacity: u64,
+        usage: buffer::Usage,
+        properties: Properties,
+        growth_factor: f64,
+    ) -> Result<Self, failure::Error> {
+        if growth_factor <= 1.0 {
+            bail_invalid!(
+                "GrowableBuffer",
+                "A growable buffer has to grow! Growth factor was {}",
+                growth_factor
+            );
+        }
+
+        // We copy buffer-to-buffer when we grow, so we need both transfer directions.
+        let usage = usage | buffer::Usage::TRANSFER_SRC | buffer::Usage::TRANSFER_DST;
+        let bundle = GrowableBuffer::allocate_bundle(adapter, device, capacity, usage, properties)?;
+
+        Ok(GrowableBuffer {
+            bundle,
+            capacity,
+            usage,
+            properties,
+            growth_factor,
+            retired: RetirementQueue::new(),
+        })
+    }
+
+    pub fn buffer(&self) -> &B::Buffer {
+        &self.bundle.buffer
+    }
+
+    pub fn has_room(&self, size: u64) -> bool {
+        self.capacity >= size
+    }
+
+    /// Writes `data` at `element_offset` (in units of `T`), growing first if it won't fit.
+    /// `frame` is the frame we're recording; it's how long the old buffer has to live.
+    /// Returns `true` if we grew, in which case any descriptor sets pointing at
+    /// the old buffer need rewriting. Only works for host-visible buffers.
+    pub fn write<T: Copy>(
+        &mut self,
+        adapter: &Adapter<B>,
+        frame: u64,
+        data: &[T],
+        element_offset: usize,
+    ) -> Result<bool, failure::Error> {
+        if self.bundle.mapped.is_none() {
+            bail_invalid!(
+                "GrowableBuffer",
+                "Can't write to a buffer that isn't mapped! Fill device-local buffers on the GPU."
+            );
+        }
+        if data.is_empty() {
+            return Ok(false);
+        }
+
+        let size = mem::size_of::<T>();
+        let range = element_offset.checked_mul(size).and_then(|start| {
+            let end = element_offset.checked_add(data.len())?.checked_mul(size)?;
+            Some((start as u64, end as u64))
+        });
+        let (start, required) = match range {
+            Some(range) => range,
+            None => bail_invalid!(
+                "GrowableBuffer",
+                "Writing {} elements at element {} runs past the end of memory!",
+                data.len(),
+                element_offset
+            ),
+        };
+        let grew = self.reserve(adapter, frame, required)?;
+
+        self.bundle.update_buffer(data, element_offset);
+        unsafe {
+            let atom_size = adapter.physical_device.limits().non_coherent_atom_size as u64;
+            self.bundle.flush_range(start..required, atom_size)?;
+        }
+
+        Ok(grew)
+    }
+
+    /// Makes sure we have at least `required` bytes, copying the old contents across
+    /// on the CPU. Only works for host-visible buffers.
+    pub fn reserve(
+        &mut self,
+        adapter: &Adapter<B>,
+        frame: u64,
+        required: u64,
+    ) -> Result<bool, failure::Error> {
+        if self.has_room(required) {
+            return Ok(false);
+        }
+
+        let old_map = match self.bundle.mapped {
+            Some(map) => map,
+            None => {
+                bail_invalid!(
+                    "GrowableBuffer",
+                    "Can't grow a buffer on the CPU when it isn't mapped! Use `reserve_on_gpu`."
+                )
+            }
+        };
+
+        let new_capacity = self.grown_capacity(required);
+        let new_bundle = GrowableBuffer::allocate_bundle(
+            adapter,
+            &self.bundle.device,
+            new_capacity,
+            self.usage,
+            self.properties,
+        )?;
+
+        unsafe {
+            if let Some(new_map) = new_bundle.mapped {
+                std::ptr::copy_nonoverlapping(old_map, new_map, self.capacity as usize);
+            }
+            new_bundle.flush()?;
+        }
+
+        self.swap_in(frame, new_bundle, new_capacity);
+        Ok(true)
+    }
+
+    /// Makes sure we have at least `required` bytes, copying the old contents across
+    /// with a `copy_buffer` on the GPU. Works for any buffer, but blocks until the copy is done.
+    pub fn reserve_on_gpu<C: Capability + Supports<Transfer>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        frame: u64,
+        required: u64,
+    ) -> Result<bool, failure::Error> {
+        if self.has_room(required) {
+            return Ok(false);
+        }
+
+        let new_capacity = self.grown_capacity(required);
+        let new_bundle = GrowableBuffer::allocate_bundle(
+            adapter,
+            &self.bundle.device,
+            new_capacity,
+            self.usage,
+            self.properties,
+        )?;
+
+        let old_capacity = self.capacity;
+        unsafe {
+            let old_bundle = &self.bundle;
+            submit_one_shot(
+                &old_bundle.device.device,
+                command_pool,
+                command_queue,
+                |cmd_buffer| {
+                    //  Finish off anything still writing into the old buffer before we read it
+                    let buffer_barrier = Barrier::whole_buffer(
+                        &*old_bundle.buffer,
+                        buffer::Access::MEMORY_WRITE..buffer::Access::TRANSFER_READ,
+                    );
+                    cmd_buffer.pipeline_barrier(
+                        (PipelineStage::VERTEX_SHADER
+                            | PipelineStage::FRAGMENT_SHADER
+                            | PipelineStage::COMPUTE_SHADER
+                            | PipelineStage::TRANSFER)
+                            ..PipelineStage::TRANSFER,
+                        Dependencies::empty(),
+                        &[buffer_barrier],
+                    );
+
+                    cmd_buffer.copy_buffer(
+                        &old_bundle.buffer,
+                        &new_bundle.buffer,
+                        [BufferCopy {
+                            src: 0,
+                            dst: 0,
+                            size: old_capacity,
+                        }],
+                    );
+
+                    let buffer_barrier = Barrier::whole_buffer(
+                        &*new_bundle.buffer,
+                        buffer::Access::TRANSFER_WRITE
+                            ..(buffer::Access::MEMORY_READ | buffer::Access::MEMORY_WRITE),
+                    );
+                    cmd_buffer.pipeline_barrier(
+                        PipelineStage::TRANSFER..PipelineStage::BOTTOM_OF_PIPE,
+                        Dependencies::empty(),
+                        &[buffer_barrier],
+                    );
+                },
+            )?;
+        }
+
+        self.swap_in(frame, new_bundle, new_capacity);
+        Ok(true)
+    }
+
+    /// Destroys every old buffer whose last frame is at or before `completed_frame`.
+    pub fn retire_frames(&mut self, completed_frame: u64) {
+        self.retired.drain_completed(completed_frame);
+    }
+
+    /// Makes the buffer again on `device` after the old one was lost. It keeps its
+    /// capacity but comes back empty, so write everything into it again.
+    pub fn recover(
+        &mut self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+    ) -> Result<(), failure::Error> {
+        self.bundle = GrowableBuffer::allocate_bundle(
+            adapter,
+            device,
+            self.capacity,
+            self.usage,
+            self.properties,
+        )?;
+        //  The old device is gone, so nothing can still be using what we retired
+        self.retired.drain_all();
+        Ok(())
+    }
+
+    fn grown_capacity(&self, required: u64) -> u64 {
+        let grown = (self.capacity as f64 * self.growth_factor).ceil() as u64;
+        grown.max(required)
+    }
+
+    fn swap_in(&mut self, frame: u64, new_bundle: BufferBundle<B>, new_capacity: u64) {
+        let old_bundle = mem::replace(&mut self.bundle, new_bundle);
+        self.retired.retire(frame, old_bundle);
+        self.capacity = new_capacity;
+    }
+
+    fn allocate_bundle(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        capacity: u64,
+        usage: buffer::Usage,
+        properties: Properties,
+    ) -> Result<BufferBundle<B>, failure::Error> {
+        let map_it = properties.contains(Properties::CPU_VISIBLE);
+        unsafe { BufferBundle::allocate(adapter, device, capacity, usage, properties, map_it) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GrowableBuffer;
+    use crate::{
+        mock_backend::{self, Mock, MockCommand},
+        DeviceContext, ErrorKind,
+    };
+    use gfx_hal::buffer;
+    use std::sync::Arc;
+
+    fn buffers(device: &DeviceContext<Mock>) -> usize {
+        device
+            .live_objects()
+            .iter()
+            .find(|&&(kind, _)| kind == "Buffer")
+            .map_or(0, |&(_, count)| count)
+    }
+
+    fn read(buffer: &GrowableBuffer<Mock>, len: usize) -> Vec<u32> {
+        let map = buffer.bundle.mapped.unwrap() as *const u32;
+        unsafe { std::slice::from_raw_parts(map, len).to_vec() }
+    }
+
+    fn mapped(device: &Arc<DeviceContext<Mock>>) -> GrowableBuffer<Mock> {
+        GrowableBuffer::new(
+            &mock_backend::adapter(),
+            device,
+            16,
+            buffer::Usage::VERTEX,
+            2.0,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn writes_only_flush_what_they_wrote() {
+        let device = mock_backend::device();
+        let mut buffer = mapped(&device);
+
+        assert!(!buffer
+            .write(&mock_backend::adapter(), 1, &[7u32, 8], 1)
+            .unwrap());
+        assert_eq!(read(&buffer, 3), vec![0, 7, 8]);
+        assert_eq!(device.flushes(), vec![4..12]);
+    }
+
+    #[test]
+    fn offsets_past_the_end_of_memory_are_refused() {
+        let device = mock_backend::device();
+        let mut buffer = mapped(&device);
+
+        for &offset in &[usize::MAX, usize::MAX / 4] {
+            let error = buffer
+                .write(&mock_backend::adapter(), 1, &[7u32, 8], offset)
+                .unwrap_err();
+            assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+        }
+        assert_eq!(buffer.capacity, 16);
+    }
+
+    #[test]
+    fn growing_carries_the_contents_over_and_retires_the_old_buffer() {
+        let device = mock_backend::device();
+        let adapter = mock_backend::adapter();
+        let mut buffer = mapped(&device);
+        buffer.write(&adapter, 1, &[1u32, 2, 3, 4], 0).unwrap();
+
+        assert!(buffer.write(&adapter, 2, &[5u32], 4).unwrap());
+        assert_eq!(buffer.capacity, 32);
+        assert_eq!(read(&buffer, 5), vec![1, 2, 3, 4, 5]);
+
+        //  The old buffer lives until frame 2 is done with it
+        assert_eq!(buffers(&device), 2);
+        buffer.retire_frames(1);
+        assert_eq!(buffers(&device), 2);
+        buffer.retire_frames(2);
+        assert_eq!(buffers(&device), 1);
+
+        //  Asking for less than we've got doesn't grow
+        assert!(!buffer.reserve(&adapter, 3, 32).unwrap());
+    }
+
+    #[test]
+    fn device_local_buffers_refuse_cpu_writes_and_grow_on_the_gpu() {
+        let device = mock_backend::device();
+        let adapter = mock_backend::adapter();
+        let mut buffer =
+            GrowableBuffer::new_device_local(&adapter, &device, 16, buffer::Usage::STORAGE, 2.0)
+                .unwrap();
+
+        //  Even with room to spare, there's nowhere to put the data
+        let error = buffer.write(&adapter, 1, &[1u32], 0).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+        let error = buffer.reserve(&adapter, 1, 64).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+
+        let grew = buffer
+            .reserve_on_gpu(
+                &adapter,
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+                1,
+                20,
+            )
+            .unwrap();
+        assert!(grew);
+        assert_eq!(buffer.capacity, 32);
+        match device.take_commands().as_slice() {
+            [MockCommand::BufferBarrier { .. }, MockCommand::CopyBuffer {
+                src: 0,
+                dst: 0,
+                size: 16,
+            }, MockCommand::BufferBarrier { access, .. }] => {
+                assert_eq!(access.start, buffer::Access::TRANSFER_WRITE);
+            }
+            other => panic!("Expected a copy between barriers, got {:?}", other),
+        }
+
+        buffer.retire_frames(1);
+        assert_eq!(buffers(&device), 1);
+    }
+
+    #[test]
+    fn recovery_keeps_the_capacity_and_drops_everything_on_the_old_device() {
+        let lost = mock_backend::device();
+        let adapter = mock_backend::adapter();
+        let mut buffer = mapped(&lost);
+        buffer.write(&adapter, 1, &[1u32; 8], 0).unwrap();
+        assert_eq!(buffers(&lost), 2);
+
+        let device = mock_backend::device();
+        buffer.recover(&adapter, &device).unwrap();
+
+        assert_eq!(buffer.capacity, 32);
+        assert!(Arc::ptr_eq(&buffer.bundle.device, &device));
+        assert_eq!(buffers(&lost), 0);
+        assert_eq!(buffers(&device), 1);
+        assert!(!buffer.write(&adapter, 2, &[2u32; 8], 0).unwrap());
+    }
+}
@@ -1,5 +1,4 @@
 #![warn(elided_lifetimes_in_paths)]
-#![allow(unreachable_code)]
 
 #[macro_use]
 extern crate failure;
@@ -17,52 +16,151 @@ macro_rules! manual_new {
     };
 }
 
+macro_rules! bail_invalid {
+    ($resource:expr, $($message:tt)+) => {
+        return Err($crate::RenderError::invalid($resource, format!($($message)+)).into())
+    };
+}
+
+mod bindless_table;
 mod buffer_bundle;
+mod descriptor_allocator;
+mod descriptor_layout;
+mod device_context;
+mod device_recovery;
 mod errors;
+mod glyph_cache;
+mod growable_buffer;
 mod loaded_image;
+#[cfg(test)]
+mod mock_backend;
+mod one_shot;
+mod pipeline_builder;
 mod pipeline_bundle;
+mod pipeline_cache;
+mod pipeline_watcher;
+mod retirement_queue;
+#[cfg(feature = "shader-compiler")]
+mod shader_compiler;
+mod shader_reflection;
+mod texture_atlas;
+mod texture_options;
+mod texture_watcher;
+mod uniform_ring;
 mod utilities;
 
-use buffer_bundle::*;
-use errors::*;
-use loaded_image::*;
-use pipeline_bundle::PipelineBundle;
-use utilities::*;
+pub use bindless_table::*;
+pub use buffer_bundle::*;
+pub use descriptor_allocator::*;
+pub use descriptor_layout::*;
+pub use device_context::*;
+pub use device_recovery::*;
+pub use errors::*;
+pub use glyph_cache::*;
+pub use growable_buffer::*;
+pub use loaded_image::*;
+pub use one_shot::*;
+pub use pipeline_builder::*;
+pub use pipeline_bundle::PipelineBundle;
+pub use pipeline_cache::*;
+pub use pipeline_watcher::*;
+pub use retirement_queue::*;
+#[cfg(feature = "shader-compiler")]
+pub use shader_compiler::*;
+pub use shader_reflection::*;
+pub use texture_atlas::*;
+pub use texture_options::*;
+pub use texture_watcher::*;
+pub use uniform_ring::*;
+pub use utilities::*;
 
-#[cfg(feature = "dx12")]
-use gfx_backend_dx12 as back;
-#[cfg(feature = "metal")]
-use gfx_backend_metal as back;
-#[cfg(feature = "vulkan")]
-use gfx_backend_vulkan as back;
+/// Everything `register_texture` needs to make a texture, along with the
+/// textures it's made so far, which remember their pixels so they can be made
+/// again after the device is lost.
+pub struct RendererComponent<B: gfx_hal::Backend> {
+    pub adapter: gfx_hal::Adapter<B>,
+    pub device: std::sync::Arc<DeviceContext<B>>,
+    pub command_pool: gfx_hal::pool::CommandPool<B, gfx_hal::Graphics>,
+    pub command_queue: gfx_hal::CommandQueue<B, gfx_hal::Graphics>,
+    pub pipeline_bundle: PipelineBundle<B>,
+    pub textures: ResourceRegistry<B>,
+}
 
-pub struct RendererComponent;
+impl<B: gfx_hal::Backend> RendererComponent<B> {
+    /// Makes every registered texture again. Put a new device, and a command
+    /// pool, command queue and pipeline bundle made on it, into the renderer first.
+    pub fn recover(&mut self) -> Result<(), failure::Error> {
+        self.textures.recover(
+            &self.adapter,
+            &self.device,
+            &mut self.command_pool,
+            &mut self.command_queue,
+            &mut self.pipeline_bundle,
+        )
+    }
+}
 
-pub fn register_texture(
-    renderer: &mut RendererComponent,
+pub fn register_texture<B: gfx_hal::Backend>(
+    renderer: &mut RendererComponent<B>,
     image: &image::RgbaImage,
-) -> Result<usize, failure::Error> {
-    let texture = {
-        let mut pipeline_bundle = unimplemented!();
-
-        LoadedImage::allocate_and_create(
-            unimplemented!(),
-            unimplemented!(),
-            unimplemented!(),
-            unimplemented!(),
-            &mut pipeline_bundle,
-            &*image,
-            image.width() as usize,
-            image.height() as usize,
-            gfx_hal::image::Filter::Nearest,
-        )?
-    };
+) -> Result<TextureHandle, failure::Error> {
+    renderer.textures.register_texture(
+        TextureSource::Pixels {
+            data: image.to_vec(),
+            width: image.width() as usize,
+            height: image.height() as usize,
+        },
+        &renderer.adapter,
+        &renderer.device,
+        &mut renderer.command_pool,
+        &mut renderer.command_queue,
+        &mut renderer.pipeline_bundle,
+        gfx_hal::image::Filter::Nearest,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mock_backend, register_texture, RendererComponent, ResourceRegistry};
+    use std::sync::Arc;
+
+    fn renderer() -> RendererComponent<mock_backend::Mock> {
+        let device = mock_backend::device();
+        RendererComponent {
+            adapter: mock_backend::adapter(),
+            command_pool: mock_backend::command_pool(&device),
+            command_queue: mock_backend::command_queue(),
+            pipeline_bundle: mock_backend::pipeline_bundle(&device, 2),
+            textures: ResourceRegistry::new(),
+            device,
+        }
+    }
+
+    #[test]
+    fn registered_textures_get_their_own_handles() {
+        let mut renderer = renderer();
+        let image = image::RgbaImage::new(2, 2);
+
+        let first = register_texture(&mut renderer, &image).unwrap();
+        let second = register_texture(&mut renderer, &image).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(renderer.textures.texture(second).unwrap().width, 2);
+    }
+
+    #[test]
+    fn registered_textures_come_back_after_the_device_is_lost() {
+        let mut renderer = renderer();
+        let handle = register_texture(&mut renderer, &image::RgbaImage::new(3, 2)).unwrap();
 
-    let ret = unimplemented!();
-    /*
-    // Add to some texture array here...
-    renderer.textures.push(texture);
-    */
+        let device = mock_backend::device();
+        renderer.command_pool = mock_backend::command_pool(&device);
+        renderer.command_queue = mock_backend::command_queue();
+        renderer.pipeline_bundle = mock_backend::pipeline_bundle(&device, 2);
+        renderer.device = device;
+        renderer.recover().unwrap();
 
-    Ok(ret)
+        let texture = renderer.textures.texture(handle).unwrap();
+        assert!(Arc::ptr_eq(&texture.device, &renderer.device));
+        assert_eq!((texture.width, texture.height), (3, 2));
+    }
 }
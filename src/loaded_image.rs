@@ -1,19 +1,22 @@
-use super::{BufferBundle, BufferError, LoadedImageError, PipelineBundle, Vec2Int};
+use super::{
+    submit_one_shot, BufferBundle, DescriptorLayout, DeviceContext, HalResultExt, PipelineBundle,
+    PooledDescriptorSet, RectInt, RenderError, TextureOptions, Vec2Int,
+};
 use core::mem::ManuallyDrop;
 use gfx_hal::{
     adapter::{Adapter, MemoryTypeId, PhysicalDevice},
     buffer,
     device::Device,
-    format::{Aspects, Format},
+    format::Aspects,
     image::Offset,
     image::{Layout, SubresourceRange, Usage},
     memory::{Properties, Requirements},
     pool::CommandPool,
     pso::PipelineStage,
-    pso::{Descriptor, DescriptorSetWrite},
+    pso::{Descriptor, DescriptorSetWrite, DescriptorType},
     Backend, Capability, CommandQueue, Supports, Transfer,
 };
-use std::{marker::PhantomData, ops::Deref};
+use std::{ops::Deref, sync::Arc};
 
 pub struct LoadedImage<B: Backend> {
     pub image: ManuallyDrop<B::Image>,
@@ -21,37 +24,151 @@ pub struct LoadedImage<B: Backend> {
     pub memory: ManuallyDrop<B::Memory>,
     pub image_view: ManuallyDrop<B::ImageView>,
     pub sampler: ManuallyDrop<B::Sampler>,
-    pub descriptor_set: ManuallyDrop<B::DescriptorSet>,
-    pub phantom: PhantomData<B::Device>,
+    pub descriptor_set: Option<PooledDescriptorSet<B>>,
+    pub width: u32,
+    pub height: u32,
+    pub device: Arc<DeviceContext<B>>,
 }
 
 impl<B: Backend> LoadedImage<B> {
+    #[allow(clippy::too_many_arguments)]
     pub fn allocate_and_create<C: Capability + Supports<Transfer>>(
         adapter: &Adapter<B>,
-        device: &B::Device,
+        device: &Arc<DeviceContext<B>>,
         command_pool: &mut CommandPool<B, C>,
         command_queue: &mut CommandQueue<B, C>,
         pipeline_bundle: &mut PipelineBundle<B>,
         img: &[u8],
         width: usize,
         height: usize,
-        filter: gfx_hal::image::Filter,
+        options: impl Into<TextureOptions>,
     ) -> Result<Self, failure::Error> {
+        let mut texture = LoadedImage::new(
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            img,
+            width,
+            height,
+            options,
+        )?;
+
+        //  The texture gives everything back on its own if this fails
+        let descriptor_set = pipeline_bundle.allocate_descriptor_set()?;
+
         unsafe {
+            texture.write_descriptors(&descriptor_set, &pipeline_bundle.descriptor_layout)?;
+        }
+
+        texture.descriptor_set = Some(descriptor_set);
+        Ok(texture)
+    }
+
+    /// Points every image, sampler and combined image-sampler binding in `layout`
+    /// at this texture. Bindings of other types are left for someone else to fill,
+    /// as are samplers baked into the layout.
+    ///
+    /// # Safety
+    ///
+    /// `set` has to have been allocated from `layout` on our device, and mustn't
+    /// be in use by any command buffer the GPU hasn't finished with.
+    pub unsafe fn write_descriptors(
+        &self,
+        set: &B::DescriptorSet,
+        layout: &DescriptorLayout<B>,
+    ) -> Result<(), failure::Error> {
+        let mut writes = Vec::new();
+        for declared in &layout.bindings {
+            let descriptor = match declared.ty {
+                DescriptorType::SampledImage => {
+                    Descriptor::Image(self.image_view.deref(), Layout::ShaderReadOnlyOptimal)
+                }
+                DescriptorType::Sampler if !declared.immutable_samplers => {
+                    Descriptor::Sampler(self.sampler.deref())
+                }
+                //  If the sampler's immutable, the one we pass here just gets ignored
+                DescriptorType::CombinedImageSampler => Descriptor::CombinedImageSampler(
+                    self.image_view.deref(),
+                    Layout::ShaderReadOnlyOptimal,
+                    self.sampler.deref(),
+                ),
+                _ => continue,
+            };
+
+            writes.push(DescriptorSetWrite {
+                set,
+                binding: declared.binding,
+                array_offset: 0,
+                descriptors: Some(descriptor),
+            });
+        }
+
+        if writes.is_empty() {
+            return Err(RenderError::invalid(
+                "LoadedImage",
+                "The descriptor layout doesn't have a binding to put a texture in!",
+            )
+            .into());
+        }
+
+        self.device.write_descriptor_sets(writes);
+        Ok(())
+    }
+
+    /// Makes and uploads the image without a descriptor set of its own, for when
+    /// something else binds it, like a `BindlessTable`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<C: Capability + Supports<Transfer>>(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        options: impl Into<TextureOptions>,
+    ) -> Result<Self, failure::Error> {
+        let needed = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(4));
+        if needed.is_none_or(|needed| img.len() < needed) {
+            bail_invalid!(
+                "LoadedImage",
+                "A {}x{} image needs more than the {} bytes we were given!",
+                width,
+                height,
+                img.len()
+            );
+        }
+
+        let options = options.into();
+        unsafe {
+            //  Anything we've made goes back to the device if we bail out part way through
+            let mut partial: PartialImage<'_, B> = PartialImage {
+                device: &**device,
+                image: None,
+                memory: None,
+                image_view: None,
+                sampler: None,
+            };
+
             // Make the Image Object!
-            let mut image_object = device
-                .create_image(
-                    gfx_hal::image::Kind::D2(width as u32, height as u32, 1, 1),
-                    1,
-                    Format::Rgba8Srgb,
-                    gfx_hal::image::Tiling::Optimal,
-                    Usage::TRANSFER_DST | Usage::SAMPLED,
-                    gfx_hal::image::ViewCapabilities::empty(),
-                )
-                .map_err(|e| LoadedImageError::CreateImage(e))?;
+            let image_object = partial.image.insert(
+                device
+                    .create_image(
+                        gfx_hal::image::Kind::D2(width as u32, height as u32, 1, 1),
+                        1,
+                        options.format(),
+                        gfx_hal::image::Tiling::Optimal,
+                        Usage::TRANSFER_DST | Usage::SAMPLED,
+                        gfx_hal::image::ViewCapabilities::empty(),
+                    )
+                    .making("Image", None)?,
+            );
 
             //  Allocate the memory and bind it
-            let requirements = device.get_image_requirements(&image_object);
+            let requirements = device.get_image_requirements(image_object);
             let memory_type_id = adapter
                 .physical_device
                 .memory_properties()
@@ -63,139 +180,342 @@ impl<B: Backend> LoadedImage<B> {
                         && memory_type.properties.contains(Properties::DEVICE_LOCAL)
                 })
                 .map(|(id, _)| MemoryTypeId(id))
-                .ok_or(BufferError::MemoryId)?;
+                .ok_or_else(|| {
+                    RenderError::unsupported(
+                        "Memory",
+                        "No device-local memory type fits the image!",
+                    )
+                })?;
 
-            let memory = device
-                .allocate_memory(memory_type_id, requirements.size)
-                .map_err(|e| BufferError::Allocate(e))?;
+            let memory = partial.memory.insert(
+                device
+                    .allocate_memory(memory_type_id, requirements.size)
+                    .making("Memory", Some(requirements.size))?,
+            );
 
             device
-                .bind_image_memory(&memory, 0, &mut image_object)
-                .map_err(|e| BufferError::Bind(e))?;
+                .bind_image_memory(memory, 0, image_object)
+                .making("Memory", Some(requirements.size))?;
 
             //  Create image view and sampler
-            let image_view = device
-                .create_image_view(
-                    &image_object,
-                    gfx_hal::image::ViewKind::D2,
-                    Format::Rgba8Srgb,
-                    gfx_hal::format::Swizzle::NO,
-                    SubresourceRange {
-                        aspects: Aspects::COLOR,
-                        levels: 0..1,
-                        layers: 0..1,
-                    },
-                )
-                .map_err(|e| LoadedImageError::ImageView(e))?;
-
-            let sampler = device
-                .create_sampler(gfx_hal::image::SamplerInfo::new(
-                    filter,
-                    gfx_hal::image::WrapMode::Clamp,
-                ))
-                .map_err(|e| LoadedImageError::Sampler(e))?;
-
-            let descriptor_set = pipeline_bundle.allocate_descriptor_set()?;
-
-            //  Create our image and do some final tweaking to it!
-            let texture = {
-                let mut texture = Self {
-                    image: manual_new!(image_object),
-                    requirements,
-                    memory: manual_new!(memory),
-                    image_view: manual_new!(image_view),
-                    sampler: manual_new!(sampler),
-                    descriptor_set: manual_new!(descriptor_set),
-                    phantom: PhantomData,
-                };
-
-                // Create a staging bundle of our passed in Data
-                // and upload it into the image object
-                texture.edit_image(
-                    width as u32,
-                    height as u32,
+            partial.image_view = Some(
+                device
+                    .create_image_view(
+                        image_object,
+                        gfx_hal::image::ViewKind::D2,
+                        options.format(),
+                        gfx_hal::format::Swizzle::NO,
+                        SubresourceRange {
+                            aspects: Aspects::COLOR,
+                            levels: 0..1,
+                            layers: 0..1,
+                        },
+                    )
+                    .making("ImageView", None)?,
+            );
+
+            partial.sampler = Some(
+                device
+                    .create_sampler(options.sampler.into())
+                    .making("Sampler", None)?,
+            );
+
+            // Create a staging bundle of our passed in Data
+            // and upload it into the image object
+            LoadedImage::upload(
+                adapter,
+                device,
+                image_object,
+                RectInt::new(
                     Vec2Int::new(0, 0),
-                    img,
-                    adapter,
-                    device,
-                    command_pool,
-                    command_queue,
-                )?;
-
-                // Write that fucker: Write the descriptors into the descriptor set
-                device.write_descriptor_sets(vec![
-                    DescriptorSetWrite {
-                        set: texture.descriptor_set.deref(),
-                        binding: 0,
-                        array_offset: 0,
-                        descriptors: Some(Descriptor::Image(
-                            texture.image_view.deref(),
-                            Layout::ShaderReadOnlyOptimal,
-                        )),
-                    },
-                    DescriptorSetWrite {
-                        set: texture.descriptor_set.deref(),
-                        binding: 1,
-                        array_offset: 0,
-                        descriptors: Some(Descriptor::Sampler(texture.sampler.deref())),
-                    },
-                ]);
+                    Vec2Int::new(width as i32, height as i32),
+                ),
+                img,
+                Layout::Undefined,
+                command_pool,
+                command_queue,
+            )?;
 
-                texture
-            };
+            //  Nothing can fail after this, so the texture takes it all from here
+            let (image_object, memory, image_view, sampler) = partial.finish();
+
+            device.track("LoadedImage");
+            Ok(Self {
+                image: manual_new!(image_object),
+                requirements,
+                memory: manual_new!(memory),
+                image_view: manual_new!(image_view),
+                sampler: manual_new!(sampler),
+                descriptor_set: None,
+                width: width as u32,
+                height: height as u32,
+                device: Arc::clone(device),
+            })
+        }
+    }
+
+    /// Makes a new image for `img`, which can be a different size to the one we
+    /// have, and points our descriptor set at it. Whatever already holds our
+    /// descriptor set can keep using it. Gives back the old image, which frames
+    /// already recorded might still be sampling.
+    #[allow(clippy::too_many_arguments)]
+    pub fn reallocate<C: Capability + Supports<Transfer>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        descriptor_layout: &DescriptorLayout<B>,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        options: impl Into<TextureOptions>,
+    ) -> Result<Self, failure::Error> {
+        let texture = LoadedImage::new(
+            adapter,
+            &self.device,
+            command_pool,
+            command_queue,
+            img,
+            width,
+            height,
+            options,
+        )?;
 
-            Ok(texture)
+        self.replace_with(texture, descriptor_layout)
+    }
+
+    /// Swaps `texture` in for our image, keeping our descriptor set and pointing
+    /// it at the new image. Gives back the old image, which frames already
+    /// recorded might still be sampling. If the descriptor set can't be written,
+    /// nothing changes and `texture` is dropped.
+    pub fn replace_with(
+        &mut self,
+        mut texture: LoadedImage<B>,
+        descriptor_layout: &DescriptorLayout<B>,
+    ) -> Result<Self, failure::Error> {
+        if let Some(descriptor_set) = &self.descriptor_set {
+            unsafe {
+                texture.write_descriptors(descriptor_set, descriptor_layout)?;
+            }
         }
+
+        texture.descriptor_set = self.descriptor_set.take();
+        Ok(std::mem::replace(self, texture))
     }
 
+    /// The whole image, in pixels.
+    pub fn bounds(&self) -> RectInt {
+        RectInt::new(
+            Vec2Int::new(0, 0),
+            Vec2Int::new(self.width as i32, self.height as i32),
+        )
+    }
+
+    /// Copies blocks of `source` into this image on the GPU. Each region is
+    /// `(source_rect, offset)`: the block of `source` to copy, and where its top
+    /// left corner lands in this image. Both images are left ready for shaders to
+    /// read again.
+    pub fn copy_from<C: Capability + Supports<Transfer>>(
+        &mut self,
+        source: &LoadedImage<B>,
+        regions: &[(RectInt, Vec2Int)],
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+    ) -> Result<(), failure::Error> {
+        let layers = || gfx_hal::image::SubresourceLayers {
+            aspects: Aspects::COLOR,
+            level: 0,
+            layers: 0..1,
+        };
+        let mut copies = Vec::with_capacity(regions.len());
+        for &(source_rect, offset) in regions {
+            let dest_rect = RectInt::checked_new(offset, source_rect.size());
+            let fits = dest_rect.is_some_and(|dest_rect| self.bounds().contains(&dest_rect));
+            if source_rect.is_empty() || !source.bounds().contains(&source_rect) || !fits {
+                bail_invalid!(
+                    "LoadedImage",
+                    "Can't copy {} of a {}x{} image to {} of a {}x{} image!",
+                    source_rect,
+                    source.width,
+                    source.height,
+                    offset,
+                    self.width,
+                    self.height
+                );
+            }
+            copies.push(gfx_hal::command::ImageCopy {
+                src_subresource: layers(),
+                src_offset: source_rect.offset(),
+                dst_subresource: layers(),
+                dst_offset: RectInt::new(offset, source_rect.size()).offset(),
+                extent: source_rect.extent(),
+            });
+        }
+
+        let range = SubresourceRange {
+            aspects: Aspects::COLOR,
+            levels: 0..1,
+            layers: 0..1,
+        };
+        let shader_read = (
+            gfx_hal::image::Access::SHADER_READ,
+            Layout::ShaderReadOnlyOptimal,
+        );
+        let transfer_read = (
+            gfx_hal::image::Access::TRANSFER_READ,
+            Layout::TransferSrcOptimal,
+        );
+        let transfer_write = (
+            gfx_hal::image::Access::TRANSFER_WRITE,
+            Layout::TransferDstOptimal,
+        );
+
+        unsafe {
+            submit_one_shot(
+                &self.device.device,
+                command_pool,
+                command_queue,
+                |cmd_buffer| {
+                    cmd_buffer.pipeline_barrier(
+                        PipelineStage::FRAGMENT_SHADER..PipelineStage::TRANSFER,
+                        gfx_hal::memory::Dependencies::empty(),
+                        &[
+                            gfx_hal::memory::Barrier::Image {
+                                states: shader_read..transfer_read,
+                                target: &*source.image,
+                                families: None,
+                                range: range.clone(),
+                            },
+                            gfx_hal::memory::Barrier::Image {
+                                states: shader_read..transfer_write,
+                                target: &*self.image,
+                                families: None,
+                                range: range.clone(),
+                            },
+                        ],
+                    );
+
+                    cmd_buffer.copy_image(
+                        &source.image,
+                        Layout::TransferSrcOptimal,
+                        &self.image,
+                        Layout::TransferDstOptimal,
+                        &copies,
+                    );
+
+                    cmd_buffer.pipeline_barrier(
+                        PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+                        gfx_hal::memory::Dependencies::empty(),
+                        &[
+                            gfx_hal::memory::Barrier::Image {
+                                states: transfer_read..shader_read,
+                                target: &*source.image,
+                                families: None,
+                                range: range.clone(),
+                            },
+                            gfx_hal::memory::Barrier::Image {
+                                states: transfer_write..shader_read,
+                                target: &*self.image,
+                                families: None,
+                                range: range.clone(),
+                            },
+                        ],
+                    );
+                },
+            )
+        }
+    }
+
+    /// Copies `data`, a block of RGBA8 pixels the size of `rect`, into that part
+    /// of the image. The rest of the image is left as it was.
     pub fn edit_image<C: Capability + Supports<Transfer>>(
         &mut self,
-        width: u32,
-        height: u32,
-        offset: Vec2Int,
+        rect: RectInt,
         data: &[u8],
         adapter: &Adapter<B>,
-        device: &B::Device,
         command_pool: &mut CommandPool<B, C>,
         command_queue: &mut CommandQueue<B, C>,
     ) -> Result<(), failure::Error> {
+        if !self.bounds().contains(&rect) {
+            bail_invalid!(
+                "LoadedImage",
+                "Can't edit {} of a {}x{} image!",
+                rect,
+                self.width,
+                self.height
+            );
+        }
+        if rect.is_empty() {
+            return Ok(());
+        }
+        let needed = rect.area() as usize * 4;
+        if data.len() < needed {
+            bail_invalid!(
+                "LoadedImage",
+                "Editing {} needs {} bytes, but we were given {}!",
+                rect,
+                needed,
+                data.len()
+            );
+        }
+
         unsafe {
-            // allocate texture
-            let (staging_bundle, buffer_width) = LoadedImage::create_staging_buffer(
+            LoadedImage::upload(
                 adapter,
-                device,
+                &self.device,
+                &self.image,
+                rect,
                 data,
-                width as usize,
-                height as usize,
-            )?;
-
-            // edit the texture with the appropriate offset
-            LoadedImage::load_staging_buffer_into_image_object(
-                &*self.image,
-                &staging_bundle,
-                buffer_width,
-                width,
-                height,
-                Offset {
-                    x: offset.x,
-                    y: offset.y,
-                    z: 0,
-                },
-                device,
+                Layout::ShaderReadOnlyOptimal,
                 command_pool,
                 command_queue,
-            )?;
+            )
+        }
+    }
 
-            staging_bundle.manually_drop(device);
+    /// Copies `data` into `image_object` through a staging bundle, which is
+    /// dropped again whether or not the upload worked.
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn upload<C: Capability + Supports<Transfer>>(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        image_object: &B::Image,
+        rect: RectInt,
+        data: &[u8],
+        previous_layout: Layout,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+    ) -> Result<(), failure::Error> {
+        // allocate texture
+        let (staging_bundle, buffer_width) = LoadedImage::create_staging_buffer(
+            adapter,
+            device,
+            data,
+            rect.width() as usize,
+            rect.height() as usize,
+        )?;
 
-            // donzo!
-            Ok(())
-        }
+        // edit the texture with the appropriate offset
+        LoadedImage::load_staging_buffer_into_image_object(
+            image_object,
+            &staging_bundle,
+            buffer_width,
+            rect.width() as u32,
+            rect.height() as u32,
+            rect.offset(),
+            previous_layout,
+            device,
+            command_pool,
+            command_queue,
+        )?;
+
+        // donzo!
+        Ok(())
     }
 
     unsafe fn create_staging_buffer(
         adapter: &Adapter<B>,
-        device: &B::Device,
+        device: &Arc<DeviceContext<B>>,
         img: &[u8],
         width: usize,
         height: usize,
@@ -210,7 +530,7 @@ impl<B: Backend> LoadedImage<B> {
 
         let required_bytes = (row_pitch * height) as u64;
         let staging_bundle = BufferBundle::new(
-            &adapter,
+            adapter,
             device,
             required_bytes,
             buffer::Usage::TRANSFER_SRC,
@@ -222,7 +542,7 @@ impl<B: Backend> LoadedImage<B> {
                 &staging_bundle.memory,
                 0..staging_bundle.requirements.size,
             )
-            .map_err(|e| LoadedImageError::AcquireMappingWriter(e))?;
+            .making("StagingBuffer", Some(staging_bundle.requirements.size))?;
 
         for y in 0..height {
             let index = y * row_size..(y + 1) * row_size;
@@ -233,7 +553,7 @@ impl<B: Backend> LoadedImage<B> {
 
         device
             .release_mapping_writer(writer)
-            .map_err(|e| LoadedImageError::ReleaseMappingWriter(e))?;
+            .making("StagingBuffer", None)?;
 
         Ok((
             staging_bundle,
@@ -241,6 +561,7 @@ impl<B: Backend> LoadedImage<B> {
         ))
     }
 
+    #[allow(clippy::too_many_arguments)]
     unsafe fn load_staging_buffer_into_image_object<C: Capability + Supports<Transfer>>(
         image_object: &B::Image,
         staging_bundle: &BufferBundle<B>,
@@ -248,109 +569,555 @@ impl<B: Backend> LoadedImage<B> {
         image_width: u32,
         image_height: u32,
         image_offset: Offset,
+        previous_layout: Layout,
         device: &B::Device,
         command_pool: &mut CommandPool<B, C>,
         command_queue: &mut CommandQueue<B, C>,
     ) -> Result<(), failure::Error> {
-        let mut cmd_buffer = command_pool.acquire_command_buffer::<gfx_hal::command::OneShot>();
-        cmd_buffer.begin();
-
-        //  Use a pipeline barrier to transition the image from empty/undefined
-        //  to TRANSFER_WRITE/TransferDstOptimal
-        let image_barrier = gfx_hal::memory::Barrier::Image {
-            states: (gfx_hal::image::Access::empty(), Layout::Undefined)
-                ..(
-                    gfx_hal::image::Access::TRANSFER_WRITE,
-                    Layout::TransferDstOptimal,
-                ),
-            target: image_object,
-            families: None,
-            range: SubresourceRange {
-                aspects: Aspects::COLOR,
-                levels: 0..1,
-                layers: 0..1,
-            },
+        //  A new image has nothing worth keeping, but an edit has to hold onto
+        //  everything outside the block it's writing
+        let (previous_access, previous_stage) = match previous_layout {
+            Layout::Undefined => (gfx_hal::image::Access::empty(), PipelineStage::TOP_OF_PIPE),
+            _ => (
+                gfx_hal::image::Access::SHADER_READ,
+                PipelineStage::FRAGMENT_SHADER,
+            ),
         };
-        cmd_buffer.pipeline_barrier(
-            PipelineStage::TOP_OF_PIPE..PipelineStage::TRANSFER,
-            gfx_hal::memory::Dependencies::empty(),
-            &[image_barrier],
-        );
 
-        //  COPY THE BUFFER!
-        cmd_buffer.copy_buffer_to_image(
-            &staging_bundle.buffer,
-            &image_object,
-            Layout::TransferDstOptimal,
-            &[gfx_hal::command::BufferImageCopy {
-                buffer_offset: 0,
-                buffer_width,
-                buffer_height: image_height,
-                image_layers: gfx_hal::image::SubresourceLayers {
+        submit_one_shot(device, command_pool, command_queue, |cmd_buffer| {
+            //  Use a pipeline barrier to transition the image from whatever it was
+            //  to TRANSFER_WRITE/TransferDstOptimal
+            let image_barrier = gfx_hal::memory::Barrier::Image {
+                states: (previous_access, previous_layout)
+                    ..(
+                        gfx_hal::image::Access::TRANSFER_WRITE,
+                        Layout::TransferDstOptimal,
+                    ),
+                target: image_object,
+                families: None,
+                range: SubresourceRange {
                     aspects: Aspects::COLOR,
-                    level: 0,
+                    levels: 0..1,
                     layers: 0..1,
                 },
-                image_offset: gfx_hal::image::Offset { x: 0, y: 0, z: 0 },
-                image_extent: gfx_hal::image::Extent {
-                    width: image_width,
-                    height: image_height,
-                    depth: 1,
+            };
+            cmd_buffer.pipeline_barrier(
+                previous_stage..PipelineStage::TRANSFER,
+                gfx_hal::memory::Dependencies::empty(),
+                &[image_barrier],
+            );
+
+            //  COPY THE BUFFER!
+            cmd_buffer.copy_buffer_to_image(
+                &staging_bundle.buffer,
+                image_object,
+                Layout::TransferDstOptimal,
+                &[gfx_hal::command::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_width,
+                    buffer_height: image_height,
+                    image_layers: gfx_hal::image::SubresourceLayers {
+                        aspects: Aspects::COLOR,
+                        level: 0,
+                        layers: 0..1,
+                    },
+                    image_offset,
+                    image_extent: gfx_hal::image::Extent {
+                        width: image_width,
+                        height: image_height,
+                        depth: 1,
+                    },
+                }],
+            );
+
+            //  Use pipeline barrier to transition the image back to SHADER_READ
+            //   and ShaderReadOnlyOptimal layout
+            let image_barrier = gfx_hal::memory::Barrier::Image {
+                states: (
+                    gfx_hal::image::Access::TRANSFER_WRITE,
+                    Layout::TransferDstOptimal,
+                )
+                    ..(
+                        gfx_hal::image::Access::SHADER_READ,
+                        Layout::ShaderReadOnlyOptimal,
+                    ),
+                target: image_object,
+                families: None,
+                range: SubresourceRange {
+                    aspects: Aspects::COLOR,
+                    levels: 0..1,
+                    layers: 0..1,
                 },
-            }],
+            };
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+                gfx_hal::memory::Dependencies::empty(),
+                &[image_barrier],
+            );
+        })
+    }
+}
+
+impl<B: Backend> Drop for LoadedImage<B> {
+    fn drop(&mut self) {
+        use core::ptr::read;
+        // Our descriptor set gives itself back to its pool when it's dropped.
+        unsafe {
+            self.device
+                .destroy_sampler(ManuallyDrop::into_inner(read(&self.sampler)));
+            self.device
+                .destroy_image_view(manual_drop!(self.image_view));
+            self.device.destroy_image(manual_drop!(self.image));
+            self.device.free_memory(manual_drop!(self.memory));
+        }
+        self.device.untrack("LoadedImage");
+    }
+}
+
+/// Everything `allocate_and_create` has made so far. If we bail out before the
+/// `LoadedImage` exists, dropping this hands it all back to the device.
+struct PartialImage<'a, B: Backend> {
+    device: &'a B::Device,
+    image: Option<B::Image>,
+    memory: Option<B::Memory>,
+    image_view: Option<B::ImageView>,
+    sampler: Option<B::Sampler>,
+}
+
+impl<'a, B: Backend> PartialImage<'a, B> {
+    fn finish(mut self) -> (B::Image, B::Memory, B::ImageView, B::Sampler) {
+        (
+            self.image.take().unwrap(),
+            self.memory.take().unwrap(),
+            self.image_view.take().unwrap(),
+            self.sampler.take().unwrap(),
+        )
+    }
+}
+
+impl<'a, B: Backend> Drop for PartialImage<'a, B> {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(sampler) = self.sampler.take() {
+                self.device.destroy_sampler(sampler);
+            }
+            if let Some(image_view) = self.image_view.take() {
+                self.device.destroy_image_view(image_view);
+            }
+            if let Some(image) = self.image.take() {
+                self.device.destroy_image(image);
+            }
+            if let Some(memory) = self.memory.take() {
+                self.device.free_memory(memory);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoadedImage;
+    use crate::{
+        mock_backend::{self, Mock, MockCommand, MockHandle},
+        DescriptorLayoutBuilder, DeviceContext, ErrorKind, PipelineBundle, RectInt, Vec2Int,
+    };
+    use gfx_hal::{
+        adapter::MemoryTypeId,
+        image::{Access, Extent, Filter, Layout, Offset},
+        memory::Properties,
+        pso::{DescriptorType, PipelineStage, ShaderStageFlags},
+    };
+    use std::sync::Arc;
+
+    const SIZE: usize = 4;
+
+    fn load(
+        device: &Arc<DeviceContext<Mock>>,
+        pipeline_bundle: &mut PipelineBundle<Mock>,
+    ) -> Result<LoadedImage<Mock>, failure::Error> {
+        LoadedImage::allocate_and_create(
+            &mock_backend::adapter(),
+            device,
+            &mut mock_backend::command_pool(device),
+            &mut mock_backend::command_queue(),
+            pipeline_bundle,
+            &[0xFF; SIZE * SIZE * 4],
+            SIZE,
+            SIZE,
+            gfx_hal::image::Filter::Nearest,
+        )
+    }
+
+    #[test]
+    fn loading_and_dropping_gives_everything_back() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let before = device.live_objects();
+
+        let texture = load(&device, &mut pipeline_bundle).unwrap();
+        drop(texture);
+        drop(pipeline_bundle);
+
+        assert!(before.iter().all(|&(kind, _)| kind != "DescriptorSet"));
+        assert!(device.live_objects().is_empty());
+        assert!(device.live_resources().is_empty());
+    }
+
+    #[test]
+    fn rejects_pixels_that_dont_cover_the_image() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+
+        for &(width, height) in &[(SIZE, SIZE + 1), (usize::MAX, 2)] {
+            let error = LoadedImage::allocate_and_create(
+                &mock_backend::adapter(),
+                &device,
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+                &mut pipeline_bundle,
+                &[0xFF; SIZE * SIZE * 4],
+                width,
+                height,
+                Filter::Nearest,
+            )
+            .err()
+            .unwrap();
+            assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+        }
+        drop(pipeline_bundle);
+        assert!(device.live_objects().is_empty());
+    }
+
+    #[test]
+    fn allocate_and_create_unwinds_from_every_failure() {
+        let faults: &[(&'static str, usize)] = &[
+            ("create_image", 0),
+            ("allocate_memory", 0),
+            ("bind_image_memory", 0),
+            ("create_image_view", 0),
+            ("create_sampler", 0),
+            // The staging bundle for the upload...
+            ("create_buffer", 0),
+            ("allocate_memory", 1),
+            ("bind_buffer_memory", 0),
+            ("map_memory", 0),
+            ("flush_mapped_memory_ranges", 0),
+            // ...the upload itself...
+            ("create_fence", 0),
+            ("wait_for_fence", 0),
+            // ...and finally the descriptor set.
+            ("allocate_set", 0),
+        ];
+
+        for &(call, skip) in faults {
+            let device = mock_backend::device();
+            let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+            let before = device.live_objects();
+            device.inject_fault(call, skip);
+
+            assert!(
+                load(&device, &mut pipeline_bundle).is_err(),
+                "{} #{} didn't fail",
+                call,
+                skip
+            );
+            assert_eq!(device.live_objects(), before, "{} #{} leaked", call, skip);
+            assert_eq!(
+                device.live_resources(),
+                vec![
+                    ("DescriptorAllocator", 1),
+                    ("DescriptorLayout", 1),
+                    ("PipelineBundle", 1)
+                ],
+                "{} #{} leaked",
+                call,
+                skip
+            );
+
+            // The failed load mustn't have used up the pool's only set.
+            load(&device, &mut pipeline_bundle).unwrap();
+        }
+    }
+
+    #[test]
+    fn loading_more_textures_than_a_pool_holds() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 4);
+
+        let textures: Vec<_> = (0..10)
+            .map(|_| load(&device, &mut pipeline_bundle).unwrap())
+            .collect();
+        assert!(device.live_objects().contains(&("DescriptorPool", 3)));
+
+        drop(textures);
+        assert!(device
+            .live_objects()
+            .iter()
+            .all(|&(kind, _)| kind != "DescriptorSet"));
+    }
+
+    #[test]
+    fn allocate_and_create_unwinds_without_a_memory_type() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let before = device.live_objects();
+        let adapter = mock_backend::adapter_with_memory(vec![Properties::CPU_VISIBLE]);
+
+        let texture = LoadedImage::allocate_and_create(
+            &adapter,
+            &device,
+            &mut mock_backend::command_pool(&device),
+            &mut mock_backend::command_queue(),
+            &mut pipeline_bundle,
+            &[0xFF; SIZE * SIZE * 4],
+            SIZE,
+            SIZE,
+            gfx_hal::image::Filter::Nearest,
         );
 
-        //  Use pipeline barrier to transition the image back to SHADER_READ
-        //   and ShaderReadOnlyOptimal layout
-        let image_barrier = gfx_hal::memory::Barrier::Image {
-            states: (
-                gfx_hal::image::Access::TRANSFER_WRITE,
-                Layout::TransferDstOptimal,
+        assert!(texture.is_err());
+        assert_eq!(device.live_objects(), before);
+    }
+
+    #[test]
+    fn uploads_rows_at_the_pitch_the_device_wants() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let _texture = LoadedImage::allocate_and_create(
+            &mock_backend::adapter_with_copy_pitch(256),
+            &device,
+            &mut mock_backend::command_pool(&device),
+            &mut mock_backend::command_queue(),
+            &mut pipeline_bundle,
+            &[0xFF; 3 * 2 * 4],
+            3,
+            2,
+            Filter::Nearest,
+        )
+        .unwrap();
+
+        //  A row of three texels is 12 bytes, which gets padded out to 256
+        assert_eq!(
+            device.allocations(),
+            vec![(MemoryTypeId(0), 3 * 2 * 4), (MemoryTypeId(1), 256 * 2)]
+        );
+        assert_eq!(
+            device.take_commands(),
+            vec![
+                MockCommand::ImageBarrier {
+                    stages: PipelineStage::TOP_OF_PIPE..PipelineStage::TRANSFER,
+                    access: Access::empty()..Access::TRANSFER_WRITE,
+                    layouts: Layout::Undefined..Layout::TransferDstOptimal,
+                },
+                MockCommand::CopyBufferToImage {
+                    layout: Layout::TransferDstOptimal,
+                    buffer_offset: 0,
+                    buffer_width: 64,
+                    buffer_height: 2,
+                    offset: Offset::ZERO,
+                    extent: Extent {
+                        width: 3,
+                        height: 2,
+                        depth: 1
+                    },
+                },
+                MockCommand::ImageBarrier {
+                    stages: PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+                    access: Access::TRANSFER_WRITE..Access::SHADER_READ,
+                    layouts: Layout::TransferDstOptimal..Layout::ShaderReadOnlyOptimal,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn edit_image_waits_for_the_shader_and_writes_only_its_rect() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let mut texture = load(&device, &mut pipeline_bundle).unwrap();
+        device.take_commands();
+
+        texture
+            .edit_image(
+                RectInt::new(Vec2Int::new(1, 2), Vec2Int::new(2, 2)),
+                &[0; 2 * 2 * 4],
+                &mock_backend::adapter(),
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
             )
-                ..(
-                    gfx_hal::image::Access::SHADER_READ,
-                    Layout::ShaderReadOnlyOptimal,
-                ),
-            target: image_object,
-            families: None,
-            range: SubresourceRange {
-                aspects: Aspects::COLOR,
-                levels: 0..1,
-                layers: 0..1,
-            },
-        };
-        cmd_buffer.pipeline_barrier(
-            PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
-            gfx_hal::memory::Dependencies::empty(),
-            &[image_barrier],
+            .unwrap();
+
+        let commands = device.take_commands();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(
+            commands[0],
+            MockCommand::ImageBarrier {
+                stages: PipelineStage::FRAGMENT_SHADER..PipelineStage::TRANSFER,
+                access: Access::SHADER_READ..Access::TRANSFER_WRITE,
+                layouts: Layout::ShaderReadOnlyOptimal..Layout::TransferDstOptimal,
+            }
         );
+        match &commands[1] {
+            MockCommand::CopyBufferToImage {
+                buffer_width,
+                offset,
+                extent,
+                ..
+            } => {
+                assert_eq!(*buffer_width, 2);
+                assert_eq!(*offset, Offset { x: 1, y: 2, z: 0 });
+                assert_eq!((extent.width, extent.height), (2, 2));
+            }
+            other => panic!("Expected the upload, got {:?}", other),
+        }
+    }
 
-        //  Aaand we're done!
-        cmd_buffer.finish();
+    #[test]
+    fn copy_from_moves_both_images_through_transfer_layouts_and_back() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 2);
+        let source = load(&device, &mut pipeline_bundle).unwrap();
+        let mut dest = load(&device, &mut pipeline_bundle).unwrap();
+        device.take_commands();
 
-        let upload_fence = device
-            .create_fence(false)
-            .map_err(|e| LoadedImageError::UploadFence(e))?;
+        dest.copy_from(
+            &source,
+            &[(
+                RectInt::new(Vec2Int::new(0, 0), Vec2Int::new(2, 2)),
+                Vec2Int::new(2, 2),
+            )],
+            &mut mock_backend::command_pool(&device),
+            &mut mock_backend::command_queue(),
+        )
+        .unwrap();
 
-        // Submit it!
-        command_queue.submit_without_semaphores(Some(&cmd_buffer), Some(&upload_fence));
+        let layouts: Vec<_> = device
+            .take_commands()
+            .into_iter()
+            .map(|command| match command {
+                MockCommand::ImageBarrier { layouts, .. } => Some(layouts),
+                MockCommand::CopyImage { src, dst, extent } => {
+                    assert_eq!(src, Offset::ZERO);
+                    assert_eq!(dst, Offset { x: 2, y: 2, z: 0 });
+                    assert_eq!((extent.width, extent.height), (2, 2));
+                    None
+                }
+                other => panic!("Didn't expect {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            layouts,
+            vec![
+                Some(Layout::ShaderReadOnlyOptimal..Layout::TransferSrcOptimal),
+                Some(Layout::ShaderReadOnlyOptimal..Layout::TransferDstOptimal),
+                None,
+                Some(Layout::TransferSrcOptimal..Layout::ShaderReadOnlyOptimal),
+                Some(Layout::TransferDstOptimal..Layout::ShaderReadOnlyOptimal),
+            ]
+        );
+    }
 
-        device
-            .wait_for_fence(&upload_fence, core::u64::MAX)
-            .map_err(|e| LoadedImageError::WaitForFence(e))?;
-        device.destroy_fence(upload_fence);
+    #[test]
+    fn copy_from_rejects_regions_that_run_off_the_edge() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 2);
+        let source = load(&device, &mut pipeline_bundle).unwrap();
+        let mut dest = load(&device, &mut pipeline_bundle).unwrap();
+        device.take_commands();
 
-        //  11. Free our cmd_buffer!
-        command_pool.free(Some(cmd_buffer));
+        let square = RectInt::new(Vec2Int::new(0, 0), Vec2Int::new(2, 2));
+        for &offset in &[Vec2Int::new(3, 0), Vec2Int::new(i32::MAX, i32::MAX)] {
+            let error = dest
+                .copy_from(
+                    &source,
+                    &[(square, offset)],
+                    &mut mock_backend::command_pool(&device),
+                    &mut mock_backend::command_queue(),
+                )
+                .unwrap_err();
+            assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+        }
+        assert!(device.take_commands().is_empty());
+    }
 
-        Ok(())
+    #[test]
+    fn edit_image_drops_its_staging_bundle_on_failure() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        let mut texture = load(&device, &mut pipeline_bundle).unwrap();
+        let before = device.live_objects();
+
+        for &call in &["map_memory", "create_fence", "wait_for_fence"] {
+            device.inject_fault(call, 0);
+            let edited = texture.edit_image(
+                RectInt::new(Vec2Int::new(1, 1), Vec2Int::new(2, 2)),
+                &[0; 2 * 2 * 4],
+                &mock_backend::adapter(),
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+            );
+
+            assert!(edited.is_err(), "{} didn't fail", call);
+            assert_eq!(device.live_objects(), before, "{} leaked", call);
+        }
     }
 
-    pub unsafe fn manually_drop(&self, device: &B::Device) {
-        use core::ptr::read;
-        device.destroy_sampler(ManuallyDrop::into_inner(read(&self.sampler)));
-        device.destroy_image(manual_drop!(self.image));
-        device.destroy_image_view(manual_drop!(self.image_view));
-        device.free_memory(manual_drop!(self.memory));
+    #[test]
+    fn writes_descriptors_where_the_layout_declares_them() {
+        let device = mock_backend::device();
+        let descriptor_layout = DescriptorLayoutBuilder::new()
+            .binding(
+                0,
+                DescriptorType::UniformBuffer,
+                1,
+                ShaderStageFlags::VERTEX,
+            )
+            .binding(
+                3,
+                DescriptorType::CombinedImageSampler,
+                1,
+                ShaderStageFlags::FRAGMENT,
+            )
+            .immutable_samplers(
+                5,
+                DescriptorType::Sampler,
+                ShaderStageFlags::FRAGMENT,
+                &[&MockHandle],
+            )
+            .build(&device)
+            .unwrap();
+        let mut pipeline_bundle =
+            mock_backend::pipeline_bundle_with_layout(&device, 1, descriptor_layout);
+
+        let _texture = load(&device, &mut pipeline_bundle).unwrap();
+        assert_eq!(
+            device.descriptor_writes(),
+            vec![(3, "CombinedImageSampler")]
+        );
+    }
+
+    #[test]
+    fn allocate_and_create_needs_somewhere_to_put_the_texture() {
+        let device = mock_backend::device();
+        let descriptor_layout = DescriptorLayoutBuilder::new()
+            .binding(
+                0,
+                DescriptorType::UniformBuffer,
+                1,
+                ShaderStageFlags::VERTEX,
+            )
+            .build(&device)
+            .unwrap();
+        let mut pipeline_bundle =
+            mock_backend::pipeline_bundle_with_layout(&device, 1, descriptor_layout);
+        let before = device.live_resources();
+
+        assert!(load(&device, &mut pipeline_bundle).is_err());
+        assert_eq!(device.live_resources(), before);
+        assert!(device
+            .live_objects()
+            .iter()
+            .all(|&(kind, _)| kind != "Image" && kind != "DescriptorSet"));
+        assert!(device.descriptor_writes().is_empty());
     }
 }
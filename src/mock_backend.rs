@@ -0,0 +1,1597 @@
+//! A backend which doesn't talk to a GPU at all. The device counts every object
+//! it hands out and takes back, and can be told to fail a particular call, so
+//! tests can walk through our error paths and check nothing was left behind.
+//!
+//! It also keeps every memory allocation, and every barrier, copy and dispatch
+//! that gets submitted, so tests can check what we asked the GPU to do.
+
+use super::{
+    DescriptorAllocator, DescriptorLayout, DescriptorLayoutBuilder, DeviceContext, PipelineBundle,
+};
+use gfx_hal::{
+    adapter::{AdapterInfo, DeviceType, MemoryProperties, MemoryType, QueuePriority},
+    buffer,
+    command::{
+        AttachmentClear, BufferCopy, BufferImageCopy, ClearColorRaw, ClearDepthStencilRaw,
+        ClearValueRaw, CommandBufferFlags, CommandBufferInheritanceInfo, DescriptorSetOffset,
+        ImageBlit, ImageCopy, ImageResolve, RawCommandBuffer, RawLevel, SubpassContents,
+    },
+    device::{AllocationError, BindError, DeviceLost, OomOrDeviceLost, OutOfMemory, ShaderError},
+    error::{DeviceCreationError, HostExecutionError},
+    format, image, mapping,
+    memory::{Barrier, Dependencies, Properties, Requirements},
+    pass,
+    pool::{CommandPool, CommandPoolCreateFlags, RawCommandPool},
+    pso::{self, DescriptorPoolCreateFlags},
+    query,
+    queue::{QueueFamilyId, QueueType, RawCommandQueue, Submission},
+    range::RangeArg,
+    window::{
+        AcquireError, CreationError, PresentError, PresentMode, Suboptimal, SurfaceCapabilities,
+        SwapImageIndex, SwapchainConfig,
+    },
+    Adapter, Backend, CommandQueue, Features, General, Gpu, Graphics, Limits, MemoryTypeId,
+    WorkGroupCount,
+};
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum Mock {}
+
+impl Backend for Mock {
+    type PhysicalDevice = MockPhysicalDevice;
+    type Device = MockDevice;
+
+    type Surface = MockSurface;
+    type Swapchain = MockSwapchain;
+
+    type QueueFamily = MockQueueFamily;
+    type CommandQueue = MockCommandQueue;
+    type CommandBuffer = MockCommandBuffer;
+
+    type ShaderModule = MockHandle;
+    type RenderPass = MockHandle;
+    type Framebuffer = MockHandle;
+
+    type Memory = MockMemory;
+    type CommandPool = MockCommandPool;
+
+    type Buffer = MockBuffer;
+    type BufferView = MockHandle;
+    type Image = MockImage;
+    type ImageView = MockHandle;
+    type Sampler = MockHandle;
+
+    type ComputePipeline = MockHandle;
+    type GraphicsPipeline = MockHandle;
+    type PipelineCache = MockPipelineCache;
+    type PipelineLayout = MockHandle;
+    type DescriptorPool = MockDescriptorPool;
+    type DescriptorSet = MockHandle;
+    type DescriptorSetLayout = MockHandle;
+
+    type Fence = MockHandle;
+    type Semaphore = MockHandle;
+    type Event = MockHandle;
+    type QueryPool = MockHandle;
+}
+
+/// The bookkeeping shared by the device and everything it makes, so pools can
+/// fail and count their sets too.
+#[derive(Debug, Default)]
+pub struct MockState {
+    faults: Mutex<Vec<(&'static str, usize)>>,
+    live: Mutex<HashMap<&'static str, usize>>,
+    writes: Mutex<Vec<(u32, &'static str)>>,
+    allocations: Mutex<Vec<(MemoryTypeId, u64)>>,
+    flushes: Mutex<Vec<Range<u64>>>,
+    submitted: Mutex<Vec<MockCommand>>,
+    pipeline_cache_uuid: Mutex<[u8; 16]>,
+}
+
+impl MockState {
+    /// Whether `call` should fail this time around. Each injected fault only fires once.
+    fn fails(&self, call: &'static str) -> bool {
+        let mut faults = self.faults.lock().unwrap();
+        match faults.iter().position(|(name, _)| *name == call) {
+            Some(i) if faults[i].1 == 0 => {
+                faults.remove(i);
+                true
+            }
+            Some(i) => {
+                faults[i].1 -= 1;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn created(&self, kind: &'static str) {
+        *self.live.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    fn destroyed(&self, kind: &'static str) {
+        let mut live = self.live.lock().unwrap();
+        let count = live
+            .get_mut(kind)
+            .unwrap_or_else(|| panic!("Destroyed a {} the mock device never made!", kind));
+        *count -= 1;
+        if *count == 0 {
+            live.remove(kind);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MockHandle;
+
+#[derive(Debug)]
+pub struct MockBuffer {
+    size: u64,
+}
+
+#[derive(Debug)]
+pub struct MockPipelineCache {
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct MockImage {
+    size: u64,
+}
+
+#[derive(Debug)]
+pub struct MockMemory {
+    data: Mutex<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+pub struct MockDevice {
+    pub state: Arc<MockState>,
+}
+
+impl MockDevice {
+    /// Makes the call named `call` fail after letting `skip` of them through.
+    pub fn inject_fault(&self, call: &'static str, skip: usize) {
+        self.state.faults.lock().unwrap().push((call, skip));
+    }
+
+    /// Every kind of object still alive on the device, with how many there are.
+    pub fn live_objects(&self) -> Vec<(&'static str, usize)> {
+        let live = self.state.live.lock().unwrap();
+        let mut objects: Vec<_> = live.iter().map(|(kind, count)| (*kind, *count)).collect();
+        objects.sort();
+        objects
+    }
+
+    /// Every descriptor written so far, as its binding and what kind of descriptor it was.
+    pub fn descriptor_writes(&self) -> Vec<(u32, &'static str)> {
+        self.state.writes.lock().unwrap().clone()
+    }
+
+    /// Every memory allocation made so far, as its memory type and size.
+    pub fn allocations(&self) -> Vec<(MemoryTypeId, u64)> {
+        self.state.allocations.lock().unwrap().clone()
+    }
+
+    /// Every range of mapped memory flushed so far, in bytes.
+    pub fn flushes(&self) -> Vec<Range<u64>> {
+        self.state.flushes.lock().unwrap().clone()
+    }
+
+    /// Takes every command submitted since we last looked, in the order the GPU
+    /// would've run them.
+    pub fn take_commands(&self) -> Vec<MockCommand> {
+        self.state.submitted.lock().unwrap().drain(..).collect()
+    }
+
+    /// Pretends the driver was updated, so pipeline caches made before now don't fit.
+    pub fn set_pipeline_cache_uuid(&self, uuid: [u8; 16]) {
+        *self.state.pipeline_cache_uuid.lock().unwrap() = uuid;
+    }
+
+    /// The header a Vulkan driver puts at the front of its pipeline cache data.
+    fn pipeline_cache_header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(32);
+        header.extend_from_slice(&32u32.to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&MOCK_VENDOR.to_le_bytes());
+        header.extend_from_slice(&MOCK_DEVICE.to_le_bytes());
+        header.extend_from_slice(&*self.state.pipeline_cache_uuid.lock().unwrap());
+        header
+    }
+
+    fn make(&self, call: &'static str, kind: &'static str) -> bool {
+        if self.state.fails(call) {
+            false
+        } else {
+            self.state.created(kind);
+            true
+        }
+    }
+}
+
+pub const MOCK_VENDOR: u32 = 0x1AB5;
+pub const MOCK_DEVICE: u32 = 0x0042;
+
+const OOM: OutOfMemory = OutOfMemory::OutOfDeviceMemory;
+
+impl gfx_hal::Device<Mock> for MockDevice {
+    unsafe fn allocate_memory(
+        &self,
+        memory_type: MemoryTypeId,
+        size: u64,
+    ) -> Result<MockMemory, AllocationError> {
+        if !self.make("allocate_memory", "Memory") {
+            return Err(AllocationError::OutOfMemory(OOM));
+        }
+        self.state
+            .allocations
+            .lock()
+            .unwrap()
+            .push((memory_type, size));
+        Ok(MockMemory {
+            data: Mutex::new(vec![0; size as usize]),
+        })
+    }
+
+    unsafe fn free_memory(&self, _memory: MockMemory) {
+        self.state.destroyed("Memory");
+    }
+
+    unsafe fn create_command_pool(
+        &self,
+        _family: QueueFamilyId,
+        _create_flags: CommandPoolCreateFlags,
+    ) -> Result<MockCommandPool, OutOfMemory> {
+        if !self.make("create_command_pool", "CommandPool") {
+            return Err(OOM);
+        }
+        Ok(MockCommandPool {
+            state: Arc::clone(&self.state),
+        })
+    }
+
+    unsafe fn destroy_command_pool(&self, _pool: MockCommandPool) {
+        self.state.destroyed("CommandPool");
+    }
+
+    unsafe fn create_render_pass<'a, IA, IS, ID>(
+        &self,
+        _attachments: IA,
+        _subpasses: IS,
+        _dependencies: ID,
+    ) -> Result<MockHandle, OutOfMemory>
+    where
+        IA: IntoIterator,
+        IA::Item: Borrow<pass::Attachment>,
+        IS: IntoIterator,
+        IS::Item: Borrow<pass::SubpassDesc<'a>>,
+        ID: IntoIterator,
+        ID::Item: Borrow<pass::SubpassDependency>,
+    {
+        if !self.make("create_render_pass", "RenderPass") {
+            return Err(OOM);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_render_pass(&self, _rp: MockHandle) {
+        self.state.destroyed("RenderPass");
+    }
+
+    unsafe fn create_pipeline_layout<IS, IR>(
+        &self,
+        _set_layouts: IS,
+        _push_constant: IR,
+    ) -> Result<MockHandle, OutOfMemory>
+    where
+        IS: IntoIterator,
+        IS::Item: Borrow<MockHandle>,
+        IR: IntoIterator,
+        IR::Item: Borrow<(pso::ShaderStageFlags, Range<u32>)>,
+    {
+        if !self.make("create_pipeline_layout", "PipelineLayout") {
+            return Err(OOM);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_pipeline_layout(&self, _layout: MockHandle) {
+        self.state.destroyed("PipelineLayout");
+    }
+
+    unsafe fn create_pipeline_cache(
+        &self,
+        data: Option<&[u8]>,
+    ) -> Result<MockPipelineCache, OutOfMemory> {
+        if !self.make("create_pipeline_cache", "PipelineCache") {
+            return Err(OOM);
+        }
+
+        //  Like a real driver, quietly start over if the data isn't ours
+        let header = self.pipeline_cache_header();
+        let data = match data {
+            Some(data) if data.starts_with(&header) => data.to_vec(),
+            _ => header,
+        };
+        Ok(MockPipelineCache { data })
+    }
+
+    unsafe fn get_pipeline_cache_data(
+        &self,
+        cache: &MockPipelineCache,
+    ) -> Result<Vec<u8>, OutOfMemory> {
+        Ok(cache.data.clone())
+    }
+
+    unsafe fn merge_pipeline_caches<I>(
+        &self,
+        _target: &MockPipelineCache,
+        _sources: I,
+    ) -> Result<(), OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<MockPipelineCache>,
+    {
+        Ok(())
+    }
+
+    unsafe fn destroy_pipeline_cache(&self, _cache: MockPipelineCache) {
+        self.state.destroyed("PipelineCache");
+    }
+
+    unsafe fn create_graphics_pipeline<'a>(
+        &self,
+        _desc: &pso::GraphicsPipelineDesc<'a, Mock>,
+        _cache: Option<&MockPipelineCache>,
+    ) -> Result<MockHandle, pso::CreationError> {
+        if !self.make("create_graphics_pipeline", "GraphicsPipeline") {
+            return Err(pso::CreationError::Other);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_graphics_pipeline(&self, _pipeline: MockHandle) {
+        self.state.destroyed("GraphicsPipeline");
+    }
+
+    unsafe fn create_compute_pipeline<'a>(
+        &self,
+        _desc: &pso::ComputePipelineDesc<'a, Mock>,
+        _cache: Option<&MockPipelineCache>,
+    ) -> Result<MockHandle, pso::CreationError> {
+        if !self.make("create_compute_pipeline", "ComputePipeline") {
+            return Err(pso::CreationError::Other);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_compute_pipeline(&self, _pipeline: MockHandle) {
+        self.state.destroyed("ComputePipeline");
+    }
+
+    unsafe fn create_framebuffer<I>(
+        &self,
+        _pass: &MockHandle,
+        _attachments: I,
+        _extent: image::Extent,
+    ) -> Result<MockHandle, OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<MockHandle>,
+    {
+        if !self.make("create_framebuffer", "Framebuffer") {
+            return Err(OOM);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_framebuffer(&self, _buf: MockHandle) {
+        self.state.destroyed("Framebuffer");
+    }
+
+    unsafe fn create_shader_module(&self, _spirv_data: &[u32]) -> Result<MockHandle, ShaderError> {
+        if !self.make("create_shader_module", "ShaderModule") {
+            return Err(ShaderError::CompilationFailed("Injected fault".to_owned()));
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_shader_module(&self, _shader: MockHandle) {
+        self.state.destroyed("ShaderModule");
+    }
+
+    unsafe fn create_buffer(
+        &self,
+        size: u64,
+        _usage: buffer::Usage,
+    ) -> Result<MockBuffer, buffer::CreationError> {
+        if !self.make("create_buffer", "Buffer") {
+            return Err(buffer::CreationError::OutOfMemory(OOM));
+        }
+        Ok(MockBuffer { size })
+    }
+
+    unsafe fn get_buffer_requirements(&self, buf: &MockBuffer) -> Requirements {
+        Requirements {
+            size: buf.size,
+            alignment: 1,
+            type_mask: !0,
+        }
+    }
+
+    unsafe fn bind_buffer_memory(
+        &self,
+        _memory: &MockMemory,
+        _offset: u64,
+        _buf: &mut MockBuffer,
+    ) -> Result<(), BindError> {
+        if self.state.fails("bind_buffer_memory") {
+            return Err(BindError::OutOfMemory(OOM));
+        }
+        Ok(())
+    }
+
+    unsafe fn destroy_buffer(&self, _buffer: MockBuffer) {
+        self.state.destroyed("Buffer");
+    }
+
+    unsafe fn create_buffer_view<R: RangeArg<u64>>(
+        &self,
+        _buf: &MockBuffer,
+        _fmt: Option<format::Format>,
+        _range: R,
+    ) -> Result<MockHandle, buffer::ViewCreationError> {
+        if !self.make("create_buffer_view", "BufferView") {
+            return Err(buffer::ViewCreationError::OutOfMemory(OOM));
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_buffer_view(&self, _view: MockHandle) {
+        self.state.destroyed("BufferView");
+    }
+
+    unsafe fn create_image(
+        &self,
+        kind: image::Kind,
+        _mip_levels: image::Level,
+        format: format::Format,
+        _tiling: image::Tiling,
+        _usage: image::Usage,
+        _view_caps: image::ViewCapabilities,
+    ) -> Result<MockImage, image::CreationError> {
+        if !self.make("create_image", "Image") {
+            return Err(image::CreationError::OutOfMemory(OOM));
+        }
+        let extent = kind.extent();
+        let texel_size = format.surface_desc().bits as u64 / 8;
+        Ok(MockImage {
+            size: extent.width as u64 * extent.height as u64 * extent.depth as u64 * texel_size,
+        })
+    }
+
+    unsafe fn get_image_requirements(&self, image: &MockImage) -> Requirements {
+        Requirements {
+            size: image.size,
+            alignment: 1,
+            type_mask: !0,
+        }
+    }
+
+    unsafe fn get_image_subresource_footprint(
+        &self,
+        image: &MockImage,
+        _subresource: image::Subresource,
+    ) -> image::SubresourceFootprint {
+        image::SubresourceFootprint {
+            slice: 0..image.size,
+            row_pitch: 0,
+            array_pitch: 0,
+            depth_pitch: 0,
+        }
+    }
+
+    unsafe fn bind_image_memory(
+        &self,
+        _memory: &MockMemory,
+        _offset: u64,
+        _image: &mut MockImage,
+    ) -> Result<(), BindError> {
+        if self.state.fails("bind_image_memory") {
+            return Err(BindError::OutOfMemory(OOM));
+        }
+        Ok(())
+    }
+
+    unsafe fn destroy_image(&self, _image: MockImage) {
+        self.state.destroyed("Image");
+    }
+
+    unsafe fn create_image_view(
+        &self,
+        _image: &MockImage,
+        _view_kind: image::ViewKind,
+        _format: format::Format,
+        _swizzle: format::Swizzle,
+        _range: image::SubresourceRange,
+    ) -> Result<MockHandle, image::ViewError> {
+        if !self.make("create_image_view", "ImageView") {
+            return Err(image::ViewError::OutOfMemory(OOM));
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_image_view(&self, _view: MockHandle) {
+        self.state.destroyed("ImageView");
+    }
+
+    unsafe fn create_sampler(
+        &self,
+        _info: image::SamplerInfo,
+    ) -> Result<MockHandle, AllocationError> {
+        if !self.make("create_sampler", "Sampler") {
+            return Err(AllocationError::OutOfMemory(OOM));
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_sampler(&self, _sampler: MockHandle) {
+        self.state.destroyed("Sampler");
+    }
+
+    unsafe fn create_descriptor_pool<I>(
+        &self,
+        max_sets: usize,
+        _descriptor_ranges: I,
+        flags: DescriptorPoolCreateFlags,
+    ) -> Result<MockDescriptorPool, OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<pso::DescriptorRangeDesc>,
+    {
+        if !self.make("create_descriptor_pool", "DescriptorPool") {
+            return Err(OOM);
+        }
+        Ok(MockDescriptorPool {
+            state: Arc::clone(&self.state),
+            max_sets,
+            allocated: 0,
+            flags,
+        })
+    }
+
+    unsafe fn destroy_descriptor_pool(&self, pool: MockDescriptorPool) {
+        // Destroying a pool frees every set that came out of it.
+        for _ in 0..pool.allocated {
+            self.state.destroyed("DescriptorSet");
+        }
+        self.state.destroyed("DescriptorPool");
+    }
+
+    unsafe fn create_descriptor_set_layout<I, J>(
+        &self,
+        _bindings: I,
+        _immutable_samplers: J,
+    ) -> Result<MockHandle, OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<pso::DescriptorSetLayoutBinding>,
+        J: IntoIterator,
+        J::Item: Borrow<MockHandle>,
+    {
+        if !self.make("create_descriptor_set_layout", "DescriptorSetLayout") {
+            return Err(OOM);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_descriptor_set_layout(&self, _layout: MockHandle) {
+        self.state.destroyed("DescriptorSetLayout");
+    }
+
+    unsafe fn write_descriptor_sets<'a, I, J>(&self, write_iter: I)
+    where
+        I: IntoIterator<Item = pso::DescriptorSetWrite<'a, Mock, J>>,
+        J: IntoIterator,
+        J::Item: Borrow<pso::Descriptor<'a, Mock>>,
+    {
+        let mut writes = self.state.writes.lock().unwrap();
+        for write in write_iter {
+            for descriptor in write.descriptors {
+                let kind = match descriptor.borrow() {
+                    pso::Descriptor::Sampler(_) => "Sampler",
+                    pso::Descriptor::Image(..) => "Image",
+                    pso::Descriptor::CombinedImageSampler(..) => "CombinedImageSampler",
+                    pso::Descriptor::Buffer(..) => "Buffer",
+                    pso::Descriptor::UniformTexelBuffer(_) => "UniformTexelBuffer",
+                    pso::Descriptor::StorageTexelBuffer(_) => "StorageTexelBuffer",
+                };
+                writes.push((write.binding, kind));
+            }
+        }
+    }
+
+    unsafe fn copy_descriptor_sets<'a, I>(&self, _copy_iter: I)
+    where
+        I: IntoIterator,
+        I::Item: Borrow<pso::DescriptorSetCopy<'a, Mock>>,
+    {
+    }
+
+    unsafe fn map_memory<R>(&self, memory: &MockMemory, range: R) -> Result<*mut u8, mapping::Error>
+    where
+        R: RangeArg<u64>,
+    {
+        if self.state.fails("map_memory") {
+            return Err(mapping::Error::OutOfMemory(OOM));
+        }
+        let start = *range.start().unwrap_or(&0) as usize;
+        Ok(memory.data.lock().unwrap().as_mut_ptr().add(start))
+    }
+
+    unsafe fn flush_mapped_memory_ranges<'a, I, R>(&self, ranges: I) -> Result<(), OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<(&'a MockMemory, R)>,
+        R: RangeArg<u64>,
+    {
+        if self.state.fails("flush_mapped_memory_ranges") {
+            return Err(OOM);
+        }
+        let mut flushes = self.state.flushes.lock().unwrap();
+        for range in ranges {
+            let (memory, range) = range.borrow();
+            let size = memory.data.lock().unwrap().len() as u64;
+            flushes.push(*range.start().unwrap_or(&0)..*range.end().unwrap_or(&size));
+        }
+        Ok(())
+    }
+
+    unsafe fn invalidate_mapped_memory_ranges<'a, I, R>(
+        &self,
+        _ranges: I,
+    ) -> Result<(), OutOfMemory>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<(&'a MockMemory, R)>,
+        R: RangeArg<u64>,
+    {
+        Ok(())
+    }
+
+    unsafe fn unmap_memory(&self, _memory: &MockMemory) {}
+
+    fn create_semaphore(&self) -> Result<MockHandle, OutOfMemory> {
+        if !self.make("create_semaphore", "Semaphore") {
+            return Err(OOM);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_semaphore(&self, _semaphore: MockHandle) {
+        self.state.destroyed("Semaphore");
+    }
+
+    fn create_fence(&self, _signaled: bool) -> Result<MockHandle, OutOfMemory> {
+        if !self.make("create_fence", "Fence") {
+            return Err(OOM);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn reset_fence(&self, _fence: &MockHandle) -> Result<(), OutOfMemory> {
+        Ok(())
+    }
+
+    unsafe fn wait_for_fence(
+        &self,
+        _fence: &MockHandle,
+        _timeout_ns: u64,
+    ) -> Result<bool, OomOrDeviceLost> {
+        if self.state.fails("wait_for_fence") {
+            return Err(OomOrDeviceLost::DeviceLost(DeviceLost));
+        }
+        Ok(true)
+    }
+
+    unsafe fn get_fence_status(&self, _fence: &MockHandle) -> Result<bool, DeviceLost> {
+        Ok(true)
+    }
+
+    unsafe fn destroy_fence(&self, _fence: MockHandle) {
+        self.state.destroyed("Fence");
+    }
+
+    fn create_event(&self) -> Result<MockHandle, OutOfMemory> {
+        if !self.make("create_event", "Event") {
+            return Err(OOM);
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_event(&self, _event: MockHandle) {
+        self.state.destroyed("Event");
+    }
+
+    unsafe fn get_event_status(&self, _event: &MockHandle) -> Result<bool, OomOrDeviceLost> {
+        Ok(true)
+    }
+
+    unsafe fn set_event(&self, _event: &MockHandle) -> Result<(), OutOfMemory> {
+        Ok(())
+    }
+
+    unsafe fn reset_event(&self, _event: &MockHandle) -> Result<(), OutOfMemory> {
+        Ok(())
+    }
+
+    unsafe fn create_query_pool(
+        &self,
+        _ty: query::Type,
+        _count: query::Id,
+    ) -> Result<MockHandle, query::CreationError> {
+        if !self.make("create_query_pool", "QueryPool") {
+            return Err(query::CreationError::OutOfMemory(OOM));
+        }
+        Ok(MockHandle)
+    }
+
+    unsafe fn destroy_query_pool(&self, _pool: MockHandle) {
+        self.state.destroyed("QueryPool");
+    }
+
+    unsafe fn get_query_pool_results(
+        &self,
+        _pool: &MockHandle,
+        _queries: Range<query::Id>,
+        _data: &mut [u8],
+        _stride: buffer::Offset,
+        _flags: query::ResultFlags,
+    ) -> Result<bool, OomOrDeviceLost> {
+        Ok(true)
+    }
+
+    unsafe fn create_swapchain(
+        &self,
+        _surface: &mut MockSurface,
+        _config: SwapchainConfig,
+        _old_swapchain: Option<MockSwapchain>,
+    ) -> Result<(MockSwapchain, Vec<MockImage>), CreationError> {
+        Err(CreationError::WindowInUse(gfx_hal::device::WindowInUse))
+    }
+
+    unsafe fn destroy_swapchain(&self, swapchain: MockSwapchain) {
+        match swapchain {}
+    }
+
+    fn wait_idle(&self) -> Result<(), HostExecutionError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MockDescriptorPool {
+    state: Arc<MockState>,
+    max_sets: usize,
+    allocated: usize,
+    flags: DescriptorPoolCreateFlags,
+}
+
+impl pso::DescriptorPool<Mock> for MockDescriptorPool {
+    unsafe fn allocate_set(
+        &mut self,
+        _layout: &MockHandle,
+    ) -> Result<MockHandle, pso::AllocationError> {
+        if self.allocated == self.max_sets {
+            return Err(pso::AllocationError::OutOfPoolMemory);
+        }
+        if self.state.fails("allocate_set") {
+            return Err(pso::AllocationError::OutOfDeviceMemory);
+        }
+        self.state.created("DescriptorSet");
+        self.allocated += 1;
+        Ok(MockHandle)
+    }
+
+    unsafe fn free_sets<I>(&mut self, descriptor_sets: I)
+    where
+        I: IntoIterator<Item = MockHandle>,
+    {
+        assert!(
+            self.flags
+                .contains(DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET),
+            "Freed descriptor sets back to a pool made without FREE_DESCRIPTOR_SET!"
+        );
+        for _ in descriptor_sets {
+            self.state.destroyed("DescriptorSet");
+            self.allocated -= 1;
+        }
+    }
+
+    unsafe fn reset(&mut self) {
+        for _ in 0..self.allocated {
+            self.state.destroyed("DescriptorSet");
+        }
+        self.allocated = 0;
+    }
+}
+
+#[derive(Debug)]
+pub struct MockCommandPool {
+    state: Arc<MockState>,
+}
+
+impl RawCommandPool<Mock> for MockCommandPool {
+    unsafe fn reset(&mut self, _release_resources: bool) {}
+
+    fn allocate_one(&mut self, _level: RawLevel) -> MockCommandBuffer {
+        self.state.created("CommandBuffer");
+        MockCommandBuffer {
+            commands: Vec::new(),
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    unsafe fn free<I>(&mut self, buffers: I)
+    where
+        I: IntoIterator<Item = MockCommandBuffer>,
+    {
+        for _ in buffers {
+            self.state.destroyed("CommandBuffer");
+        }
+    }
+}
+
+/// The commands we check on. Each barrier in a `pipeline_barrier` and each
+/// region of a copy is a command of its own.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockCommand {
+    BufferBarrier {
+        stages: Range<pso::PipelineStage>,
+        access: Range<buffer::Access>,
+    },
+    AllBuffersBarrier {
+        stages: Range<pso::PipelineStage>,
+        access: Range<buffer::Access>,
+    },
+    ImageBarrier {
+        stages: Range<pso::PipelineStage>,
+        access: Range<image::Access>,
+        layouts: Range<image::Layout>,
+    },
+    AllImagesBarrier {
+        stages: Range<pso::PipelineStage>,
+        access: Range<image::Access>,
+    },
+    CopyBuffer {
+        src: buffer::Offset,
+        dst: buffer::Offset,
+        size: buffer::Offset,
+    },
+    CopyImage {
+        src: image::Offset,
+        dst: image::Offset,
+        extent: image::Extent,
+    },
+    /// `buffer_width` is the row pitch in texels.
+    CopyBufferToImage {
+        layout: image::Layout,
+        buffer_offset: buffer::Offset,
+        buffer_width: u32,
+        buffer_height: u32,
+        offset: image::Offset,
+        extent: image::Extent,
+    },
+    CopyImageToBuffer {
+        layout: image::Layout,
+        buffer_offset: buffer::Offset,
+        buffer_width: u32,
+        buffer_height: u32,
+        offset: image::Offset,
+        extent: image::Extent,
+    },
+    Dispatch(WorkGroupCount),
+    /// `offset` is in words, like the push-constant ranges.
+    PushConstants {
+        offset: u32,
+        constants: Vec<u32>,
+    },
+}
+
+/// Records the commands in `MockCommand` and hands them to the device when
+/// it's submitted. Every other command is a no-op.
+#[derive(Debug)]
+pub struct MockCommandBuffer {
+    commands: Vec<MockCommand>,
+    state: Arc<MockState>,
+}
+
+impl RawCommandBuffer<Mock> for MockCommandBuffer {
+    unsafe fn begin(
+        &mut self,
+        _flags: CommandBufferFlags,
+        _inheritance_info: CommandBufferInheritanceInfo<'_, Mock>,
+    ) {
+        self.commands.clear();
+    }
+
+    unsafe fn finish(&mut self) {}
+
+    unsafe fn reset(&mut self, _release_resources: bool) {
+        self.commands.clear();
+    }
+
+    unsafe fn pipeline_barrier<'a, T>(
+        &mut self,
+        stages: Range<pso::PipelineStage>,
+        _dependencies: Dependencies,
+        barriers: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<Barrier<'a, Mock>>,
+    {
+        for barrier in barriers {
+            let stages = stages.clone();
+            self.commands.push(match barrier.borrow() {
+                Barrier::AllBuffers(access) => MockCommand::AllBuffersBarrier {
+                    stages,
+                    access: access.clone(),
+                },
+                Barrier::AllImages(access) => MockCommand::AllImagesBarrier {
+                    stages,
+                    access: access.clone(),
+                },
+                Barrier::Buffer { states, .. } => MockCommand::BufferBarrier {
+                    stages,
+                    access: states.clone(),
+                },
+                Barrier::Image { states, .. } => MockCommand::ImageBarrier {
+                    stages,
+                    access: states.start.0..states.end.0,
+                    layouts: states.start.1..states.end.1,
+                },
+            });
+        }
+    }
+
+    unsafe fn fill_buffer<R>(&mut self, _buffer: &MockBuffer, _range: R, _data: u32)
+    where
+        R: RangeArg<buffer::Offset>,
+    {
+    }
+
+    unsafe fn update_buffer(
+        &mut self,
+        _buffer: &MockBuffer,
+        _offset: buffer::Offset,
+        _data: &[u8],
+    ) {
+    }
+
+    unsafe fn clear_image<T>(
+        &mut self,
+        _image: &MockImage,
+        _layout: image::Layout,
+        _color: ClearColorRaw,
+        _depth_stencil: ClearDepthStencilRaw,
+        _subresource_ranges: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<image::SubresourceRange>,
+    {
+    }
+
+    unsafe fn clear_attachments<T, U>(&mut self, _clears: T, _rects: U)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<AttachmentClear>,
+        U: IntoIterator,
+        U::Item: Borrow<pso::ClearRect>,
+    {
+    }
+
+    unsafe fn resolve_image<T>(
+        &mut self,
+        _src: &MockImage,
+        _src_layout: image::Layout,
+        _dst: &MockImage,
+        _dst_layout: image::Layout,
+        _regions: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<ImageResolve>,
+    {
+    }
+
+    unsafe fn blit_image<T>(
+        &mut self,
+        _src: &MockImage,
+        _src_layout: image::Layout,
+        _dst: &MockImage,
+        _dst_layout: image::Layout,
+        _filter: image::Filter,
+        _regions: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<ImageBlit>,
+    {
+    }
+
+    unsafe fn bind_index_buffer(&mut self, _view: buffer::IndexBufferView<'_, Mock>) {}
+
+    unsafe fn bind_vertex_buffers<I, T>(&mut self, _first_binding: pso::BufferIndex, _buffers: I)
+    where
+        I: IntoIterator<Item = (T, buffer::Offset)>,
+        T: Borrow<MockBuffer>,
+    {
+    }
+
+    unsafe fn set_viewports<T>(&mut self, _first_viewport: u32, _viewports: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<pso::Viewport>,
+    {
+    }
+
+    unsafe fn set_scissors<T>(&mut self, _first_scissor: u32, _rects: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<pso::Rect>,
+    {
+    }
+
+    unsafe fn set_stencil_reference(&mut self, _faces: pso::Face, _value: pso::StencilValue) {}
+
+    unsafe fn set_stencil_read_mask(&mut self, _faces: pso::Face, _value: pso::StencilValue) {}
+
+    unsafe fn set_stencil_write_mask(&mut self, _faces: pso::Face, _value: pso::StencilValue) {}
+
+    unsafe fn set_blend_constants(&mut self, _color: pso::ColorValue) {}
+
+    unsafe fn set_depth_bounds(&mut self, _bounds: Range<f32>) {}
+
+    unsafe fn set_line_width(&mut self, _width: f32) {}
+
+    unsafe fn set_depth_bias(&mut self, _depth_bias: pso::DepthBias) {}
+
+    unsafe fn begin_render_pass<T>(
+        &mut self,
+        _render_pass: &MockHandle,
+        _framebuffer: &MockHandle,
+        _render_area: pso::Rect,
+        _clear_values: T,
+        _first_subpass: SubpassContents,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<ClearValueRaw>,
+    {
+    }
+
+    unsafe fn next_subpass(&mut self, _contents: SubpassContents) {}
+
+    unsafe fn end_render_pass(&mut self) {}
+
+    unsafe fn bind_graphics_pipeline(&mut self, _pipeline: &MockHandle) {}
+
+    unsafe fn bind_graphics_descriptor_sets<I, J>(
+        &mut self,
+        _layout: &MockHandle,
+        _first_set: usize,
+        _sets: I,
+        _offsets: J,
+    ) where
+        I: IntoIterator,
+        I::Item: Borrow<MockHandle>,
+        J: IntoIterator,
+        J::Item: Borrow<DescriptorSetOffset>,
+    {
+    }
+
+    unsafe fn bind_compute_pipeline(&mut self, _pipeline: &MockHandle) {}
+
+    unsafe fn bind_compute_descriptor_sets<I, J>(
+        &mut self,
+        _layout: &MockHandle,
+        _first_set: usize,
+        _sets: I,
+        _offsets: J,
+    ) where
+        I: IntoIterator,
+        I::Item: Borrow<MockHandle>,
+        J: IntoIterator,
+        J::Item: Borrow<DescriptorSetOffset>,
+    {
+    }
+
+    unsafe fn dispatch(&mut self, count: WorkGroupCount) {
+        self.commands.push(MockCommand::Dispatch(count));
+    }
+
+    unsafe fn dispatch_indirect(&mut self, _buffer: &MockBuffer, _offset: buffer::Offset) {}
+
+    unsafe fn copy_buffer<T>(&mut self, _src: &MockBuffer, _dst: &MockBuffer, regions: T)
+    where
+        T: IntoIterator,
+        T::Item: Borrow<BufferCopy>,
+    {
+        for region in regions {
+            let region = region.borrow();
+            self.commands.push(MockCommand::CopyBuffer {
+                src: region.src,
+                dst: region.dst,
+                size: region.size,
+            });
+        }
+    }
+
+    unsafe fn copy_image<T>(
+        &mut self,
+        _src: &MockImage,
+        _src_layout: image::Layout,
+        _dst: &MockImage,
+        _dst_layout: image::Layout,
+        regions: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<ImageCopy>,
+    {
+        for region in regions {
+            let region = region.borrow();
+            self.commands.push(MockCommand::CopyImage {
+                src: region.src_offset,
+                dst: region.dst_offset,
+                extent: region.extent,
+            });
+        }
+    }
+
+    unsafe fn copy_buffer_to_image<T>(
+        &mut self,
+        _src: &MockBuffer,
+        _dst: &MockImage,
+        dst_layout: image::Layout,
+        regions: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<BufferImageCopy>,
+    {
+        for region in regions {
+            let region = region.borrow();
+            self.commands.push(MockCommand::CopyBufferToImage {
+                layout: dst_layout,
+                buffer_offset: region.buffer_offset,
+                buffer_width: region.buffer_width,
+                buffer_height: region.buffer_height,
+                offset: region.image_offset,
+                extent: region.image_extent,
+            });
+        }
+    }
+
+    unsafe fn copy_image_to_buffer<T>(
+        &mut self,
+        _src: &MockImage,
+        src_layout: image::Layout,
+        _dst: &MockBuffer,
+        regions: T,
+    ) where
+        T: IntoIterator,
+        T::Item: Borrow<BufferImageCopy>,
+    {
+        for region in regions {
+            let region = region.borrow();
+            self.commands.push(MockCommand::CopyImageToBuffer {
+                layout: src_layout,
+                buffer_offset: region.buffer_offset,
+                buffer_width: region.buffer_width,
+                buffer_height: region.buffer_height,
+                offset: region.image_offset,
+                extent: region.image_extent,
+            });
+        }
+    }
+
+    unsafe fn draw(&mut self, _vertices: Range<u32>, _instances: Range<u32>) {}
+
+    unsafe fn draw_indexed(
+        &mut self,
+        _indices: Range<u32>,
+        _base_vertex: i32,
+        _instances: Range<u32>,
+    ) {
+    }
+
+    unsafe fn draw_indirect(
+        &mut self,
+        _buffer: &MockBuffer,
+        _offset: buffer::Offset,
+        _draw_count: u32,
+        _stride: u32,
+    ) {
+    }
+
+    unsafe fn draw_indexed_indirect(
+        &mut self,
+        _buffer: &MockBuffer,
+        _offset: buffer::Offset,
+        _draw_count: u32,
+        _stride: u32,
+    ) {
+    }
+
+    unsafe fn set_event(&mut self, _event: &MockHandle, _stages: pso::PipelineStage) {}
+
+    unsafe fn reset_event(&mut self, _event: &MockHandle, _stages: pso::PipelineStage) {}
+
+    unsafe fn wait_events<'a, I, J>(
+        &mut self,
+        _events: I,
+        _stages: Range<pso::PipelineStage>,
+        _barriers: J,
+    ) where
+        I: IntoIterator,
+        I::Item: Borrow<MockHandle>,
+        J: IntoIterator,
+        J::Item: Borrow<Barrier<'a, Mock>>,
+    {
+    }
+
+    unsafe fn begin_query(&mut self, _query: query::Query<'_, Mock>, _flags: query::ControlFlags) {}
+
+    unsafe fn end_query(&mut self, _query: query::Query<'_, Mock>) {}
+
+    unsafe fn reset_query_pool(&mut self, _pool: &MockHandle, _queries: Range<query::Id>) {}
+
+    unsafe fn copy_query_pool_results(
+        &mut self,
+        _pool: &MockHandle,
+        _queries: Range<query::Id>,
+        _buffer: &MockBuffer,
+        _offset: buffer::Offset,
+        _stride: buffer::Offset,
+        _flags: query::ResultFlags,
+    ) {
+    }
+
+    unsafe fn write_timestamp(
+        &mut self,
+        _stage: pso::PipelineStage,
+        _query: query::Query<'_, Mock>,
+    ) {
+    }
+
+    unsafe fn push_graphics_constants(
+        &mut self,
+        _layout: &MockHandle,
+        _stages: pso::ShaderStageFlags,
+        _offset: u32,
+        _constants: &[u32],
+    ) {
+    }
+
+    unsafe fn push_compute_constants(
+        &mut self,
+        _layout: &MockHandle,
+        offset: u32,
+        constants: &[u32],
+    ) {
+        self.commands.push(MockCommand::PushConstants {
+            offset,
+            constants: constants.to_vec(),
+        });
+    }
+
+    unsafe fn execute_commands<'a, T, I>(&mut self, _cmd_buffers: I)
+    where
+        T: 'a + Borrow<MockCommandBuffer>,
+        I: IntoIterator<Item = &'a T>,
+    {
+    }
+}
+
+#[derive(Debug)]
+pub struct MockCommandQueue;
+
+impl RawCommandQueue<Mock> for MockCommandQueue {
+    unsafe fn submit<'a, T, Ic, S, Iw, Is>(
+        &mut self,
+        submission: Submission<Ic, Iw, Is>,
+        _fence: Option<&MockHandle>,
+    ) where
+        T: 'a + Borrow<MockCommandBuffer>,
+        Ic: IntoIterator<Item = &'a T>,
+        S: 'a + Borrow<MockHandle>,
+        Iw: IntoIterator<Item = (&'a S, pso::PipelineStage)>,
+        Is: IntoIterator<Item = &'a S>,
+    {
+        for cmd_buffer in submission.command_buffers {
+            let cmd_buffer = cmd_buffer.borrow();
+            cmd_buffer
+                .state
+                .submitted
+                .lock()
+                .unwrap()
+                .extend(cmd_buffer.commands.iter().cloned());
+        }
+    }
+
+    unsafe fn present<'a, W, Is, S, Iw>(
+        &mut self,
+        _swapchains: Is,
+        _wait_semaphores: Iw,
+    ) -> Result<Option<Suboptimal>, PresentError>
+    where
+        W: 'a + Borrow<MockSwapchain>,
+        Is: IntoIterator<Item = (&'a W, SwapImageIndex)>,
+        S: 'a + Borrow<MockHandle>,
+        Iw: IntoIterator<Item = &'a S>,
+    {
+        Ok(None)
+    }
+
+    fn wait_idle(&self) -> Result<(), HostExecutionError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MockPhysicalDevice {
+    pub memory_types: Vec<Properties>,
+    pub limits: Limits,
+    pub features: Features,
+}
+
+impl gfx_hal::PhysicalDevice<Mock> for MockPhysicalDevice {
+    unsafe fn open(
+        &self,
+        _families: &[(&MockQueueFamily, &[QueuePriority])],
+        _requested_features: Features,
+    ) -> Result<Gpu<Mock>, DeviceCreationError> {
+        Err(DeviceCreationError::InitializationFailed)
+    }
+
+    fn format_properties(&self, _format: Option<format::Format>) -> format::Properties {
+        format::Properties::default()
+    }
+
+    fn image_format_properties(
+        &self,
+        _format: format::Format,
+        _dimensions: u8,
+        _tiling: image::Tiling,
+        _usage: image::Usage,
+        _view_caps: image::ViewCapabilities,
+    ) -> Option<image::FormatProperties> {
+        None
+    }
+
+    fn memory_properties(&self) -> MemoryProperties {
+        MemoryProperties {
+            memory_types: self
+                .memory_types
+                .iter()
+                .map(|&properties| MemoryType {
+                    properties,
+                    heap_index: 0,
+                })
+                .collect(),
+            memory_heaps: vec![!0],
+        }
+    }
+
+    fn features(&self) -> Features {
+        self.features
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits
+    }
+}
+
+#[derive(Debug)]
+pub struct MockQueueFamily;
+
+impl gfx_hal::QueueFamily for MockQueueFamily {
+    fn queue_type(&self) -> QueueType {
+        QueueType::General
+    }
+
+    fn max_queues(&self) -> usize {
+        1
+    }
+
+    fn id(&self) -> QueueFamilyId {
+        QueueFamilyId(0)
+    }
+}
+
+/// We never make a window in tests, so there are no surfaces or swapchains.
+#[derive(Debug)]
+pub enum MockSurface {}
+
+impl gfx_hal::Surface<Mock> for MockSurface {
+    fn supports_queue_family(&self, _family: &MockQueueFamily) -> bool {
+        match *self {}
+    }
+
+    fn compatibility(
+        &self,
+        _physical_device: &MockPhysicalDevice,
+    ) -> (
+        SurfaceCapabilities,
+        Option<Vec<format::Format>>,
+        Vec<PresentMode>,
+    ) {
+        match *self {}
+    }
+}
+
+#[derive(Debug)]
+pub enum MockSwapchain {}
+
+impl gfx_hal::Swapchain<Mock> for MockSwapchain {
+    unsafe fn acquire_image(
+        &mut self,
+        _timeout_ns: u64,
+        _semaphore: Option<&MockHandle>,
+        _fence: Option<&MockHandle>,
+    ) -> Result<(SwapImageIndex, Option<Suboptimal>), AcquireError> {
+        match *self {}
+    }
+}
+
+/// An adapter with a device-local and a host-visible memory type.
+pub fn adapter() -> Adapter<Mock> {
+    adapter_with_memory(vec![
+        Properties::DEVICE_LOCAL,
+        Properties::CPU_VISIBLE | Properties::COHERENT,
+    ])
+}
+
+/// Like `adapter`, but buffer-to-image copies want rows `alignment` bytes apart.
+pub fn adapter_with_copy_pitch(alignment: u64) -> Adapter<Mock> {
+    let mut adapter = adapter();
+    adapter
+        .physical_device
+        .limits
+        .optimal_buffer_copy_pitch_alignment = alignment;
+    adapter
+}
+
+pub fn adapter_with_memory(memory_types: Vec<Properties>) -> Adapter<Mock> {
+    let limits = Limits {
+        optimal_buffer_copy_pitch_alignment: 4,
+        min_uniform_buffer_offset_alignment: 256,
+        max_uniform_buffer_range: 1 << 16,
+        max_per_stage_descriptor_sampled_images: 1 << 10,
+        max_descriptor_set_sampled_images: 1 << 10,
+        max_per_stage_descriptor_samplers: 16,
+        max_descriptor_set_samplers: 16,
+        ..Limits::default()
+    };
+
+    Adapter {
+        info: AdapterInfo {
+            name: "Mock".to_owned(),
+            vendor: MOCK_VENDOR as usize,
+            device: MOCK_DEVICE as usize,
+            device_type: DeviceType::VirtualGpu,
+        },
+        physical_device: MockPhysicalDevice {
+            memory_types,
+            limits,
+            features: Features::SHADER_SAMPLED_IMAGE_ARRAY_DYNAMIC_INDEXING,
+        },
+        queue_families: vec![MockQueueFamily],
+    }
+}
+
+pub fn device() -> Arc<DeviceContext<Mock>> {
+    DeviceContext::new(MockDevice::default())
+}
+
+pub fn command_pool(device: &DeviceContext<Mock>) -> CommandPool<Mock, Graphics> {
+    unsafe {
+        CommandPool::new(MockCommandPool {
+            state: Arc::clone(&device.state),
+        })
+    }
+}
+
+pub fn command_queue() -> CommandQueue<Mock, Graphics> {
+    unsafe { CommandQueue::new(MockCommandQueue) }
+}
+
+/// A pool and queue which can do compute work as well as graphics.
+pub fn general_command_pool(device: &DeviceContext<Mock>) -> CommandPool<Mock, General> {
+    unsafe {
+        CommandPool::new(MockCommandPool {
+            state: Arc::clone(&device.state),
+        })
+    }
+}
+
+pub fn general_command_queue() -> CommandQueue<Mock, General> {
+    unsafe { CommandQueue::new(MockCommandQueue) }
+}
+
+/// A graphics pipeline bundle whose descriptor pools have room for `sets_per_pool` sets,
+/// with an image at binding 0 and a sampler at binding 1.
+pub fn pipeline_bundle(
+    device: &Arc<DeviceContext<Mock>>,
+    sets_per_pool: usize,
+) -> PipelineBundle<Mock> {
+    let descriptor_layout = DescriptorLayoutBuilder::new()
+        .binding(
+            0,
+            pso::DescriptorType::SampledImage,
+            1,
+            pso::ShaderStageFlags::FRAGMENT,
+        )
+        .binding(
+            1,
+            pso::DescriptorType::Sampler,
+            1,
+            pso::ShaderStageFlags::FRAGMENT,
+        )
+        .build(device)
+        .unwrap();
+    pipeline_bundle_with_layout(device, sets_per_pool, descriptor_layout)
+}
+
+pub fn pipeline_bundle_with_layout(
+    device: &Arc<DeviceContext<Mock>>,
+    sets_per_pool: usize,
+    descriptor_layout: DescriptorLayout<Mock>,
+) -> PipelineBundle<Mock> {
+    use gfx_hal::Device;
+    unsafe {
+        let descriptor_allocator = DescriptorAllocator::new(
+            device,
+            sets_per_pool,
+            &descriptor_layout.pool_ranges(sets_per_pool),
+        )
+        .unwrap();
+        let pipeline_layout = device
+            .create_pipeline_layout(Some(&*descriptor_layout), &[])
+            .unwrap();
+        device.state.created("GraphicsPipeline");
+
+        PipelineBundle::new(
+            device,
+            descriptor_layout,
+            Some(&descriptor_allocator),
+            pipeline_layout,
+            MockHandle,
+        )
+    }
+}
+
+/// A compute pipeline bundle with a storage buffer at binding 0 and no descriptor allocator.
+pub fn compute_bundle(device: &Arc<DeviceContext<Mock>>) -> PipelineBundle<Mock> {
+    use gfx_hal::Device;
+    let descriptor_layout = DescriptorLayoutBuilder::new()
+        .binding(
+            0,
+            pso::DescriptorType::StorageBuffer,
+            1,
+            pso::ShaderStageFlags::COMPUTE,
+        )
+        .build(device)
+        .unwrap();
+    unsafe {
+        let pipeline_layout = device
+            .create_pipeline_layout(Some(&*descriptor_layout), &[])
+            .unwrap();
+        device.state.created("ComputePipeline");
+
+        PipelineBundle::new_compute(device, descriptor_layout, None, pipeline_layout, MockHandle)
+    }
+}
@@ -0,0 +1,55 @@
+use super::{HalResultExt, RenderError};
+use gfx_hal::{
+    command::{CommandBuffer, OneShot},
+    device::Device,
+    pool::CommandPool,
+    Backend, Capability, CommandQueue,
+};
+
+/// Records a single command buffer with `record`, submits it and blocks until
+/// the GPU has finished with it. This is how we get data onto the GPU for
+/// textures and device-local buffers alike.
+///
+/// # Safety
+///
+/// `command_pool` and `command_queue` have to belong to `device`, and whatever
+/// `record` records has to be valid to run on it.
+pub unsafe fn submit_one_shot<B: Backend, C: Capability, F>(
+    device: &B::Device,
+    command_pool: &mut CommandPool<B, C>,
+    command_queue: &mut CommandQueue<B, C>,
+    record: F,
+) -> Result<(), failure::Error>
+where
+    F: FnOnce(&mut CommandBuffer<B, C, OneShot>),
+{
+    let mut cmd_buffer = command_pool.acquire_command_buffer::<OneShot>();
+    cmd_buffer.begin();
+
+    record(&mut cmd_buffer);
+
+    //  Aaand we're done!
+    cmd_buffer.finish();
+
+    let upload_fence = match device.create_fence(false) {
+        Ok(fence) => fence,
+        Err(e) => {
+            command_pool.free(Some(cmd_buffer));
+            return Err(RenderError::from_hal("Fence", None, e).into());
+        }
+    };
+
+    // Submit it!
+    command_queue.submit_without_semaphores(Some(&cmd_buffer), Some(&upload_fence));
+
+    let wait_result = device
+        .wait_for_fence(&upload_fence, u64::MAX)
+        .making("Fence", None);
+    device.destroy_fence(upload_fence);
+
+    //  Free our cmd_buffer!
+    command_pool.free(Some(cmd_buffer));
+
+    wait_result?;
+    Ok(())
+}
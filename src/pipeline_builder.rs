@@ -0,0 +1,530 @@
+use super::{
+    DescriptorLayout, DeviceContext, HalResultExt, PipelineBundle, PipelineCache,
+    PipelineReflection, RenderError, ShaderReflection, SharedDescriptorAllocator,
+};
+use gfx_hal::{
+    device::Device,
+    format::Format,
+    pass::Subpass,
+    pso::{
+        AttributeDesc, BakedStates, BlendDesc, ColorBlendDesc, DepthStencilDesc, DepthTest,
+        Element, EntryPoint, GraphicsPipelineDesc, GraphicsShaderSet, InputAssemblerDesc,
+        Multisampling, Rasterizer, ShaderStageFlags, Specialization, VertexBufferDesc,
+        VertexInputRate,
+    },
+    Backend, Primitive,
+};
+use std::{ops::Range, sync::Arc};
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+/// Everything that goes into a graphics pipeline, filled in one piece at a time.
+/// Anything left alone gets the same defaults as `GraphicsPipelineDesc::new`:
+/// no culling, no blending, no depth test, and a dynamic viewport and scissor.
+///
+/// ```ignore
+/// let pipeline_bundle = GraphicsPipelineBuilder::new(subpass, &vertex_spirv)
+///     .fragment(&fragment_spirv)
+///     .vertex_buffer(mem::size_of::<Vertex>() as u32, VertexInputRate::Vertex)
+///     .attribute(0, 0, Format::Rgb32Sfloat, 0)
+///     .attribute(1, 0, Format::Rg32Sfloat, 12)
+///     .push_constants(ShaderStageFlags::VERTEX, 0..16)
+///     .build(&device, descriptor_layout, Some(&descriptor_allocator))?;
+/// ```
+pub struct GraphicsPipelineBuilder<'a, B: Backend> {
+    subpass: Subpass<'a, B>,
+    vertex_spirv: &'a [u32],
+    fragment_spirv: Option<&'a [u32]>,
+    entry: &'a str,
+    vertex_buffers: Vec<VertexBufferDesc>,
+    attributes: Vec<AttributeDesc>,
+    input_assembler: InputAssemblerDesc,
+    rasterizer: Rasterizer,
+    blender: BlendDesc,
+    depth_stencil: DepthStencilDesc,
+    multisampling: Option<Multisampling>,
+    baked_states: BakedStates,
+    push_constants: Vec<(ShaderStageFlags, Range<u32>)>,
+    cache: Option<&'a PipelineCache<B>>,
+}
+
+impl<'a, B: Backend> GraphicsPipelineBuilder<'a, B> {
+    /// Starts a pipeline for `subpass` of a render pass, running the vertex shader in
+    /// `vertex_spirv`. Every shader's entry point is `main` unless `entry` says otherwise.
+    pub fn new(subpass: Subpass<'a, B>, vertex_spirv: &'a [u32]) -> Self {
+        GraphicsPipelineBuilder {
+            subpass,
+            vertex_spirv,
+            fragment_spirv: None,
+            entry: "main",
+            vertex_buffers: Vec::new(),
+            attributes: Vec::new(),
+            input_assembler: InputAssemblerDesc::new(Primitive::TriangleList),
+            rasterizer: Rasterizer::FILL,
+            blender: BlendDesc::default(),
+            depth_stencil: DepthStencilDesc::default(),
+            multisampling: None,
+            baked_states: BakedStates::default(),
+            push_constants: Vec::new(),
+            cache: None,
+        }
+    }
+
+    pub fn fragment(mut self, fragment_spirv: &'a [u32]) -> Self {
+        self.fragment_spirv = Some(fragment_spirv);
+        self
+    }
+
+    pub fn entry(mut self, entry: &'a str) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    /// Declares the next vertex buffer binding, starting from 0.
+    pub fn vertex_buffer(mut self, stride: u32, rate: VertexInputRate) -> Self {
+        self.vertex_buffers.push(VertexBufferDesc {
+            binding: self.vertex_buffers.len() as u32,
+            stride,
+            rate,
+        });
+        self
+    }
+
+    /// Reads `location` in the vertex shader from `offset` bytes into each element
+    /// of vertex buffer `binding`.
+    pub fn attribute(mut self, location: u32, binding: u32, format: Format, offset: u32) -> Self {
+        self.attributes.push(AttributeDesc {
+            location,
+            binding,
+            element: Element { format, offset },
+        });
+        self
+    }
+
+    pub fn primitive(mut self, primitive: Primitive) -> Self {
+        self.input_assembler = InputAssemblerDesc::new(primitive);
+        self
+    }
+
+    pub fn rasterizer(mut self, rasterizer: Rasterizer) -> Self {
+        self.rasterizer = rasterizer;
+        self
+    }
+
+    /// Adds the blend state of the next color attachment in the subpass. If there's a
+    /// fragment shader and we never add one, the pipeline writes a single attachment
+    /// without blending.
+    pub fn blend_target(mut self, target: ColorBlendDesc) -> Self {
+        self.blender.targets.push(target);
+        self
+    }
+
+    pub fn depth_test(mut self, depth: DepthTest) -> Self {
+        self.depth_stencil.depth = Some(depth);
+        self
+    }
+
+    pub fn depth_stencil(mut self, depth_stencil: DepthStencilDesc) -> Self {
+        self.depth_stencil = depth_stencil;
+        self
+    }
+
+    pub fn multisampling(mut self, multisampling: Multisampling) -> Self {
+        self.multisampling = Some(multisampling);
+        self
+    }
+
+    /// Bakes in whichever of the viewport, scissor, blend color and depth bounds are
+    /// `Some`. The rest stay dynamic and have to be set on the command buffer.
+    pub fn baked_states(mut self, baked_states: BakedStates) -> Self {
+        self.baked_states = baked_states;
+        self
+    }
+
+    /// Adds a push constant block to `stages`. Like everywhere else in gfx-hal, the
+    /// range counts 32-bit words, not bytes.
+    pub fn push_constants(mut self, stages: ShaderStageFlags, range: Range<u32>) -> Self {
+        self.push_constants.push((stages, range));
+        self
+    }
+
+    /// Looks the pipeline up in `cache`, and adds it there if it's not in yet.
+    pub fn cache(mut self, cache: &'a PipelineCache<B>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Makes the shader modules, the pipeline layout and the pipeline, and bundles
+    /// them up with `descriptor_layout`. The shader modules are thrown away again
+    /// once the pipeline's made.
+    pub fn build(
+        mut self,
+        device: &Arc<DeviceContext<B>>,
+        descriptor_layout: DescriptorLayout<B>,
+        descriptor_allocator: Option<&SharedDescriptorAllocator<B>>,
+    ) -> Result<PipelineBundle<B>, failure::Error> {
+        self.validate()?;
+        if self.fragment_spirv.is_some() && self.blender.targets.is_empty() {
+            self.blender.targets.push(ColorBlendDesc::EMPTY);
+        }
+
+        unsafe {
+            let vertex_module = device
+                .create_shader_module(self.vertex_spirv)
+                .making("ShaderModule", None)?;
+            let fragment_module = match self
+                .fragment_spirv
+                .map(|spirv| device.create_shader_module(spirv))
+                .transpose()
+            {
+                Ok(module) => module,
+                Err(e) => {
+                    device.destroy_shader_module(vertex_module);
+                    return Err(RenderError::from_hal("ShaderModule", None, e).into());
+                }
+            };
+
+            let created = self.create_pipeline(
+                device,
+                &descriptor_layout,
+                &vertex_module,
+                fragment_module.as_ref(),
+            );
+
+            //  The pipeline has everything it needs from the modules by now
+            device.destroy_shader_module(vertex_module);
+            if let Some(fragment_module) = fragment_module {
+                device.destroy_shader_module(fragment_module);
+            }
+
+            let (pipeline_layout, graphics_pipeline) = created?;
+            Ok(PipelineBundle::new(
+                device,
+                descriptor_layout,
+                descriptor_allocator,
+                pipeline_layout,
+                graphics_pipeline,
+            )
+            .with_push_constants(self.push_constants))
+        }
+    }
+
+    /// Like `build`, but works out the descriptor layout and push constants from
+    /// the shaders, so they can't disagree with them. If no vertex buffers were
+    /// declared, the vertex inputs are packed into buffer 0 in location order.
+    /// Otherwise every input has to be fed by an attribute of the same format.
+    pub fn build_reflected(
+        mut self,
+        device: &Arc<DeviceContext<B>>,
+        descriptor_allocator: Option<&SharedDescriptorAllocator<B>>,
+    ) -> Result<PipelineBundle<B>, failure::Error> {
+        self.validate()?;
+        let mut stages = vec![ShaderReflection::new(self.vertex_spirv, self.entry)?];
+        if let Some(fragment_spirv) = self.fragment_spirv {
+            stages.push(ShaderReflection::new(fragment_spirv, self.entry)?);
+        }
+        let reflection = PipelineReflection::new(&stages)?;
+
+        if self.vertex_buffers.is_empty() && self.attributes.is_empty() {
+            let mut offset = 0;
+            for input in &reflection.vertex_inputs {
+                self = self.attribute(input.location, 0, input.format, offset);
+                offset += input.size;
+            }
+            if offset > 0 {
+                self = self.vertex_buffer(offset, VertexInputRate::Vertex);
+            }
+        } else {
+            for input in &reflection.vertex_inputs {
+                match self
+                    .attributes
+                    .iter()
+                    .find(|a| a.location == input.location)
+                {
+                    Some(attribute) if attribute.element.format == input.format => {}
+                    Some(attribute) => bail_invalid!(
+                        "GraphicsPipeline",
+                        "The vertex shader reads location {} as {:?}, but the attribute is {:?}!",
+                        input.location,
+                        input.format,
+                        attribute.element.format
+                    ),
+                    None => bail_invalid!(
+                        "GraphicsPipeline",
+                        "The vertex shader reads location {}, but no attribute feeds it!",
+                        input.location
+                    ),
+                }
+            }
+        }
+
+        self.push_constants = reflection.push_constants.clone();
+        let descriptor_layout = reflection.descriptor_layout()?.build(device)?;
+        self.build(device, descriptor_layout, descriptor_allocator)
+    }
+
+    fn validate(&self) -> Result<(), failure::Error> {
+        let shaders = Some(("vertex", self.vertex_spirv))
+            .into_iter()
+            .chain(self.fragment_spirv.map(|spirv| ("fragment", spirv)));
+        for (stage, spirv) in shaders {
+            if spirv.first() != Some(&SPIRV_MAGIC) {
+                bail_invalid!("ShaderModule", "The {} shader isn't SPIR-V!", stage);
+            }
+        }
+
+        for attribute in &self.attributes {
+            if attribute.binding as usize >= self.vertex_buffers.len() {
+                bail_invalid!(
+                    "GraphicsPipeline",
+                    "Attribute {} reads from vertex buffer {}, but only {} were declared!",
+                    attribute.location,
+                    attribute.binding,
+                    self.vertex_buffers.len()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the pipeline layout and then the pipeline, giving the layout back if
+    /// the pipeline can't be made.
+    unsafe fn create_pipeline(
+        &self,
+        device: &B::Device,
+        descriptor_layout: &DescriptorLayout<B>,
+        vertex_module: &B::ShaderModule,
+        fragment_module: Option<&B::ShaderModule>,
+    ) -> Result<(B::PipelineLayout, B::GraphicsPipeline), failure::Error> {
+        let pipeline_layout = device
+            .create_pipeline_layout(Some(&**descriptor_layout), &self.push_constants)
+            .making("PipelineLayout", None)?;
+
+        let shaders = GraphicsShaderSet {
+            vertex: self.entry_point(vertex_module),
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: fragment_module.map(|module| self.entry_point(module)),
+        };
+        let desc = GraphicsPipelineDesc {
+            vertex_buffers: self.vertex_buffers.clone(),
+            attributes: self.attributes.clone(),
+            input_assembler: self.input_assembler.clone(),
+            blender: self.blender.clone(),
+            depth_stencil: self.depth_stencil,
+            multisampling: self.multisampling.clone(),
+            baked_states: self.baked_states.clone(),
+            ..GraphicsPipelineDesc::new(
+                shaders,
+                Primitive::TriangleList,
+                self.rasterizer,
+                &pipeline_layout,
+                Subpass {
+                    index: self.subpass.index,
+                    main_pass: self.subpass.main_pass,
+                },
+            )
+        };
+
+        match device.create_graphics_pipeline(&desc, self.cache.map(|cache| &**cache)) {
+            Ok(pipeline) => Ok((pipeline_layout, pipeline)),
+            Err(e) => {
+                device.destroy_pipeline_layout(pipeline_layout);
+                Err(RenderError::from_hal("GraphicsPipeline", None, e).into())
+            }
+        }
+    }
+
+    fn entry_point<'m>(&'m self, module: &'m B::ShaderModule) -> EntryPoint<'m, B> {
+        EntryPoint {
+            entry: self.entry,
+            module,
+            specialization: Specialization::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GraphicsPipelineBuilder;
+    use crate::{
+        mock_backend::{self, Mock, MockHandle},
+        shader_reflection, DescriptorLayout, DescriptorLayoutBuilder, DeviceContext, ErrorKind,
+        RenderError,
+    };
+    use gfx_hal::{
+        format::Format,
+        pass::Subpass,
+        pso::{DescriptorType, ShaderStageFlags, VertexInputRate},
+    };
+    use std::sync::Arc;
+
+    const SPIRV: &[u32] = &[super::SPIRV_MAGIC, 0x0001_0000, 0, 1, 0];
+
+    fn descriptor_layout(device: &Arc<DeviceContext<Mock>>) -> DescriptorLayout<Mock> {
+        DescriptorLayoutBuilder::new()
+            .binding(
+                0,
+                DescriptorType::CombinedImageSampler,
+                1,
+                ShaderStageFlags::FRAGMENT,
+            )
+            .build(device)
+            .unwrap()
+    }
+
+    fn builder(render_pass: &MockHandle) -> GraphicsPipelineBuilder<'_, Mock> {
+        GraphicsPipelineBuilder::new(
+            Subpass {
+                index: 0,
+                main_pass: render_pass,
+            },
+            SPIRV,
+        )
+        .fragment(SPIRV)
+        .vertex_buffer(20, VertexInputRate::Vertex)
+        .attribute(0, 0, Format::Rgb32Sfloat, 0)
+        .attribute(1, 0, Format::Rg32Sfloat, 12)
+        .push_constants(ShaderStageFlags::VERTEX, 0..4)
+    }
+
+    #[test]
+    fn builds_a_bundle_and_throws_the_modules_away() {
+        let device = mock_backend::device();
+        let pipeline_bundle = builder(&MockHandle)
+            .build(&device, descriptor_layout(&device), None)
+            .unwrap();
+
+        assert!(pipeline_bundle.graphics_pipeline().is_some());
+        assert_eq!(
+            device.live_objects(),
+            vec![
+                ("DescriptorSetLayout", 1),
+                ("GraphicsPipeline", 1),
+                ("PipelineLayout", 1)
+            ]
+        );
+
+        drop(pipeline_bundle);
+        assert!(device.live_objects().is_empty());
+        assert!(device.live_resources().is_empty());
+    }
+
+    #[test]
+    fn build_unwinds_from_every_failure() {
+        let faults: &[(&'static str, usize)] = &[
+            ("create_shader_module", 0),
+            ("create_shader_module", 1),
+            ("create_pipeline_layout", 0),
+            ("create_graphics_pipeline", 0),
+        ];
+
+        for &(call, skip) in faults {
+            let device = mock_backend::device();
+            let descriptor_layout = descriptor_layout(&device);
+            device.inject_fault(call, skip);
+
+            let pipeline_bundle = builder(&MockHandle).build(&device, descriptor_layout, None);
+
+            let error = match pipeline_bundle {
+                Ok(_) => panic!("{} #{} didn't fail", call, skip),
+                Err(error) => error,
+            };
+            assert!(error.downcast_ref::<RenderError>().is_some());
+            if call == "create_shader_module" {
+                assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+            }
+            assert!(
+                device.live_objects().is_empty(),
+                "{} #{} leaked",
+                call,
+                skip
+            );
+            assert!(
+                device.live_resources().is_empty(),
+                "{} #{} leaked",
+                call,
+                skip
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_shaders_and_attributes() {
+        let device = mock_backend::device();
+        let not_spirv = GraphicsPipelineBuilder::new(
+            Subpass {
+                index: 0,
+                main_pass: &MockHandle,
+            },
+            &[0xDEAD_BEEF],
+        )
+        .build(&device, descriptor_layout(&device), None);
+        let stray_attribute = builder(&MockHandle)
+            .attribute(2, 1, Format::Rgba8Unorm, 0)
+            .build(&device, descriptor_layout(&device), None);
+
+        for result in [not_spirv, stray_attribute] {
+            match result {
+                Ok(_) => panic!("A bad pipeline was built"),
+                Err(error) => assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument),
+            }
+        }
+        assert!(device.live_objects().is_empty());
+    }
+
+    #[test]
+    fn build_reflected_takes_the_layout_from_the_shaders() {
+        let device = mock_backend::device();
+        let vertex = shader_reflection::tests::vertex_shader();
+        let fragment = shader_reflection::tests::fragment_shader(1, false);
+
+        let pipeline_bundle = GraphicsPipelineBuilder::new(
+            Subpass {
+                index: 0,
+                main_pass: &MockHandle,
+            },
+            &vertex,
+        )
+        .fragment(&fragment)
+        .build_reflected(&device, None)
+        .unwrap();
+
+        let bindings: Vec<_> = pipeline_bundle
+            .descriptor_layout
+            .bindings
+            .iter()
+            .map(|b| (b.binding, b.ty))
+            .collect();
+        assert_eq!(
+            bindings,
+            vec![
+                (0, DescriptorType::UniformBuffer),
+                (1, DescriptorType::CombinedImageSampler)
+            ]
+        );
+    }
+
+    #[test]
+    fn build_reflected_checks_the_declared_attributes() {
+        let device = mock_backend::device();
+        let vertex = shader_reflection::tests::vertex_shader();
+
+        let pipeline_bundle = GraphicsPipelineBuilder::new(
+            Subpass {
+                index: 0,
+                main_pass: &MockHandle,
+            },
+            &vertex,
+        )
+        .vertex_buffer(20, VertexInputRate::Vertex)
+        .attribute(0, 0, Format::Rg32Sfloat, 0)
+        .attribute(1, 0, Format::Rg32Sfloat, 12)
+        .build_reflected(&device, None);
+
+        assert!(pipeline_bundle.is_err());
+        assert!(device.live_objects().is_empty());
+    }
+}
@@ -1,52 +1,512 @@
+use super::{
+    submit_one_shot, BufferBundle, DescriptorLayout, DeviceContext, LayoutId, PooledDescriptorSet,
+    SharedDescriptorAllocator,
+};
 use core::mem::ManuallyDrop;
-use gfx_hal::{device::Device, Backend, DescriptorPool};
+use gfx_hal::{
+    buffer,
+    command::{CommandBuffer, DescriptorSetOffset, Level, Shot},
+    device::Device,
+    image,
+    memory::{Barrier, Dependencies},
+    pool::CommandPool,
+    pso::{
+        Descriptor, DescriptorSetLayoutBinding, DescriptorSetWrite, DescriptorType, PipelineStage,
+        ShaderStageFlags,
+    },
+    Backend, Capability, CommandQueue, Compute, Supports, Transfer, WorkGroupCount,
+};
+use std::{ops::Range, path::PathBuf, sync::Arc};
+
+pub enum Pipeline<B: Backend> {
+    Graphics(B::GraphicsPipeline),
+    Compute(B::ComputePipeline),
+}
 
 pub struct PipelineBundle<B: Backend> {
-    pub descriptor_set_layout: Option<<B as Backend>::DescriptorSetLayout>,
-    pub descriptor_pool: Option<B::DescriptorPool>,
+    pub descriptor_layout: DescriptorLayout<B>,
+    pub descriptor_allocator: Option<SharedDescriptorAllocator<B>>,
+    pub layout_id: Option<LayoutId>,
     pub pipeline_layout: ManuallyDrop<<B as Backend>::PipelineLayout>,
-    pub graphics_pipeline: ManuallyDrop<<B as Backend>::GraphicsPipeline>,
+    pub pipeline: ManuallyDrop<Pipeline<B>>,
+    /// The push-constant ranges the pipeline layout was made with, in words.
+    pub push_constants: Vec<(ShaderStageFlags, Range<u32>)>,
+    /// Where the shaders came from, for a `PipelineWatcher` to keep an eye on.
+    pub shader_sources: Vec<PathBuf>,
+    pub device: Arc<DeviceContext<B>>,
 }
 
 impl<B: Backend> PipelineBundle<B> {
     pub fn new(
-        descriptor_set_layout: B::DescriptorSetLayout,
-        descriptor_pool: Option<B::DescriptorPool>,
+        device: &Arc<DeviceContext<B>>,
+        descriptor_layout: DescriptorLayout<B>,
+        descriptor_allocator: Option<&SharedDescriptorAllocator<B>>,
         pipeline_layout: B::PipelineLayout,
         graphics_pipeline: B::GraphicsPipeline,
     ) -> Self {
+        PipelineBundle::with_pipeline(
+            device,
+            descriptor_layout,
+            descriptor_allocator,
+            pipeline_layout,
+            Pipeline::Graphics(graphics_pipeline),
+        )
+    }
+
+    pub fn new_compute(
+        device: &Arc<DeviceContext<B>>,
+        descriptor_layout: DescriptorLayout<B>,
+        descriptor_allocator: Option<&SharedDescriptorAllocator<B>>,
+        pipeline_layout: B::PipelineLayout,
+        compute_pipeline: B::ComputePipeline,
+    ) -> Self {
+        PipelineBundle::with_pipeline(
+            device,
+            descriptor_layout,
+            descriptor_allocator,
+            pipeline_layout,
+            Pipeline::Compute(compute_pipeline),
+        )
+    }
+
+    fn with_pipeline(
+        device: &Arc<DeviceContext<B>>,
+        descriptor_layout: DescriptorLayout<B>,
+        descriptor_allocator: Option<&SharedDescriptorAllocator<B>>,
+        pipeline_layout: B::PipelineLayout,
+        pipeline: Pipeline<B>,
+    ) -> Self {
+        let layout_id = descriptor_allocator.map(|allocator| {
+            allocator
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .register_layout()
+        });
+
+        device.track("PipelineBundle");
         PipelineBundle {
-            descriptor_set_layout: Some(descriptor_set_layout),
+            descriptor_layout,
             pipeline_layout: manual_new!(pipeline_layout),
-            descriptor_pool,
-            graphics_pipeline: manual_new!(graphics_pipeline),
+            descriptor_allocator: descriptor_allocator.cloned(),
+            layout_id,
+            pipeline: manual_new!(pipeline),
+            push_constants: Vec::new(),
+            shader_sources: Vec::new(),
+            device: Arc::clone(device),
         }
     }
 
-    pub fn allocate_descriptor_set(&mut self) -> Result<B::DescriptorSet, failure::Error> {
-        match &mut self.descriptor_pool {
-            Some(dp) => unsafe {
-                if let Some(descriptor_set_layout) = &self.descriptor_set_layout {
-                    dp.allocate_set(descriptor_set_layout)
-                        .map_err(|e| format_err!("Couldn't allocate a descriptor set! => {}", e))
-                } else {
-                    bail!("Couldn't find the descriptor layout!")
-                }
-            },
+    /// Remembers the files the shaders were built from, in the order the
+    /// pipeline's stages use them.
+    pub fn with_shader_sources<P: Into<PathBuf>>(
+        mut self,
+        paths: impl IntoIterator<Item = P>,
+    ) -> Self {
+        self.shader_sources = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Remembers the push-constant ranges `pipeline_layout` was made with, so
+    /// pushes can go where the shader expects them.
+    pub fn with_push_constants(mut self, ranges: Vec<(ShaderStageFlags, Range<u32>)>) -> Self {
+        self.push_constants = ranges;
+        self
+    }
 
-            None => bail!(
-                "No descriptor pool has been created, but attempting to allocate a descriptor set!
-                Please make a descriptor pool first!",
+    pub fn graphics_pipeline(&self) -> Option<&B::GraphicsPipeline> {
+        match &*self.pipeline {
+            Pipeline::Graphics(pipeline) => Some(pipeline),
+            Pipeline::Compute(_) => None,
+        }
+    }
+
+    pub fn compute_pipeline(&self) -> Option<&B::ComputePipeline> {
+        match &*self.pipeline {
+            Pipeline::Compute(pipeline) => Some(pipeline),
+            Pipeline::Graphics(_) => None,
+        }
+    }
+
+    pub fn allocate_descriptor_set(&mut self) -> Result<PooledDescriptorSet<B>, failure::Error> {
+        match (&self.descriptor_allocator, self.layout_id) {
+            (Some(allocator), Some(layout_id)) => {
+                PooledDescriptorSet::allocate(allocator, layout_id, &self.descriptor_layout)
+            }
+
+            _ => bail_invalid!(
+                "PipelineBundle",
+                "No descriptor allocator has been given, but attempting to allocate a descriptor set!
+                Please make a descriptor allocator first!",
             ),
         }
     }
 
-    pub unsafe fn manually_drop(self, device: &B::Device) {
+    /// The binding to put in a descriptor set layout for a storage buffer.
+    pub fn storage_buffer_binding(
+        binding: u32,
+        stage_flags: ShaderStageFlags,
+    ) -> DescriptorSetLayoutBinding {
+        DescriptorSetLayoutBinding {
+            binding,
+            ty: DescriptorType::StorageBuffer,
+            count: 1,
+            stage_flags,
+            immutable_samplers: false,
+        }
+    }
+
+    /// Points `binding` of `set` at the whole of `buffer_bundle`. The buffer needs
+    /// to have been made with `buffer::Usage::STORAGE`.
+    ///
+    /// # Safety
+    ///
+    /// `set` has to have been allocated on `device`, and mustn't be in use by
+    /// any command buffer the GPU hasn't finished with.
+    pub unsafe fn write_storage_buffer(
+        device: &B::Device,
+        set: &B::DescriptorSet,
+        binding: u32,
+        buffer_bundle: &BufferBundle<B>,
+    ) {
+        device.write_descriptor_sets(vec![DescriptorSetWrite {
+            set,
+            binding,
+            array_offset: 0,
+            descriptors: Some(Descriptor::Buffer(&*buffer_bundle.buffer, None..None)),
+        }]);
+    }
+
+    /// Records a dispatch of our compute pipeline into `cmd_buffer`, followed by
+    /// `barriers`, so anything after it sees what the shader wrote. Storage buffers
+    /// only need `Barrier::AllBuffers`, but a storage image the shader wrote into
+    /// needs a `Barrier::Image` into whatever layout gets read next. Push constants
+    /// go at the start of the layout's compute range; layout ranges count words,
+    /// but pushes are offset in bytes.
+    ///
+    /// # Safety
+    ///
+    /// `cmd_buffer` has to be recording, and the descriptor sets and everything
+    /// `barriers` names have to have been made on our device.
+    pub unsafe fn record_dispatch<C: Supports<Compute> + Supports<Transfer>, S: Shot, L: Level>(
+        &self,
+        cmd_buffer: &mut CommandBuffer<B, C, S, L>,
+        descriptor_sets: &[&B::DescriptorSet],
+        dynamic_offsets: &[DescriptorSetOffset],
+        push_constants: &[u32],
+        group_count: WorkGroupCount,
+        barriers: &[Barrier<'_, B>],
+    ) -> Result<(), failure::Error> {
+        let compute_pipeline = match self.compute_pipeline() {
+            Some(pipeline) => pipeline,
+            None => {
+                bail_invalid!(
+                    "PipelineBundle",
+                    "Can't dispatch a graphics pipeline! Make the bundle with `new_compute`."
+                )
+            }
+        };
+        let push_offset = if push_constants.is_empty() {
+            0
+        } else {
+            match self
+                .push_constants
+                .iter()
+                .find(|(stages, _)| stages.contains(ShaderStageFlags::COMPUTE))
+            {
+                Some((_, range)) if push_constants.len() as u32 <= range.end - range.start => {
+                    range.start * 4
+                }
+                Some((_, range)) => bail_invalid!(
+                    "PipelineBundle",
+                    "Pushed {} words of constants, but the compute range only holds {}!",
+                    push_constants.len(),
+                    range.end - range.start
+                ),
+                None => bail_invalid!(
+                    "PipelineBundle",
+                    "The pipeline has no compute push constants! Give the bundle `with_push_constants` first."
+                ),
+            }
+        };
+
+        cmd_buffer.bind_compute_pipeline(compute_pipeline);
+        if !descriptor_sets.is_empty() {
+            cmd_buffer.bind_compute_descriptor_sets(
+                &self.pipeline_layout,
+                0,
+                descriptor_sets.iter().cloned(),
+                dynamic_offsets,
+            );
+        }
+        if !push_constants.is_empty() {
+            cmd_buffer.push_compute_constants(&self.pipeline_layout, push_offset, push_constants);
+        }
+        cmd_buffer.dispatch(group_count);
+
+        //  Make the shader's writes visible to whoever reads them next
+        if !barriers.is_empty() {
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::COMPUTE_SHADER
+                    ..(PipelineStage::VERTEX_INPUT
+                        | PipelineStage::VERTEX_SHADER
+                        | PipelineStage::FRAGMENT_SHADER
+                        | PipelineStage::COMPUTE_SHADER
+                        | PipelineStage::TRANSFER),
+                Dependencies::empty(),
+                barriers,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Dispatches our compute pipeline on its own command buffer and waits for it
+    /// to finish, just like we do for texture uploads. Afterwards, everything the
+    /// shader wrote into buffers and images is visible, but images stay in the
+    /// layout they were in; use `record_dispatch` to move them into another one.
+    ///
+    /// # Safety
+    ///
+    /// `command_pool`, `command_queue` and the descriptor sets have to belong to
+    /// our device.
+    pub unsafe fn dispatch<C: Capability + Supports<Compute> + Supports<Transfer>>(
+        &self,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        descriptor_sets: &[&B::DescriptorSet],
+        dynamic_offsets: &[DescriptorSetOffset],
+        push_constants: &[u32],
+        group_count: WorkGroupCount,
+    ) -> Result<(), failure::Error> {
+        if self.compute_pipeline().is_none() {
+            bail_invalid!(
+                "PipelineBundle",
+                "Can't dispatch a graphics pipeline! Make the bundle with `new_compute`."
+            );
+        }
+
+        let mut recorded = Ok(());
+        submit_one_shot(
+            &self.device.device,
+            command_pool,
+            command_queue,
+            |cmd_buffer| {
+                recorded = self.record_dispatch(
+                    cmd_buffer,
+                    descriptor_sets,
+                    dynamic_offsets,
+                    push_constants,
+                    group_count,
+                    &[
+                        Barrier::AllBuffers(
+                            buffer::Access::SHADER_WRITE..buffer::Access::MEMORY_READ,
+                        ),
+                        Barrier::AllImages(image::Access::SHADER_WRITE..image::Access::MEMORY_READ),
+                    ],
+                );
+            },
+        )?;
+
+        recorded
+    }
+}
+
+impl<B: Backend> Drop for PipelineBundle<B> {
+    fn drop(&mut self) {
         use core::ptr::read;
-        if let Some(this_layout) = self.descriptor_set_layout {
-            device.destroy_descriptor_set_layout(this_layout);
+        if let (Some(allocator), Some(layout_id)) = (&self.descriptor_allocator, self.layout_id) {
+            allocator
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .unregister_layout(layout_id);
+        }
+        unsafe {
+            self.device
+                .destroy_pipeline_layout(manual_drop!(self.pipeline_layout));
+            match manual_drop!(self.pipeline) {
+                Pipeline::Graphics(pipeline) => self.device.destroy_graphics_pipeline(pipeline),
+                Pipeline::Compute(pipeline) => self.device.destroy_compute_pipeline(pipeline),
+            }
+        }
+        self.device.untrack("PipelineBundle");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mock_backend::{self, MockCommand},
+        submit_one_shot, BufferBundle, ErrorKind, LoadedImage,
+    };
+    use gfx_hal::{
+        buffer,
+        format::Aspects,
+        image::{self, Filter, Layout, SubresourceRange},
+        memory::Barrier,
+        pso::{PipelineStage, ShaderStageFlags},
+    };
+
+    #[test]
+    fn dispatch_is_followed_by_a_barrier_for_storage_buffer_writes() {
+        let device = mock_backend::device();
+        let bundle = mock_backend::compute_bundle(&device);
+        unsafe {
+            bundle
+                .dispatch(
+                    &mut mock_backend::general_command_pool(&device),
+                    &mut mock_backend::general_command_queue(),
+                    &[],
+                    &[],
+                    &[],
+                    [8, 4, 1],
+                )
+                .unwrap();
         }
-        device.destroy_pipeline_layout(manual_drop!(self.pipeline_layout));
-        device.destroy_graphics_pipeline(manual_drop!(self.graphics_pipeline));
+
+        match device.take_commands().as_slice() {
+            [MockCommand::Dispatch([8, 4, 1]), MockCommand::AllBuffersBarrier { stages, access }, MockCommand::AllImagesBarrier {
+                access: image_access,
+                ..
+            }] => {
+                assert_eq!(stages.start, PipelineStage::COMPUTE_SHADER);
+                assert!(stages.end.contains(PipelineStage::VERTEX_INPUT));
+                assert_eq!(
+                    *access,
+                    buffer::Access::SHADER_WRITE..buffer::Access::MEMORY_READ
+                );
+                assert_eq!(
+                    *image_access,
+                    image::Access::SHADER_WRITE..image::Access::MEMORY_READ
+                );
+            }
+            other => panic!("Expected a dispatch and its barriers, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn record_dispatch_pushes_into_the_compute_range_and_ends_with_the_callers_barriers() {
+        let device = mock_backend::device();
+        let bundle = mock_backend::compute_bundle(&device).with_push_constants(vec![
+            (ShaderStageFlags::VERTEX, 0..2),
+            (ShaderStageFlags::COMPUTE, 2..4),
+        ]);
+        let target = LoadedImage::new(
+            &mock_backend::adapter(),
+            &device,
+            &mut mock_backend::command_pool(&device),
+            &mut mock_backend::command_queue(),
+            &[0; 4],
+            1,
+            1,
+            Filter::Nearest,
+        )
+        .unwrap();
+        device.take_commands();
+
+        let mut recorded = Ok(());
+        unsafe {
+            submit_one_shot(
+                &device.device,
+                &mut mock_backend::general_command_pool(&device),
+                &mut mock_backend::general_command_queue(),
+                |cmd_buffer| {
+                    recorded = bundle.record_dispatch(
+                        cmd_buffer,
+                        &[],
+                        &[],
+                        &[7, 8],
+                        [1, 1, 1],
+                        &[Barrier::Image {
+                            states: (image::Access::SHADER_WRITE, Layout::General)
+                                ..(image::Access::SHADER_READ, Layout::ShaderReadOnlyOptimal),
+                            target: &*target.image,
+                            families: None,
+                            range: SubresourceRange {
+                                aspects: Aspects::COLOR,
+                                levels: 0..1,
+                                layers: 0..1,
+                            },
+                        }],
+                    );
+                },
+            )
+            .unwrap();
+        }
+        recorded.unwrap();
+
+        match device.take_commands().as_slice() {
+            [MockCommand::PushConstants { offset, constants }, MockCommand::Dispatch(_), MockCommand::ImageBarrier { layouts, .. }] =>
+            {
+                assert_eq!((*offset, constants.as_slice()), (8, &[7, 8][..]));
+                assert_eq!(*layouts, Layout::General..Layout::ShaderReadOnlyOptimal);
+            }
+            other => panic!("Expected a push, a dispatch and a barrier, got {:?}", other),
+        }
+
+        //  Three words don't fit in a range of two
+        let mut recorded = Ok(());
+        unsafe {
+            submit_one_shot(
+                &device.device,
+                &mut mock_backend::general_command_pool(&device),
+                &mut mock_backend::general_command_queue(),
+                |cmd_buffer| {
+                    recorded =
+                        bundle.record_dispatch(cmd_buffer, &[], &[], &[1, 2, 3], [1, 1, 1], &[]);
+                },
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            ErrorKind::of(&recorded.unwrap_err()),
+            ErrorKind::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn dispatching_a_graphics_pipeline_submits_nothing() {
+        let device = mock_backend::device();
+        let bundle = mock_backend::pipeline_bundle(&device, 1);
+        let dispatched = unsafe {
+            bundle.dispatch(
+                &mut mock_backend::general_command_pool(&device),
+                &mut mock_backend::general_command_queue(),
+                &[],
+                &[],
+                &[],
+                [1, 1, 1],
+            )
+        };
+
+        assert_eq!(
+            ErrorKind::of(&dispatched.unwrap_err()),
+            ErrorKind::InvalidArgument
+        );
+        assert!(device.take_commands().is_empty());
+    }
+
+    #[test]
+    fn sets_need_an_allocator_and_dropping_gives_everything_back() {
+        let device = mock_backend::device();
+        let mut bundle = mock_backend::compute_bundle(&device);
+        let storage = BufferBundle::new(
+            &mock_backend::adapter(),
+            &device,
+            64,
+            buffer::Usage::STORAGE,
+            false,
+        )
+        .unwrap();
+
+        let error = match bundle.allocate_descriptor_set() {
+            Ok(_) => panic!("Allocated a set without an allocator!"),
+            Err(e) => e,
+        };
+        assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+
+        drop(storage);
+        drop(bundle);
+        assert!(device.live_objects().is_empty());
+        assert!(device.live_resources().is_empty());
     }
 }
@@ -0,0 +1,349 @@
+use super::{DeviceContext, HalResultExt};
+use core::mem::ManuallyDrop;
+use gfx_hal::{
+    adapter::{Adapter, AdapterInfo},
+    device::Device,
+    Backend,
+};
+use std::{
+    fs, io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// What we put in front of the driver's data, so we can tell our files apart
+/// from anything else and notice if one got cut short or scribbled on.
+const FILE_MAGIC: &[u8; 4] = b"PLC1";
+const FILE_HEADER_SIZE: usize = 12;
+/// The smallest header a Vulkan driver writes at the front of its cache data.
+const DRIVER_HEADER_SIZE: usize = 32;
+
+/// Where the data in a `PipelineCache` came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineCacheStatus {
+    /// The file was there and made by this GPU and driver.
+    Loaded,
+    /// There wasn't a file yet, so we started empty.
+    Missing,
+    /// There was a file, but we couldn't use it, so we started empty. Says why.
+    Rejected(String),
+}
+
+/// A pipeline cache that's read from a file at startup and written back with
+/// `save`, so pipelines don't get compiled from scratch every run. Pass it to
+/// `GraphicsPipelineBuilder::cache`, or deref it for `create_compute_pipeline`.
+///
+/// On disk, a cache is:
+///
+/// | bytes | what                                  |
+/// |-------|---------------------------------------|
+/// | 0..4  | `PLC1`                                |
+/// | 4..8  | length of the driver's data, LE `u32` |
+/// | 8..12 | FNV-1a hash of the driver's data      |
+/// | 12..  | the driver's data, header and all     |
+pub struct PipelineCache<B: Backend> {
+    pub cache: ManuallyDrop<B::PipelineCache>,
+    pub path: PathBuf,
+    pub status: PipelineCacheStatus,
+    pub device: Arc<DeviceContext<B>>,
+}
+
+impl<B: Backend> PipelineCache<B> {
+    /// Makes a cache out of the file at `path`. If the file's missing, damaged, or
+    /// came from another GPU or driver version, we start with an empty cache instead
+    /// and say why in `status`. Only failing to make a cache at all is an error.
+    pub fn load<P: AsRef<Path>>(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        path: P,
+    ) -> Result<Self, failure::Error> {
+        let path = path.as_ref().to_owned();
+        unsafe {
+            let empty = device
+                .create_pipeline_cache(None)
+                .making("PipelineCache", None)?;
+
+            //  An empty cache tells us which header this driver writes today
+            let expected = device
+                .get_pipeline_cache_data(&empty)
+                .ok()
+                .and_then(|data| DriverHeader::parse(&data));
+
+            let (cache, status) = match fs::read(&path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    (empty, PipelineCacheStatus::Missing)
+                }
+                Err(e) => (
+                    empty,
+                    PipelineCacheStatus::Rejected(format!("Couldn't read it => {}", e)),
+                ),
+                Ok(file) => match validate(&file, &adapter.info, expected.as_ref()) {
+                    Err(reason) => (empty, PipelineCacheStatus::Rejected(reason)),
+                    Ok(data) => match device.create_pipeline_cache(Some(data)) {
+                        Ok(cache) => {
+                            device.destroy_pipeline_cache(empty);
+                            (cache, PipelineCacheStatus::Loaded)
+                        }
+                        Err(e) => (
+                            empty,
+                            PipelineCacheStatus::Rejected(format!(
+                                "The driver wouldn't take it => {}",
+                                e
+                            )),
+                        ),
+                    },
+                },
+            };
+
+            device.track("PipelineCache");
+            Ok(PipelineCache {
+                cache: manual_new!(cache),
+                path,
+                status,
+                device: Arc::clone(device),
+            })
+        }
+    }
+
+    /// Writes everything the driver has cached so far back to our file. Call this
+    /// on shutdown, or whenever a batch of pipelines has been made.
+    pub fn save(&self) -> Result<(), failure::Error> {
+        let data = unsafe {
+            self.device
+                .get_pipeline_cache_data(&self.cache)
+                .making("PipelineCache", None)?
+        };
+
+        let mut file = Vec::with_capacity(FILE_HEADER_SIZE + data.len());
+        file.extend_from_slice(FILE_MAGIC);
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(&fnv1a(&data).to_le_bytes());
+        file.extend_from_slice(&data);
+
+        //  Write next to it and swap it in, so a crash part way through
+        //  doesn't leave a half written file behind
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, &file)?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+impl<B: Backend> Deref for PipelineCache<B> {
+    type Target = B::PipelineCache;
+
+    fn deref(&self) -> &B::PipelineCache {
+        &self.cache
+    }
+}
+
+impl<B: Backend> Drop for PipelineCache<B> {
+    fn drop(&mut self) {
+        use core::ptr::read;
+        unsafe {
+            self.device.destroy_pipeline_cache(manual_drop!(self.cache));
+        }
+        self.device.untrack("PipelineCache");
+    }
+}
+
+/// The part of `VkPipelineCacheHeaderVersionOne` we check. It's always little
+/// endian, whatever the host is.
+#[derive(Debug, PartialEq, Eq)]
+struct DriverHeader {
+    vendor: u32,
+    device: u32,
+    uuid: [u8; 16],
+}
+
+impl DriverHeader {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < DRIVER_HEADER_SIZE
+            || (read_u32(data, 0) as usize) < DRIVER_HEADER_SIZE
+            || read_u32(data, 4) != 1
+        {
+            return None;
+        }
+
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&data[16..32]);
+        Some(DriverHeader {
+            vendor: read_u32(data, 8),
+            device: read_u32(data, 12),
+            uuid,
+        })
+    }
+}
+
+/// Checks a whole file over and hands back the driver's data in it, or why we can't use it.
+fn validate<'a>(
+    file: &'a [u8],
+    info: &AdapterInfo,
+    expected: Option<&DriverHeader>,
+) -> Result<&'a [u8], String> {
+    if file.len() < FILE_HEADER_SIZE || &file[..4] != FILE_MAGIC {
+        return Err("It isn't a pipeline cache file".to_owned());
+    }
+    let data = &file[FILE_HEADER_SIZE..];
+    if read_u32(file, 4) as usize != data.len() {
+        return Err("It's been cut short".to_owned());
+    }
+    if read_u32(file, 8) != fnv1a(data) {
+        return Err("It's corrupted".to_owned());
+    }
+
+    let header = DriverHeader::parse(data).ok_or("The driver's header is missing")?;
+    if header.vendor as usize != info.vendor || header.device as usize != info.device {
+        return Err(format!(
+            "It was made on GPU {:04x}:{:04x}, but this is {:04x}:{:04x}",
+            header.vendor, header.device, info.vendor, info.device
+        ));
+    }
+    match expected {
+        Some(expected) if expected.uuid == header.uuid => Ok(data),
+        Some(_) => Err("It was made by a different driver version".to_owned()),
+        None => Err("This backend doesn't tell us which driver made it".to_owned()),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PipelineCache, PipelineCacheStatus, FILE_HEADER_SIZE};
+    use crate::mock_backend;
+    use std::{fs, path::PathBuf};
+
+    fn assert_rejected(status: &PipelineCacheStatus) {
+        assert_ne!(*status, PipelineCacheStatus::Loaded);
+        assert_ne!(*status, PipelineCacheStatus::Missing);
+    }
+
+    fn cache_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "pipeline_cache_{}_{}.bin",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Saves a cache with `body` after the driver's header, the way it would be
+    /// after a run which made some pipelines.
+    fn saved_cache(path: &PathBuf, body: &[u8]) {
+        let device = mock_backend::device();
+        let cache = PipelineCache::load(&mock_backend::adapter(), &device, path).unwrap();
+        cache.save().unwrap();
+
+        let mut file = fs::read(path).unwrap();
+        file.extend_from_slice(body);
+        let data_len = (file.len() - FILE_HEADER_SIZE) as u32;
+        file[4..8].copy_from_slice(&data_len.to_le_bytes());
+        let hash = super::fnv1a(&file[FILE_HEADER_SIZE..]);
+        file[8..12].copy_from_slice(&hash.to_le_bytes());
+        fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn starts_empty_and_round_trips_through_the_file() {
+        let path = cache_path("round_trip");
+        let device = mock_backend::device();
+        let adapter = mock_backend::adapter();
+
+        let cache = PipelineCache::load(&adapter, &device, &path).unwrap();
+        assert_eq!(cache.status, PipelineCacheStatus::Missing);
+        drop(cache);
+
+        saved_cache(&path, b"compiled pipelines");
+        let cache = PipelineCache::load(&adapter, &device, &path).unwrap();
+        assert_eq!(cache.status, PipelineCacheStatus::Loaded);
+        cache.save().unwrap();
+        assert!(fs::read(&path).unwrap().ends_with(b"compiled pipelines"));
+
+        drop(cache);
+        assert!(device.live_objects().is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn falls_back_when_the_driver_changed() {
+        let path = cache_path("new_driver");
+        saved_cache(&path, b"old pipelines");
+        let device = mock_backend::device();
+        device.set_pipeline_cache_uuid([7; 16]);
+
+        let cache = PipelineCache::load(&mock_backend::adapter(), &device, &path).unwrap();
+        assert_rejected(&cache.status);
+        cache.save().unwrap();
+        assert!(!fs::read(&path).unwrap().ends_with(b"old pipelines"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn falls_back_on_another_gpu() {
+        let path = cache_path("other_gpu");
+        saved_cache(&path, b"pipelines");
+        let device = mock_backend::device();
+        let mut adapter = mock_backend::adapter();
+        adapter.info.device += 1;
+
+        let cache = PipelineCache::load(&adapter, &device, &path).unwrap();
+        assert_rejected(&cache.status);
+        assert_eq!(
+            cache.status,
+            PipelineCacheStatus::Rejected(
+                "It was made on GPU 1ab5:0042, but this is 1ab5:0043".to_owned()
+            )
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn falls_back_on_a_damaged_file() {
+        let path = cache_path("damaged");
+        saved_cache(&path, b"pipelines");
+        let mut file = fs::read(&path).unwrap();
+        let last = file.len() - 1;
+        file[last] ^= 0xFF;
+        fs::write(&path, &file).unwrap();
+        let device = mock_backend::device();
+        let adapter = mock_backend::adapter();
+
+        let flipped = PipelineCache::load(&adapter, &device, &path).unwrap();
+        fs::write(&path, &file[..file.len() / 2]).unwrap();
+        let truncated = PipelineCache::load(&adapter, &device, &path).unwrap();
+        fs::write(&path, b"not a cache").unwrap();
+        let garbage = PipelineCache::load(&adapter, &device, &path).unwrap();
+
+        for cache in &[flipped, truncated, garbage] {
+            assert_rejected(&cache.status);
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn load_unwinds_if_the_driver_refuses() {
+        let path = cache_path("refused");
+        saved_cache(&path, b"pipelines");
+        let device = mock_backend::device();
+        device.inject_fault("create_pipeline_cache", 1);
+
+        let cache = PipelineCache::load(&mock_backend::adapter(), &device, &path).unwrap();
+        assert_rejected(&cache.status);
+        assert_eq!(device.live_objects(), vec![("PipelineCache", 1)]);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,394 @@
+use super::{PipelineBundle, RenderError, RetirementQueue};
+use gfx_hal::Backend;
+use std::{fs, path::Path, time::SystemTime};
+
+/// Makes a new pipeline out of freshly loaded shaders, one lot of SPIR-V per
+/// path in the old bundle's `shader_sources`, in the same order.
+pub type PipelineRebuild<B> =
+    Box<dyn FnMut(&[Vec<u32>]) -> Result<PipelineBundle<B>, failure::Error>>;
+
+/// What happened to a pipeline when its shaders changed.
+#[derive(Debug)]
+pub enum PipelineReload {
+    /// The new pipeline is in, and the old one is waiting on its frames.
+    Swapped(usize),
+    /// The old pipeline is still in, since the new one couldn't be made.
+    Failed(usize, failure::Error),
+}
+
+struct WatchedPipeline<B: Backend> {
+    bundle: PipelineBundle<B>,
+    rebuild: PipelineRebuild<B>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// Keeps an eye on the shader files behind some pipelines, and rebuilds a
+/// pipeline whenever one of its files changes.
+///
+/// Nothing happens behind our back: `poll` checks the files between frames and
+/// swaps new pipelines in there and then, so every frame uses one pipeline or
+/// the other and never half of each. The old pipeline lives on until
+/// `retire_frames` says the frames using it are done. If the new shaders don't
+/// load or the pipeline can't be made, the old one stays in and we try again the
+/// next time the files change.
+///
+/// `.spv` files are read as they are. Anything else is compiled as GLSL with
+/// the `shader-compiler` feature, with the stage taken from the extension
+/// (`.vert`, `.frag` or `.comp`). Only the files in `shader_sources` are
+/// watched, not whatever they `#include`.
+pub struct PipelineWatcher<B: Backend> {
+    pipelines: Vec<WatchedPipeline<B>>,
+    retired: RetirementQueue<PipelineBundle<B>>,
+}
+
+impl<B: Backend> PipelineWatcher<B> {
+    pub fn new() -> Self {
+        PipelineWatcher {
+            pipelines: Vec::new(),
+            retired: RetirementQueue::new(),
+        }
+    }
+
+    /// Starts watching `bundle`'s shader sources, rebuilding it with `rebuild`
+    /// when they change. Gives back the id to find the pipeline with.
+    pub fn watch(
+        &mut self,
+        bundle: PipelineBundle<B>,
+        rebuild: PipelineRebuild<B>,
+    ) -> Result<usize, failure::Error> {
+        if bundle.shader_sources.is_empty() {
+            bail_invalid!(
+                "PipelineWatcher",
+                "The pipeline doesn't know where its shaders came from! Give it `with_shader_sources` first."
+            );
+        }
+
+        let modified = bundle.shader_sources.iter().map(|p| modified(p)).collect();
+        self.pipelines.push(WatchedPipeline {
+            bundle,
+            rebuild,
+            modified,
+        });
+        Ok(self.pipelines.len() - 1)
+    }
+
+    /// The pipeline to draw with right now.
+    pub fn pipeline(&self, id: usize) -> Option<&PipelineBundle<B>> {
+        self.pipelines.get(id).map(|watched| &watched.bundle)
+    }
+
+    pub fn pipeline_mut(&mut self, id: usize) -> Option<&mut PipelineBundle<B>> {
+        self.pipelines
+            .get_mut(id)
+            .map(|watched| &mut watched.bundle)
+    }
+
+    /// Rebuilds every pipeline whose shaders have changed since we last looked.
+    /// Frames up to `last_used_frame` might still be using the old pipelines.
+    pub fn poll(&mut self, last_used_frame: u64) -> Vec<PipelineReload> {
+        let mut reloads = Vec::new();
+        for (id, watched) in self.pipelines.iter_mut().enumerate() {
+            let now: Vec<_> = watched
+                .bundle
+                .shader_sources
+                .iter()
+                .map(|p| modified(p))
+                .collect();
+
+            //  Editors often save by deleting and renaming, so a file that's
+            //  missing right now is probably about to come back
+            if now == watched.modified || now.iter().any(Option::is_none) {
+                continue;
+            }
+            watched.modified = now;
+
+            match watched.reload() {
+                Ok(old) => {
+                    self.retired.retire(last_used_frame, old);
+                    reloads.push(PipelineReload::Swapped(id));
+                }
+                Err(e) => reloads.push(PipelineReload::Failed(id, e)),
+            }
+        }
+
+        reloads
+    }
+
+    /// Destroys every old pipeline whose last frame is at or before `completed_frame`.
+    pub fn retire_frames(&mut self, completed_frame: u64) {
+        self.retired.drain_completed(completed_frame);
+    }
+}
+
+impl<B: Backend> Default for PipelineWatcher<B> {
+    fn default() -> Self {
+        PipelineWatcher::new()
+    }
+}
+
+impl<B: Backend> WatchedPipeline<B> {
+    /// Swaps in a pipeline made from the shaders as they are now, handing back
+    /// the old one.
+    fn reload(&mut self) -> Result<PipelineBundle<B>, failure::Error> {
+        let spirv = self
+            .bundle
+            .shader_sources
+            .iter()
+            .map(|path| load_spirv(path))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut bundle = (self.rebuild)(&spirv)?;
+        bundle.shader_sources = self.bundle.shader_sources.clone();
+        Ok(std::mem::replace(&mut self.bundle, bundle))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_spirv(path: &Path) -> Result<Vec<u32>, failure::Error> {
+    if path.extension().is_some_and(|ext| ext == "spv") {
+        let bytes = fs::read(path).map_err(|e| {
+            RenderError::load(
+                "Shader",
+                format!("Couldn't read {}! => {}", path.display(), e),
+            )
+        })?;
+        if bytes.len() % 4 != 0 {
+            bail_invalid!(
+                "Shader",
+                "{} isn't a whole number of SPIR-V words!",
+                path.display()
+            );
+        }
+
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect())
+    } else {
+        compile(path)
+    }
+}
+
+#[cfg(feature = "shader-compiler")]
+fn compile(path: &Path) -> Result<Vec<u32>, failure::Error> {
+    use super::{ShaderCompiler, ShaderDirectory, ShaderLanguage, ShaderStage};
+
+    let stage = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("vert") => ShaderStage::Vertex,
+        Some("frag") => ShaderStage::Fragment,
+        Some("comp") => ShaderStage::Compute,
+        _ => bail_invalid!(
+            "Shader",
+            "Can't tell which stage {} is for!",
+            path.display()
+        ),
+    };
+    let (root, file) = match (path.parent(), path.file_name().and_then(|f| f.to_str())) {
+        (Some(root), Some(file)) => (root, file),
+        _ => bail_invalid!("Shader", "{} isn't a shader file!", path.display()),
+    };
+
+    let compiler = ShaderCompiler::new(ShaderDirectory {
+        root: root.to_path_buf(),
+    });
+    Ok(compiler.compile(file, ShaderLanguage::Glsl, stage)?)
+}
+
+#[cfg(not(feature = "shader-compiler"))]
+fn compile(path: &Path) -> Result<Vec<u32>, failure::Error> {
+    Err(RenderError::unsupported(
+        "Shader",
+        format!(
+            "{} needs compiling, which needs the `shader-compiler` feature! Use a .spv file instead.",
+            path.display()
+        ),
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PipelineReload, PipelineWatcher};
+    use crate::{
+        mock_backend::{self, Mock, MockHandle},
+        DescriptorAllocator, DescriptorLayoutBuilder, DeviceContext, GraphicsPipelineBuilder,
+        PipelineBundle, SharedDescriptorAllocator,
+    };
+    use gfx_hal::{
+        pass::Subpass,
+        pso::{DescriptorType, ShaderStageFlags},
+    };
+    use std::{
+        env, fs,
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    const SPIRV: &[u32] = &[0x0723_0203, 0x0001_0000, 0, 1, 0];
+
+    fn build(
+        device: &Arc<DeviceContext<Mock>>,
+        spirv: &[u32],
+    ) -> Result<PipelineBundle<Mock>, failure::Error> {
+        build_with(device, spirv, None)
+    }
+
+    fn build_with(
+        device: &Arc<DeviceContext<Mock>>,
+        spirv: &[u32],
+        descriptor_allocator: Option<&SharedDescriptorAllocator<Mock>>,
+    ) -> Result<PipelineBundle<Mock>, failure::Error> {
+        let descriptor_layout = DescriptorLayoutBuilder::new()
+            .binding(
+                0,
+                DescriptorType::CombinedImageSampler,
+                1,
+                ShaderStageFlags::FRAGMENT,
+            )
+            .build(device)?;
+        GraphicsPipelineBuilder::new(
+            Subpass {
+                index: 0,
+                main_pass: &MockHandle,
+            },
+            spirv,
+        )
+        .build(device, descriptor_layout, descriptor_allocator)
+    }
+
+    /// Writes `words` to `path`, with a modification time `age` seconds after the epoch
+    /// so we don't depend on how fine-grained the file system's clock is.
+    fn write_spirv(path: &PathBuf, words: &[u32], age: u64) {
+        let bytes: Vec<u8> = words
+            .iter()
+            .flat_map(|w| w.to_le_bytes().to_vec())
+            .collect();
+        fs::write(path, bytes).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(age))
+            .unwrap();
+    }
+
+    fn watched(name: &str) -> (Arc<DeviceContext<Mock>>, PipelineWatcher<Mock>, PathBuf) {
+        let device = mock_backend::device();
+        let path = env::temp_dir().join(format!("pipeline_watcher_{}.spv", name));
+        write_spirv(&path, SPIRV, 1);
+
+        let mut watcher = PipelineWatcher::new();
+        let rebuild_device = Arc::clone(&device);
+        let bundle = build(&device, SPIRV)
+            .unwrap()
+            .with_shader_sources(vec![&path]);
+        watcher
+            .watch(
+                bundle,
+                Box::new(move |spirv| build(&rebuild_device, &spirv[0])),
+            )
+            .unwrap();
+        (device, watcher, path)
+    }
+
+    #[test]
+    fn swaps_in_the_new_pipeline_and_retires_the_old_one() {
+        let (device, mut watcher, path) = watched("swap");
+        assert!(watcher.poll(1).is_empty());
+
+        write_spirv(&path, SPIRV, 2);
+        match watcher.poll(1).as_slice() {
+            [PipelineReload::Swapped(0)] => {}
+            other => panic!("expected a swap, got {:?}", other),
+        }
+        assert_eq!(
+            watcher.pipeline(0).unwrap().shader_sources,
+            vec![path.clone()]
+        );
+        assert_eq!(
+            device.live_resources(),
+            vec![("DescriptorLayout", 2), ("PipelineBundle", 2)]
+        );
+
+        watcher.retire_frames(0);
+        assert_eq!(
+            device.live_resources(),
+            vec![("DescriptorLayout", 2), ("PipelineBundle", 2)]
+        );
+        watcher.retire_frames(1);
+        assert_eq!(
+            device.live_resources(),
+            vec![("DescriptorLayout", 1), ("PipelineBundle", 1)]
+        );
+
+        drop(watcher);
+        assert!(device.live_objects().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reloading_over_and_over_doesnt_pile_up_layouts() {
+        let device = mock_backend::device();
+        let allocator = DescriptorAllocator::new(&device, 4, &[]).unwrap();
+        let path = env::temp_dir().join("pipeline_watcher_layouts.spv");
+        write_spirv(&path, SPIRV, 1);
+
+        let mut watcher = PipelineWatcher::new();
+        let (rebuild_device, rebuild_allocator) = (Arc::clone(&device), Arc::clone(&allocator));
+        let bundle = build_with(&device, SPIRV, Some(&allocator))
+            .unwrap()
+            .with_shader_sources(vec![&path]);
+        watcher
+            .watch(
+                bundle,
+                Box::new(move |spirv| {
+                    build_with(&rebuild_device, &spirv[0], Some(&rebuild_allocator))
+                }),
+            )
+            .unwrap();
+
+        for frame in 1..20 {
+            write_spirv(&path, SPIRV, frame + 1);
+            assert_eq!(watcher.poll(frame).len(), 1);
+            watcher.retire_frames(frame);
+            assert_eq!(allocator.lock().unwrap().layout_count(), 1);
+        }
+
+        drop(watcher);
+        assert_eq!(allocator.lock().unwrap().layout_count(), 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_old_pipeline_when_the_new_one_fails() {
+        let (device, mut watcher, path) = watched("fail");
+        let old_pipeline = watcher.pipeline(0).unwrap() as *const PipelineBundle<Mock>;
+
+        //  Not SPIR-V at all
+        write_spirv(&path, &[1, 2, 3], 2);
+        match watcher.poll(1).as_slice() {
+            [PipelineReload::Failed(0, _)] => {}
+            other => panic!("expected a failure, got {:?}", other),
+        }
+        assert!(watcher.poll(1).is_empty());
+
+        //  Fine, but the device won't have it
+        device.inject_fault("create_graphics_pipeline", 0);
+        write_spirv(&path, SPIRV, 3);
+        match watcher.poll(1).as_slice() {
+            [PipelineReload::Failed(0, _)] => {}
+            other => panic!("expected a failure, got {:?}", other),
+        }
+
+        assert_eq!(watcher.pipeline(0).unwrap() as *const _, old_pipeline);
+        assert_eq!(
+            device.live_resources(),
+            vec![("DescriptorLayout", 1), ("PipelineBundle", 1)]
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
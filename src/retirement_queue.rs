@@ -0,0 +1,88 @@
+/// Holds onto things the GPU might still be reading until the frame that last
+/// used them has finished. Frames are just an ever-increasing counter the
+/// renderer owns; we don't care what they mean as long as they go up.
+pub struct RetirementQueue<T> {
+    pending: Vec<(u64, T)>,
+}
+
+impl<T> RetirementQueue<T> {
+    pub fn new() -> Self {
+        RetirementQueue {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Queues `item` to be handed back once `last_used_frame` has completed.
+    pub fn retire(&mut self, last_used_frame: u64, item: T) {
+        self.pending.push((last_used_frame, item));
+    }
+
+    /// Hands back everything whose last frame is at or before `completed_frame`.
+    pub fn drain_completed(&mut self, completed_frame: u64) -> Vec<T> {
+        let mut completed = Vec::new();
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].0 <= completed_frame {
+                completed.push(self.pending.swap_remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+
+        completed
+    }
+
+    /// Hands back everything, no matter which frame it was waiting on. Only do
+    /// this once the device is idle!
+    pub fn drain_all(&mut self) -> Vec<T> {
+        self.pending.drain(..).map(|(_, item)| item).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl<T> Default for RetirementQueue<T> {
+    fn default() -> Self {
+        RetirementQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetirementQueue;
+
+    #[test]
+    fn items_come_back_once_their_frame_is_done() {
+        let mut queue = RetirementQueue::new();
+        queue.retire(3, "c");
+        queue.retire(1, "a");
+        queue.retire(2, "b");
+
+        assert!(queue.drain_completed(0).is_empty());
+
+        let mut done = queue.drain_completed(2);
+        done.sort();
+        assert_eq!(done, vec!["a", "b"]);
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(queue.drain_completed(2), Vec::<&str>::new());
+        assert_eq!(queue.drain_completed(5), vec!["c"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drain_all_ignores_frames() {
+        let mut queue = RetirementQueue::default();
+        queue.retire(10, 1);
+        queue.retire(20, 2);
+
+        assert_eq!(queue.drain_all().len(), 2);
+        assert!(queue.is_empty());
+    }
+}
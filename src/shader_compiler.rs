@@ -0,0 +1,465 @@
+// `#[derive(Fail)]` puts its impls inside a `const _`, which newer compilers
+// warn about; see errors.rs.
+#![allow(non_local_definitions)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// How deep `#include`s can nest before we assume something's gone wrong.
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderLanguage {
+    Glsl,
+    Hlsl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Compute,
+}
+
+/// Somewhere to read shader sources from. Paths always use `/`, and are
+/// relative to the root of whatever's being read.
+pub trait ShaderFileSystem {
+    fn read(&self, path: &str) -> Option<String>;
+}
+
+/// Sources held in memory, keyed by path.
+impl ShaderFileSystem for HashMap<String, String> {
+    fn read(&self, path: &str) -> Option<String> {
+        self.get(path).cloned()
+    }
+}
+
+/// Sources read from a directory on disk.
+pub struct ShaderDirectory {
+    pub root: PathBuf,
+}
+
+impl ShaderFileSystem for ShaderDirectory {
+    fn read(&self, path: &str) -> Option<String> {
+        fs::read_to_string(self.root.join(path)).ok()
+    }
+}
+
+/// Something wrong with a shader, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShaderDiagnostic {
+    pub file: String,
+    /// 1-based, or 0 when the problem isn't on any one line.
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+}
+
+impl fmt::Display for ShaderDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum ShaderCompileError {
+    #[fail(display = "Couldn't find the shader {}!", _0)]
+    NotFound(String),
+    #[fail(
+        display = "Runtime compilation of {:?} isn't supported! Compile it to SPIR-V offline instead.",
+        _0
+    )]
+    Unsupported(ShaderLanguage),
+    #[fail(display = "The shader didn't compile:\n{}", _0)]
+    Diagnostics(DiagnosticList),
+}
+
+/// The diagnostics from one compile, one per line when displayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticList(pub Vec<ShaderDiagnostic>);
+
+impl fmt::Display for DiagnosticList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for diagnostic in &self.0 {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compiles shader source to SPIR-V at runtime, pulling `#include`s out of a
+/// `ShaderFileSystem` and adding `#define`s we've been given.
+///
+/// `#include "file"` is relative to the including file, and `#include <file>`
+/// to the root of the file system. A file with `#pragma once` in it is only
+/// ever included once.
+pub struct ShaderCompiler<F: ShaderFileSystem> {
+    pub file_system: F,
+    defines: Vec<(String, String)>,
+}
+
+impl<F: ShaderFileSystem> ShaderCompiler<F> {
+    pub fn new(file_system: F) -> Self {
+        ShaderCompiler {
+            file_system,
+            defines: Vec::new(),
+        }
+    }
+
+    /// Adds `#define name value` to every shader we compile from now on.
+    pub fn define(&mut self, name: &str, value: &str) -> &mut Self {
+        self.defines.retain(|(existing, _)| existing != name);
+        self.defines.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn compile(
+        &self,
+        path: &str,
+        language: ShaderLanguage,
+        stage: ShaderStage,
+    ) -> Result<Vec<u32>, ShaderCompileError> {
+        if language == ShaderLanguage::Hlsl {
+            return Err(ShaderCompileError::Unsupported(language));
+        }
+
+        let source = self
+            .file_system
+            .read(path)
+            .ok_or_else(|| ShaderCompileError::NotFound(path.to_owned()))?;
+
+        let mut expanded = Expanded::default();
+        let mut stack = vec![path.to_owned()];
+        self.expand(path, &source, &mut stack, &mut expanded)
+            .map_err(|e| ShaderCompileError::Diagnostics(DiagnosticList(vec![e])))?;
+
+        self.compile_glsl(&expanded, stage)
+            .map_err(|e| ShaderCompileError::Diagnostics(DiagnosticList(e)))
+    }
+
+    /// Copies `source` into `expanded` line by line, pasting includes in where
+    /// they're asked for and remembering where every line came from.
+    fn expand(
+        &self,
+        path: &str,
+        source: &str,
+        stack: &mut Vec<String>,
+        expanded: &mut Expanded,
+    ) -> Result<(), ShaderDiagnostic> {
+        if source.lines().any(|line| line.trim() == "#pragma once")
+            && !expanded.included_once.insert(path.to_owned())
+        {
+            return Ok(());
+        }
+
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index as u32 + 1;
+            let diagnostic = |message: String| ShaderDiagnostic {
+                file: path.to_owned(),
+                line: line_number,
+                column: 1,
+                message,
+            };
+
+            let trimmed = line.trim();
+            if trimmed == "#pragma once" {
+                expanded.push("", path, line_number);
+                continue;
+            }
+            if !trimmed.starts_with("#include") {
+                expanded.push(line, path, line_number);
+                continue;
+            }
+
+            let target = trimmed["#include".len()..].trim();
+            let included = match (target.chars().next(), target.chars().last()) {
+                (Some('"'), Some('"')) if target.len() >= 2 => {
+                    relative_to(path, &target[1..target.len() - 1])
+                }
+                (Some('<'), Some('>')) => target[1..target.len() - 1].to_owned(),
+                _ => return Err(diagnostic(format!("`{}` isn't a file to include", target))),
+            };
+
+            if stack.contains(&included) {
+                return Err(diagnostic(format!(
+                    "Including {} again would go around in circles: {} -> {}",
+                    included,
+                    stack.join(" -> "),
+                    included
+                )));
+            }
+            if stack.len() >= MAX_INCLUDE_DEPTH {
+                return Err(diagnostic(format!(
+                    "Includes nest more than {} deep",
+                    MAX_INCLUDE_DEPTH
+                )));
+            }
+            let included_source = self
+                .file_system
+                .read(&included)
+                .ok_or_else(|| diagnostic(format!("Couldn't find the include {}", included)))?;
+
+            stack.push(included.clone());
+            self.expand(&included, &included_source, stack, expanded)?;
+            stack.pop();
+        }
+
+        Ok(())
+    }
+
+    fn compile_glsl(
+        &self,
+        expanded: &Expanded,
+        stage: ShaderStage,
+    ) -> Result<Vec<u32>, Vec<ShaderDiagnostic>> {
+        use naga::{
+            back::spv,
+            front::glsl,
+            valid::{Capabilities, ValidationFlags, Validator},
+        };
+
+        let mut options = glsl::Options::from(match stage {
+            ShaderStage::Vertex => naga::ShaderStage::Vertex,
+            ShaderStage::Fragment => naga::ShaderStage::Fragment,
+            ShaderStage::Compute => naga::ShaderStage::Compute,
+        });
+        for (name, value) in &self.defines {
+            options.defines.insert(name.clone(), value.clone());
+        }
+
+        let module = glsl::Frontend::default()
+            .parse(&options, &expanded.source)
+            .map_err(|errors| {
+                errors
+                    .iter()
+                    .map(|e| {
+                        let location = e.meta.location(&expanded.source);
+                        expanded.diagnostic(
+                            location.line_number,
+                            location.line_position,
+                            e.to_string(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })?;
+
+        let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+            .validate(&module)
+            .map_err(|e| {
+                let message = e.as_inner().to_string();
+                vec![match e.location(&expanded.source) {
+                    Some(location) => {
+                        expanded.diagnostic(location.line_number, location.line_position, message)
+                    }
+                    None => expanded.diagnostic(0, 0, message),
+                }]
+            })?;
+
+        //  naga flips gl_Position.y by default, which glslc doesn't
+        let spv_options = spv::Options {
+            flags: if cfg!(debug_assertions) {
+                spv::WriterFlags::DEBUG
+            } else {
+                spv::WriterFlags::empty()
+            },
+            ..spv::Options::default()
+        };
+        spv::write_vec(&module, &info, &spv_options, None)
+            .map_err(|e| vec![expanded.diagnostic(0, 0, e.to_string())])
+    }
+}
+
+/// A shader with its includes pasted in.
+#[derive(Default)]
+struct Expanded {
+    source: String,
+    /// Which file and line each line of `source` came from.
+    lines: Vec<(String, u32)>,
+    included_once: HashSet<String>,
+}
+
+impl Expanded {
+    fn push(&mut self, line: &str, file: &str, line_number: u32) {
+        self.source.push_str(line);
+        self.source.push('\n');
+        self.lines.push((file.to_owned(), line_number));
+    }
+
+    /// A diagnostic for `line` of the expanded source, pointing back at the file it came from.
+    fn diagnostic(&self, line: u32, column: u32, message: String) -> ShaderDiagnostic {
+        match self.lines.get((line as usize).wrapping_sub(1)) {
+            Some((file, original)) => ShaderDiagnostic {
+                file: file.clone(),
+                line: *original,
+                column,
+                message,
+            },
+            None => ShaderDiagnostic {
+                file: self
+                    .lines
+                    .first()
+                    .map(|(file, _)| file.clone())
+                    .unwrap_or_default(),
+                line: 0,
+                column: 0,
+                message,
+            },
+        }
+    }
+}
+
+/// `target` as seen from the directory `from` is in.
+fn relative_to(from: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = match Path::new(from).parent() {
+        Some(_) => from.split('/').collect(),
+        None => Vec::new(),
+    };
+    parts.pop();
+    for part in target.split('/') {
+        match part {
+            "." | "" => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ShaderCompileError, ShaderCompiler, ShaderLanguage, ShaderStage};
+    use crate::ShaderReflection;
+    use std::collections::HashMap;
+
+    fn compiler(files: &[(&str, &str)]) -> ShaderCompiler<HashMap<String, String>> {
+        ShaderCompiler::new(
+            files
+                .iter()
+                .map(|(path, source)| (path.to_string(), source.to_string()))
+                .collect(),
+        )
+    }
+
+    const FRAGMENT: &str = "#version 450
+#include \"common/colors.glsl\"
+layout(location = 0) out vec4 out_color;
+layout(set = 0, binding = 1) uniform texture2D tex;
+layout(set = 0, binding = 2) uniform sampler samp;
+layout(location = 0) in vec2 uv;
+void main() {
+    out_color = texture(sampler2D(tex, samp), uv) * TINT;
+}
+";
+
+    const COLORS: &str = "#pragma once
+#include <common/constants.glsl>
+const vec4 TINT = vec4(BRIGHTNESS);
+";
+
+    #[test]
+    fn compiles_with_includes_and_defines() {
+        let mut compiler = compiler(&[
+            ("shaders/sprite.frag", FRAGMENT),
+            ("shaders/common/colors.glsl", COLORS),
+            ("common/constants.glsl", "#pragma once\n"),
+        ]);
+        compiler.define("BRIGHTNESS", "0.5");
+
+        let spirv = compiler
+            .compile(
+                "shaders/sprite.frag",
+                ShaderLanguage::Glsl,
+                ShaderStage::Fragment,
+            )
+            .unwrap();
+
+        let reflection = ShaderReflection::new(&spirv, "main").unwrap();
+        assert_eq!(reflection.bindings.len(), 2);
+    }
+
+    #[test]
+    fn errors_point_at_the_file_they_came_from() {
+        let compiler = compiler(&[
+            (
+                "main.frag",
+                "#version 450\n#include \"broken.glsl\"\nvoid main() {}\n",
+            ),
+            ("broken.glsl", "// fine\nvec4 oops = ;\n"),
+        ]);
+
+        let diagnostics =
+            match compiler.compile("main.frag", ShaderLanguage::Glsl, ShaderStage::Fragment) {
+                Err(ShaderCompileError::Diagnostics(diagnostics)) => diagnostics.0,
+                other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+            };
+        assert_eq!(diagnostics[0].file, "broken.glsl");
+        assert_eq!(diagnostics[0].line, 2);
+    }
+
+    #[test]
+    fn missing_and_circular_includes_are_diagnosed() {
+        let missing = compiler(&[("a.glsl", "#version 450\n\n#include \"nope.glsl\"\n")]);
+        let circular = compiler(&[
+            ("a.glsl", "#version 450\n#include \"b.glsl\"\n"),
+            ("b.glsl", "#include \"a.glsl\"\n"),
+        ]);
+
+        for (compiler, file, line) in &[(missing, "a.glsl", 3), (circular, "b.glsl", 1)] {
+            match compiler.compile("a.glsl", ShaderLanguage::Glsl, ShaderStage::Vertex) {
+                Err(ShaderCompileError::Diagnostics(diagnostics)) => {
+                    assert_eq!(diagnostics.0[0].file, *file);
+                    assert_eq!(diagnostics.0[0].line, *line);
+                }
+                other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn vertex_positions_are_left_the_way_glslc_leaves_them() {
+        const OP_F_NEGATE: u32 = 127;
+        let compiler = compiler(&[(
+            "quad.vert",
+            "#version 450
+layout(location = 0) in vec2 position;
+void main() {
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+",
+        )]);
+
+        let spirv = compiler
+            .compile("quad.vert", ShaderLanguage::Glsl, ShaderStage::Vertex)
+            .unwrap();
+
+        //  Walk the instructions after the five word header
+        let mut index = 5;
+        while index < spirv.len() {
+            assert_ne!(
+                spirv[index] & 0xFFFF,
+                OP_F_NEGATE,
+                "gl_Position was flipped"
+            );
+            index += (spirv[index] >> 16).max(1) as usize;
+        }
+    }
+
+    #[test]
+    fn hlsl_is_reported_as_unsupported() {
+        let compiler = compiler(&[("a.hlsl", "float4 main() : SV_Target { return 0; }")]);
+
+        match compiler.compile("a.hlsl", ShaderLanguage::Hlsl, ShaderStage::Fragment) {
+            Err(ShaderCompileError::Unsupported(ShaderLanguage::Hlsl)) => {}
+            other => panic!("expected unsupported, got {:?}", other.map(|_| ())),
+        }
+    }
+}
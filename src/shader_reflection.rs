@@ -0,0 +1,872 @@
+use super::{DescriptorLayoutBuilder, RenderError};
+use gfx_hal::{
+    format::Format,
+    pso::{DescriptorType, ShaderStageFlags},
+    Backend,
+};
+use std::{collections::HashMap, ops::Range};
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+//  The handful of opcodes, decorations and storage classes we care about
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+/// A descriptor a shader reads, and which stages read it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflectedBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub ty: DescriptorType,
+    pub count: usize,
+    pub stage_flags: ShaderStageFlags,
+}
+
+/// One `layout(location = N) in` of a vertex shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReflectedVertexInput {
+    pub location: u32,
+    pub format: Format,
+    /// How many bytes it takes up in a vertex buffer.
+    pub size: u32,
+}
+
+/// Everything about one entry point of a SPIR-V module that has to line up with
+/// the pipeline it goes in.
+#[derive(Debug, Clone)]
+pub struct ShaderReflection {
+    pub stage: ShaderStageFlags,
+    pub bindings: Vec<ReflectedBinding>,
+    /// The push constant block, counted in 32-bit words like gfx-hal wants.
+    pub push_constants: Option<Range<u32>>,
+    /// Sorted by location. Only vertex shaders have any.
+    pub vertex_inputs: Vec<ReflectedVertexInput>,
+}
+
+impl ShaderReflection {
+    /// Reads the entry point called `entry` out of `spirv`.
+    pub fn new(spirv: &[u32], entry: &str) -> Result<Self, failure::Error> {
+        let module = Module::parse(spirv)?;
+        let entry_point = match module.entry_points.iter().find(|e| e.name == entry) {
+            Some(entry_point) => entry_point,
+            None => bail_invalid!(
+                "Shader",
+                "The shader has no entry point called `{}`!",
+                entry
+            ),
+        };
+        let stage = match entry_point.execution_model {
+            0 => ShaderStageFlags::VERTEX,
+            1 => ShaderStageFlags::HULL,
+            2 => ShaderStageFlags::DOMAIN,
+            3 => ShaderStageFlags::GEOMETRY,
+            4 => ShaderStageFlags::FRAGMENT,
+            5 => ShaderStageFlags::COMPUTE,
+            model => {
+                return Err(RenderError::unsupported(
+                    "Shader",
+                    format!("Execution model {} isn't a shader stage we know!", model),
+                )
+                .into())
+            }
+        };
+
+        let mut reflection = ShaderReflection {
+            stage,
+            bindings: Vec::new(),
+            push_constants: None,
+            vertex_inputs: Vec::new(),
+        };
+
+        for variable in &module.variables {
+            let pointee = match module.types.get(&variable.ty) {
+                Some(Type::Pointer { pointee }) => *pointee,
+                _ => bail_invalid!("Shader", "Variable %{} isn't a pointer!", variable.id),
+            };
+
+            match variable.storage {
+                STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                    if let Some((ty, count)) = module.descriptor(variable, pointee)? {
+                        reflection.bindings.push(ReflectedBinding {
+                            set: module
+                                .decoration(variable.id, DECORATION_DESCRIPTOR_SET)
+                                .unwrap_or(0),
+                            binding: match module.decoration(variable.id, DECORATION_BINDING) {
+                                Some(binding) => binding,
+                                None => bail_invalid!(
+                                    "Shader",
+                                    "Descriptor %{} has no binding!",
+                                    variable.id
+                                ),
+                            },
+                            ty,
+                            count,
+                            stage_flags: stage,
+                        });
+                    }
+                }
+                STORAGE_PUSH_CONSTANT => {
+                    if reflection.push_constants.is_some() {
+                        bail_invalid!(
+                            "Shader",
+                            "A shader stage can only have one push constant block!"
+                        );
+                    }
+                    let bytes = module.struct_extent(pointee)?;
+                    reflection.push_constants = Some(bytes.start / 4..bytes.end.div_ceil(4));
+                }
+                STORAGE_INPUT
+                    if stage == ShaderStageFlags::VERTEX
+                        && entry_point.interface.contains(&variable.id)
+                        && module
+                            .decoration(variable.id, DECORATION_BUILT_IN)
+                            .is_none() =>
+                {
+                    let location = match module.decoration(variable.id, DECORATION_LOCATION) {
+                        Some(location) => location,
+                        None => bail_invalid!(
+                            "Shader",
+                            "Vertex input %{} has no location!",
+                            variable.id
+                        ),
+                    };
+                    module.vertex_inputs(pointee, location, &mut reflection.vertex_inputs)?;
+                }
+                _ => {}
+            }
+        }
+
+        reflection.bindings.sort_by_key(|b| (b.set, b.binding));
+        reflection.vertex_inputs.sort_by_key(|input| input.location);
+        Ok(reflection)
+    }
+}
+
+/// The stages of a pipeline put together, checked against each other.
+#[derive(Debug, Clone)]
+pub struct PipelineReflection {
+    pub bindings: Vec<ReflectedBinding>,
+    /// One range per block, with every stage that declares that same block.
+    pub push_constants: Vec<(ShaderStageFlags, Range<u32>)>,
+    pub vertex_inputs: Vec<ReflectedVertexInput>,
+}
+
+impl PipelineReflection {
+    /// Puts the stages together, failing if two of them disagree about what's
+    /// at a binding.
+    pub fn new(stages: &[ShaderReflection]) -> Result<Self, failure::Error> {
+        let mut merged = PipelineReflection {
+            bindings: Vec::new(),
+            push_constants: Vec::new(),
+            vertex_inputs: Vec::new(),
+        };
+
+        for stage in stages {
+            for binding in &stage.bindings {
+                match merged
+                    .bindings
+                    .iter_mut()
+                    .find(|b| b.set == binding.set && b.binding == binding.binding)
+                {
+                    Some(existing)
+                        if existing.ty != binding.ty || existing.count != binding.count =>
+                    {
+                        bail_invalid!(
+                            "Pipeline",
+                            "Set {} binding {} is {} x {:?} in the {} shader, but {} x {:?} in the {} shader!",
+                            binding.set,
+                            binding.binding,
+                            existing.count,
+                            existing.ty,
+                            stage_names(existing.stage_flags),
+                            binding.count,
+                            binding.ty,
+                            stage_names(binding.stage_flags)
+                        );
+                    }
+                    Some(existing) => existing.stage_flags |= binding.stage_flags,
+                    None => merged.bindings.push(binding.clone()),
+                }
+            }
+
+            if let Some(range) = &stage.push_constants {
+                match merged.push_constants.iter_mut().find(|(_, r)| r == range) {
+                    Some((stage_flags, _)) => *stage_flags |= stage.stage,
+                    None => merged.push_constants.push((stage.stage, range.clone())),
+                }
+            }
+
+            if stage.stage == ShaderStageFlags::VERTEX {
+                merged.vertex_inputs = stage.vertex_inputs.clone();
+            }
+        }
+
+        merged.bindings.sort_by_key(|b| (b.set, b.binding));
+        Ok(merged)
+    }
+
+    /// A builder with every binding the shaders use declared, ready to `build`.
+    /// A `PipelineBundle` holds a single set, so everything has to be in set 0.
+    pub fn descriptor_layout<B: Backend>(
+        &self,
+    ) -> Result<DescriptorLayoutBuilder<'static, B>, failure::Error> {
+        let mut builder = DescriptorLayoutBuilder::new();
+        for binding in &self.bindings {
+            if binding.set != 0 {
+                bail_invalid!(
+                    "Pipeline",
+                    "Binding {} is in set {}, but a pipeline bundle only has set 0!",
+                    binding.binding,
+                    binding.set
+                );
+            }
+            builder = builder.binding(
+                binding.binding,
+                binding.ty,
+                binding.count,
+                binding.stage_flags,
+            );
+        }
+        Ok(builder)
+    }
+}
+
+fn stage_names(stage_flags: ShaderStageFlags) -> String {
+    let names = [
+        (ShaderStageFlags::VERTEX, "vertex"),
+        (ShaderStageFlags::HULL, "hull"),
+        (ShaderStageFlags::DOMAIN, "domain"),
+        (ShaderStageFlags::GEOMETRY, "geometry"),
+        (ShaderStageFlags::FRAGMENT, "fragment"),
+        (ShaderStageFlags::COMPUTE, "compute"),
+    ];
+    names
+        .iter()
+        .filter(|(flag, _)| stage_flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+#[derive(Debug)]
+enum Type {
+    Bool,
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray,
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+struct EntryPoint {
+    execution_model: u32,
+    name: String,
+    interface: Vec<u32>,
+}
+
+struct Variable {
+    id: u32,
+    ty: u32,
+    storage: u32,
+}
+
+/// Just enough of a SPIR-V module to find its interface.
+#[derive(Default)]
+struct Module {
+    entry_points: Vec<EntryPoint>,
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    variables: Vec<Variable>,
+}
+
+impl Module {
+    fn parse(spirv: &[u32]) -> Result<Self, failure::Error> {
+        if spirv.len() < 5 || spirv[0] != SPIRV_MAGIC {
+            bail_invalid!("Shader", "That isn't SPIR-V!");
+        }
+
+        let mut module = Module::default();
+        let mut words = &spirv[5..];
+        while !words.is_empty() {
+            let word_count = (words[0] >> 16) as usize;
+            let opcode = words[0] & 0xFFFF;
+            if word_count == 0 || word_count > words.len() {
+                bail_invalid!("Shader", "The SPIR-V is cut short or corrupted!");
+            }
+            let operands = &words[1..word_count];
+            words = &words[word_count..];
+
+            let a = match operands.first() {
+                Some(&a) => a,
+                None => continue,
+            };
+            let b = operands.get(1).cloned().unwrap_or(0);
+            let rest = operands.get(2..).unwrap_or(&[]);
+            let ty = match opcode {
+                OP_ENTRY_POINT => {
+                    let (name, name_words) = read_string(rest);
+                    module.entry_points.push(EntryPoint {
+                        execution_model: a,
+                        name,
+                        interface: rest[name_words..].to_vec(),
+                    });
+                    continue;
+                }
+                OP_TYPE_BOOL => Type::Bool,
+                OP_TYPE_INT => Type::Int {
+                    width: b,
+                    signed: rest.first() == Some(&1),
+                },
+                OP_TYPE_FLOAT => Type::Float { width: b },
+                OP_TYPE_VECTOR if !rest.is_empty() => Type::Vector {
+                    component: b,
+                    count: rest[0],
+                },
+                OP_TYPE_MATRIX if !rest.is_empty() => Type::Matrix {
+                    column: b,
+                    count: rest[0],
+                },
+                OP_TYPE_IMAGE if rest.len() >= 5 => Type::Image {
+                    dim: rest[0],
+                    sampled: rest[4],
+                },
+                OP_TYPE_SAMPLER => Type::Sampler,
+                OP_TYPE_SAMPLED_IMAGE => Type::SampledImage,
+                OP_TYPE_ARRAY if !rest.is_empty() => Type::Array {
+                    element: b,
+                    length: rest[0],
+                },
+                OP_TYPE_RUNTIME_ARRAY => Type::RuntimeArray,
+                OP_TYPE_STRUCT => Type::Struct {
+                    members: operands[1..].to_vec(),
+                },
+                OP_TYPE_POINTER if !rest.is_empty() => Type::Pointer { pointee: rest[0] },
+                OP_CONSTANT if !rest.is_empty() => {
+                    module.constants.insert(b, rest[0]);
+                    continue;
+                }
+                OP_VARIABLE if !rest.is_empty() => {
+                    module.variables.push(Variable {
+                        id: b,
+                        ty: a,
+                        storage: rest[0],
+                    });
+                    continue;
+                }
+                OP_DECORATE => {
+                    module
+                        .decorations
+                        .insert((a, b), rest.first().cloned().unwrap_or(0));
+                    continue;
+                }
+                OP_MEMBER_DECORATE if !rest.is_empty() => {
+                    module
+                        .member_decorations
+                        .insert((a, b, rest[0]), rest.get(1).cloned().unwrap_or(0));
+                    continue;
+                }
+                _ => continue,
+            };
+
+            //  The result id of a type is its first operand
+            module.types.insert(a, ty);
+        }
+
+        Ok(module)
+    }
+
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).cloned()
+    }
+
+    fn ty(&self, id: u32) -> Result<&Type, failure::Error> {
+        match self.types.get(&id) {
+            Some(ty) => Ok(ty),
+            None => bail_invalid!(
+                "Shader",
+                "Type %{} is missing or isn't one we understand!",
+                id
+            ),
+        }
+    }
+
+    /// What kind of descriptor a variable is, and how many, or `None` if it isn't one.
+    fn descriptor(
+        &self,
+        variable: &Variable,
+        pointee: u32,
+    ) -> Result<Option<(DescriptorType, usize)>, failure::Error> {
+        let mut ty_id = pointee;
+        let mut count = 1;
+        loop {
+            match self.ty(ty_id)? {
+                Type::Array { element, length } => {
+                    count *= match self.constants.get(length) {
+                        Some(&length) => length as usize,
+                        None => bail_invalid!(
+                            "Shader",
+                            "Descriptor %{} has an array length we can't work out!",
+                            variable.id
+                        ),
+                    };
+                    ty_id = *element;
+                }
+                Type::RuntimeArray if variable.storage == STORAGE_UNIFORM_CONSTANT => {
+                    return Err(RenderError::unsupported(
+                        "Shader",
+                        format!(
+                            "Descriptor %{} is an unsized array, so we can't make a layout for it!",
+                            variable.id
+                        ),
+                    )
+                    .into())
+                }
+                _ => break,
+            }
+        }
+
+        let ty = match (variable.storage, self.ty(ty_id)?) {
+            (STORAGE_UNIFORM_CONSTANT, Type::Sampler) => DescriptorType::Sampler,
+            (STORAGE_UNIFORM_CONSTANT, Type::SampledImage) => DescriptorType::CombinedImageSampler,
+            (STORAGE_UNIFORM_CONSTANT, Type::Image { dim, sampled }) => match (*dim, *sampled) {
+                (DIM_BUFFER, 2) => DescriptorType::StorageTexelBuffer,
+                (DIM_BUFFER, _) => DescriptorType::UniformTexelBuffer,
+                (DIM_SUBPASS_DATA, _) => DescriptorType::InputAttachment,
+                (_, 2) => DescriptorType::StorageImage,
+                _ => DescriptorType::SampledImage,
+            },
+            (STORAGE_UNIFORM, _) if self.decoration(ty_id, DECORATION_BUFFER_BLOCK).is_some() => {
+                DescriptorType::StorageBuffer
+            }
+            (STORAGE_UNIFORM, _) if self.decoration(ty_id, DECORATION_BLOCK).is_some() => {
+                DescriptorType::UniformBuffer
+            }
+            (STORAGE_STORAGE_BUFFER, _) => DescriptorType::StorageBuffer,
+            _ => return Ok(None),
+        };
+
+        Ok(Some((ty, count)))
+    }
+
+    /// The bytes a struct's members cover, from the first member's offset to the
+    /// end of the last one.
+    fn struct_extent(&self, id: u32) -> Result<Range<u32>, failure::Error> {
+        let members = match self.ty(id)? {
+            Type::Struct { members } => members,
+            _ => bail_invalid!("Shader", "Type %{} should be a struct!", id),
+        };
+
+        let mut extent: Option<Range<u32>> = None;
+        for (index, &member) in members.iter().enumerate() {
+            let index = index as u32;
+            let offset = match self.member_decorations.get(&(id, index, DECORATION_OFFSET)) {
+                Some(&offset) => offset,
+                None => bail_invalid!(
+                    "Shader",
+                    "Member {} of struct %{} has no offset!",
+                    index,
+                    id
+                ),
+            };
+            let size = match (
+                self.ty(member)?,
+                self.member_decorations
+                    .get(&(id, index, DECORATION_MATRIX_STRIDE)),
+            ) {
+                (Type::Matrix { count, .. }, Some(&stride)) => count * stride,
+                _ => self.size_of(member)?,
+            };
+
+            extent = Some(match extent {
+                Some(extent) => extent.start.min(offset)..extent.end.max(offset + size),
+                None => offset..offset + size,
+            });
+        }
+
+        Ok(extent.unwrap_or(0..0))
+    }
+
+    fn size_of(&self, id: u32) -> Result<u32, failure::Error> {
+        Ok(match self.ty(id)? {
+            Type::Bool => 4,
+            Type::Int { width, .. } | Type::Float { width } => width / 8,
+            Type::Vector { component, count } => count * self.size_of(*component)?,
+            Type::Matrix { column, count } => count * self.size_of(*column)?,
+            Type::Array { element, length } => {
+                let length = match self.constants.get(length) {
+                    Some(&length) => length,
+                    None => {
+                        bail_invalid!("Shader", "Array %{} has a length we can't work out!", id)
+                    }
+                };
+                let stride = match self.decoration(id, DECORATION_ARRAY_STRIDE) {
+                    Some(stride) => stride,
+                    None => self.size_of(*element)?,
+                };
+                length * stride
+            }
+            Type::Struct { .. } => self.struct_extent(id)?.end,
+            _ => bail_invalid!("Shader", "Type %{} doesn't have a size!", id),
+        })
+    }
+
+    /// Adds the vertex inputs a variable of type `id` at `location` takes up.
+    /// Matrices take one location per column.
+    fn vertex_inputs(
+        &self,
+        id: u32,
+        location: u32,
+        inputs: &mut Vec<ReflectedVertexInput>,
+    ) -> Result<(), failure::Error> {
+        if let Type::Matrix { column, count } = self.ty(id)? {
+            for i in 0..*count {
+                self.vertex_inputs(*column, location + i, inputs)?;
+            }
+            return Ok(());
+        }
+
+        let (scalar, count) = match self.ty(id)? {
+            Type::Vector { component, count } => (self.ty(*component)?, *count),
+            scalar => (scalar, 1),
+        };
+        let format = match (scalar, count) {
+            (Type::Float { width: 32 }, 1) => Format::R32Sfloat,
+            (Type::Float { width: 32 }, 2) => Format::Rg32Sfloat,
+            (Type::Float { width: 32 }, 3) => Format::Rgb32Sfloat,
+            (Type::Float { width: 32 }, 4) => Format::Rgba32Sfloat,
+            (
+                Type::Int {
+                    width: 32,
+                    signed: true,
+                },
+                1,
+            ) => Format::R32Sint,
+            (
+                Type::Int {
+                    width: 32,
+                    signed: true,
+                },
+                2,
+            ) => Format::Rg32Sint,
+            (
+                Type::Int {
+                    width: 32,
+                    signed: true,
+                },
+                3,
+            ) => Format::Rgb32Sint,
+            (
+                Type::Int {
+                    width: 32,
+                    signed: true,
+                },
+                4,
+            ) => Format::Rgba32Sint,
+            (
+                Type::Int {
+                    width: 32,
+                    signed: false,
+                },
+                1,
+            ) => Format::R32Uint,
+            (
+                Type::Int {
+                    width: 32,
+                    signed: false,
+                },
+                2,
+            ) => Format::Rg32Uint,
+            (
+                Type::Int {
+                    width: 32,
+                    signed: false,
+                },
+                3,
+            ) => Format::Rgb32Uint,
+            (
+                Type::Int {
+                    width: 32,
+                    signed: false,
+                },
+                4,
+            ) => Format::Rgba32Uint,
+            _ => {
+                return Err(RenderError::unsupported(
+                    "Shader",
+                    format!(
+                        "Vertex input at location {} isn't a 32-bit scalar or vector!",
+                        location
+                    ),
+                )
+                .into())
+            }
+        };
+
+        inputs.push(ReflectedVertexInput {
+            location,
+            format,
+            size: 4 * count,
+        });
+        Ok(())
+    }
+}
+
+/// Reads a nul-terminated string packed into words, and how many words it took.
+fn read_string(words: &[u32]) -> (String, usize) {
+    let mut bytes = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        for &byte in &word.to_le_bytes() {
+            if byte == 0 {
+                return (String::from_utf8_lossy(&bytes).into_owned(), i + 1);
+            }
+            bytes.push(byte);
+        }
+    }
+    (String::from_utf8_lossy(&bytes).into_owned(), words.len())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{PipelineReflection, ShaderReflection};
+    use gfx_hal::{
+        format::Format,
+        pso::{DescriptorType, ShaderStageFlags},
+    };
+
+    /// Puts together a SPIR-V module one instruction at a time, so we can test
+    /// without an offline compiler.
+    pub struct Assembler {
+        words: Vec<u32>,
+        next_id: u32,
+    }
+
+    impl Assembler {
+        pub fn new() -> Self {
+            Assembler {
+                words: vec![super::SPIRV_MAGIC, 0x0001_0000, 0, 0, 0],
+                next_id: 1,
+            }
+        }
+
+        pub fn id(&mut self) -> u32 {
+            self.next_id += 1;
+            self.next_id - 1
+        }
+
+        pub fn op(&mut self, opcode: u32, operands: &[u32]) {
+            self.words
+                .push(((operands.len() as u32 + 1) << 16) | opcode);
+            self.words.extend_from_slice(operands);
+        }
+
+        pub fn entry_point(&mut self, model: u32, function: u32, name: &str, interface: &[u32]) {
+            let mut operands = vec![model, function];
+            let mut bytes = name.as_bytes().to_vec();
+            bytes.resize((bytes.len() / 4 + 1) * 4, 0);
+            operands.extend(
+                bytes
+                    .chunks(4)
+                    .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])),
+            );
+            operands.extend_from_slice(interface);
+            self.op(super::OP_ENTRY_POINT, &operands);
+        }
+
+        /// A variable of `pointee` in `storage`, with a pointer type to go with it.
+        pub fn variable(&mut self, pointee: u32, storage: u32) -> u32 {
+            let pointer = self.id();
+            self.op(super::OP_TYPE_POINTER, &[pointer, storage, pointee]);
+            let variable = self.id();
+            self.op(super::OP_VARIABLE, &[pointer, variable, storage]);
+            variable
+        }
+
+        pub fn decorate(&mut self, id: u32, decoration: u32, value: u32) {
+            self.op(super::OP_DECORATE, &[id, decoration, value]);
+        }
+
+        pub fn finish(mut self) -> Vec<u32> {
+            self.words[3] = self.next_id;
+            self.words
+        }
+    }
+
+    /// A vertex shader reading a position and uv, a uniform block at binding 0
+    /// and a `mat4` push constant block.
+    pub fn vertex_shader() -> Vec<u32> {
+        let mut asm = Assembler::new();
+        let main = asm.id();
+        let float = asm.id();
+        let vec2 = asm.id();
+        let vec3 = asm.id();
+        let vec4 = asm.id();
+        let mat4 = asm.id();
+        let block = asm.id();
+        asm.op(super::OP_TYPE_FLOAT, &[float, 32]);
+        asm.op(super::OP_TYPE_VECTOR, &[vec2, float, 2]);
+        asm.op(super::OP_TYPE_VECTOR, &[vec3, float, 3]);
+        asm.op(super::OP_TYPE_VECTOR, &[vec4, float, 4]);
+        asm.op(super::OP_TYPE_MATRIX, &[mat4, vec4, 4]);
+        asm.op(super::OP_TYPE_STRUCT, &[block, mat4]);
+        asm.decorate(block, super::DECORATION_BLOCK, 0);
+        asm.op(
+            super::OP_MEMBER_DECORATE,
+            &[block, 0, super::DECORATION_OFFSET, 0],
+        );
+        asm.op(
+            super::OP_MEMBER_DECORATE,
+            &[block, 0, super::DECORATION_MATRIX_STRIDE, 16],
+        );
+
+        let position = asm.variable(vec3, super::STORAGE_INPUT);
+        let uv = asm.variable(vec2, super::STORAGE_INPUT);
+        let vertex_index = asm.variable(float, super::STORAGE_INPUT);
+        let uniforms = asm.variable(block, super::STORAGE_UNIFORM);
+        let push = asm.variable(block, super::STORAGE_PUSH_CONSTANT);
+        asm.decorate(position, super::DECORATION_LOCATION, 0);
+        asm.decorate(uv, super::DECORATION_LOCATION, 1);
+        asm.decorate(vertex_index, super::DECORATION_BUILT_IN, 42);
+        asm.decorate(uniforms, super::DECORATION_DESCRIPTOR_SET, 0);
+        asm.decorate(uniforms, super::DECORATION_BINDING, 0);
+        let _ = push;
+
+        asm.entry_point(0, main, "main", &[position, uv, vertex_index]);
+        asm.finish()
+    }
+
+    /// A fragment shader sampling `texture_binding` through a combined
+    /// image-sampler (or a sampled image, if `separate` is set).
+    pub fn fragment_shader(texture_binding: u32, separate: bool) -> Vec<u32> {
+        let mut asm = Assembler::new();
+        let main = asm.id();
+        let float = asm.id();
+        let image = asm.id();
+        asm.op(super::OP_TYPE_FLOAT, &[float, 32]);
+        asm.op(super::OP_TYPE_IMAGE, &[image, float, 1, 0, 0, 0, 1, 0]);
+        let texture = if separate {
+            asm.variable(image, super::STORAGE_UNIFORM_CONSTANT)
+        } else {
+            let sampled_image = asm.id();
+            asm.op(super::OP_TYPE_SAMPLED_IMAGE, &[sampled_image, image]);
+            asm.variable(sampled_image, super::STORAGE_UNIFORM_CONSTANT)
+        };
+        asm.decorate(texture, super::DECORATION_DESCRIPTOR_SET, 0);
+        asm.decorate(texture, super::DECORATION_BINDING, texture_binding);
+
+        asm.entry_point(4, main, "main", &[]);
+        asm.finish()
+    }
+
+    #[test]
+    fn reads_a_vertex_shaders_interface() {
+        let reflection = ShaderReflection::new(&vertex_shader(), "main").unwrap();
+
+        assert_eq!(reflection.stage, ShaderStageFlags::VERTEX);
+        assert_eq!(reflection.bindings.len(), 1);
+        assert_eq!(reflection.bindings[0].ty, DescriptorType::UniformBuffer);
+        assert_eq!(reflection.push_constants, Some(0..16));
+        let inputs: Vec<_> = reflection
+            .vertex_inputs
+            .iter()
+            .map(|input| (input.location, input.format, input.size))
+            .collect();
+        assert_eq!(
+            inputs,
+            vec![(0, Format::Rgb32Sfloat, 12), (1, Format::Rg32Sfloat, 8)]
+        );
+    }
+
+    #[test]
+    fn merges_stages_into_one_layout() {
+        let pipeline = PipelineReflection::new(&[
+            ShaderReflection::new(&vertex_shader(), "main").unwrap(),
+            ShaderReflection::new(&fragment_shader(1, false), "main").unwrap(),
+        ])
+        .unwrap();
+
+        let bindings: Vec<_> = pipeline
+            .bindings
+            .iter()
+            .map(|b| (b.binding, b.ty, b.stage_flags))
+            .collect();
+        assert_eq!(
+            bindings,
+            vec![
+                (0, DescriptorType::UniformBuffer, ShaderStageFlags::VERTEX),
+                (
+                    1,
+                    DescriptorType::CombinedImageSampler,
+                    ShaderStageFlags::FRAGMENT
+                ),
+            ]
+        );
+        assert_eq!(
+            pipeline.push_constants,
+            vec![(ShaderStageFlags::VERTEX, 0..16)]
+        );
+    }
+
+    #[test]
+    fn stages_which_disagree_are_an_error() {
+        let error = PipelineReflection::new(&[
+            ShaderReflection::new(&vertex_shader(), "main").unwrap(),
+            ShaderReflection::new(&fragment_shader(0, true), "main").unwrap(),
+        ])
+        .unwrap_err();
+
+        assert!(error.to_string().contains("vertex shader"));
+        assert!(error.to_string().contains("fragment shader"));
+    }
+
+    #[test]
+    fn rejects_modules_it_cant_read() {
+        assert!(ShaderReflection::new(&[0xDEAD_BEEF; 8], "main").is_err());
+        assert!(ShaderReflection::new(&vertex_shader(), "not_main").is_err());
+
+        let mut truncated = vertex_shader();
+        truncated.truncate(truncated.len() - 2);
+        assert!(ShaderReflection::new(&truncated, "main").is_err());
+    }
+}
@@ -0,0 +1,989 @@
+use super::{
+    DeviceContext, LoadedImage, PipelineBundle, Rect, RectInt, RetirementQueue, TextureOptions,
+    Vec2Int,
+};
+use gfx_hal::{
+    adapter::Adapter, pool::CommandPool, Backend, Capability, CommandQueue, Supports, Transfer,
+};
+use std::sync::Arc;
+
+/// Packs rectangles into a fixed size area by keeping track of the "skyline",
+/// the top edge of everything placed so far, and putting each new rectangle
+/// wherever it keeps the skyline lowest.
+#[derive(Debug, Clone)]
+pub struct SkylinePacker {
+    pub width: u32,
+    pub height: u32,
+    /// `(x, y, width)` of each flat stretch of the skyline, left to right.
+    skyline: Vec<(u32, u32, u32)>,
+}
+
+impl SkylinePacker {
+    pub fn new(width: u32, height: u32) -> Self {
+        SkylinePacker {
+            width,
+            height,
+            skyline: vec![(0, 0, width)],
+        }
+    }
+
+    /// Finds room for a `width` by `height` rectangle and gives back its top left
+    /// corner, or `None` if there isn't any.
+    pub fn pack(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        //  Lowest bottom edge wins, then the narrowest stretch it sits on
+        let mut best: Option<(usize, u32, u32)> = None;
+        for i in 0..self.skyline.len() {
+            if let Some(y) = self.fits(i, width, height) {
+                let stretch = self.skyline[i].2;
+                let better = match best {
+                    Some((best_i, best_y, _)) => {
+                        y < best_y || (y == best_y && stretch < self.skyline[best_i].2)
+                    }
+                    None => true,
+                };
+                if better {
+                    best = Some((i, y, self.skyline[i].0));
+                }
+            }
+        }
+
+        let (i, y, x) = best?;
+        self.place(i, x, y + height, width);
+        Some((x, y))
+    }
+
+    /// Forgets everything that's been packed.
+    pub fn clear(&mut self) {
+        self.skyline = vec![(0, 0, self.width)];
+    }
+
+    /// How high a rectangle starting at stretch `i` would have to sit, if it fits at all.
+    fn fits(&self, i: usize, width: u32, height: u32) -> Option<u32> {
+        let x = self.skyline[i].0;
+        if x + width > self.width {
+            return None;
+        }
+
+        let mut y = 0;
+        let mut width_left = width;
+        for &(_, stretch_y, stretch_width) in &self.skyline[i..] {
+            y = y.max(stretch_y);
+            if y + height > self.height {
+                return None;
+            }
+            if stretch_width >= width_left {
+                return Some(y);
+            }
+            width_left -= stretch_width;
+        }
+
+        None
+    }
+
+    fn place(&mut self, i: usize, x: u32, top: u32, width: u32) {
+        self.skyline.insert(i, (x, top, width));
+
+        //  Cut back whatever the new stretch now covers
+        let end = x + width;
+        let j = i + 1;
+        while j < self.skyline.len() {
+            let (stretch_x, stretch_y, stretch_width) = self.skyline[j];
+            if stretch_x >= end {
+                break;
+            }
+            let covered = end - stretch_x;
+            if covered >= stretch_width {
+                self.skyline.remove(j);
+            } else {
+                self.skyline[j] = (end, stretch_y, stretch_width - covered);
+                break;
+            }
+        }
+
+        //  And join up neighbours at the same height
+        let mut j = 0;
+        while j + 1 < self.skyline.len() {
+            if self.skyline[j].1 == self.skyline[j + 1].1 {
+                self.skyline[j].2 += self.skyline[j + 1].2;
+                self.skyline.remove(j + 1);
+            } else {
+                j += 1;
+            }
+        }
+    }
+}
+
+/// Free rectangles left behind by things taken out of a packed area, to go
+/// alongside a `SkylinePacker`, which can't take anything back. Holes that share
+/// a whole edge are merged as they come back, so neighbouring ones together can
+/// fit something bigger than either.
+#[derive(Debug, Clone, Default)]
+pub struct HoleList {
+    holes: Vec<RectInt>,
+}
+
+impl HoleList {
+    pub fn new() -> Self {
+        HoleList { holes: Vec::new() }
+    }
+
+    /// Fits a `width` by `height` rectangle into the snuggest hole that'll take
+    /// it and gives back its top left corner. What's left of the hole to the
+    /// right of and below it stays free.
+    pub fn take(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let i = self
+            .holes
+            .iter()
+            .enumerate()
+            .filter(|(_, hole)| hole.width() as u32 >= width && hole.height() as u32 >= height)
+            .min_by_key(|(_, hole)| hole.area())
+            .map(|(i, _)| i)?;
+
+        let hole = self.holes.swap_remove(i);
+        let (top, below) = hole.split_y(height as i32);
+        let (_, right) = top.split_x(width as i32);
+        for &rest in &[right, below] {
+            if !rest.is_empty() {
+                self.add(rest);
+            }
+        }
+
+        let corner = hole.origin();
+        Some((corner.x as u32, corner.y as u32))
+    }
+
+    /// Gives `hole` back, merging it with any neighbour it lines up with.
+    pub fn add(&mut self, mut hole: RectInt) {
+        while let Some((i, merged)) = self
+            .holes
+            .iter()
+            .enumerate()
+            .find_map(|(i, other)| merge_holes(&hole, other).map(|merged| (i, merged)))
+        {
+            self.holes.swap_remove(i);
+            hole = merged;
+        }
+        self.holes.push(hole);
+    }
+
+    /// Forgets every hole.
+    pub fn clear(&mut self) {
+        self.holes.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.holes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.holes.is_empty()
+    }
+}
+
+/// The rectangle covering `a` and `b`, if they sit side by side or one on top of
+/// the other with edges that line up exactly.
+fn merge_holes(a: &RectInt, b: &RectInt) -> Option<RectInt> {
+    let (top, bottom) = if a.min.y <= b.min.y { (a, b) } else { (b, a) };
+    if top.min.x == bottom.min.x && top.max.x == bottom.max.x && top.max.y == bottom.min.y {
+        return Some(RectInt::from_min_max(top.min, bottom.max));
+    }
+    let (left, right) = if a.min.x <= b.min.x { (a, b) } else { (b, a) };
+    if left.min.y == right.min.y && left.max.y == right.max.y && left.max.x == right.min.x {
+        return Some(RectInt::from_min_max(left.min, right.max));
+    }
+    None
+}
+
+/// Where an image ended up in a `TextureAtlas`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasHandle {
+    pub id: u32,
+    pub page: usize,
+    /// Where the image is in its page, in pixels. The padding's outside this.
+    pub rect: RectInt,
+    /// The same rectangle in texture coordinates.
+    pub uv: Rect,
+}
+
+/// How much of an atlas page is in use, in pixels. Padding counts as used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtlasPageUsage {
+    /// Taken up by images still in the atlas.
+    pub live: u32,
+    /// Packed at some point but not used by anything any more. Only a
+    /// defragment gets this back, apart from whatever removed images leave
+    /// big enough holes for new ones.
+    pub wasted: u32,
+    /// Never packed at all.
+    pub free: u32,
+}
+
+struct AtlasPage<B: Backend> {
+    texture: LoadedImage<B>,
+    packer: SkylinePacker,
+    /// Padded rectangles left behind by removed images.
+    holes: HoleList,
+    packed_pixels: u32,
+    live_pixels: u32,
+}
+
+/// Packs lots of little images into a few big `LoadedImage` pages, so they can
+/// share memory, samplers and descriptor sets. A new page is made whenever an
+/// image doesn't fit on any of the ones we have.
+///
+/// Every image gets `padding` pixels around it, filled in by stretching its
+/// edges outwards, so filtering near the edge of one image never picks up the
+/// one next to it.
+///
+/// Removing an image leaves a hole that new images can go into. Whatever's
+/// left over of a hole stays free, and holes next to each other merge, but space
+/// can still end up in too many small pieces. `defragment` packs a page's images
+/// back together on the GPU, and hands back the handles of everything that moved.
+pub struct TextureAtlas<B: Backend> {
+    pub page_size: u32,
+    pub padding: u32,
+    pub options: TextureOptions,
+    pages: Vec<AtlasPage<B>>,
+    handles: Vec<Option<AtlasHandle>>,
+    free_ids: Vec<u32>,
+    retired: RetirementQueue<LoadedImage<B>>,
+}
+
+impl<B: Backend> TextureAtlas<B> {
+    pub fn new(page_size: u32, padding: u32, options: impl Into<TextureOptions>) -> Self {
+        TextureAtlas {
+            page_size,
+            padding,
+            options: options.into(),
+            pages: Vec::new(),
+            handles: Vec::new(),
+            free_ids: Vec::new(),
+            retired: RetirementQueue::new(),
+        }
+    }
+
+    /// Packs `img`, a `width` by `height` RGBA8 image, into the first page with
+    /// room for it and uploads it there. Each new page gets a descriptor set
+    /// from `pipeline_bundle`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert<C: Capability + Supports<Transfer>>(
+        &mut self,
+        img: &[u8],
+        width: u32,
+        height: u32,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+    ) -> Result<AtlasHandle, failure::Error> {
+        let padded = self
+            .padding
+            .checked_mul(2)
+            .and_then(|padding| Some((width.checked_add(padding)?, height.checked_add(padding)?)));
+        let padded = match padded {
+            Some(padded)
+                if width != 0
+                    && height != 0
+                    && padded.0 <= self.page_size
+                    && padded.1 <= self.page_size =>
+            {
+                padded
+            }
+            _ => bail_invalid!(
+                "TextureAtlas",
+                "A {}x{} image with {} pixels of padding doesn't fit on a {}x{} atlas page!",
+                width,
+                height,
+                self.padding,
+                self.page_size,
+                self.page_size
+            ),
+        };
+        let needed = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(4));
+        if needed.is_none_or(|needed| img.len() < needed) {
+            bail_invalid!(
+                "TextureAtlas",
+                "A {}x{} image needs more than the {} bytes we were given!",
+                width,
+                height,
+                img.len()
+            );
+        }
+
+        let mut found = None;
+        for (index, page) in self.pages.iter_mut().enumerate() {
+            if let Some(corner) = page.allocate(padded.0, padded.1) {
+                found = Some((index, corner));
+                break;
+            }
+        }
+        let (page, (x, y)) = match found {
+            Some(found) => found,
+            None => {
+                self.add_page(
+                    adapter,
+                    device,
+                    command_pool,
+                    command_queue,
+                    pipeline_bundle,
+                )?;
+                let page = self.pages.len() - 1;
+                let corner = self.pages[page]
+                    .allocate(padded.0, padded.1)
+                    .expect("an empty page has room for anything that fits on a page");
+                (page, corner)
+            }
+        };
+
+        //  If the upload fails, the space is wasted until the next defragment
+        let padded_rect = RectInt::new(
+            Vec2Int::new(x as i32, y as i32),
+            Vec2Int::new(padded.0 as i32, padded.1 as i32),
+        );
+        let extruded = extrude(img, width, height, self.padding);
+        self.pages[page].texture.edit_image(
+            padded_rect,
+            &extruded,
+            adapter,
+            command_pool,
+            command_queue,
+        )?;
+        self.pages[page].live_pixels += padded.0 * padded.1;
+
+        let id = match self.free_ids.pop() {
+            Some(id) => id,
+            None => {
+                self.handles.push(None);
+                self.handles.len() as u32 - 1
+            }
+        };
+        let handle = self.place(id, page, padded_rect);
+        Ok(handle)
+    }
+
+    /// Takes an image out of the atlas. Its space can be packed again straight
+    /// away, so only do this once nothing's going to draw it any more.
+    pub fn remove(&mut self, id: u32) -> bool {
+        let handle = match self.handles.get_mut(id as usize).and_then(Option::take) {
+            Some(handle) => handle,
+            None => return false,
+        };
+
+        let padded = self.padded_rect(&handle);
+        let page = &mut self.pages[handle.page];
+        page.live_pixels -= padded.area() as u32;
+        page.holes.add(padded);
+        self.free_ids.push(id);
+        true
+    }
+
+    pub fn handle(&self, id: u32) -> Option<&AtlasHandle> {
+        self.handles.get(id as usize).and_then(Option::as_ref)
+    }
+
+    /// The texture for `page`, to bind when drawing anything on it.
+    pub fn page(&self, page: usize) -> Option<&LoadedImage<B>> {
+        self.pages.get(page).map(|page| &page.texture)
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn page_usage(&self, page: usize) -> Option<AtlasPageUsage> {
+        self.pages.get(page).map(|page| AtlasPageUsage {
+            live: page.live_pixels,
+            wasted: page.packed_pixels - page.live_pixels,
+            free: self.page_size * self.page_size - page.packed_pixels,
+        })
+    }
+
+    /// Packs the images on up to `max_pages` pages back together, most wasted
+    /// page first, and hands back the handles of every image that moved. Moving
+    /// means copying the page into a new image on the GPU. The old one might still
+    /// be in use by frames up to `last_used_frame`, so it sticks around until
+    /// `retire_frames` says they're done.
+    ///
+    /// The page keeps its descriptor set, which gets pointed at the new image, so
+    /// like `TextureWatcher::poll`, call this between frames once the frames
+    /// using the set have finished.
+    pub fn defragment<C: Capability + Supports<Transfer>>(
+        &mut self,
+        max_pages: usize,
+        last_used_frame: u64,
+        adapter: &Adapter<B>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &PipelineBundle<B>,
+    ) -> Result<Vec<AtlasHandle>, failure::Error> {
+        let mut wasteful: Vec<usize> = (0..self.pages.len())
+            .filter(|&page| self.pages[page].packed_pixels > self.pages[page].live_pixels)
+            .collect();
+        wasteful.sort_by_key(|&page| {
+            std::cmp::Reverse(self.pages[page].packed_pixels - self.pages[page].live_pixels)
+        });
+
+        let mut moved = Vec::new();
+        for page in wasteful.into_iter().take(max_pages) {
+            self.defragment_page(
+                page,
+                last_used_frame,
+                adapter,
+                command_pool,
+                command_queue,
+                pipeline_bundle,
+                &mut moved,
+            )?;
+        }
+
+        Ok(moved)
+    }
+
+    /// Destroys every old page image whose last frame is at or before `completed_frame`.
+    pub fn retire_frames(&mut self, completed_frame: u64) {
+        self.retired.drain_completed(completed_frame);
+    }
+
+    /// Makes every page again on `device` after the old one was lost, with
+    /// descriptor sets from `pipeline_bundle`. We don't keep images around on our
+    /// side, so `pixels` gives back the RGBA8 pixels of image `id` again, and they
+    /// go back exactly where they were. Handles stay the same, and nothing is
+    /// replaced unless everything could be made, so a failed recovery can be tried again.
+    #[allow(clippy::too_many_arguments)]
+    pub fn recover<C: Capability + Supports<Transfer>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+        mut pixels: impl FnMut(u32) -> Result<Vec<u8>, failure::Error>,
+    ) -> Result<(), failure::Error> {
+        let mut textures = Vec::with_capacity(self.pages.len());
+        for _ in &self.pages {
+            textures.push(self.blank_page(
+                adapter,
+                device,
+                command_pool,
+                command_queue,
+                pipeline_bundle,
+            )?);
+        }
+
+        for handle in self.handles.iter().flatten() {
+            let width = handle.rect.width() as u32;
+            let height = handle.rect.height() as u32;
+            let img = pixels(handle.id)?;
+            if img.len() < (width * height) as usize * 4 {
+                bail_invalid!(
+                    "TextureAtlas",
+                    "Image {} is {}x{}, but we were given {} bytes for it!",
+                    handle.id,
+                    width,
+                    height,
+                    img.len()
+                );
+            }
+            textures[handle.page].edit_image(
+                self.padded_rect(handle),
+                &extrude(&img, width, height, self.padding),
+                adapter,
+                command_pool,
+                command_queue,
+            )?;
+        }
+
+        //  The old device is gone, so nothing can still be using what we retired
+        self.retired.drain_all();
+        for (page, texture) in self.pages.iter_mut().zip(textures) {
+            page.texture = texture;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn defragment_page<C: Capability + Supports<Transfer>>(
+        &mut self,
+        page: usize,
+        last_used_frame: u64,
+        adapter: &Adapter<B>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &PipelineBundle<B>,
+        moved: &mut Vec<AtlasHandle>,
+    ) -> Result<(), failure::Error> {
+        //  Tallest first packs tightest on a skyline
+        let mut live: Vec<AtlasHandle> = self
+            .handles
+            .iter()
+            .filter_map(|handle| *handle)
+            .filter(|handle| handle.page == page)
+            .collect();
+        live.sort_by_key(|handle| std::cmp::Reverse((handle.rect.height(), handle.rect.width())));
+
+        let mut packer = SkylinePacker::new(self.page_size, self.page_size);
+        let mut placements = Vec::with_capacity(live.len());
+        for handle in &live {
+            let old = self.padded_rect(handle);
+            match packer.pack(old.width() as u32, old.height() as u32) {
+                Some((x, y)) => placements.push((
+                    old,
+                    RectInt::new(Vec2Int::new(x as i32, y as i32), old.size()),
+                )),
+                //  Packing the same images in a different order can come out worse,
+                //  in which case the page is fine as it is
+                None => return Ok(()),
+            }
+        }
+
+        if !live.is_empty() {
+            let old_page = &self.pages[page].texture;
+            let blank = self.blank_pixels()?;
+            let mut texture = LoadedImage::new(
+                adapter,
+                &old_page.device,
+                command_pool,
+                command_queue,
+                &blank,
+                self.page_size as usize,
+                self.page_size as usize,
+                self.options,
+            )?;
+
+            let regions: Vec<_> = placements
+                .iter()
+                .map(|&(old, new)| (old, new.origin()))
+                .collect();
+            texture.copy_from(old_page, &regions, command_pool, command_queue)?;
+
+            let old = self.pages[page]
+                .texture
+                .replace_with(texture, &pipeline_bundle.descriptor_layout)?;
+            self.retired.retire(last_used_frame, old);
+        }
+
+        let atlas_page = &mut self.pages[page];
+        atlas_page.packer = packer;
+        atlas_page.holes.clear();
+        atlas_page.packed_pixels = atlas_page.live_pixels;
+
+        for (handle, &(_, new)) in live.iter().zip(&placements) {
+            let moved_to = self.place(handle.id, page, new);
+            if moved_to != *handle {
+                moved.push(moved_to);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records that image `id` is in the `padded` rectangle of `page`.
+    fn place(&mut self, id: u32, page: usize, padded: RectInt) -> AtlasHandle {
+        let padding = Vec2Int::new(self.padding as i32, self.padding as i32);
+        let rect = RectInt::from_min_max(padded.min + padding, padded.max - padding);
+        let page_size = Vec2Int::new(self.page_size as i32, self.page_size as i32);
+        let handle = AtlasHandle {
+            id,
+            page,
+            rect,
+            uv: rect.to_uv(page_size),
+        };
+        self.handles[id as usize] = Some(handle);
+        handle
+    }
+
+    fn padded_rect(&self, handle: &AtlasHandle) -> RectInt {
+        let padding = Vec2Int::new(self.padding as i32, self.padding as i32);
+        RectInt::from_min_max(handle.rect.min - padding, handle.rect.max + padding)
+    }
+
+    fn add_page<C: Capability + Supports<Transfer>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+    ) -> Result<(), failure::Error> {
+        let texture = self.blank_page(
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            pipeline_bundle,
+        )?;
+
+        self.pages.push(AtlasPage {
+            texture,
+            packer: SkylinePacker::new(self.page_size, self.page_size),
+            holes: HoleList::new(),
+            packed_pixels: 0,
+            live_pixels: 0,
+        });
+        Ok(())
+    }
+
+    fn blank_page<C: Capability + Supports<Transfer>>(
+        &self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+    ) -> Result<LoadedImage<B>, failure::Error> {
+        let blank = self.blank_pixels()?;
+        LoadedImage::allocate_and_create(
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            pipeline_bundle,
+            &blank,
+            self.page_size as usize,
+            self.page_size as usize,
+            self.options,
+        )
+    }
+
+    fn blank_pixels(&self) -> Result<Vec<u8>, failure::Error> {
+        let bytes = (self.page_size as usize)
+            .checked_mul(self.page_size as usize)
+            .and_then(|pixels| pixels.checked_mul(4));
+        match bytes {
+            Some(bytes) => Ok(vec![0; bytes]),
+            None => bail_invalid!(
+                "TextureAtlas",
+                "A {}x{} atlas page is too big to upload!",
+                self.page_size,
+                self.page_size
+            ),
+        }
+    }
+}
+
+impl<B: Backend> AtlasPage<B> {
+    /// Finds room for a padded rectangle, in the snuggest hole that'll take it
+    /// if there is one and on the skyline if not.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        if let Some(corner) = self.holes.take(width, height) {
+            return Some(corner);
+        }
+
+        let corner = self.packer.pack(width, height)?;
+        self.packed_pixels += width * height;
+        Some(corner)
+    }
+}
+
+/// Copies an RGBA8 image into the middle of one `padding` pixels bigger on every
+/// side, filling the border with the nearest edge pixel.
+fn extrude(img: &[u8], width: u32, height: u32, padding: u32) -> Vec<u8> {
+    let padded_width = width + padding * 2;
+    let padded_height = height + padding * 2;
+    let mut extruded = Vec::with_capacity((padded_width * padded_height) as usize * 4);
+
+    for y in 0..padded_height {
+        let source_y = y.saturating_sub(padding).min(height - 1);
+        for x in 0..padded_width {
+            let source_x = x.saturating_sub(padding).min(width - 1);
+            let i = ((source_y * width + source_x) * 4) as usize;
+            extruded.extend_from_slice(&img[i..i + 4]);
+        }
+    }
+
+    extruded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        extrude, merge_holes, AtlasHandle, AtlasPageUsage, HoleList, SkylinePacker, TextureAtlas,
+    };
+    use crate::{
+        mock_backend::{self, Mock},
+        DeviceContext, PipelineBundle, Rect, RectInt, Vec2, Vec2Int,
+    };
+    use gfx_hal::image::Filter;
+    use std::sync::Arc;
+
+    fn insert(
+        atlas: &mut TextureAtlas<Mock>,
+        device: &Arc<DeviceContext<Mock>>,
+        pipeline_bundle: &mut PipelineBundle<Mock>,
+        width: u32,
+        height: u32,
+    ) -> AtlasHandle {
+        atlas
+            .insert(
+                &vec![0xFF; (width * height) as usize * 4],
+                width,
+                height,
+                &mock_backend::adapter(),
+                device,
+                &mut mock_backend::command_pool(device),
+                &mut mock_backend::command_queue(),
+                pipeline_bundle,
+            )
+            .unwrap()
+    }
+
+    fn images(device: &DeviceContext<Mock>) -> usize {
+        device
+            .live_objects()
+            .iter()
+            .find(|&&(kind, _)| kind == "Image")
+            .map_or(0, |&(_, count)| count)
+    }
+
+    fn overlaps(a: (u32, u32, u32, u32), b: (u32, u32, u32, u32)) -> bool {
+        a.0 < b.0 + b.2 && b.0 < a.0 + a.2 && a.1 < b.1 + b.3 && b.1 < a.1 + a.3
+    }
+
+    #[test]
+    fn skyline_packs_without_overlapping_until_full() {
+        let mut packer = SkylinePacker::new(64, 64);
+        let mut placed = Vec::new();
+        let sizes = [(16, 8), (8, 16), (32, 4), (5, 5), (20, 11), (13, 7)];
+
+        for &(width, height) in sizes.iter().cycle() {
+            match packer.pack(width, height) {
+                Some((x, y)) => {
+                    assert!(x + width <= 64 && y + height <= 64);
+                    let rect = (x, y, width, height);
+                    assert!(placed.iter().all(|&other| !overlaps(rect, other)));
+                    placed.push(rect);
+                }
+                None => break,
+            }
+        }
+
+        let used: u32 = placed.iter().map(|&(_, _, w, h)| w * h).sum();
+        assert!(used > 64 * 64 / 2, "only used {} pixels", used);
+        assert_eq!(packer.pack(65, 1), None);
+    }
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> RectInt {
+        RectInt::new(Vec2Int::new(x, y), Vec2Int::new(width, height))
+    }
+
+    #[test]
+    fn neighbouring_holes_merge() {
+        assert_eq!(
+            merge_holes(&rect(0, 0, 8, 8), &rect(0, 8, 8, 4)),
+            Some(rect(0, 0, 8, 12))
+        );
+        assert_eq!(
+            merge_holes(&rect(8, 0, 8, 8), &rect(0, 0, 8, 8)),
+            Some(rect(0, 0, 16, 8))
+        );
+        assert_eq!(merge_holes(&rect(0, 0, 8, 8), &rect(8, 8, 8, 8)), None);
+        assert_eq!(merge_holes(&rect(0, 0, 8, 8), &rect(8, 0, 8, 4)), None);
+    }
+
+    #[test]
+    fn holes_keep_what_is_left_over_and_merge_back_together() {
+        let mut holes = HoleList::new();
+        holes.add(rect(0, 0, 8, 8));
+        assert_eq!(holes.take(9, 1), None);
+
+        //  Leaves 2x6 to the right and 8x2 below
+        assert_eq!(holes.take(6, 6), Some((0, 0)));
+        assert_eq!(holes.len(), 2);
+        assert_eq!(holes.take(8, 2), Some((0, 6)));
+        assert_eq!(holes.take(2, 6), Some((6, 0)));
+        assert!(holes.is_empty());
+
+        //  Four quarters merge back into the whole
+        for &(x, y) in &[(0, 0), (8, 8), (8, 0), (0, 8)] {
+            holes.add(rect(x, y, 8, 8));
+        }
+        assert_eq!(holes.len(), 1);
+        assert_eq!(holes.take(16, 16), Some((0, 0)));
+    }
+
+    #[test]
+    fn extrusion_stretches_the_edges() {
+        //  A 2x1 image: red then green
+        let img = [255, 0, 0, 255, 0, 255, 0, 255];
+        let extruded = extrude(&img, 2, 1, 1);
+        let pixel = |x: usize, y: usize| &extruded[(y * 4 + x) * 4..(y * 4 + x) * 4 + 4];
+
+        assert_eq!(extruded.len(), 4 * 3 * 4);
+        for y in 0..3 {
+            assert_eq!(pixel(0, y), &img[0..4]);
+            assert_eq!(pixel(1, y), &img[0..4]);
+            assert_eq!(pixel(2, y), &img[4..8]);
+            assert_eq!(pixel(3, y), &img[4..8]);
+        }
+    }
+
+    #[test]
+    fn spills_onto_a_new_page_when_one_fills_up() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 4);
+        let mut atlas = TextureAtlas::<Mock>::new(16, 1, Filter::Linear);
+
+        let handles: Vec<_> = (0..5)
+            .map(|_| insert(&mut atlas, &device, &mut pipeline_bundle, 6, 6))
+            .collect();
+
+        //  Four 8x8 padded sprites fill a 16x16 page
+        assert_eq!(atlas.page_count(), 2);
+        assert!(handles[..4].iter().all(|handle| handle.page == 0));
+        assert_eq!(handles[4].page, 1);
+        assert_eq!(
+            handles[0].uv,
+            Rect::from_min_max(Vec2::new(1.0, 1.0) / 16.0, Vec2::new(7.0, 7.0) / 16.0)
+        );
+        assert_eq!(atlas.handle(4), Some(&handles[4]));
+
+        let too_big = atlas.insert(
+            &[0; 15 * 15 * 4],
+            15,
+            15,
+            &mock_backend::adapter(),
+            &device,
+            &mut mock_backend::command_pool(&device),
+            &mut mock_backend::command_queue(),
+            &mut pipeline_bundle,
+        );
+        assert!(too_big.is_err());
+
+        //  Padding doesn't overflow the size before it's checked
+        let overflowing = atlas.insert(
+            &[0; 4],
+            u32::MAX,
+            1,
+            &mock_backend::adapter(),
+            &device,
+            &mut mock_backend::command_pool(&device),
+            &mut mock_backend::command_queue(),
+            &mut pipeline_bundle,
+        );
+        assert!(overflowing.is_err());
+        assert_eq!(atlas.page_count(), 2);
+    }
+
+    #[test]
+    fn removed_images_leave_holes_for_new_ones() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 4);
+        let mut atlas = TextureAtlas::<Mock>::new(16, 0, Filter::Linear);
+
+        let first = insert(&mut atlas, &device, &mut pipeline_bundle, 8, 8);
+        insert(&mut atlas, &device, &mut pipeline_bundle, 8, 8);
+        assert!(atlas.remove(first.id));
+        assert!(!atlas.remove(first.id));
+        assert_eq!(atlas.handle(first.id), None);
+        assert_eq!(
+            atlas.page_usage(0),
+            Some(AtlasPageUsage {
+                live: 64,
+                wasted: 64,
+                free: 128
+            })
+        );
+
+        let refill = insert(&mut atlas, &device, &mut pipeline_bundle, 6, 6);
+        assert_eq!((refill.id, refill.page), (first.id, 0));
+        assert_eq!(refill.rect.origin(), first.rect.origin());
+        assert_eq!(atlas.page_usage(0).unwrap().free, 128);
+
+        //  What the refill didn't need is still free
+        let leftover = insert(&mut atlas, &device, &mut pipeline_bundle, 8, 2);
+        assert_eq!(
+            leftover.rect.origin(),
+            first.rect.origin() + Vec2Int::new(0, 6)
+        );
+        assert_eq!(atlas.page_usage(0).unwrap().free, 128);
+    }
+
+    #[test]
+    fn defragment_packs_live_images_together_on_a_new_image() {
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 4);
+        let mut atlas = TextureAtlas::<Mock>::new(16, 0, Filter::Linear);
+        let handles: Vec<_> = (0..4)
+            .map(|_| insert(&mut atlas, &device, &mut pipeline_bundle, 8, 8))
+            .collect();
+
+        //  Empty the top row, so the bottom one has to move up
+        atlas.remove(handles[0].id);
+        atlas.remove(handles[1].id);
+        let moved = atlas
+            .defragment(
+                1,
+                7,
+                &mock_backend::adapter(),
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+                &pipeline_bundle,
+            )
+            .unwrap();
+
+        assert_eq!(moved.len(), 2);
+        for handle in &moved {
+            assert_eq!(handle.rect.min.y, 0);
+            assert_eq!(atlas.handle(handle.id), Some(handle));
+        }
+        assert_eq!(atlas.page_usage(0).unwrap().wasted, 0);
+
+        //  The old page image waits on its frames
+        assert_eq!(images(&device), 2);
+        atlas.retire_frames(6);
+        assert_eq!(images(&device), 2);
+        atlas.retire_frames(7);
+        assert_eq!(images(&device), 1);
+
+        let wide = insert(&mut atlas, &device, &mut pipeline_bundle, 16, 8);
+        assert_eq!((wide.page, wide.rect.origin()), (0, Vec2Int::new(0, 8)));
+        assert_eq!(atlas.page_count(), 1);
+    }
+
+    #[test]
+    fn recovery_puts_every_image_back_where_it_was() {
+        let lost = mock_backend::device();
+        let mut lost_bundle = mock_backend::pipeline_bundle(&lost, 4);
+        let mut atlas = TextureAtlas::new(16, 1, Filter::Nearest);
+        let kept = insert(&mut atlas, &lost, &mut lost_bundle, 6, 6);
+        let removed = insert(&mut atlas, &lost, &mut lost_bundle, 6, 6);
+        insert(&mut atlas, &lost, &mut lost_bundle, 14, 14);
+        atlas.remove(removed.id);
+
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 4);
+        let mut asked_for = Vec::new();
+        atlas
+            .recover(
+                &mock_backend::adapter(),
+                &device,
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+                &mut pipeline_bundle,
+                |id| {
+                    asked_for.push(id);
+                    let size = if id == 2 { 14 } else { 6 };
+                    Ok(vec![0xFF; size * size * 4])
+                },
+            )
+            .unwrap();
+
+        asked_for.sort();
+        assert_eq!(asked_for, vec![0, 2]);
+        assert_eq!(atlas.handle(kept.id), Some(&kept));
+        assert_eq!(atlas.page_count(), 2);
+        assert_eq!(images(&device), 2);
+        assert_eq!(images(&lost), 0);
+    }
+}
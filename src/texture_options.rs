@@ -0,0 +1,181 @@
+use gfx_hal::{
+    format::Format,
+    image::{Anisotropic, Filter, Lod, PackedColor, SamplerInfo, WrapMode},
+};
+
+/// How a texture gets sampled. It's plain data, so it can go in asset files
+/// with the `serde` feature, and becomes a `SamplerInfo` when the sampler's made.
+/// Anything a file leaves out comes from `SamplerDesc::default()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct SamplerDesc {
+    pub min_filter: Filter,
+    pub mag_filter: Filter,
+    pub mip_filter: Filter,
+    /// How U, V and W outside `[0, 1]` are handled, in that order.
+    pub wrap: [WrapMode; 3],
+    /// What `WrapMode::Border` reads, as RGBA from 0 to 1.
+    pub border: [f32; 4],
+    /// Most anisotropic filtering samples to take, or `None` for none at all.
+    pub anisotropy: Option<u8>,
+}
+
+impl SamplerDesc {
+    /// Filters with `filter` everywhere and wraps every axis with `wrap`, like
+    /// `SamplerInfo::new`.
+    pub fn new(filter: Filter, wrap: WrapMode) -> Self {
+        SamplerDesc {
+            min_filter: filter,
+            mag_filter: filter,
+            mip_filter: filter,
+            wrap: [wrap; 3],
+            border: [0.0; 4],
+            anisotropy: None,
+        }
+    }
+}
+
+/// Linear and clamped, which is what every texture got before there was a choice.
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        SamplerDesc::new(Filter::Linear, WrapMode::Clamp)
+    }
+}
+
+impl From<Filter> for SamplerDesc {
+    fn from(filter: Filter) -> SamplerDesc {
+        SamplerDesc::new(filter, WrapMode::Clamp)
+    }
+}
+
+impl From<SamplerDesc> for SamplerInfo {
+    fn from(desc: SamplerDesc) -> SamplerInfo {
+        SamplerInfo {
+            min_filter: desc.min_filter,
+            mag_filter: desc.mag_filter,
+            mip_filter: desc.mip_filter,
+            wrap_mode: (desc.wrap[0], desc.wrap[1], desc.wrap[2]),
+            lod_bias: Lod::ZERO,
+            lod_range: Lod::ZERO..Lod::MAX,
+            comparison: None,
+            border: PackedColor::from(desc.border),
+            normalized: true,
+            anisotropic: match desc.anisotropy {
+                Some(samples) => Anisotropic::On(samples),
+                None => Anisotropic::Off,
+            },
+        }
+    }
+}
+
+/// Everything about how a `LoadedImage` is made, apart from its pixels. Anything
+/// a file leaves out comes from `TextureOptions::default()`.
+///
+/// A `Filter` or a `SamplerDesc` on its own turns into these, so they can be
+/// passed anywhere these are wanted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct TextureOptions {
+    pub sampler: SamplerDesc,
+    /// Whether the pixels are colours in sRGB, which the GPU converts to linear
+    /// when sampling. Turn it off for data like normal maps, which are linear already.
+    pub srgb: bool,
+}
+
+impl TextureOptions {
+    pub fn format(&self) -> Format {
+        if self.srgb {
+            Format::Rgba8Srgb
+        } else {
+            Format::Rgba8Unorm
+        }
+    }
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        TextureOptions {
+            sampler: SamplerDesc::default(),
+            srgb: true,
+        }
+    }
+}
+
+impl From<SamplerDesc> for TextureOptions {
+    fn from(sampler: SamplerDesc) -> TextureOptions {
+        TextureOptions {
+            sampler,
+            ..TextureOptions::default()
+        }
+    }
+}
+
+impl From<Filter> for TextureOptions {
+    fn from(filter: Filter) -> TextureOptions {
+        SamplerDesc::from(filter).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SamplerDesc, TextureOptions};
+    use gfx_hal::{
+        format::Format,
+        image::{Anisotropic, Filter, SamplerInfo, WrapMode},
+    };
+
+    #[test]
+    fn a_filter_on_its_own_makes_the_same_sampler_as_before() {
+        let info = SamplerInfo::from(TextureOptions::from(Filter::Nearest).sampler);
+        let before = SamplerInfo::new(Filter::Nearest, WrapMode::Clamp);
+
+        assert_eq!(info.min_filter, before.min_filter);
+        assert_eq!(info.wrap_mode, before.wrap_mode);
+        assert_eq!(info.border, before.border);
+        assert_eq!(info.anisotropic, before.anisotropic);
+        assert_eq!(
+            TextureOptions::from(Filter::Nearest).format(),
+            Format::Rgba8Srgb
+        );
+    }
+
+    #[test]
+    fn sampler_descs_fill_in_every_field() {
+        let desc = SamplerDesc {
+            wrap: [WrapMode::Tile, WrapMode::Mirror, WrapMode::Border],
+            border: [1.0, 0.0, 0.0, 1.0],
+            anisotropy: Some(8),
+            ..SamplerDesc::new(Filter::Linear, WrapMode::Clamp)
+        };
+        let info = SamplerInfo::from(desc);
+
+        assert_eq!(
+            info.wrap_mode,
+            (WrapMode::Tile, WrapMode::Mirror, WrapMode::Border)
+        );
+        assert_eq!(info.border.0, 0xFF00_00FF);
+        assert_eq!(info.anisotropic, Anisotropic::On(8));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn options_round_trip_and_default_what_is_missing() {
+        let options = TextureOptions {
+            sampler: SamplerDesc::new(Filter::Nearest, WrapMode::Tile),
+            srgb: false,
+        };
+        let json = serde_json::to_string(&options).unwrap();
+        assert_eq!(
+            serde_json::from_str::<TextureOptions>(&json).unwrap(),
+            options
+        );
+
+        let sparse: TextureOptions =
+            serde_json::from_str(r#"{ "sampler": { "mag_filter": "Nearest" } }"#).unwrap();
+        assert_eq!(sparse.sampler.mag_filter, Filter::Nearest);
+        assert_eq!(sparse.sampler.min_filter, Filter::Linear);
+        assert!(sparse.srgb);
+    }
+}
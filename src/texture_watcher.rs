@@ -0,0 +1,403 @@
+use super::{
+    DeviceContext, LoadedImage, PipelineBundle, RenderError, RetirementQueue, TextureOptions,
+};
+use gfx_hal::{
+    adapter::Adapter, pool::CommandPool, Backend, Capability, CommandQueue, Supports, Transfer,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+/// What happened to a texture when its file changed.
+#[derive(Debug)]
+pub enum TextureReload {
+    /// The new pixels went straight into the image we had.
+    Uploaded(usize),
+    /// The file changed size, so the texture has a new image. The old one is
+    /// waiting on its frames.
+    Reallocated(usize),
+    /// The texture still has its old pixels, since the file couldn't be loaded.
+    Failed(usize, failure::Error),
+}
+
+struct WatchedTexture<B: Backend> {
+    texture: LoadedImage<B>,
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    options: TextureOptions,
+}
+
+/// Textures loaded from files, which get loaded again whenever their files
+/// change. A texture keeps its handle and its descriptor set through a reload,
+/// so nothing holding onto either has to know it happened.
+///
+/// Every file is decoded to RGBA8, so only a change in size needs a new image.
+/// Then `poll` rewrites the texture's descriptor set, so call it between frames
+/// once the frames using the set have finished, just like `BindlessTable` wants
+/// for `register_texture`.
+pub struct TextureWatcher<B: Backend> {
+    textures: Vec<WatchedTexture<B>>,
+    retired: RetirementQueue<LoadedImage<B>>,
+}
+
+impl<B: Backend> TextureWatcher<B> {
+    pub fn new() -> Self {
+        TextureWatcher {
+            textures: Vec::new(),
+            retired: RetirementQueue::new(),
+        }
+    }
+
+    /// Loads the image at `path` into a texture with a descriptor set from
+    /// `pipeline_bundle`, and keeps an eye on the file. Gives back the texture's handle.
+    #[allow(clippy::too_many_arguments)]
+    pub fn register_file<C: Capability + Supports<Transfer>>(
+        &mut self,
+        path: impl Into<PathBuf>,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+        options: impl Into<TextureOptions>,
+    ) -> Result<usize, failure::Error> {
+        let path = path.into();
+        let options = options.into();
+        let modified = modified(&path);
+        let image = decode_texture(&path)?;
+
+        let texture = LoadedImage::allocate_and_create(
+            adapter,
+            device,
+            command_pool,
+            command_queue,
+            pipeline_bundle,
+            &image,
+            image.width() as usize,
+            image.height() as usize,
+            options,
+        )?;
+
+        self.textures.push(WatchedTexture {
+            texture,
+            path,
+            modified,
+            options,
+        });
+        Ok(self.textures.len() - 1)
+    }
+
+    pub fn texture(&self, handle: usize) -> Option<&LoadedImage<B>> {
+        self.textures.get(handle).map(|watched| &watched.texture)
+    }
+
+    pub fn path(&self, handle: usize) -> Option<&Path> {
+        self.textures
+            .get(handle)
+            .map(|watched| watched.path.as_path())
+    }
+
+    /// Loads every texture whose file has changed since we last looked.
+    /// `pipeline_bundle` needs to be the one the textures were registered with,
+    /// and frames up to `last_used_frame` might still be using the old images.
+    pub fn poll<C: Capability + Supports<Transfer>>(
+        &mut self,
+        last_used_frame: u64,
+        adapter: &Adapter<B>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &PipelineBundle<B>,
+    ) -> Vec<TextureReload> {
+        let mut reloads = Vec::new();
+        for (handle, watched) in self.textures.iter_mut().enumerate() {
+            //  A file that's gone is probably halfway through being saved
+            let now = modified(&watched.path);
+            if now.is_none() || now == watched.modified {
+                continue;
+            }
+            watched.modified = now;
+
+            let image = match decode_texture(&watched.path) {
+                Ok(image) => image,
+                Err(e) => {
+                    reloads.push(TextureReload::Failed(handle, e));
+                    continue;
+                }
+            };
+
+            let texture = &mut watched.texture;
+            if (texture.width, texture.height) == image.dimensions() {
+                match texture.edit_image(
+                    texture.bounds(),
+                    &image,
+                    adapter,
+                    command_pool,
+                    command_queue,
+                ) {
+                    Ok(()) => reloads.push(TextureReload::Uploaded(handle)),
+                    Err(e) => reloads.push(TextureReload::Failed(handle, e)),
+                }
+            } else {
+                match texture.reallocate(
+                    adapter,
+                    command_pool,
+                    command_queue,
+                    &pipeline_bundle.descriptor_layout,
+                    &image,
+                    image.width() as usize,
+                    image.height() as usize,
+                    watched.options,
+                ) {
+                    Ok(old) => {
+                        self.retired.retire(last_used_frame, old);
+                        reloads.push(TextureReload::Reallocated(handle));
+                    }
+                    Err(e) => reloads.push(TextureReload::Failed(handle, e)),
+                }
+            }
+        }
+
+        reloads
+    }
+
+    /// Destroys every old image whose last frame is at or before `completed_frame`.
+    pub fn retire_frames(&mut self, completed_frame: u64) {
+        self.retired.drain_completed(completed_frame);
+    }
+
+    /// Loads every texture again on `device` after the old one was lost, with
+    /// descriptor sets from `pipeline_bundle`, which has to have been made on
+    /// `device` too. Handles stay the same, and nothing is replaced unless every
+    /// file could be loaded, so a failed recovery can be tried again.
+    pub fn recover<C: Capability + Supports<Transfer>>(
+        &mut self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        command_pool: &mut CommandPool<B, C>,
+        command_queue: &mut CommandQueue<B, C>,
+        pipeline_bundle: &mut PipelineBundle<B>,
+    ) -> Result<(), failure::Error> {
+        let mut textures = Vec::with_capacity(self.textures.len());
+        for watched in &self.textures {
+            let modified = modified(&watched.path);
+            let image = decode_texture(&watched.path)?;
+            let texture = LoadedImage::allocate_and_create(
+                adapter,
+                device,
+                command_pool,
+                command_queue,
+                pipeline_bundle,
+                &image,
+                image.width() as usize,
+                image.height() as usize,
+                watched.options,
+            )?;
+            textures.push((texture, modified));
+        }
+
+        //  The old device is gone, so nothing can still be using what we retired
+        self.retired.drain_all();
+        for (watched, (texture, modified)) in self.textures.iter_mut().zip(textures) {
+            watched.texture = texture;
+            watched.modified = modified;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Backend> Default for TextureWatcher<B> {
+    fn default() -> Self {
+        TextureWatcher::new()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub(crate) fn decode_texture(path: &Path) -> Result<image::RgbaImage, failure::Error> {
+    let image = image::open(path).map_err(|e| {
+        RenderError::load(
+            "Texture",
+            format!("Couldn't load the texture {}! => {}", path.display(), e),
+        )
+    })?;
+    Ok(image.to_rgba())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TextureReload, TextureWatcher};
+    use crate::{
+        mock_backend::{self, Mock},
+        DeviceContext, PipelineBundle,
+    };
+    use gfx_hal::image::Filter;
+    use std::{
+        env, fs,
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    /// Stamps `path` with a modification time `age` seconds after the epoch, so
+    /// we don't depend on how fine-grained the file system's clock is.
+    fn touch(path: &PathBuf, age: u64) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(age))
+            .unwrap();
+    }
+
+    fn save_png(path: &PathBuf, size: u32, age: u64) {
+        image::RgbaImage::new(size, size).save(path).unwrap();
+        touch(path, age);
+    }
+
+    struct Fixture {
+        device: Arc<DeviceContext<Mock>>,
+        pipeline_bundle: PipelineBundle<Mock>,
+        watcher: TextureWatcher<Mock>,
+        path: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let device = mock_backend::device();
+            let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+            let path = env::temp_dir().join(format!("texture_watcher_{}.png", name));
+            save_png(&path, 4, 1);
+
+            let mut watcher = TextureWatcher::new();
+            let handle = watcher
+                .register_file(
+                    &path,
+                    &mock_backend::adapter(),
+                    &device,
+                    &mut mock_backend::command_pool(&device),
+                    &mut mock_backend::command_queue(),
+                    &mut pipeline_bundle,
+                    Filter::Nearest,
+                )
+                .unwrap();
+            assert_eq!(handle, 0);
+
+            Fixture {
+                device,
+                pipeline_bundle,
+                watcher,
+                path,
+            }
+        }
+
+        fn poll(&mut self, last_used_frame: u64) -> Vec<TextureReload> {
+            self.watcher.poll(
+                last_used_frame,
+                &mock_backend::adapter(),
+                &mut mock_backend::command_pool(&self.device),
+                &mut mock_backend::command_queue(),
+                &self.pipeline_bundle,
+            )
+        }
+
+        fn images(&self) -> usize {
+            self.device
+                .live_objects()
+                .iter()
+                .find(|&&(kind, _)| kind == "Image")
+                .map_or(0, |&(_, count)| count)
+        }
+    }
+
+    #[test]
+    fn uploads_into_the_same_image_when_the_size_is_the_same() {
+        let mut fixture = Fixture::new("same_size");
+        assert!(fixture.poll(1).is_empty());
+        let before = fixture.device.live_objects();
+
+        touch(&fixture.path, 2);
+        match fixture.poll(1).as_slice() {
+            [TextureReload::Uploaded(0)] => {}
+            other => panic!("expected an upload, got {:?}", other),
+        }
+        assert_eq!(fixture.device.live_objects(), before);
+        fs::remove_file(&fixture.path).unwrap();
+    }
+
+    #[test]
+    fn reallocates_and_keeps_the_descriptor_set_when_the_size_changes() {
+        let mut fixture = Fixture::new("new_size");
+        let set = &**fixture
+            .watcher
+            .texture(0)
+            .unwrap()
+            .descriptor_set
+            .as_ref()
+            .unwrap() as *const _;
+        let writes = fixture.device.descriptor_writes().len();
+
+        save_png(&fixture.path, 8, 2);
+        match fixture.poll(1).as_slice() {
+            [TextureReload::Reallocated(0)] => {}
+            other => panic!("expected a reallocation, got {:?}", other),
+        }
+
+        let texture = fixture.watcher.texture(0).unwrap();
+        assert_eq!((texture.width, texture.height), (8, 8));
+        assert_eq!(&**texture.descriptor_set.as_ref().unwrap() as *const _, set);
+        assert_eq!(fixture.device.descriptor_writes().len(), writes * 2);
+
+        assert_eq!(fixture.images(), 2);
+        fixture.watcher.retire_frames(1);
+        assert_eq!(fixture.images(), 1);
+        fs::remove_file(&fixture.path).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_old_pixels_when_the_file_is_broken() {
+        let mut fixture = Fixture::new("broken");
+        let before = fixture.device.live_objects();
+
+        fs::write(&fixture.path, b"not a png").unwrap();
+        touch(&fixture.path, 2);
+        match fixture.poll(1).as_slice() {
+            [TextureReload::Failed(0, _)] => {}
+            other => panic!("expected a failure, got {:?}", other),
+        }
+        assert!(fixture.poll(1).is_empty());
+
+        let texture = fixture.watcher.texture(0).unwrap();
+        assert_eq!((texture.width, texture.height), (4, 4));
+        assert_eq!(fixture.device.live_objects(), before);
+        fs::remove_file(&fixture.path).unwrap();
+    }
+
+    #[test]
+    fn recovery_loads_every_file_again_on_the_new_device() {
+        let mut fixture = Fixture::new("recover");
+
+        let device = mock_backend::device();
+        let mut pipeline_bundle = mock_backend::pipeline_bundle(&device, 1);
+        fixture
+            .watcher
+            .recover(
+                &mock_backend::adapter(),
+                &device,
+                &mut mock_backend::command_pool(&device),
+                &mut mock_backend::command_queue(),
+                &mut pipeline_bundle,
+            )
+            .unwrap();
+
+        let texture = fixture.watcher.texture(0).unwrap();
+        assert!(Arc::ptr_eq(&texture.device, &device));
+        assert!(texture.descriptor_set.is_some());
+        assert_eq!(fixture.images(), 0);
+        fs::remove_file(&fixture.path).unwrap();
+    }
+}
@@ -0,0 +1,412 @@
+use super::{BufferBundle, DeviceContext, HalResultExt, RenderError};
+use core::mem::ManuallyDrop;
+use gfx_hal::{
+    adapter::{Adapter, PhysicalDevice},
+    buffer,
+    command::DescriptorSetOffset,
+    device::Device,
+    pso::{
+        Descriptor, DescriptorRangeDesc, DescriptorSetLayoutBinding, DescriptorSetWrite,
+        DescriptorType, ShaderStageFlags,
+    },
+    Backend, DescriptorPool,
+};
+use std::{mem, ops::Deref, sync::Arc};
+
+/// One big mapped uniform buffer, cut into a region per frame in flight. Every
+/// draw pushes its uniforms into the current frame's region and gets back a
+/// dynamic offset, so all of them can share the one `UniformBufferDynamic`
+/// descriptor set this ring owns.
+pub struct UniformRing<B: Backend> {
+    pub bundle: BufferBundle<B>,
+    pub descriptor_set_layout: ManuallyDrop<B::DescriptorSetLayout>,
+    pub descriptor_pool: ManuallyDrop<B::DescriptorPool>,
+    pub descriptor_set: ManuallyDrop<B::DescriptorSet>,
+    pub frames_in_flight: usize,
+    pub frame_size: u64,
+    pub block_size: u64,
+    pub alignment: u64,
+    /// The device's `non_coherent_atom_size`, which flushes get widened out to.
+    pub atom_size: u64,
+    pub stage_flags: ShaderStageFlags,
+    pub current_frame: usize,
+    pub cursor: u64,
+    pub device: Arc<DeviceContext<B>>,
+}
+
+impl<B: Backend> UniformRing<B> {
+    /// `block_size` is the biggest single push you'll make (it's the range the
+    /// shader sees) and `bytes_per_frame` is how much you'll push in a frame, total.
+    pub fn new(
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+        frames_in_flight: usize,
+        bytes_per_frame: u64,
+        block_size: u64,
+        stage_flags: ShaderStageFlags,
+    ) -> Result<Self, failure::Error> {
+        if frames_in_flight == 0 || block_size == 0 {
+            bail_invalid!(
+                "UniformRing",
+                "A uniform ring needs at least one frame and a non-empty block!"
+            );
+        }
+
+        let limits = adapter.physical_device.limits();
+        let alignment = limits.min_uniform_buffer_offset_alignment.max(1);
+        let atom_size = limits.non_coherent_atom_size as u64;
+        if block_size > limits.max_uniform_buffer_range {
+            bail_invalid!(
+                "UniformRing",
+                "Uniform block of {} bytes is bigger than the device's max uniform range ({})!",
+                block_size,
+                limits.max_uniform_buffer_range
+            );
+        }
+
+        //  Every push takes up a whole aligned block, however small it was
+        let blocks_per_frame = bytes_per_frame.div_ceil(block_size).max(1);
+        let frame_size = blocks_per_frame * align_up(block_size, alignment);
+        let bundle = BufferBundle::new(
+            adapter,
+            device,
+            frame_size * frames_in_flight as u64,
+            buffer::Usage::UNIFORM,
+            true,
+        )?;
+
+        unsafe {
+            let mut partial: PartialRing<'_, B> = PartialRing {
+                device: &device.device,
+                descriptor_set_layout: None,
+                descriptor_pool: None,
+            };
+
+            let descriptor_set_layout = partial.descriptor_set_layout.get_or_insert(
+                device
+                    .create_descriptor_set_layout(
+                        &[UniformRing::<B>::layout_binding(0, stage_flags)],
+                        &[],
+                    )
+                    .making("DescriptorSetLayout", None)?,
+            );
+
+            let descriptor_pool = partial.descriptor_pool.get_or_insert(
+                device
+                    .create_descriptor_pool(
+                        1,
+                        [DescriptorRangeDesc {
+                            ty: DescriptorType::UniformBufferDynamic,
+                            count: 1,
+                        }],
+                        gfx_hal::pso::DescriptorPoolCreateFlags::empty(),
+                    )
+                    .making("DescriptorPool", None)?,
+            );
+
+            //  The pool takes the set with it if anything after this fails
+            let descriptor_set = descriptor_pool
+                .allocate_set(descriptor_set_layout)
+                .making("DescriptorSet", None)?;
+
+            // The range is a single block -- the dynamic offset picks which one.
+            device.write_descriptor_sets(vec![DescriptorSetWrite {
+                set: &descriptor_set,
+                binding: 0,
+                array_offset: 0,
+                descriptors: Some(Descriptor::Buffer(
+                    bundle.buffer.deref(),
+                    Some(0)..Some(block_size),
+                )),
+            }]);
+
+            let (descriptor_set_layout, descriptor_pool) = partial.finish();
+            device.track("UniformRing");
+            Ok(UniformRing {
+                bundle,
+                descriptor_set_layout: manual_new!(descriptor_set_layout),
+                descriptor_pool: manual_new!(descriptor_pool),
+                descriptor_set: manual_new!(descriptor_set),
+                frames_in_flight,
+                frame_size,
+                block_size,
+                alignment,
+                atom_size,
+                stage_flags,
+                current_frame: 0,
+                cursor: 0,
+                device: Arc::clone(device),
+            })
+        }
+    }
+
+    /// Makes the ring again on `device` after the old one was lost, with room for
+    /// as many blocks a frame as before. It starts over on an empty first frame,
+    /// and the descriptor set is a new one, so bind that from now on.
+    pub fn recover(
+        &mut self,
+        adapter: &Adapter<B>,
+        device: &Arc<DeviceContext<B>>,
+    ) -> Result<(), failure::Error> {
+        let blocks_per_frame = self.frame_size / align_up(self.block_size, self.alignment);
+        *self = UniformRing::new(
+            adapter,
+            device,
+            self.frames_in_flight,
+            blocks_per_frame * self.block_size,
+            self.block_size,
+            self.stage_flags,
+        )?;
+        Ok(())
+    }
+
+    /// The binding to put in any descriptor set layout that wants to read from a ring.
+    pub fn layout_binding(
+        binding: u32,
+        stage_flags: ShaderStageFlags,
+    ) -> DescriptorSetLayoutBinding {
+        DescriptorSetLayoutBinding {
+            binding,
+            ty: DescriptorType::UniformBufferDynamic,
+            count: 1,
+            stage_flags,
+            immutable_samplers: false,
+        }
+    }
+
+    /// Moves onto the region for `frame_index`. Only call this once the frame
+    /// which last used that region has finished on the GPU!
+    pub fn begin_frame(&mut self, frame_index: usize) {
+        self.current_frame = frame_index % self.frames_in_flight;
+        self.cursor = 0;
+    }
+
+    pub fn has_room(&self, size: u64) -> bool {
+        self.cursor + size <= self.frame_size
+    }
+
+    /// Copies `value` into this frame's region and returns the dynamic offset to
+    /// bind it with.
+    pub fn push<T: Copy>(&mut self, value: &T) -> Result<DescriptorSetOffset, failure::Error> {
+        self.push_slice(std::slice::from_ref(value))
+    }
+
+    /// Like `push`, but for an array of `T` which the shader reads as one block.
+    pub fn push_slice<T: Copy>(
+        &mut self,
+        values: &[T],
+    ) -> Result<DescriptorSetOffset, failure::Error> {
+        let size = mem::size_of_val(values) as u64;
+        if size > self.block_size {
+            bail_invalid!(
+                "UniformRing",
+                "Pushed {} bytes of uniforms, but blocks are only {} bytes!",
+                size,
+                self.block_size
+            );
+        }
+
+        // The descriptor always reads a whole block, so that's what we reserve.
+        let reserved = align_up(self.block_size, self.alignment);
+        if !self.has_room(reserved) {
+            return Err(RenderError::exhausted(
+                "UniformRing",
+                self.frame_size,
+                format!(
+                    "Uniform ring is out of room this frame! ({} of {} bytes used)",
+                    self.cursor, self.frame_size
+                ),
+            )
+            .into());
+        }
+
+        let offset = self.current_frame as u64 * self.frame_size + self.cursor;
+        unsafe {
+            if let Some(map) = self.bundle.mapped {
+                let src = values.as_ptr() as *const u8;
+                std::ptr::copy_nonoverlapping(src, map.add(offset as usize), size as usize);
+            }
+        }
+        self.cursor += reserved;
+
+        Ok(offset as DescriptorSetOffset)
+    }
+
+    /// Makes this frame's writes visible to the GPU. Call it before submitting.
+    /// Only the blocks pushed this frame get flushed.
+    ///
+    /// # Safety
+    ///
+    /// The GPU mustn't be reading this frame's blocks while they're flushed.
+    pub unsafe fn flush(&self) -> Result<(), failure::Error> {
+        if self.cursor == 0 {
+            return Ok(());
+        }
+        let start = self.current_frame as u64 * self.frame_size;
+        self.bundle
+            .flush_range(start..start + self.cursor, self.atom_size)
+    }
+}
+
+impl<B: Backend> Drop for UniformRing<B> {
+    fn drop(&mut self) {
+        use core::ptr::read;
+        unsafe {
+            // Destroying the pool frees the set along with it.
+            let _ = manual_drop!(self.descriptor_set);
+            self.device
+                .destroy_descriptor_pool(manual_drop!(self.descriptor_pool));
+            self.device
+                .destroy_descriptor_set_layout(manual_drop!(self.descriptor_set_layout));
+        }
+        self.device.untrack("UniformRing");
+    }
+}
+
+/// The layout and pool `new` has made so far. If we bail out before the
+/// `UniformRing` exists, dropping this hands them back to the device.
+struct PartialRing<'a, B: Backend> {
+    device: &'a B::Device,
+    descriptor_set_layout: Option<B::DescriptorSetLayout>,
+    descriptor_pool: Option<B::DescriptorPool>,
+}
+
+impl<'a, B: Backend> PartialRing<'a, B> {
+    fn finish(mut self) -> (B::DescriptorSetLayout, B::DescriptorPool) {
+        (
+            self.descriptor_set_layout.take().unwrap(),
+            self.descriptor_pool.take().unwrap(),
+        )
+    }
+}
+
+impl<'a, B: Backend> Drop for PartialRing<'a, B> {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(descriptor_pool) = self.descriptor_pool.take() {
+                self.device.destroy_descriptor_pool(descriptor_pool);
+            }
+            if let Some(descriptor_set_layout) = self.descriptor_set_layout.take() {
+                self.device
+                    .destroy_descriptor_set_layout(descriptor_set_layout);
+            }
+        }
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UniformRing;
+    use crate::{
+        mock_backend::{self, Mock},
+        DeviceContext, ErrorKind,
+    };
+    use gfx_hal::pso::ShaderStageFlags;
+    use std::sync::Arc;
+
+    /// Blocks of 64 bytes, which the mock adapter aligns to 256.
+    fn ring(
+        device: &Arc<DeviceContext<Mock>>,
+        frames_in_flight: usize,
+        bytes_per_frame: u64,
+    ) -> Result<UniformRing<Mock>, failure::Error> {
+        UniformRing::new(
+            &mock_backend::adapter(),
+            device,
+            frames_in_flight,
+            bytes_per_frame,
+            64,
+            ShaderStageFlags::VERTEX,
+        )
+    }
+
+    #[test]
+    fn pushes_land_on_aligned_blocks_in_their_frame() {
+        let device = mock_backend::device();
+        let mut ring = ring(&device, 2, 3 * 64).unwrap();
+        assert_eq!(ring.frame_size, 3 * 256);
+
+        assert_eq!(ring.push(&[1.0f32; 4]).unwrap(), 0);
+        assert_eq!(ring.push(&[2.0f32; 4]).unwrap(), 256);
+
+        ring.begin_frame(1);
+        assert_eq!(ring.push(&[3.0f32; 4]).unwrap(), 3 * 256);
+
+        //  Frame 2 comes back round to the first region
+        ring.begin_frame(2);
+        assert_eq!(ring.push(&[4.0f32; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn flushing_only_flushes_what_this_frame_pushed() {
+        let device = mock_backend::device();
+        let mut ring = ring(&device, 2, 3 * 64).unwrap();
+
+        unsafe { ring.flush().unwrap() };
+        assert!(device.flushes().is_empty());
+
+        ring.begin_frame(1);
+        ring.push(&[1.0f32; 4]).unwrap();
+        ring.push(&[2.0f32; 4]).unwrap();
+        unsafe { ring.flush().unwrap() };
+        assert_eq!(device.flushes(), vec![3 * 256..5 * 256]);
+    }
+
+    #[test]
+    fn a_frame_holds_as_many_blocks_as_it_was_asked_for() {
+        let device = mock_backend::device();
+        let mut ring = ring(&device, 1, 6400).unwrap();
+
+        for _ in 0..100 {
+            ring.push(&[0u8; 64]).unwrap();
+        }
+        let error = ring.push(&[0u8; 64]).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::PoolExhausted);
+
+        let error = ring.push_slice(&[0u8; 65]).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::InvalidArgument);
+    }
+
+    #[test]
+    fn new_unwinds_from_every_failure() {
+        for &call in &[
+            "create_buffer",
+            "allocate_memory",
+            "map_memory",
+            "create_descriptor_set_layout",
+            "create_descriptor_pool",
+            "allocate_set",
+        ] {
+            let device = mock_backend::device();
+            device.inject_fault(call, 0);
+
+            assert!(ring(&device, 2, 256).is_err(), "{} didn't fail", call);
+            assert!(device.live_objects().is_empty(), "{} leaked", call);
+            assert!(device.live_resources().is_empty(), "{} leaked", call);
+        }
+
+        let device = mock_backend::device();
+        drop(ring(&device, 2, 256).unwrap());
+        assert!(device.live_objects().is_empty());
+    }
+
+    #[test]
+    fn recovery_makes_the_same_ring_on_the_new_device() {
+        let lost = mock_backend::device();
+        let mut ring = ring(&lost, 2, 3 * 64).unwrap();
+        ring.begin_frame(1);
+        ring.push(&[1.0f32; 4]).unwrap();
+
+        let device = mock_backend::device();
+        ring.recover(&mock_backend::adapter(), &device).unwrap();
+
+        assert!(Arc::ptr_eq(&ring.device, &device));
+        assert_eq!(ring.frame_size, 3 * 256);
+        assert_eq!(ring.push(&[2.0f32; 4]).unwrap(), 0);
+        assert!(lost.live_objects().is_empty());
+    }
+}
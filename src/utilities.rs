@@ -1,140 +1,1002 @@
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign},
+};
 
-#[derive(Debug, Clone, Copy, Default, PartialEq)]
-pub struct Vec2Int {
-    pub x: i32,
-    pub y: i32,
+/// The numbers a `Vec2` or `Vec3` can be made of. Every one of them fits in an
+/// `f64` exactly, which is how we convert between them.
+pub trait Scalar:
+    Copy
+    + PartialOrd
+    + Default
+    + Display
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+{
+    const ZERO: Self;
+    const ONE: Self;
+
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+    fn checked_mul(self, rhs: Self) -> Option<Self>;
+    fn checked_div(self, rhs: Self) -> Option<Self>;
+    fn saturating_add(self, rhs: Self) -> Self;
+    fn saturating_sub(self, rhs: Self) -> Self;
+    fn saturating_mul(self, rhs: Self) -> Self;
+
+    fn to_f64(self) -> f64;
+    /// `value` as one of us, if it's in range. Integers round towards zero.
+    fn from_f64(value: f64) -> Option<Self>;
+}
+
+macro_rules! integer_scalar {
+    ($($t:ident),*) => {$(
+        impl Scalar for $t {
+            const ZERO: $t = 0;
+            const ONE: $t = 1;
+
+            fn checked_add(self, rhs: $t) -> Option<$t> {
+                $t::checked_add(self, rhs)
+            }
+
+            fn checked_sub(self, rhs: $t) -> Option<$t> {
+                $t::checked_sub(self, rhs)
+            }
+
+            fn checked_mul(self, rhs: $t) -> Option<$t> {
+                $t::checked_mul(self, rhs)
+            }
+
+            fn checked_div(self, rhs: $t) -> Option<$t> {
+                $t::checked_div(self, rhs)
+            }
+
+            fn saturating_add(self, rhs: $t) -> $t {
+                $t::saturating_add(self, rhs)
+            }
+
+            fn saturating_sub(self, rhs: $t) -> $t {
+                $t::saturating_sub(self, rhs)
+            }
+
+            fn saturating_mul(self, rhs: $t) -> $t {
+                $t::saturating_mul(self, rhs)
+            }
+
+            fn to_f64(self) -> f64 {
+                f64::from(self)
+            }
+
+            fn from_f64(value: f64) -> Option<$t> {
+                //  NaN fails both of these
+                let value = value.trunc();
+                if value >= f64::from($t::MIN) && value <= f64::from($t::MAX) {
+                    Some(value as $t)
+                } else {
+                    None
+                }
+            }
+        }
+    )*};
+}
+
+integer_scalar!(i32, u32);
+
+//  Floats don't overflow, they go infinite, so that's what checking looks for.
+//  Saturating stops at the biggest finite float instead.
+impl Scalar for f32 {
+    const ZERO: f32 = 0.0;
+    const ONE: f32 = 1.0;
+
+    fn checked_add(self, rhs: f32) -> Option<f32> {
+        finite(self + rhs)
+    }
+
+    fn checked_sub(self, rhs: f32) -> Option<f32> {
+        finite(self - rhs)
+    }
+
+    fn checked_mul(self, rhs: f32) -> Option<f32> {
+        finite(self * rhs)
+    }
+
+    fn checked_div(self, rhs: f32) -> Option<f32> {
+        finite(self / rhs)
+    }
+
+    fn saturating_add(self, rhs: f32) -> f32 {
+        (self + rhs).clamp(f32::MIN, f32::MAX)
+    }
+
+    fn saturating_sub(self, rhs: f32) -> f32 {
+        (self - rhs).clamp(f32::MIN, f32::MAX)
+    }
+
+    fn saturating_mul(self, rhs: f32) -> f32 {
+        (self * rhs).clamp(f32::MIN, f32::MAX)
+    }
+
+    fn to_f64(self) -> f64 {
+        f64::from(self)
+    }
+
+    fn from_f64(value: f64) -> Option<f32> {
+        if value.is_finite() {
+            finite(value as f32)
+        } else {
+            None
+        }
+    }
 }
 
-impl Vec2Int {
-    pub fn new(x: i32, y: i32) -> Self {
-        Vec2Int { x, y }
+fn finite(value: f32) -> Option<f32> {
+    if value.is_finite() {
+        Some(value)
+    } else {
+        None
     }
+}
+
+//  `min`, `max` and `clamp`, for things that are only `PartialOrd`
 
-    pub fn clamp_components(&mut self, min_vec: &Vec2Int, max_vec: &Vec2Int) {
-        self.x = self.x.max(min_vec.x).min(max_vec.x);
-        self.y = self.y.max(min_vec.y).min(max_vec.y);
+fn lesser<T: PartialOrd>(a: T, b: T) -> T {
+    if b < a {
+        b
+    } else {
+        a
     }
+}
 
-    pub fn cwise_product(&self, other_vec: Vec2Int) -> Vec2Int {
-        let mut me: Vec2Int = self.clone();
-        me.x *= other_vec.x;
-        me.y *= other_vec.y;
-        me
+fn greater<T: PartialOrd>(a: T, b: T) -> T {
+    if b > a {
+        b
+    } else {
+        a
     }
+}
+
+/// Same as `value.max(min).min(max)`.
+fn clamp<T: PartialOrd>(value: T, min: T, max: T) -> T {
+    lesser(greater(value, min), max)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Vec2<T> {
+    pub x: T,
+    pub y: T,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Vec3<T> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
+}
 
-    pub fn cwise_div(&self, other_vec: Vec2Int) -> Vec2Int {
-        let mut me: Vec2Int = self.clone();
-        me.x /= other_vec.x;
-        me.y /= other_vec.y;
-        me
+/// Pixel positions and sizes, which is most of what we need vectors for.
+pub type Vec2Int = Vec2<i32>;
+
+impl<T> Vec2<T> {
+    pub const fn new(x: T, y: T) -> Self {
+        Vec2 { x, y }
+    }
+
+    pub fn extend(self, z: T) -> Vec3<T> {
+        Vec3::new(self.x, self.y, z)
+    }
+}
+
+impl<T> Vec3<T> {
+    pub const fn new(x: T, y: T, z: T) -> Self {
+        Vec3 { x, y, z }
     }
 
-    pub fn has_zero_dimension(&self) -> bool {
-        self.x == 0 || self.y == 0
+    pub fn truncate(self) -> Vec2<T> {
+        Vec2::new(self.x, self.y)
     }
 }
 
-impl Vec2Int {
+impl<T: Scalar> Vec2<T> {
     #[allow(dead_code)]
-    pub const ZERO: Vec2Int = Vec2Int { x: 0, y: 0 };
+    pub const ZERO: Self = Vec2 {
+        x: T::ZERO,
+        y: T::ZERO,
+    };
 
     #[allow(dead_code)]
-    pub const ONE: Vec2Int = Vec2Int { x: 1, y: 1 };
+    pub const ONE: Self = Vec2 {
+        x: T::ONE,
+        y: T::ONE,
+    };
 
     #[allow(dead_code)]
-    pub const UP: Vec2Int = Vec2Int { x: 0, y: 1 };
+    pub const UP: Self = Vec2 {
+        x: T::ZERO,
+        y: T::ONE,
+    };
 
     #[allow(dead_code)]
-    pub const RIGHT: Vec2Int = Vec2Int { x: 1, y: 0 };
+    pub const RIGHT: Self = Vec2 {
+        x: T::ONE,
+        y: T::ZERO,
+    };
+}
+
+impl<T: Scalar> Vec3<T> {
+    #[allow(dead_code)]
+    pub const ZERO: Self = Vec3 {
+        x: T::ZERO,
+        y: T::ZERO,
+        z: T::ZERO,
+    };
+
+    #[allow(dead_code)]
+    pub const ONE: Self = Vec3 {
+        x: T::ONE,
+        y: T::ONE,
+        z: T::ONE,
+    };
+}
+
+/// Everything `Vec2` and `Vec3` do the same way, one component at a time.
+macro_rules! vector {
+    ($name:ident { $($field:ident),+ }, $len:expr) => {
+        impl<T: Scalar> $name<T> {
+            pub fn splat(value: T) -> Self {
+                $name { $($field: value),+ }
+            }
+
+            pub fn clamp_components(&mut self, min_vec: &Self, max_vec: &Self) {
+                $(self.$field = clamp(self.$field, min_vec.$field, max_vec.$field);)+
+            }
+
+            pub fn cwise_product(&self, other_vec: Self) -> Self {
+                $name { $($field: self.$field * other_vec.$field),+ }
+            }
+
+            pub fn cwise_div(&self, other_vec: Self) -> Self {
+                $name { $($field: self.$field / other_vec.$field),+ }
+            }
+
+            pub fn cwise_min(&self, other_vec: Self) -> Self {
+                $name { $($field: lesser(self.$field, other_vec.$field)),+ }
+            }
+
+            pub fn cwise_max(&self, other_vec: Self) -> Self {
+                $name { $($field: greater(self.$field, other_vec.$field)),+ }
+            }
+
+            pub fn has_zero_dimension(&self) -> bool {
+                $(self.$field == T::ZERO)||+
+            }
+
+            //  The operators overflow like the numbers inside them do: a panic in
+            //  debug and wrapping in release, and dividing by zero always panics.
+            //  Anything working on numbers from outside should use these instead.
+
+            pub fn checked_add(self, rhs: Self) -> Option<Self> {
+                Some($name { $($field: self.$field.checked_add(rhs.$field)?),+ })
+            }
+
+            pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+                Some($name { $($field: self.$field.checked_sub(rhs.$field)?),+ })
+            }
+
+            pub fn checked_mul(self, rhs: T) -> Option<Self> {
+                Some($name { $($field: self.$field.checked_mul(rhs)?),+ })
+            }
+
+            pub fn checked_div(self, rhs: T) -> Option<Self> {
+                Some($name { $($field: self.$field.checked_div(rhs)?),+ })
+            }
+
+            pub fn checked_cwise_product(&self, other_vec: Self) -> Option<Self> {
+                Some($name { $($field: self.$field.checked_mul(other_vec.$field)?),+ })
+            }
+
+            pub fn checked_cwise_div(&self, other_vec: Self) -> Option<Self> {
+                Some($name { $($field: self.$field.checked_div(other_vec.$field)?),+ })
+            }
+
+            pub fn saturating_add(self, rhs: Self) -> Self {
+                $name { $($field: self.$field.saturating_add(rhs.$field)),+ }
+            }
+
+            pub fn saturating_sub(self, rhs: Self) -> Self {
+                $name { $($field: self.$field.saturating_sub(rhs.$field)),+ }
+            }
+
+            pub fn saturating_mul(self, rhs: T) -> Self {
+                $name { $($field: self.$field.saturating_mul(rhs)),+ }
+            }
+
+            pub fn saturating_cwise_product(&self, other_vec: Self) -> Self {
+                $name { $($field: self.$field.saturating_mul(other_vec.$field)),+ }
+            }
+
+            /// Converts every component to `U`, or gives `None` if any of them
+            /// don't fit. Floats round towards zero on the way to integers.
+            pub fn checked_cast<U: Scalar>(self) -> Option<$name<U>> {
+                Some($name { $($field: U::from_f64(self.$field.to_f64())?),+ })
+            }
+        }
+
+        impl $name<f32> {
+            pub fn dot(self, rhs: Self) -> f32 {
+                0.0 $(+ self.$field * rhs.$field)+
+            }
+
+            pub fn length_squared(self) -> f32 {
+                self.dot(self)
+            }
+
+            pub fn length(self) -> f32 {
+                self.length_squared().sqrt()
+            }
+
+            /// The same direction with a length of one, unless there's no
+            /// direction to speak of.
+            pub fn normalize(self) -> Option<Self> {
+                let length = self.length();
+                if length > 0.0 && length.is_finite() {
+                    Some(self / length)
+                } else {
+                    None
+                }
+            }
+        }
+
+        impl $name<i32> {
+            /// Exact for anything under 2^24, which is bigger than any texture.
+            pub fn as_f32(self) -> $name<f32> {
+                $name { $($field: self.$field as f32),+ }
+            }
+        }
+
+        impl $name<u32> {
+            /// Exact for anything under 2^24, which is bigger than any texture.
+            pub fn as_f32(self) -> $name<f32> {
+                $name { $($field: self.$field as f32),+ }
+            }
+        }
+
+        impl<T: Scalar> Add<$name<T>> for $name<T> {
+            type Output = $name<T>;
+
+            fn add(self, rhs: $name<T>) -> $name<T> {
+                $name { $($field: self.$field + rhs.$field),+ }
+            }
+        }
+
+        impl<T: Scalar> AddAssign<$name<T>> for $name<T> {
+            fn add_assign(&mut self, rhs: $name<T>) {
+                *self = *self + rhs;
+            }
+        }
+
+        impl<T: Scalar> Sub<$name<T>> for $name<T> {
+            type Output = $name<T>;
+
+            fn sub(self, rhs: $name<T>) -> $name<T> {
+                $name { $($field: self.$field - rhs.$field),+ }
+            }
+        }
+
+        impl<T: Scalar> SubAssign<$name<T>> for $name<T> {
+            fn sub_assign(&mut self, rhs: $name<T>) {
+                *self = *self - rhs;
+            }
+        }
+
+        impl<T: Scalar> Div<T> for $name<T> {
+            type Output = $name<T>;
+
+            fn div(self, rhs: T) -> $name<T> {
+                $name { $($field: self.$field / rhs),+ }
+            }
+        }
+
+        impl<T: Scalar> DivAssign<T> for $name<T> {
+            fn div_assign(&mut self, rhs: T) {
+                *self = *self / rhs;
+            }
+        }
+
+        impl<T: Scalar> Mul<T> for $name<T> {
+            type Output = $name<T>;
+
+            fn mul(self, rhs: T) -> $name<T> {
+                $name { $($field: self.$field * rhs),+ }
+            }
+        }
+
+        impl<T: Scalar> MulAssign<T> for $name<T> {
+            fn mul_assign(&mut self, rhs: T) {
+                *self = *self * rhs;
+            }
+        }
+
+        impl<T> From<[T; $len]> for $name<T> {
+            fn from(w: [T; $len]) -> $name<T> {
+                let [$($field),+] = w;
+                $name { $($field),+ }
+            }
+        }
+
+        impl<T> From<$name<T>> for [T; $len] {
+            fn from(w: $name<T>) -> [T; $len] {
+                [$(w.$field),+]
+            }
+        }
+    };
 }
 
-impl Display for Vec2Int {
+vector!(Vec2 { x, y }, 2);
+vector!(Vec3 { x, y, z }, 3);
+
+impl<T: Display> Display for Vec2<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "[{}, {}]", self.x, self.y)
     }
 }
 
-impl std::ops::Add<Vec2Int> for Vec2Int {
-    type Output = Vec2Int;
+impl<T: Display> Display for Vec3<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}, {}, {}]", self.x, self.y, self.z)
+    }
+}
 
-    fn add(self, rhs: Vec2Int) -> Vec2Int {
-        Vec2Int {
-            x: self.x + rhs.x,
-            y: self.y + rhs.y,
+impl From<Vec2<i32>> for gfx_hal::image::Offset {
+    fn from(w: Vec2<i32>) -> gfx_hal::image::Offset {
+        w.extend(0).into()
+    }
+}
+
+impl From<Vec3<i32>> for gfx_hal::image::Offset {
+    fn from(w: Vec3<i32>) -> gfx_hal::image::Offset {
+        gfx_hal::image::Offset {
+            x: w.x,
+            y: w.y,
+            z: w.z,
         }
     }
 }
 
-impl std::ops::AddAssign<Vec2Int> for Vec2Int {
-    fn add_assign(&mut self, rhs: Vec2Int) {
-        self.x += rhs.x;
-        self.y += rhs.y;
+impl From<gfx_hal::image::Offset> for Vec3<i32> {
+    fn from(offset: gfx_hal::image::Offset) -> Vec3<i32> {
+        Vec3::new(offset.x, offset.y, offset.z)
     }
 }
 
-impl std::ops::Sub<Vec2Int> for Vec2Int {
-    type Output = Vec2Int;
+impl From<Vec2<u32>> for gfx_hal::image::Extent {
+    fn from(w: Vec2<u32>) -> gfx_hal::image::Extent {
+        w.extend(1).into()
+    }
+}
 
-    fn sub(self, rhs: Vec2Int) -> Vec2Int {
-        Vec2Int {
-            x: self.x - rhs.x,
-            y: self.y - rhs.y,
+impl From<Vec3<u32>> for gfx_hal::image::Extent {
+    fn from(w: Vec3<u32>) -> gfx_hal::image::Extent {
+        gfx_hal::image::Extent {
+            width: w.x,
+            height: w.y,
+            depth: w.z,
         }
     }
 }
 
-impl std::ops::SubAssign<Vec2Int> for Vec2Int {
-    fn sub_assign(&mut self, rhs: Vec2Int) {
-        self.x -= rhs.x;
-        self.y -= rhs.y;
+impl From<gfx_hal::image::Extent> for Vec3<u32> {
+    fn from(extent: gfx_hal::image::Extent) -> Vec3<u32> {
+        Vec3::new(extent.width, extent.height, extent.depth)
     }
 }
 
-impl std::ops::Div<i32> for Vec2Int {
-    type Output = Vec2Int;
+/// A rectangle of whole pixels, from `min` up to but not including `max`.
+/// Anything with `max` not past `min` on both axes is empty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RectInt {
+    pub min: Vec2Int,
+    pub max: Vec2Int,
+}
 
-    fn div(self, rhs: i32) -> Vec2Int {
-        Vec2Int {
-            x: self.x / rhs,
-            y: self.y / rhs,
+impl RectInt {
+    pub fn new(origin: Vec2Int, size: Vec2Int) -> Self {
+        RectInt {
+            min: origin,
+            max: origin + size,
         }
     }
-}
 
-impl std::ops::DivAssign<i32> for Vec2Int {
-    fn div_assign(&mut self, rhs: i32) {
-        self.x = self.x / rhs;
-        self.y = self.y / rhs;
+    /// Like `new`, but gives `None` instead of overflowing, for rectangles that
+    /// come from outside.
+    pub fn checked_new(origin: Vec2Int, size: Vec2Int) -> Option<Self> {
+        Some(RectInt {
+            min: origin,
+            max: origin.checked_add(size)?,
+        })
     }
-}
 
-impl std::ops::Mul<i32> for Vec2Int {
-    type Output = Vec2Int;
+    pub fn from_min_max(min: Vec2Int, max: Vec2Int) -> Self {
+        RectInt { min, max }
+    }
+
+    pub fn origin(&self) -> Vec2Int {
+        self.min
+    }
+
+    pub fn size(&self) -> Vec2Int {
+        Vec2Int::new(self.width(), self.height())
+    }
+
+    pub fn width(&self) -> i32 {
+        self.max.x.saturating_sub(self.min.x).max(0)
+    }
+
+    pub fn height(&self) -> i32 {
+        self.max.y.saturating_sub(self.min.y).max(0)
+    }
+
+    pub fn area(&self) -> i64 {
+        self.width() as i64 * self.height() as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max.x <= self.min.x || self.max.y <= self.min.y
+    }
+
+    pub fn contains_point(&self, point: Vec2Int) -> bool {
+        point.x >= self.min.x
+            && point.x < self.max.x
+            && point.y >= self.min.y
+            && point.y < self.max.y
+    }
+
+    /// Whether every pixel of `other` is in here too. An empty rectangle is in everything.
+    pub fn contains(&self, other: &RectInt) -> bool {
+        other.is_empty()
+            || (other.min.x >= self.min.x
+                && other.min.y >= self.min.y
+                && other.max.x <= self.max.x
+                && other.max.y <= self.max.y)
+    }
+
+    /// The pixels in both rectangles, if there are any.
+    pub fn intersection(&self, other: &RectInt) -> Option<RectInt> {
+        let overlap = RectInt {
+            min: self.min.cwise_max(other.min),
+            max: self.max.cwise_min(other.max),
+        };
+        if overlap.is_empty() {
+            None
+        } else {
+            Some(overlap)
+        }
+    }
+
+    /// The smallest rectangle holding both. Empty rectangles don't count.
+    pub fn union(&self, other: &RectInt) -> RectInt {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+
+        RectInt {
+            min: self.min.cwise_min(other.min),
+            max: self.max.cwise_max(other.max),
+        }
+    }
+
+    /// Cuts the rectangle in two at `width` pixels from the left. The left half
+    /// comes first, and either can be empty.
+    pub fn split_x(&self, width: i32) -> (RectInt, RectInt) {
+        let at = (self.min.x + width.max(0)).min(self.max.x);
+        (
+            RectInt::from_min_max(self.min, Vec2Int::new(at, self.max.y)),
+            RectInt::from_min_max(Vec2Int::new(at, self.min.y), self.max),
+        )
+    }
+
+    /// Cuts the rectangle in two at `height` pixels from the top. The top half
+    /// comes first, and either can be empty.
+    pub fn split_y(&self, height: i32) -> (RectInt, RectInt) {
+        let at = (self.min.y + height.max(0)).min(self.max.y);
+        (
+            RectInt::from_min_max(self.min, Vec2Int::new(self.max.x, at)),
+            RectInt::from_min_max(Vec2Int::new(self.min.x, at), self.max),
+        )
+    }
+
+    /// The part of the rectangle inside `bounds`. If none of it is, that's an
+    /// empty rectangle on the edge of `bounds` nearest to it.
+    pub fn clamp_to(&self, bounds: &RectInt) -> RectInt {
+        let mut min = self.min;
+        let mut max = self.max;
+        min.clamp_components(&bounds.min, &bounds.max);
+        max.clamp_components(&min, &bounds.max);
+        RectInt { min, max }
+    }
 
-    fn mul(self, rhs: i32) -> Vec2Int {
-        Vec2Int {
-            x: self.x * rhs,
-            y: self.y * rhs,
+    /// Where the rectangle is in texture coordinates, for a texture of `size` pixels.
+    pub fn to_uv(self, size: Vec2Int) -> Rect {
+        let size = size.as_f32();
+        Rect {
+            min: self.min.as_f32().cwise_div(size),
+            max: self.max.as_f32().cwise_div(size),
         }
     }
+
+    pub fn offset(&self) -> gfx_hal::image::Offset {
+        self.min.into()
+    }
+
+    pub fn extent(&self) -> gfx_hal::image::Extent {
+        Vec2::new(self.width() as u32, self.height() as u32).into()
+    }
+
+    /// The rectangle as a viewport or scissor, if it fits in one.
+    pub fn to_pso_rect(self) -> Option<gfx_hal::pso::Rect> {
+        use std::convert::TryFrom;
+        Some(gfx_hal::pso::Rect {
+            x: i16::try_from(self.min.x).ok()?,
+            y: i16::try_from(self.min.y).ok()?,
+            w: i16::try_from(self.width()).ok()?,
+            h: i16::try_from(self.height()).ok()?,
+        })
+    }
 }
 
-impl std::ops::MulAssign<i32> for Vec2Int {
-    fn mul_assign(&mut self, rhs: i32) {
-        self.x = self.x * rhs;
-        self.y = self.y * rhs;
+impl Display for RectInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.min, self.max)
     }
 }
 
-impl From<[i32; 2]> for Vec2Int {
-    fn from(w: [i32; 2]) -> Vec2Int {
-        Vec2Int { x: w[0], y: w[1] }
+impl From<gfx_hal::pso::Rect> for RectInt {
+    fn from(rect: gfx_hal::pso::Rect) -> RectInt {
+        RectInt::new(
+            Vec2Int::new(rect.x as i32, rect.y as i32),
+            Vec2Int::new(rect.w as i32, rect.h as i32),
+        )
     }
 }
 
-impl From<Vec2Int> for [i32; 2] {
-    fn from(w: Vec2Int) -> [i32; 2] {
-        [w.x, w.y]
+/// A rectangle in floating point, like a UV rectangle, from `min` to `max`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rect {
+    pub min: Vec2<f32>,
+    pub max: Vec2<f32>,
+}
+
+impl Rect {
+    pub fn new(origin: Vec2<f32>, size: Vec2<f32>) -> Self {
+        Rect {
+            min: origin,
+            max: origin + size,
+        }
+    }
+
+    pub fn from_min_max(min: Vec2<f32>, max: Vec2<f32>) -> Self {
+        Rect { min, max }
+    }
+
+    pub fn size(&self) -> Vec2<f32> {
+        Vec2::new(self.width(), self.height())
+    }
+
+    pub fn width(&self) -> f32 {
+        (self.max.x - self.min.x).max(0.0)
+    }
+
+    pub fn height(&self) -> f32 {
+        (self.max.y - self.min.y).max(0.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max.x <= self.min.x || self.max.y <= self.min.y
+    }
+
+    pub fn contains_point(&self, point: Vec2<f32>) -> bool {
+        point.x >= self.min.x
+            && point.x < self.max.x
+            && point.y >= self.min.y
+            && point.y < self.max.y
+    }
+
+    pub fn contains(&self, other: &Rect) -> bool {
+        other.is_empty()
+            || (other.min.x >= self.min.x
+                && other.min.y >= self.min.y
+                && other.max.x <= self.max.x
+                && other.max.y <= self.max.y)
+    }
+
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let overlap = Rect {
+            min: self.min.cwise_max(other.min),
+            max: self.max.cwise_min(other.max),
+        };
+        if overlap.is_empty() {
+            None
+        } else {
+            Some(overlap)
+        }
+    }
+
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+
+        Rect {
+            min: self.min.cwise_min(other.min),
+            max: self.max.cwise_max(other.max),
+        }
+    }
+
+    pub fn clamp_to(&self, bounds: &Rect) -> Rect {
+        let mut min = self.min;
+        let mut max = self.max;
+        min.clamp_components(&bounds.min, &bounds.max);
+        max.clamp_components(&min, &bounds.max);
+        Rect { min, max }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rect, RectInt, Vec2, Vec2Int, Vec3};
+    use proptest::prelude::*;
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> RectInt {
+        RectInt::new(Vec2Int::new(x, y), Vec2Int::new(width, height))
+    }
+
+    #[test]
+    fn huge_rects_saturate_instead_of_overflowing() {
+        let everything = RectInt::from_min_max(
+            Vec2Int::new(i32::MIN, i32::MIN),
+            Vec2Int::new(i32::MAX, i32::MAX),
+        );
+
+        assert_eq!(everything.width(), i32::MAX);
+        assert_eq!(everything.height(), i32::MAX);
+        assert_eq!(everything.area(), i32::MAX as i64 * i32::MAX as i64);
+    }
+
+    #[test]
+    fn rects_that_only_touch_dont_intersect() {
+        let a = rect(0, 0, 4, 4);
+
+        assert_eq!(a.intersection(&rect(4, 0, 4, 4)), None);
+        assert_eq!(a.intersection(&rect(0, 4, 4, 4)), None);
+        assert_eq!(a.intersection(&rect(3, 3, 4, 4)), Some(rect(3, 3, 1, 1)));
+        assert_eq!(a.union(&rect(4, 4, 1, 1)), rect(0, 0, 5, 5));
+        assert_eq!(a.union(&rect(9, 9, 0, 3)), a);
+    }
+
+    #[test]
+    fn containment_stops_at_max() {
+        let a = rect(2, 2, 4, 4);
+
+        assert!(a.contains_point(Vec2Int::new(2, 2)));
+        assert!(a.contains_point(Vec2Int::new(5, 5)));
+        assert!(!a.contains_point(Vec2Int::new(6, 5)));
+        assert!(a.contains(&rect(2, 2, 4, 4)));
+        assert!(!a.contains(&rect(3, 3, 4, 1)));
+        assert!(a.contains(&rect(100, 100, 0, 0)));
+    }
+
+    #[test]
+    fn splitting_and_clamping_stay_inside() {
+        let a = rect(0, 0, 10, 6);
+
+        assert_eq!(a.split_x(4), (rect(0, 0, 4, 6), rect(4, 0, 6, 6)));
+        assert_eq!(a.split_y(20), (a, rect(0, 6, 10, 0)));
+        assert_eq!(rect(-2, 4, 5, 5).clamp_to(&a), rect(0, 4, 3, 2));
+        assert!(rect(20, 20, 5, 5).clamp_to(&a).is_empty());
+        assert!(a.contains(&rect(20, 20, 5, 5).clamp_to(&a)));
+    }
+
+    #[test]
+    fn converts_to_gfx_hal_and_uvs() {
+        let a = rect(4, 8, 12, 8);
+
+        assert_eq!((a.offset().x, a.offset().y), (4, 8));
+        assert_eq!((a.extent().width, a.extent().height), (12, 8));
+        assert_eq!(RectInt::from(a.to_pso_rect().unwrap()), a);
+        assert_eq!(rect(0, 0, 40_000, 1).to_pso_rect(), None);
+        assert_eq!(
+            a.to_uv(Vec2Int::new(16, 16)),
+            Rect::from_min_max(Vec2::new(0.25, 0.5), Vec2::new(1.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn checked_arithmetic_catches_overflow_and_saturating_stops_at_the_edge() {
+        let big = Vec2::new(i32::MAX, 1);
+
+        assert_eq!(big.checked_add(Vec2::new(1, 0)), None);
+        assert_eq!(big.checked_sub(Vec2::ONE), Some(Vec2::new(i32::MAX - 1, 0)));
+        assert_eq!(big.saturating_add(Vec2::ONE), Vec2::new(i32::MAX, 2));
+        assert_eq!(
+            Vec2::new(1u32, 5).saturating_sub(Vec2::new(2, 2)),
+            Vec2::new(0, 3)
+        );
+        assert_eq!(Vec3::new(4, 6, 8).checked_div(0), None);
+        assert_eq!(Vec2::new(f32::MAX, 0.0).checked_mul(2.0), None);
+        assert_eq!(
+            Vec2::new(f32::MAX, 0.0).saturating_mul(2.0),
+            Vec2::new(f32::MAX, 0.0)
+        );
+    }
+
+    #[test]
+    fn float_vectors_have_lengths_and_directions() {
+        let v = Vec2::new(3.0, 4.0);
+
+        assert_eq!(v.dot(Vec2::new(1.0, 1.0)), 7.0);
+        assert_eq!(v.length(), 5.0);
+        assert_eq!(v.normalize(), Some(Vec2::new(0.6, 0.8)));
+        assert_eq!(Vec3::<f32>::ZERO.normalize(), None);
+    }
+
+    #[test]
+    fn casts_check_every_component() {
+        assert_eq!(Vec2::new(-1, 2).checked_cast::<u32>(), None);
+        assert_eq!(Vec2::new(u32::MAX, 2).checked_cast::<i32>(), None);
+        assert_eq!(
+            Vec2::new(2.9f32, -2.9).checked_cast::<i32>(),
+            Some(Vec2::new(2, -2))
+        );
+        assert_eq!(Vec2::new(f32::NAN, 0.0).checked_cast::<i32>(), None);
+        assert_eq!(
+            Vec2::new(7u32, 9).checked_cast::<f32>(),
+            Some(Vec2::new(7.0, 9.0))
+        );
+        assert_eq!(Vec2::new(1, 2).extend(3).truncate(), Vec2::new(1, 2));
+        assert_eq!(Vec3::from([1, 2, 3]), Vec3::new(1, 2, 3));
+    }
+
+    #[test]
+    fn converts_to_offsets_and_extents() {
+        let offset: gfx_hal::image::Offset = Vec2Int::new(-3, 4).into();
+        let extent: gfx_hal::image::Extent = Vec2::new(16u32, 8).into();
+
+        assert_eq!(Vec3::from(offset), Vec3::new(-3, 4, 0));
+        assert_eq!(Vec3::from(extent), Vec3::new(16, 8, 1));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn vectors_and_rects_round_trip() {
+        let rect = RectInt::new(Vec2Int::new(-1, 2), Vec2Int::new(3, 4));
+        let json = serde_json::to_string(&rect).unwrap();
+
+        assert_eq!(json, r#"{"min":{"x":-1,"y":2},"max":{"x":2,"y":6}}"#);
+        assert_eq!(serde_json::from_str::<RectInt>(&json).unwrap(), rect);
+        assert_eq!(
+            serde_json::from_str::<Vec3<f32>>(r#"{"x":0.5,"y":1,"z":2}"#).unwrap(),
+            Vec3::new(0.5, 1.0, 2.0)
+        );
+    }
+
+    fn vec2() -> impl Strategy<Value = Vec2Int> {
+        (any::<i32>(), any::<i32>()).prop_map(|(x, y)| Vec2Int::new(x, y))
+    }
+
+    /// Small enough that adding or multiplying two of them can't overflow.
+    fn small_vec2() -> impl Strategy<Value = Vec2Int> {
+        (-46_340..46_340, -46_340..46_340).prop_map(|(x, y)| Vec2Int::new(x, y))
+    }
+
+    /// What `op` gives on each component in 64 bits, if that fits back in an `i32`.
+    fn wide(a: Vec2Int, b: Vec2Int, op: impl Fn(i64, i64) -> Option<i64>) -> Option<Vec2Int> {
+        let component = |a: i32, b: i32| {
+            op(a as i64, b as i64).and_then(|c| {
+                if c >= i32::MIN as i64 && c <= i32::MAX as i64 {
+                    Some(c as i32)
+                } else {
+                    None
+                }
+            })
+        };
+        Some(Vec2Int::new(component(a.x, b.x)?, component(a.y, b.y)?))
+    }
+
+    proptest! {
+        #[test]
+        fn checked_add_and_sub_fail_exactly_when_they_would_overflow(a in vec2(), b in vec2()) {
+            prop_assert_eq!(a.checked_add(b), wide(a, b, |a, b| Some(a + b)));
+            prop_assert_eq!(a.checked_sub(b), wide(a, b, |a, b| Some(a - b)));
+            if let Some(sum) = a.checked_add(b) {
+                prop_assert_eq!(sum, a + b);
+                prop_assert_eq!(sum - b, a);
+            }
+        }
+
+        #[test]
+        fn checked_products_fail_exactly_when_they_would_overflow(a in vec2(), b in vec2()) {
+            prop_assert_eq!(a.checked_cwise_product(b), wide(a, b, |a, b| Some(a * b)));
+            prop_assert_eq!(a.checked_mul(b.x), wide(a, Vec2::splat(b.x), |a, b| Some(a * b)));
+            if let Some(product) = a.checked_cwise_product(b) {
+                prop_assert_eq!(product, a.cwise_product(b));
+            }
+        }
+
+        #[test]
+        fn checked_divs_fail_on_zero_and_overflow(a in vec2(), b in vec2()) {
+            let divided = wide(a, b, |a, b| if b == 0 { None } else { Some(a / b) });
+            prop_assert_eq!(a.checked_cwise_div(b), divided);
+            prop_assert_eq!(a.checked_div(b.x), wide(a, Vec2::splat(b.x), |a, b| a.checked_div(b)));
+            if let Some(quotient) = a.checked_cwise_div(b) {
+                prop_assert_eq!(quotient, a.cwise_div(b));
+            }
+        }
+
+        #[test]
+        fn saturating_ops_stop_at_the_edge(a in vec2(), b in vec2()) {
+            let clamped = |c: i64| c.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+            let sum = a.saturating_add(b);
+            prop_assert_eq!(sum.x, clamped(a.x as i64 + b.x as i64));
+            prop_assert_eq!(sum.y, clamped(a.y as i64 + b.y as i64));
+            let product = a.saturating_cwise_product(b);
+            prop_assert_eq!(product.x, clamped(a.x as i64 * b.x as i64));
+            prop_assert_eq!(product.y, clamped(a.y as i64 * b.y as i64));
+        }
+
+        #[test]
+        fn operators_match_their_components(
+            a in small_vec2(),
+            b in small_vec2(),
+            k in -46_340..46_340,
+        ) {
+            prop_assert_eq!(a + b, Vec2Int::new(a.x + b.x, a.y + b.y));
+            prop_assert_eq!(a - b, Vec2Int::new(a.x - b.x, a.y - b.y));
+            prop_assert_eq!(a * k, Vec2Int::new(a.x * k, a.y * k));
+            prop_assert_eq!(a.cwise_product(b), Vec2Int::new(a.x * b.x, a.y * b.y));
+
+            let mut assigned = a;
+            assigned += b;
+            assigned -= b;
+            prop_assert_eq!(assigned, a);
+            if k != 0 {
+                prop_assert_eq!(a / k, Vec2Int::new(a.x / k, a.y / k));
+                let mut scaled = a;
+                scaled *= k;
+                scaled /= k;
+                prop_assert_eq!(scaled, a);
+            }
+        }
+
+        #[test]
+        fn clamp_components_lands_inside_and_leaves_the_inside_alone(
+            v in vec2(),
+            a in vec2(),
+            b in vec2(),
+        ) {
+            let (min, max) = (a.cwise_min(b), a.cwise_max(b));
+            let mut clamped = v;
+            clamped.clamp_components(&min, &max);
+
+            prop_assert!(clamped.x >= min.x && clamped.x <= max.x);
+            prop_assert!(clamped.y >= min.y && clamped.y <= max.y);
+
+            let mut again = clamped;
+            again.clamp_components(&min, &max);
+            prop_assert_eq!(again, clamped);
+            if v.x >= min.x && v.x <= max.x && v.y >= min.y && v.y <= max.y {
+                prop_assert_eq!(clamped, v);
+            }
+        }
     }
 }